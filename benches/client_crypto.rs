@@ -10,7 +10,9 @@ extern crate test;
 use std::time::Duration;
 use criterion::Bencher;
 use pung::client::pcrypto::*;
+use pung::util;
 use pung::util::bloomfilter;
+use pung::util::rle;
 use pung::db;
 use rand::ChaChaRng;
 use rand::Rng;
@@ -37,7 +39,7 @@ fn bench_derive_keys() {
         rng.fill_bytes(&mut secret);
         
         b.iter(move || {
-            test::black_box(derive_keys(&secret));
+            test::black_box(derive_keys(&secret, AeadAlgorithm::default()));
         });
     }
 
@@ -55,7 +57,7 @@ fn bench_gen_label() {
         let mut secret = [0u8; 32];
         rng.fill_bytes(&mut secret);
 
-        let keys = derive_keys(&secret);
+        let keys = derive_keys(&secret, AeadAlgorithm::default());
         let round = 0;
         let uid = 0;
         let msg_num = 0;
@@ -70,64 +72,186 @@ fn bench_gen_label() {
     bmark.bench_function("bench_gen_label", bench_gen_label);
 }
 
+macro_rules! label_prf {
+    ($name:ident, $prf:expr) => (
+        #[test]
+        fn $name() {
+            fn $name(b: &mut Bencher) {
+
+                let mut rng =ChaChaRng::new_unseeded();
+                let mut secret = [0u8; 32];
+                rng.fill_bytes(&mut secret);
+
+                let keys = derive_keys(&secret, AeadAlgorithm::default());
+
+                b.iter(move || {
+                    test::black_box(gen_label_with($prf, &keys.k_l[..], 0, 0, 0, 0));
+                });
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($name), $name);
+        }
+    )
+}
+
+// bench_gen_label measures HMAC-SHA256 (the default)
+label_prf!(bench_gen_label_blake2b, LabelPrf::Blake2b);
+label_prf!(bench_gen_label_blake2s, LabelPrf::Blake2s);
+
+macro_rules! aead {
+    ($encrypt:ident, $decrypt:ident, $aead:expr) => (
+        #[test]
+        fn $encrypt() {
+            fn $encrypt(b: &mut Bencher) {
+
+                let mut rng =ChaChaRng::new_unseeded();
+                let mut secret = [0u8; 32];
+                rng.fill_bytes(&mut secret);
+
+                let keys = derive_keys(&secret, $aead);
+                let round = 0;
+
+                let mut message = [0u8; MESSAGE_SIZE];
+                rng.fill_bytes(&mut message);
+
+                b.iter(move || {
+                    test::black_box(encrypt($aead, &keys.k_e[..], round, 0, 0, &message));
+                });
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($encrypt), $encrypt);
+        }
+
+        #[test]
+        fn $decrypt() {
+            fn $decrypt(b: &mut Bencher) {
+
+                let mut rng =ChaChaRng::new_unseeded();
+                let mut secret = [0u8; 32];
+                rng.fill_bytes(&mut secret);
+
+                let keys = derive_keys(&secret, $aead);
+                let round = 0;
+
+                let mut message = [0u8; MESSAGE_SIZE];
+                rng.fill_bytes(&mut message);
+
+                let c = encrypt($aead, &keys.k_e[..], round, 0, 0, &message);
+
+                b.iter(move || {
+                    test::black_box(decrypt($aead, &keys.k_e[..], round, 0, 0, &c.0[..], &c.1[..]).unwrap());
+                });
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($decrypt), $decrypt);
+        }
+    )
+}
+
+// ChaCha20Poly1305 keeps the original benchmark names
+aead!(bench_encrypt, bench_decrypt, AeadAlgorithm::ChaCha20Poly1305);
+aead!(bench_encrypt_aes_gcm, bench_decrypt_aes_gcm, AeadAlgorithm::AesGcm);
+
+// A round's worth of messages, encrypted the way a client with a high send rate would
+const BATCH: usize = 64;
+
 #[test]
-fn bench_encrypt() {
-    fn bench_encrypt(b: &mut Bencher) {
+fn bench_encrypt_batch() {
+    fn bench_encrypt_batch(b: &mut Bencher) {
 
         let mut rng =ChaChaRng::new_unseeded();
         let mut secret = [0u8; 32];
         rng.fill_bytes(&mut secret);
 
-        let keys = derive_keys(&secret);
-        let round = 0;
+        let keys = derive_keys(&secret, AeadAlgorithm::default());
 
         let mut message = [0u8; MESSAGE_SIZE];
         rng.fill_bytes(&mut message);
 
+        // Allocates the ciphertext, tag, and padding of every message
         b.iter(move || {
-            test::black_box(encrypt(&keys.k_e[..], round, &message));
+            for _ in 0..BATCH {
+                test::black_box(encrypt(AeadAlgorithm::default(), &keys.k_e[..], 0, 0, 0, &message));
+            }
         });
     }
 
     let mut bmark = bmark_settings!();
-    bmark.bench_function("bench_encrypt", bench_encrypt);
+    bmark.bench_function("bench_encrypt_batch", bench_encrypt_batch);
 }
 
 #[test]
-fn bench_decrypt() {
-    fn bench_decrypt(b: &mut Bencher) {
+fn bench_encrypt_into_batch() {
+    fn bench_encrypt_into_batch(b: &mut Bencher) {
 
         let mut rng =ChaChaRng::new_unseeded();
         let mut secret = [0u8; 32];
         rng.fill_bytes(&mut secret);
 
-        let keys = derive_keys(&secret);
-        let round = 0;
+        let keys = derive_keys(&secret, AeadAlgorithm::default());
 
         let mut message = [0u8; MESSAGE_SIZE];
         rng.fill_bytes(&mut message);
 
-        let c = encrypt(&keys.k_e[..], round, &message);
+        let mut encryptor = Encryptor::new();
+        let mut c = vec![0u8; MESSAGE_SIZE];
+        let mut mac = vec![0u8; db::MAC_SIZE];
 
+        // Reuses the same buffers for the whole batch
         b.iter(move || {
-            test::black_box(decrypt(&keys.k_e[..], round, &c.0[..], &c.1[..]).unwrap());
+            for i in 0..BATCH {
+                // Messages of a batch are numbered, as they are when a client sends them
+                let (aead, k_e) = (AeadAlgorithm::default(), &keys.k_e[..]);
+                encryptor.encrypt_into(aead, k_e, 0, 0, i as u64, &message, &mut c, &mut mac);
+                test::black_box(&mac);
+            }
         });
     }
 
     let mut bmark = bmark_settings!();
-    bmark.bench_function("bench_decrypt", bench_decrypt);
+    bmark.bench_function("bench_encrypt_into_batch", bench_encrypt_into_batch);
 }
 
 macro_rules! bloom_filter {
-    ($name: ident, $num:expr) => (
+    ($name: ident, $num:expr, $fp:expr) => (
         #[test]
         fn $name() {
 
             // Measure network / memory
             {
                 println!("----------------PUNG BLOOMFILTER RESULT--------------\n");
-                let bloom_test = bloomfilter::Bloom::new_for_fp_rate($num, db::BLOOM_FP); 
+                let mut rng = ChaChaRng::new_unseeded();
+                let mut bloom_test = bloomfilter::Bloom::new_for_fp_rate($num, $fp); 
+
+                // Populate the filter as the server would
+                for i in 0..($num as usize) {
+                    let mut label = [0u8; db::LABEL_SIZE];
+                    rng.fill_bytes(&mut label);
+                    bloom_test.set((i, &label[..]));
+                }
+
+                // Share of labels that are not in the filter but pass its check
+                let mut false_positives = 0;
+
+                for i in 0..($num as usize) {
+                    let mut label = [0u8; db::LABEL_SIZE];
+                    rng.fill_bytes(&mut label);
+
+                    if bloom_test.check((i, &label[..])) {
+                        false_positives += 1;
+                    }
+                }
+
+                let raw = bloom_test.to_bytes();
+                let compressed = rle::compress(&raw);
+
                 println!("{} bloomfilter size: {} bytes", stringify!($name), bloom_test.number_of_bits()/8);
+                println!("{} compressed bloomfilter size: {} bytes", stringify!($name), compressed.len());
+                println!("{} false positive rate: {} (configured {})", stringify!($name),
+                         false_positives as f64 / $num as f64, $fp);
                 println!("-----------------------------------------------------\n");
             }
 
@@ -137,7 +261,7 @@ macro_rules! bloom_filter {
 
                 b.iter_with_setup(|| {
 
-                    let mut bloom = bloomfilter::Bloom::new_for_fp_rate($num, db::BLOOM_FP);
+                    let mut bloom = bloomfilter::Bloom::new_for_fp_rate($num, $fp);
                     let mut label = [0u8; db::LABEL_SIZE];
                     rng.fill_bytes(&mut label);
 
@@ -171,7 +295,45 @@ macro_rules! bloom_filter {
     )
 }
 
-bloom_filter!(bench_bloom_filter_2048, 2048);
-bloom_filter!(bench_bloom_filter_8192, 8192);
-bloom_filter!(bench_bloom_filter_32768, 32768);
-bloom_filter!(bench_bloom_filter_131072, 131072);
+bloom_filter!(bench_bloom_filter_2048, 2048, db::BLOOM_FP);
+bloom_filter!(bench_bloom_filter_8192, 8192, db::BLOOM_FP);
+bloom_filter!(bench_bloom_filter_32768, 32768, db::BLOOM_FP);
+bloom_filter!(bench_bloom_filter_131072, 131072, db::BLOOM_FP);
+
+// Size against accuracy (see the server's --bloom-fp)
+bloom_filter!(bench_bloom_filter_fp_1e3_32768, 32768, 0.001);
+bloom_filter!(bench_bloom_filter_fp_1e7_32768, 32768, 0.0000001);
+
+// Cost of looking up a label that is not in the filter (the client then checks every index)
+// for a given number of hash functions, as set with the server's --bloom-hashes
+macro_rules! bloom_hashes {
+    ($name: ident, $num:expr, $hashes:expr) => (
+        #[test]
+        fn $name() {
+            fn $name(b: &mut Bencher) {
+                let mut rng = ChaChaRng::new_unseeded();
+                let mut bloom = db::new_bloom($num, $hashes);
+
+                for i in 0..($num as usize) {
+                    let mut label = [0u8; db::LABEL_SIZE];
+                    rng.fill_bytes(&mut label);
+                    bloom.set((i, &label[..]));
+                }
+
+                let mut label = [0u8; db::LABEL_SIZE];
+                rng.fill_bytes(&mut label);
+
+                b.iter(|| test::black_box(util::get_idx_bloom(&bloom, &label[..], $num as u64)));
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($name), $name);
+        }
+    )
+}
+
+bloom_hashes!(bench_bloom_hashes_optimal_8192, 8192, None);
+bloom_hashes!(bench_bloom_hashes_1_8192, 8192, Some(1));
+bloom_hashes!(bench_bloom_hashes_2_8192, 8192, Some(2));
+bloom_hashes!(bench_bloom_hashes_4_8192, 8192, Some(4));
+bloom_hashes!(bench_bloom_hashes_8_8192, 8192, Some(8));
@@ -152,3 +152,188 @@ fn db_sort_bst_50k() {
     let mut bmark = bmark_settings!();
     bmark.bench_function("db_sort_bst_50k", db_sort_bst_50k);
 }
+
+macro_rules! db_push {
+    ($push:ident, $push_batch:ident, $len:expr, $buckets:expr) => (
+        #[test]
+        fn $push() {
+            fn $push(b: &mut Bencher) {
+                let mut set = Vec::with_capacity($len);
+                create_db($len, 1, &mut set);
+
+                let tuples: Vec<db::PungTuple> = set.iter().map(|t| (**t).clone()).collect();
+                let partitions = pung::util::Partitioning::new($buckets).unwrap();
+
+                // One tuple at a time, as the server used to build its database
+                b.iter_with_setup(|| tuples.clone(), |data| {
+                    let scheme = db::OptScheme::Normal;
+                    let mut dbase =
+                        db::Database::new(db::RetScheme::Explicit, scheme, $buckets, 1).unwrap();
+
+                    for tuple in data {
+                        let i = partitions.bucket_of(tuple.label());
+                        dbase.push_with_ttl(i, tuple, 0);
+                    }
+                });
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($push), $push);
+        }
+
+        #[test]
+        fn $push_batch() {
+            fn $push_batch(b: &mut Bencher) {
+                let mut set = Vec::with_capacity($len);
+                create_db($len, 1, &mut set);
+
+                let tuples: Vec<db::PungTuple> = set.iter().map(|t| (**t).clone()).collect();
+
+                b.iter_with_setup(|| tuples.clone(), |data| {
+                    let scheme = db::OptScheme::Normal;
+                    let mut dbase =
+                        db::Database::new(db::RetScheme::Explicit, scheme, $buckets, 1).unwrap();
+
+                    dbase.push_batch(data);
+                });
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($push_batch), $push_batch);
+        }
+    )
+}
+
+db_push!(db_push_500k, db_push_batch_500k, 500000, 64);
+db_push!(db_push_50k, db_push_batch_50k, 50000, 64);
+
+// The label comparison used before util::label_cmp became a slice compare: it reinterprets
+// each label as [u64; 4], which is only sound for 32-byte, 8-byte aligned labels.
+#[inline]
+fn label_cmp_cast(l1: &[u8], l2: &[u8]) -> std::cmp::Ordering {
+    debug_assert!(l1.len() == db::LABEL_SIZE && l2.len() == db::LABEL_SIZE);
+    debug_assert!(l1.as_ptr() as usize % 8 == 0 && l2.as_ptr() as usize % 8 == 0);
+
+    unsafe {
+        (&*(l1 as *const [u8] as *const [u64; 4])).cmp(&*(l2 as *const [u8] as *const [u64; 4]))
+    }
+}
+
+macro_rules! label_cmp {
+    ($name:ident, $cmp:path, $len:expr) => (
+        #[test]
+        fn $name() {
+            fn $name(b: &mut Bencher) {
+                let labels: Vec<Vec<u8>> =
+                    (0..$len).map(|_| rand::random::<[u8; 32]>().to_vec()).collect();
+
+                b.iter_with_setup(|| labels.clone(), |mut data| {
+                    data.sort_by(|l1, l2| $cmp(&l1[..], &l2[..]))
+                });
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($name), $name);
+        }
+    )
+}
+
+label_cmp!(label_cmp_50k, pung::util::label_cmp, 50000);
+label_cmp!(label_cmp_cast_50k, label_cmp_cast, 50000);
+
+// Encodes a database of $buckets buckets of $len tuples each, one bucket after another (as
+// the server used to) or all of them in parallel (Database::encode)
+macro_rules! db_encode {
+    ($serial:ident, $parallel:ident, $len:expr, $buckets:expr, $opt_scheme:expr) => (
+        #[test]
+        fn $serial() {
+            fn $serial(b: &mut Bencher) {
+                let mut set = Vec::with_capacity($len * $buckets);
+                create_db($len * $buckets, 1, &mut set);
+
+                let tuples: Vec<db::PungTuple> = set.iter().map(|t| (**t).clone()).collect();
+
+                b.iter_with_setup(|| {
+                    let mut dbase =
+                        db::Database::new(db::RetScheme::Tree, $opt_scheme, $buckets, 1).unwrap();
+                    dbase.push_batch(tuples.clone());
+                    dbase
+                }, |mut dbase| {
+                    for bucket in dbase.get_buckets_mut() {
+                        bucket.encode().unwrap();
+                    }
+                });
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($serial), $serial);
+        }
+
+        #[test]
+        fn $parallel() {
+            fn $parallel(b: &mut Bencher) {
+                let mut set = Vec::with_capacity($len * $buckets);
+                create_db($len * $buckets, 1, &mut set);
+
+                let tuples: Vec<db::PungTuple> = set.iter().map(|t| (**t).clone()).collect();
+
+                b.iter_with_setup(|| {
+                    let mut dbase =
+                        db::Database::new(db::RetScheme::Tree, $opt_scheme, $buckets, 1).unwrap();
+                    dbase.push_batch(tuples.clone());
+                    dbase
+                }, |mut dbase| dbase.encode().unwrap());
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($parallel), $parallel);
+        }
+    )
+}
+
+db_encode!(db_encode_serial_16x50k, db_encode_parallel_16x50k, 50000, 16, db::OptScheme::Normal);
+db_encode!(db_encode_serial_h4_16x50k, db_encode_parallel_h4_16x50k, 50000, 16,
+           db::OptScheme::Hybrid4);
+
+// XOR of tuples byte by byte, as PungTuple's BitXor used to be
+fn tuple_xor_bytes(t1: &db::PungTuple, t2: &db::PungTuple) -> db::PungTuple {
+    let mut xored = t1.clone();
+
+    for (x, b) in xored.data.iter_mut().zip(t2.data.iter()) {
+        *x ^= *b;
+    }
+
+    xored
+}
+
+fn tuple_xor_words(t1: &db::PungTuple, t2: &db::PungTuple) -> db::PungTuple {
+    t1 ^ t2
+}
+
+macro_rules! tuple_xor {
+    ($name:ident, $xor:path, $len:expr) => (
+        #[test]
+        fn $name() {
+            fn $name(b: &mut Bencher) {
+                let mut set = Vec::with_capacity($len);
+                create_db($len, 1, &mut set);
+
+                let tuples: Vec<db::PungTuple> = set.iter().map(|t| (**t).clone()).collect();
+
+                // Both XORs give the same tuples
+                for pair in tuples.windows(2).take(1000) {
+                    assert!(tuple_xor_bytes(&pair[0], &pair[1])
+                        .full_eq(&tuple_xor_words(&pair[0], &pair[1])));
+                }
+
+                b.iter(|| tuples.iter().fold(db::PungTuple::default(), |acc, t| $xor(&acc, t)));
+            }
+
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(stringify!($name), $name);
+        }
+    )
+}
+
+tuple_xor!(tuple_xor_bytes_100k, tuple_xor_bytes, 100000);
+tuple_xor!(tuple_xor_words_100k, tuple_xor_words, 100000);
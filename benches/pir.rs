@@ -45,7 +45,7 @@ macro_rules! pir_server {
                    collection.push(x);
                 }
 
-                b.iter(|| PirServer::new(&collection, $alpha, $d));
+                b.iter(|| PirServer::new(&collection, $alpha, $d).unwrap());
             }
 
             let mut bmark = bmark_settings!();
@@ -60,7 +60,7 @@ macro_rules! pir_client {
         #[test]
         fn $name() {
             fn $name(b: &mut Bencher) {
-                b.iter(|| PirClient::new($size, $num, $alpha, $d));
+                b.iter(|| PirClient::new($size, $num, $alpha, $d).unwrap());
             }
 
             let mut bmark = bmark_settings!();
@@ -82,8 +82,8 @@ macro_rules! pir_query {
                 // Measure network / memory
                 println!("----------------PUNG QUERY MEMORY RESULT------------------\n");
 
-                let client = PirClient::new($size, $num, $alpha, $d);
-                let query = client.gen_query(rand::random::<u64>() % $num);
+                let client = PirClient::new($size, $num, $alpha, $d).unwrap();
+                let query = client.gen_query(rand::random::<u64>() % $num).unwrap();
 
                 println!("{} query size: {} bytes", stringify!($name), query.query.len());
 
@@ -93,10 +93,10 @@ macro_rules! pir_query {
 
             // Measure time
             fn $name(b: &mut Bencher) {
-                let client = PirClient::new($size, $num, $alpha, $d);
+                let client = PirClient::new($size, $num, $alpha, $d).unwrap();
                 b.iter_with_setup(|| rand::random::<u64>() % $num,
                                   |idx| {
-                                     client.gen_query(idx);
+                                     client.gen_query(idx).unwrap();
                                   });
             }
 
@@ -126,13 +126,13 @@ macro_rules! pir_answer {
                    collection.push(x);
                 }
 
-                let server = PirServer::new(&collection, $alpha, $d);
-                let client = PirClient::new($size, $num, $alpha, $d);
+                let server = PirServer::new(&collection, $alpha, $d).unwrap();
+                let client = PirClient::new($size, $num, $alpha, $d).unwrap();
 
-                let query = client.gen_query(rand::random::<u64>() % $num);
+                let query = client.gen_query(rand::random::<u64>() % $num).unwrap();
                 println!("{} query size: {} bytes", stringify!($name), query.query.len());
 
-                let answer = server.gen_answer(query.query, query.num);
+                let answer = server.gen_answer(query.query, query.num).unwrap();
                 println!("{} answer size: {} bytes", stringify!($name), answer.answer.len());
 
                 println!("-----------------------------------------------------\n");
@@ -152,13 +152,13 @@ macro_rules! pir_answer {
                    collection.push(x);
                 }
 
-                let server = PirServer::new(&collection, $alpha, $d);
-                let client = PirClient::new($size, $num, $alpha, $d);
+                let server = PirServer::new(&collection, $alpha, $d).unwrap();
+                let client = PirClient::new($size, $num, $alpha, $d).unwrap();
 
                 b.iter_with_setup(|| {
-                        client.gen_query(rand::random::<u64>() % $num)
+                        client.gen_query(rand::random::<u64>() % $num).unwrap()
                     }, |query| {
-                        server.gen_answer(query.query, query.num);
+                        server.gen_answer(query.query, query.num).unwrap();
                     });
             }
 
@@ -188,16 +188,16 @@ macro_rules! pir_decode {
                    collection.push(x);
                 }
 
-                let server = PirServer::new(&collection, $alpha, $d);
-                let client = PirClient::new($size, $num, $alpha, $d);
+                let server = PirServer::new(&collection, $alpha, $d).unwrap();
+                let client = PirClient::new($size, $num, $alpha, $d).unwrap();
 
-                let query = client.gen_query(rand::random::<u64>() % $num);
+                let query = client.gen_query(rand::random::<u64>() % $num).unwrap();
                 println!("{} query size: {} bytes", stringify!($name), query.query.len());
 
-                let answer = server.gen_answer(query.query, query.num);
+                let answer = server.gen_answer(query.query, query.num).unwrap();
                 println!("{} answer size: {} bytes", stringify!($name), answer.answer.len());
 
-                let result = client.decode_answer(answer.answer, answer.num);
+                let result = client.decode_answer(answer.answer, answer.num).unwrap();
                 println!("{} decoded result size: {} bytes", stringify!($name), result.result.len());
 
                 println!("-----------------------------------------------------\n");
@@ -219,14 +219,14 @@ macro_rules! pir_decode {
                 }
 
 
-                let server = PirServer::new(&collection, $alpha, $d);
-                let client = PirClient::new($size, $num, $alpha, $d);
+                let server = PirServer::new(&collection, $alpha, $d).unwrap();
+                let client = PirClient::new($size, $num, $alpha, $d).unwrap();
 
                 b.iter_with_setup(|| {
-                        let query = client.gen_query(rand::random::<u64>() % $num);
-                        server.gen_answer(query.query, query.num)
+                        let query = client.gen_query(rand::random::<u64>() % $num).unwrap();
+                        server.gen_answer(query.query, query.num).unwrap()
                     }, |answer| {
-                        client.decode_answer(answer.answer, answer.num);
+                        client.decode_answer(answer.answer, answer.num).unwrap();
                     });
            }
 
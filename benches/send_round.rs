@@ -0,0 +1,105 @@
+extern crate criterion;
+extern crate pung;
+extern crate rand;
+extern crate timely;
+
+use criterion::Bencher;
+use pung::db;
+use pung::server::direct_send::DirectSend;
+use pung::server::send_dataflow;
+use pung::server::timely_shim::{SendPath, TimelySend};
+use pung::server::ServerConfig;
+use rand::{ChaChaRng, Rng};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+macro_rules! bmark_settings {
+    () => {{
+
+        // If you want to change settings call .sample_size() or any of the other options
+        //
+        // Example:
+        let mut crit = criterion::Criterion::default();
+        crit.sample_size(20)
+            .measurement_time(Duration::new(0, 5000)); // in (sec, ns)
+        crit
+    }};
+
+}
+
+fn create_tuples(num: usize) -> Vec<db::PungTuple> {
+    let mut rng = ChaChaRng::new_unseeded();
+
+    (0..num)
+        .map(|_| {
+            let mut raw_tuple = [0u8; db::TUPLE_SIZE];
+            rng.fill_bytes(&mut raw_tuple);
+            db::PungTuple::new(&raw_tuple[..])
+        })
+        .collect()
+}
+
+// Sends `tuples` through `path` and builds the round, as the server does once every client
+// has sent (with a single worker). Rounds must keep increasing across calls.
+fn send_round(
+    b: &mut Bencher,
+    path: &mut SendPath,
+    dbase: &db::DatabasePtr,
+    tuples: &[db::PungTuple],
+    round: &mut u64,
+) {
+    b.iter(|| {
+        dbase.borrow_mut().clear();
+
+        for tuple in tuples {
+            path.send((tuple.clone(), 0));
+        }
+
+        path.finish_round(*round);
+        *round += 1;
+    });
+}
+
+macro_rules! send_round {
+    ($name: ident, $num:expr) => (
+        #[test]
+        fn $name() {
+            let config = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+            let tuples = create_tuples($num);
+
+            // Through timely dataflow (what the server does by default)
+            let timely_tuples = tuples.clone();
+            let timely_config = config.clone();
+
+            timely::execute(timely::Configuration::Thread, move |mut worker| {
+                let dbase = Rc::new(RefCell::new(timely_config.database().unwrap()));
+                let handler = send_dataflow::graph(&mut worker, dbase.clone());
+
+                let mut path = TimelySend {
+                    handler: handler,
+                    worker: worker.clone(),
+                };
+
+                let mut round = 0;
+                let mut bmark = bmark_settings!();
+                bmark.bench_function(concat!(stringify!($name), "_timely"), |b| {
+                    send_round(b, &mut path, &dbase, &timely_tuples, &mut round)
+                });
+            }).unwrap();
+
+            // Straight into the database (--no-timely)
+            let dbase = Rc::new(RefCell::new(config.database().unwrap()));
+            let mut path = DirectSend::new(dbase.clone());
+
+            let mut round = 0;
+            let mut bmark = bmark_settings!();
+            bmark.bench_function(concat!(stringify!($name), "_direct"), |b| {
+                send_round(b, &mut path, &dbase, &tuples, &mut round)
+            });
+        }
+    )
+}
+
+send_round!(bench_send_round_1k, 1000);
+send_round!(bench_send_round_16k, 16000);
@@ -1,3 +1,6 @@
+extern crate env_logger;
+#[macro_use]
+extern crate log;
 extern crate pung;
 extern crate gj;
 extern crate gjio;
@@ -7,16 +10,136 @@ extern crate time;
 
 use getopts::Options;
 
-use pung::client::PungClient;
+use pung::client::{ClientConfig, PungClient, PungError, RetrOutcome, MESSAGE_SIZE};
+use pung::client::chunk::{self, LargeMessage};
+use pung::client::metrics::{CountingMetrics, RpcKind};
+use pung::client::pcrypto;
 use pung::db;
+use pung::util::secret;
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 use time::PreciseTime;
 
+// Strips the zeros with which a retrieved message is padded
+fn unpadded(msg: &[u8]) -> &[u8] {
+    let len = msg.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &msg[..len]
+}
+
+// A retrieved message as text if it is UTF-8 (and `hex` is not set), or else in hex
+fn printable(msg: &[u8], hex: bool) -> String {
+    let msg = unpadded(msg);
+
+    match std::str::from_utf8(msg) {
+        Ok(s) if !hex => s.to_string(),
+        _ => format!("0x{}", secret::to_hex(msg)),
+    }
+}
+
+// Writes a retrieved message (or a large message put back together) to `out_dir/name`
+fn write_retrieved(out_dir: &Path, name: &str, data: &[u8]) -> Result<(), PungError> {
+    let path = out_dir.join(name);
+    fs::write(&path, data)?;
+
+    println!("Wrote {} bytes to {}", data.len(), path.display());
+    Ok(())
+}
+
+// Reads lines from stdin in the background, so that rounds go on while the user types. The
+// channel is closed once stdin is.
+fn spawn_stdin_reader() -> mpsc::Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let stdin = std::io::stdin();
+
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+// Sends every line typed on stdin to `peer_name` (up to `send_rate` lines per round, the
+// rest in later rounds) and prints the messages retrieved every round with their sender,
+// until stdin is closed and every line was sent. Sends are padded with cover tuples (see
+// PungClient::set_cover_rates), so that rounds in which nothing was typed go on as well.
+fn run_interactive(
+    client: &mut PungClient,
+    peer_name: &str,
+    send_rate: u32,
+    ret_rate: u32,
+    wait_scope: &gj::WaitScope,
+    event_port: &mut gjio::EventPort,
+) -> Result<(), PungError> {
+    let lines = spawn_stdin_reader();
+    let mut typed: Vec<Vec<u8>> = Vec::new();
+    let mut eof = false;
+
+    let peers: Vec<&str> = (0..ret_rate).map(|_| peer_name).collect();
+
+    println!("Type messages for {} (one per line, end with Ctrl-D)", peer_name);
+
+    while !eof || !typed.is_empty() {
+        loop {
+            match lines.try_recv() {
+                Ok(line) => {
+                    if line.is_empty() {
+                        continue;
+                    } else if line.len() > MESSAGE_SIZE {
+                        println!("(only the first {} bytes are sent)", MESSAGE_SIZE);
+                    }
+
+                    let mut msg = line.into_bytes();
+                    msg.truncate(MESSAGE_SIZE);
+                    typed.push(msg);
+                }
+
+                Err(mpsc::TryRecvError::Empty) => break,
+
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    eof = true;
+                    break;
+                }
+            }
+        }
+
+        let take = std::cmp::min(typed.len(), send_rate as usize);
+        let mut messages: Vec<Vec<u8>> = typed.drain(..take).collect();
+
+        client.send(peer_name, &mut messages, wait_scope, event_port)?;
+
+        for (sender, msg) in client.retr_attributed(&peers[..], wait_scope, event_port)? {
+            println!("{}: {}", sender, String::from_utf8_lossy(unpadded(&msg)));
+        }
+
+        client.inc_round(1);
+    }
+
+    Ok(())
+}
+
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
 pub fn main() {
+    // Bandwidth is logged at debug level, so it only shows up with RUST_LOG=debug
+    env_logger::init();
 
     let args: Vec<String> = std::env::args().collect();
     let program = args[0].clone();
@@ -27,18 +150,30 @@ pub fn main() {
     // required parameters
     opts.reqopt("n", "name", "name of this client", "NAME");
     opts.reqopt("p", "peer", "name of peer", "PEER");
-    opts.reqopt("x", "secret", "shared secret", "SECRET");
 
     // optional parameters
+    opts.optopt("x", "secret", "shared secret (default: agreed on through the server)", "SECRET");
+    opts.optopt("", "peer-timeout", "seconds to wait for the peer's key (default 60)", "SECS");
     opts.optopt("h", "host", "server's address", "IP:PORT");
+    opts.optmulti("", "worker", "another worker of the server to spread PIR over", "IP:PORT");
     opts.optopt("k", "ret-rate", "ret rate", "RATE");
     opts.optopt("s", "send-rate", "send rate", "RATE");
-    //    opts.optopt("a", "alpha", "PIR aggregation", "ALPHA");
+    opts.optopt("a", "alpha", "PIR aggregation (must match server's)", "ALPHA");
     opts.optopt("d", "depth", "PIR depth", "DEPTH");
-    opts.optopt("o", "opt", "power (p) or hybrid (h)", "p / h");
+    opts.optopt("o", "opt", "power (p) or hybrid (h2, h4, h8)", "p / h2 / h4 / h8");
     opts.optopt("r", "round", "number of rounds", "ROUND");
+    opts.optflag("", "interactive", "send lines typed on stdin to the peer (until EOF)");
+    opts.optopt("", "send-file", "send the file to the peer (in chunks, over the rounds)", "PATH");
+    opts.optflag("", "hex", "print retrieved messages in hex rather than as text");
+    opts.optopt("", "out-dir", "write every retrieved message to a file in DIR", "DIR");
     opts.optopt("t", "type", "retrieval type", "e / b / t");
-    opts.optopt("b", "extra", "change server extra", "EXTRA");
+    opts.optopt("b", "extra", "change server extra (needs --allow-change-extra)", "EXTRA");
+    opts.optflag("z", "compress", "compress blooms, labels, and PIR answers");
+    opts.optflag("", "verify", "verify PIR answers (server needs --debug)");
+    opts.optflag("", "self-check", "cross-check retrievals (not private; server needs --debug)");
+    opts.optopt("", "secret-format", "how the secret is written (default raw)", "raw / hex / base64");
+    opts.optopt("", "aead", "message encryption (must match peer's)", "chacha / aes");
+    opts.optopt("", "label-prf", "label PRF (must match peer's)", "hmac / blake2b / blake2s");
 
     // TODO: Maybe an option for a JSON config file to describe multiple peers.
 
@@ -59,19 +194,43 @@ pub fn main() {
     // required params (no available defaults)
     let user_name: String = matches.opt_str("n").unwrap();
     let peer_name: String = matches.opt_str("p").unwrap();
-    let secret: Vec<u8> = matches.opt_str("x").unwrap().into_bytes();
+
+    let secret_format: secret::SecretFormat = match matches.opt_str("secret-format") {
+        Some(v) => match v.parse() {
+            Ok(f) => f,
+            Err(_) => panic!("Invalid secret format {}. Choose either raw, hex, or base64.", v),
+        },
+
+        None => secret::SecretFormat::Raw,
+    };
 
     // optional params
+    let secret: Option<Vec<u8>> = match matches.opt_str("x") {
+        Some(v) => match secret::decode(&v, secret_format) {
+            Ok(s) => Some(s),
+            Err(e) => panic!("Invalid secret: {}", e),
+        },
+
+        None => None,
+    };
+
     let server_addr: String = match matches.opt_str("h") {
         Some(v) => v,
         None => "127.0.0.1:12345".to_string(),
     };
 
+    // Other workers of the server (see ClientConfig::workers)
+    let workers: Vec<String> = matches.opt_strs("worker");
+
     let ret_rate: u32 = match matches.opt_str("k") {
         Some(v) => u32::from_str_radix(&v, 10).unwrap(),
         None => 1,
     };
 
+    if ret_rate == 0 {
+        panic!("Invalid retrieval rate 0. The number of buckets must be at least 1.");
+    }
+
     let send_rate: u32 = match matches.opt_str("s") {
         Some(v) => u32::from_str_radix(&v, 10).unwrap(),
         None => 1,
@@ -82,11 +241,15 @@ pub fn main() {
         None => 1,
     };
 
-    //   let alpha: u64 = match matches.opt_str("a") {
-    // Some(v) => u64::from_str_radix(&v, 10).unwrap(),
-    // None => 1,
-    // };
-    //
+    // Without one, the client uses the server's (see ClientConfig::alpha)
+    let alpha: Option<u64> = match matches.opt_str("a") {
+        Some(v) => Some(u64::from_str_radix(&v, 10).unwrap()),
+        None => None,
+    };
+
+    if alpha == Some(0) {
+        panic!("Invalid PIR alpha 0. It must be at least 1.");
+    }
 
     let rounds: usize = match matches.opt_str("r") {
         Some(v) => usize::from_str_radix(&v, 10).unwrap(),
@@ -98,6 +261,81 @@ pub fn main() {
         None => 0,
     };
 
+    let compress: bool = matches.opt_present("z");
+    let verify: bool = matches.opt_present("verify");
+    // How long to wait for the peer's key when there is no shared secret (see --peer-timeout)
+    let peer_timeout: u64 = match matches.opt_str("peer-timeout") {
+        Some(v) => u64::from_str_radix(&v, 10).unwrap(),
+        None => 60,
+    };
+
+    let self_check: bool = matches.opt_present("self-check");
+    let hex: bool = matches.opt_present("hex");
+
+    // Chat with the peer instead of running the benchmark rounds
+    let interactive: bool = matches.opt_present("interactive");
+
+    // Contents of a file to send instead of some of the synthetic messages (see --send-file)
+    let send_file: Option<Vec<u8>> = match matches.opt_str("send-file") {
+        Some(v) => match fs::read(&v) {
+            Ok(data) => Some(data),
+            Err(e) => panic!("Could not read {}: {}", v, e),
+        },
+
+        None => None,
+    };
+
+    // Directory to which retrieved messages are written (see --out-dir)
+    let out_dir: Option<PathBuf> = match matches.opt_str("out-dir") {
+        Some(v) => match fs::create_dir_all(&v) {
+            Ok(()) => Some(PathBuf::from(v)),
+            Err(e) => panic!("Could not create {}: {}", v, e),
+        },
+
+        None => None,
+    };
+
+    if interactive && (send_file.is_some() || out_dir.is_some()) {
+        panic!("--send-file and --out-dir do not work with --interactive");
+    } else if self_check && send_file.is_some() {
+        panic!("--self-check does not put the chunks of --send-file back together");
+    }
+
+    if let Some(ref data) = send_file {
+        if send_rate == 0 {
+            panic!("Invalid send rate 0. Sending a file requires a send rate of at least 1.");
+        } else if data.len() > chunk::MAX_LARGE_SIZE {
+            panic!("File of {} bytes is longer than the maximum ({})",
+                   data.len(),
+                   chunk::MAX_LARGE_SIZE);
+        }
+    }
+
+    let aead: pcrypto::AeadAlgorithm = match matches.opt_str("aead") {
+        Some(v) => {
+            match v.as_ref() {
+                "chacha" => pcrypto::AeadAlgorithm::ChaCha20Poly1305,
+                "aes" => pcrypto::AeadAlgorithm::AesGcm,
+                _ => panic!("Invalid AEAD parameter {}. Choose either chacha or aes.", v),
+            }
+        }
+
+        None => pcrypto::AeadAlgorithm::ChaCha20Poly1305,
+    };
+
+    let label_prf: pcrypto::LabelPrf = match matches.opt_str("label-prf") {
+        Some(v) => {
+            match v.as_ref() {
+                "hmac" => pcrypto::LabelPrf::HmacSha256,
+                "blake2b" => pcrypto::LabelPrf::Blake2b,
+                "blake2s" => pcrypto::LabelPrf::Blake2s,
+                _ => panic!("Invalid label PRF {}. Choose either hmac, blake2b, or blake2s.", v),
+            }
+        }
+
+        None => pcrypto::LabelPrf::HmacSha256,
+    };
+
     let ret_scheme: db::RetScheme = match matches.opt_str("t") {
         Some(v) => {
             match v.as_ref() {
@@ -120,7 +358,8 @@ pub fn main() {
                     "p" => db::OptScheme::Aliasing,
                     "h2" => db::OptScheme::Hybrid2,
                     "h4" => db::OptScheme::Hybrid4,
-                    _ => panic!("Invalid optimization parameters {}. Choose either p, h2, or h4.", v),
+                    "h8" => db::OptScheme::Hybrid8,
+                    _ => panic!("Invalid optimization parameters {}. Choose p, h2, h4, or h8.", v),
                 }
 
             } else {
@@ -135,23 +374,66 @@ pub fn main() {
     gj::EventLoop::top_level(move |wait_scope| -> Result<(), capnp::Error> {
 
             let mut event_port = gjio::EventPort::new().unwrap();
-            let mut client = PungClient::new(&user_name,
-                                             &server_addr,
-                                             send_rate,
-                                             ret_rate,
-                                             depth,
-                                             ret_scheme,
-                                             opt_scheme,
-                                             wait_scope,
-                                             &mut event_port);
+
+            let config = ClientConfig {
+                send_rate: send_rate,
+                ret_rate: ret_rate,
+                depth: depth,
+                alpha: alpha,
+                ret_scheme: ret_scheme,
+                opt_scheme: opt_scheme,
+                compression: compress,
+                verify_answers: verify,
+                aead: aead,
+                label_prf: label_prf,
+                workers: workers,
+                ..ClientConfig::new(&user_name, &server_addr)
+            };
+
+            let mut client = PungClient::with_config(config, wait_scope, &mut event_port)?;
+
+            // Bytes uploaded and downloaded, logged (at debug level) after every round
+            let metrics = Rc::new(CountingMetrics::new());
+            client.set_metrics(Box::new(metrics.clone()));
 
             client.init_dummy_peer();
-            client.add_peer(&peer_name, &secret);
+
+            if let Some(ref secret) = secret {
+                client.add_peer_with_secret(&peer_name, secret);
+            }
+
+            // Rounds go on whether or not anything was typed (see run_interactive)
+            if interactive {
+                client.set_cover_rates(send_rate, ret_rate)?;
+            }
 
             // Register with the service
             let unique_id: u64 = (client.register(&wait_scope, &mut event_port))?;
             println!("{} - Registered with Pung server", unique_id);
 
+            // Without a shared secret, agree on one with the peer's key (once it registers)
+            if secret.is_none() {
+                println!("{} - Waiting for {}'s public key", unique_id, peer_name);
+
+                let deadline = Instant::now() + Duration::from_secs(peer_timeout);
+
+                loop {
+                    match client.add_peer(&peer_name, &wait_scope, &mut event_port) {
+                        Ok(()) => break,
+                        Err(PungError::InvalidPeer(_)) if Instant::now() < deadline => {
+                            thread::sleep(Duration::from_millis(100))
+                        }
+                        Err(PungError::InvalidPeer(_)) => {
+                            let msg = format!("{} did not register within {} seconds",
+                                              peer_name,
+                                              peer_timeout);
+                            return Err(PungError::Failed(msg).into());
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+
             // Changing the extra tuple value at the server (if requested).
             if extra > 0 {
                 client.extra(extra, &wait_scope, &mut event_port)?;
@@ -164,23 +446,58 @@ pub fn main() {
 
             //        std::thread::sleep(std::time::Duration::new(5, 0));
 
+            if interactive {
+                run_interactive(&mut client, &peer_name, send_rate, ret_rate, &wait_scope,
+                                &mut event_port)?;
+
+                println!("{} - Closing the connection", unique_id);
+                client.close(&wait_scope, &mut event_port)?;
+                return Ok(());
+            }
+
+            // Chunks of the file that are still to be sent, up to send_rate of them per round
+            let mut file_chunks: Vec<Vec<u8>> = match send_file {
+                // (id 0 is that of plain messages)
+                Some(ref data) => chunk::split(data, std::cmp::max(unique_id as u32, 1))?,
+                None => Vec::new(),
+            };
+
+            let file_rounds = if file_chunks.is_empty() {
+                0
+            } else {
+                (file_chunks.len() + send_rate as usize - 1) / send_rate as usize
+            };
+
+            let rounds = if file_rounds > rounds {
+                println!("{} - Sending the file takes {} rounds", unique_id, file_rounds);
+                file_rounds
+            } else {
+                rounds
+            };
+
             let start_round = PreciseTime::now();
             for _ in 0..rounds {
 
                 //      println!("{} - Sending {} tuples for round {}", unique_id, send_rate, client.get_round());
 
-                // create random message
-                let mut messages = Vec::with_capacity(send_rate as usize);
+                let start = PreciseTime::now();
 
-                for i in 0..send_rate {
-                    let msg = format!("msg #{} from {}", i, unique_id).into_bytes();
-                    messages.push(msg);
-                }
+                // the file's next chunks, and random messages for the rest of the send rate
+                {
+                    let take = std::cmp::min(file_chunks.len(), send_rate as usize);
+                    let mut session = client.send_session();
 
-                let start = PreciseTime::now();
+                    for c in file_chunks.drain(..take) {
+                        session.add_chunk(&peer_name, &c)?;
+                    }
 
-                // send tuple
-                client.send(&peer_name, &mut messages, &wait_scope, &mut event_port)?;
+                    for i in take as u32..send_rate {
+                        let msg = format!("msg #{} from {}", i, unique_id).into_bytes();
+                        session.add(&peer_name, &msg)?;
+                    }
+
+                    session.finish(&wait_scope, &mut event_port)?;
+                }
 
                 let end = PreciseTime::now();
                 let duration = start.to(end);
@@ -200,17 +517,72 @@ pub fn main() {
                     peers.push(&peer_name);
                 }
 
-                let msgs = client.retr(&peers[..], &wait_scope, &mut event_port)?;
+                let msgs: Vec<LargeMessage> = if self_check {
+                    // Diagnostics only: the server learns every label (see retr_self_check)
+                    let (outcomes, missed) =
+                        client.retr_self_check(&peers[..], &wait_scope, &mut event_port)?;
+
+                    for i in missed {
+                        println!("{} - Self-check: message {} was sent but not retrieved",
+                                 unique_id,
+                                 i);
+                    }
+
+                    outcomes.into_iter().filter_map(|outcome| match outcome {
+                        RetrOutcome::Found(m) => Some(LargeMessage {
+                            sender: peer_name.clone(),
+                            id: 0,
+                            data: m,
+                            missing: Vec::new(),
+                        }),
+                        _ => None,
+                    }).collect()
+                } else {
+                    // Chunks of the file are buffered by the client until all of them are in
+                    client.retr_large(&peers[..], &wait_scope, &mut event_port)?
+                };
 
                 let end = PreciseTime::now();
                 println!("retr ({} msgs): {:?} usec",
                          msgs.len(),
                          start.to(end).num_microseconds().unwrap());
 
-                for msg in msgs {
-                    println!("{} - Retrieved msg is {}", unique_id, String::from_utf8(msg).unwrap());
+                let round = client.get_round();
+
+                // Plain messages (id 0) come first, then large messages whose last chunk
+                // came this round
+                for (i, msg) in msgs.into_iter().enumerate() {
+                    if msg.id == 0 {
+                        println!("{} - Retrieved msg is {}", unique_id, printable(&msg.data, hex));
+
+                        if let Some(ref dir) = out_dir {
+                            let name = format!("{}-round{}-{}", msg.sender, round, i);
+                            write_retrieved(dir, &name, unpadded(&msg.data))?;
+                        }
+                    } else {
+                        println!("{} - Retrieved message {} ({} bytes)",
+                                 unique_id,
+                                 msg.id,
+                                 msg.data.len());
+
+                        if let Some(ref dir) = out_dir {
+                            let name = format!("{}-round{}-msg{}", msg.sender, round, msg.id);
+                            write_retrieved(dir, &name, &msg.data)?;
+                        }
+                    }
+                }
+
+                for &kind in &[RpcKind::Send, RpcKind::Labels, RpcKind::Bloom, RpcKind::Pir] {
+                    let totals = metrics.get(kind);
+
+                    debug!("{:?}: upload {} bytes, download {} bytes ({} bytes uncompressed)",
+                           kind,
+                           totals.uploaded,
+                           totals.downloaded,
+                           totals.downloaded_uncompressed);
                 }
 
+                metrics.reset();
                 client.inc_round(1);
             }
 
@@ -218,6 +590,19 @@ pub fn main() {
             let duration = start_round.to(end_round);
             println!("processed {} rounds in {} usec", rounds, duration.num_microseconds().unwrap());
 
+            // What was retrieved of large messages that never completed
+            if let Some(ref dir) = out_dir {
+                for large in client.take_incomplete_large() {
+                    warn!("Message {} from {} is missing {} chunks",
+                          large.id,
+                          large.sender,
+                          large.missing.len());
+
+                    let name = format!("{}-msg{}.partial", large.sender, large.id);
+                    write_retrieved(dir, &name, &large.data)?;
+                }
+            }
+
             client.close(&wait_scope, &mut event_port)?;
 
             Ok(())
@@ -1,3 +1,4 @@
+extern crate env_logger;
 extern crate pung;
 extern crate getopts;
 extern crate timely;
@@ -9,9 +10,12 @@ use getopts::Options;
 
 use pung::db;
 use pung::server::send_dataflow;
+use pung::server::{RpcLimit, ServerConfig};
 use std::cell::RefCell;
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::time::Duration;
 
 macro_rules! timely_opt {
     ($matches:ident, $list:ident, $opt:expr) => {{
@@ -28,6 +32,8 @@ fn print_usage(program: &str, opts: Options) {
 }
 
 fn main() {
+    // Logs at info level (rounds, disconnections) unless RUST_LOG says otherwise
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     let args: Vec<String> = std::env::args().collect();
     let program = args[0].clone();
@@ -43,16 +49,33 @@ fn main() {
     opts.optopt("n", "processes", "number of processes", "NUM");
     opts.optopt("h", "hostfile", "text file whose lines are process addresses", "FILE");
     opts.optflag("r", "report", "reports connection progress");
+    opts.optflag("", "no-timely", "build rounds without timely (single worker only)");
 
     // pung parameters
     opts.optopt("i", "ip", "address of pung RPC", "IP");
     opts.optopt("s", "port", "initial port of pung RPC", "PORT");
     opts.optopt("k", "buckets", "number of buckets", "BUCKETS");
-    //    opts.optopt("a", "alpha", "PIR aggregation", "ALPHA");
+    opts.optopt("a", "alpha", "PIR aggregation (default: from each level's size)", "ALPHA");
     opts.optopt("d", "depth", "PIR depth", "DEPTH");
     opts.optopt("b", "extra", "extra tuples added", "EXTRA");
+    opts.optflag("", "allow-change-extra", "let clients change extra tuples (testing only)");
+    opts.optflag("", "debug", "allow non-private debugging RPCs (diagnostics only)");
     opts.optopt("m", "messages", "min messages", "MESSAGES");
-    opts.optopt("o", "opt", "power (p) or hybrid (h)", "p / h");
+    opts.optopt("e", "expect-clients", "clients to wait for before the first round", "NUM");
+    opts.optopt("l", "retain-rounds", "past rounds kept for late retrievals", "NUM");
+    opts.optopt("", "max-query-factor", "reject queries larger than this times expected", "FACTOR");
+    opts.optopt("", "send-lookahead", "most rounds ahead clients may send for (default 1)", "NUM");
+    opts.optopt("", "tuple-seed", "seed for extra tuples (reproducible, not private)", "SEED");
+    opts.optopt("", "max-connections", "most client connections open at once", "NUM");
+    opts.optopt("", "max-pending", "most connections that have not synced yet", "NUM");
+    opts.optopt("", "bloom-hashes", "hash functions of bloom filters (default optimal)", "NUM");
+    opts.optopt("", "bloom-fp", "false positive rate of bloom filters (default 1e-5)", "RATE");
+    opts.optopt("", "round-timeout", "longest a send phase may last (default none)", "MS");
+    opts.optopt("", "rpc-rate", "calls per second allowed per client (default unlimited)", "RATE");
+    opts.optopt("", "rpc-burst", "calls a client may make at once (default: the rate)", "NUM");
+    opts.optopt("", "dump-db-after-round", "append round layouts to PATH (leaks labels)", "PATH");
+    opts.optopt("", "key-file", "keep the key directory in PATH across restarts", "PATH");
+    opts.optopt("o", "opt", "power (p) or hybrid (h2, h4, h8)", "p / h2 / h4 / h8");
     opts.optopt("t", "type", "retrieval type", "e / b / t");
 
     // Parse parameters
@@ -79,6 +102,18 @@ fn main() {
         timely_args.push("-r".to_string());
     }
 
+    let no_timely: bool = matches.opt_present("no-timely");
+
+    if no_timely {
+        for opt in &["w", "n"] {
+            if let Some(v) = matches.opt_str(opt) {
+                if v != "1" {
+                    panic!("Invalid -{} {} with --no-timely. It supports a single worker.", opt, v);
+                }
+            }
+        }
+    }
+
     // process pung parameters
     let rpc_addr: String = match matches.opt_str("i") {
         Some(v) => v,
@@ -95,11 +130,19 @@ fn main() {
         None => 1,
     };
 
-    //    let alpha: u64 = match matches.opt_str("a") {
-    // Some(v) => u64::from_str_radix(&v, 10).unwrap(),
-    // None => 1,
-    // };
-    //
+    if buckets == 0 {
+        panic!("Invalid number of buckets 0. It must be at least 1.");
+    }
+
+    // Clients learn it when they register (see ServerConfig::alpha)
+    let alpha: Option<u64> = match matches.opt_str("a") {
+        Some(v) => Some(u64::from_str_radix(&v, 10).unwrap()),
+        None => None,
+    };
+
+    if alpha == Some(0) {
+        panic!("Invalid PIR alpha 0. It must be at least 1.");
+    }
 
     let depth: u64 = match matches.opt_str("d") {
         Some(v) => u64::from_str_radix(&v, 10).unwrap(),
@@ -111,11 +154,84 @@ fn main() {
         None => 0,
     };
 
+    let allow_change_extra: bool = matches.opt_present("allow-change-extra");
+
+    let debug: bool = matches.opt_present("debug");
+
     let min_messages: u32 = match matches.opt_str("m") {
         Some(v) => u32::from_str_radix(&v, 10).unwrap(),
         None => 1,
     };
 
+    let expected_clients: u32 = match matches.opt_str("e") {
+        Some(v) => u32::from_str_radix(&v, 10).unwrap(),
+        None => 0,
+    };
+
+    let retain_rounds: u32 = match matches.opt_str("l") {
+        Some(v) => u32::from_str_radix(&v, 10).unwrap(),
+        None => 0,
+    };
+
+    let max_query_factor: f64 = match matches.opt_str("max-query-factor") {
+        Some(v) => f64::from_str(&v).unwrap(),
+        None => pung::server::DEFAULT_MAX_QUERY_FACTOR,
+    };
+
+    if !(max_query_factor >= 1.0) {
+        panic!("Invalid max query factor {}. It must be at least 1.", max_query_factor);
+    }
+
+    let send_lookahead: u64 = match matches.opt_str("send-lookahead") {
+        Some(v) => u64::from_str_radix(&v, 10).unwrap(),
+        None => 1,
+    };
+
+    if send_lookahead == 0 {
+        panic!("Invalid send lookahead 0. It must be at least 1.");
+    }
+
+    let tuple_seed: Option<u64> = match matches.opt_str("tuple-seed") {
+        Some(v) => Some(u64::from_str_radix(&v, 10).unwrap()),
+        None => None,
+    };
+
+    let max_connections: Option<usize> = match matches.opt_str("max-connections") {
+        Some(v) => Some(usize::from_str_radix(&v, 10).unwrap()),
+        None => None,
+    };
+
+    if max_connections == Some(0) {
+        panic!("Invalid max connections 0. It must be at least 1.");
+    }
+
+    let max_pending: Option<usize> = match matches.opt_str("max-pending") {
+        Some(v) => Some(usize::from_str_radix(&v, 10).unwrap()),
+        None => None,
+    };
+
+    if max_pending == Some(0) {
+        panic!("Invalid max pending connections 0. It must be at least 1.");
+    }
+
+    let bloom_hashes: Option<u32> = match matches.opt_str("bloom-hashes") {
+        Some(v) => Some(u32::from_str_radix(&v, 10).unwrap()),
+        None => None,
+    };
+
+    if bloom_hashes == Some(0) {
+        panic!("Invalid number of bloom hash functions 0. It must be at least 1.");
+    }
+
+    let bloom_fp: f64 = match matches.opt_str("bloom-fp") {
+        Some(v) => f64::from_str(&v).unwrap(),
+        None => db::BLOOM_FP,
+    };
+
+    if let Err(e) = db::check_bloom_fp(bloom_fp) {
+        panic!("Invalid bloom filter false positive rate {}: {}", bloom_fp, e);
+    }
+
     let ret_scheme: db::RetScheme = match matches.opt_str("t") {
         Some(v) => {
             match v.as_ref() {
@@ -135,32 +251,116 @@ fn main() {
                 "p" => db::OptScheme::Aliasing,
                 "h2" => db::OptScheme::Hybrid2,
                 "h4" => db::OptScheme::Hybrid4,
-                _ => panic!("Invalid optimization parameters {}. Choose either p or h.", v),
+                "h8" => db::OptScheme::Hybrid8,
+                _ => panic!("Invalid optimization parameters {}. Choose p, h2, h4, or h8.", v),
             }
         }
 
         None => db::OptScheme::Normal,
     };
 
+    if let Err(e) = db::check_config(ret_scheme, opt_scheme, depth) {
+        panic!("Invalid configuration: {}", e);
+    }
+
+    let round_timeout: Option<Duration> = match matches.opt_str("round-timeout") {
+        Some(v) => Some(Duration::from_millis(u64::from_str_radix(&v, 10).unwrap())),
+        None => None,
+    };
+
+    if round_timeout == Some(Duration::from_millis(0)) {
+        panic!("Invalid round timeout 0. It must be at least 1 ms.");
+    }
+
+    // Sends and retrievals are not limited by these (see ServerConfig::rpc_limit)
+    let rpc_limit: Option<RpcLimit> = match matches.opt_str("rpc-rate") {
+        Some(v) => {
+            let rate = f64::from_str(&v).unwrap();
+            let burst = match matches.opt_str("rpc-burst") {
+                Some(b) => u32::from_str_radix(&b, 10).unwrap(),
+                None => rate.ceil().max(1.0) as u32,
+            };
+
+            match RpcLimit::new(burst, rate) {
+                Ok(limit) => Some(limit),
+                Err(e) => panic!("Invalid RPC limit ({} per second, burst {}): {}", rate, burst, e),
+            }
+        }
+
+        None => {
+            if matches.opt_present("rpc-burst") {
+                panic!("--rpc-burst requires --rpc-rate");
+            }
+
+            None
+        }
+    };
+
+    // Debugging only: the dump reveals every label of every round
+    let dump_db_after_round: Option<PathBuf> =
+        matches.opt_str("dump-db-after-round").map(PathBuf::from);
+
+    let key_file: Option<PathBuf> = matches.opt_str("key-file").map(PathBuf::from);
+
+    let config = ServerConfig {
+        depth: depth,
+        alpha: alpha,
+        extra_tuples: extra_tuples,
+        allow_change_extra: allow_change_extra,
+        debug: debug,
+        min_messages: min_messages,
+        expected_clients: expected_clients,
+        retain_rounds: retain_rounds,
+        max_query_factor: max_query_factor,
+        send_lookahead: send_lookahead,
+        tuple_seed: tuple_seed,
+        max_connections: max_connections,
+        max_pending: max_pending,
+        bloom_hashes: bloom_hashes,
+        bloom_fp: bloom_fp,
+        dump_db_after_round: dump_db_after_round,
+        round_timeout: round_timeout,
+        rpc_limit: rpc_limit,
+        key_file: key_file,
+        ..ServerConfig::new(ret_scheme, opt_scheme, buckets)
+    };
+
+    if no_timely {
+        let dbase = config.database().expect("configuration was checked");
+        let addr = FromStr::from_str(&format!("{}:{}", &rpc_addr, port)).unwrap();
+
+        if let Err(e) = pung::server::run_direct(addr, Rc::new(RefCell::new(dbase)), config) {
+            panic!("Could not start the server: {}", e);
+        }
+
+        return;
+    }
+
     // For each worker thred
     timely::execute_from_args(timely_args.into_iter(), move |mut worker| {
 
             let index = worker.index();
-            let dbase = Rc::new(RefCell::new(db::Database::new(ret_scheme, opt_scheme, buckets, depth)));
+            let dbase = config.database().expect("configuration was checked");
+            let dbase = Rc::new(RefCell::new(dbase));
 
-            let send_handle = send_dataflow::graph(&mut worker, dbase.clone(), buckets);
+            let send_handle = send_dataflow::graph(&mut worker, dbase.clone());
 
             let worker_port = port + index; // port of this worker
             let addr = FromStr::from_str(&format!("{}:{}", &rpc_addr, worker_port)).unwrap();
 
+            // Every worker has its own key directory (clients register with one of them)
+            let mut config = config.clone();
+
+            if worker.peers() > 1 {
+                config.key_file = config
+                    .key_file
+                    .map(|path| PathBuf::from(format!("{}.{}", path.display(), index)));
+            }
+
             // Run RPC server on this worker.
-            pung::server::run_rpc(addr,
-                                  worker.clone(),
-                                  send_handle,
-                                  dbase,
-                                  extra_tuples,
-                                  min_messages,
-                                  opt_scheme);
+            if let Err(e) = pung::server::run(addr, worker.clone(), send_handle, dbase, config) {
+                panic!("Could not start the server on worker {}: {}", index, e);
+            }
 
         })
         .expect("Timely dataflow error");
@@ -0,0 +1,26 @@
+// Policies for choosing, under aliasing, from which of a message's two buckets a client
+// retrieves it (see PungClient::schedule).
+
+/// Decides in which of its candidate buckets a label is retrieved. With aliasing, every
+/// message is stored in two different buckets and the client only needs to fetch it from
+/// one of them; spreading labels evenly keeps the number of retrieval rounds low.
+pub trait BucketAssigner {
+    /// Returns the position in `candidates` of the bucket from which to retrieve a label.
+    /// `loads[b]` is the number of labels already scheduled for bucket `b`.
+    fn assign(&self, candidates: &[usize; 2], loads: &[usize]) -> usize;
+}
+
+/// Picks the bucket with the fewest labels scheduled so far (the second one on ties).
+/// This is the default policy.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct LeastFull;
+
+impl BucketAssigner for LeastFull {
+    fn assign(&self, candidates: &[usize; 2], loads: &[usize]) -> usize {
+        if loads[candidates[0]] < loads[candidates[1]] {
+            0
+        } else {
+            1
+        }
+    }
+}
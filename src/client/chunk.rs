@@ -0,0 +1,258 @@
+// Messages longer than a tuple, which are sent as several chunks (see PungClient::send_large).
+
+use byteorder::{BigEndian, ByteOrder};
+use gj;
+use gjio;
+use rand::Rng;
+use std::collections::BTreeMap;
+
+use super::{PungClient, PungError, FRAME_CHUNK, MESSAGE_SIZE};
+
+// Layout of a chunk: message id (4 bytes), index (2), total (2), length (1), and then the
+// chunk's part of the message, padded with zeros like any other message. Chunks are sent
+// under their own frame (see PungClient::seal), which is how they are told apart from
+// messages sent with send, whatever the latter contain.
+const HEADER_SIZE: usize = 9;
+
+/// Number of bytes of a large message that fit in each chunk (i.e., in each tuple).
+pub const CHUNK_PAYLOAD: usize = MESSAGE_SIZE - HEADER_SIZE;
+
+/// Length (in bytes) of the longest message that `send_large` can send.
+pub const MAX_LARGE_SIZE: usize = CHUNK_PAYLOAD * (u16::max_value() as usize);
+
+/// A message sent with `send_large`, as put back together by `retr_large`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LargeMessage {
+    /// The peer that sent the message.
+    pub sender: String,
+
+    /// Identifies the message among those that the sender sends with `send_large`. Never 0,
+    /// which `retr_large` gives to messages sent with `send`.
+    pub id: u32,
+
+    /// The message, or (if incomplete) the chunks of it that were retrieved, in order.
+    pub data: Vec<u8>,
+
+    /// Indices of the chunks that were not retrieved. Empty if the message is complete.
+    pub missing: Vec<u16>,
+}
+
+impl LargeMessage {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// Splits `msg` into the chunks (each a message of at most `MESSAGE_SIZE` bytes) under which
+/// it is sent. Every chunk is tagged with `id`, its index, and the number of chunks, so that
+/// `reassemble` can put them back together in whatever order they are retrieved. Chunks
+/// must be sent as such (see `send_large` and `SendSession::add_chunk`), not with `send`.
+/// The id must not be 0 (see `LargeMessage::id`).
+pub fn split(msg: &[u8], id: u32) -> Result<Vec<Vec<u8>>, PungError> {
+    if id == 0 {
+        return Err(PungError::Failed("Id 0 is reserved for plain messages".to_string()));
+    } else if msg.len() > MAX_LARGE_SIZE {
+        return Err(PungError::Failed(format!(
+            "Message of {} bytes is longer than the maximum ({})",
+            msg.len(),
+            MAX_LARGE_SIZE
+        )));
+    }
+
+    // An empty message is still sent, as a single empty chunk
+    let parts: Vec<&[u8]> = if msg.is_empty() {
+        vec![msg]
+    } else {
+        msg.chunks(CHUNK_PAYLOAD).collect()
+    };
+
+    let total = parts.len() as u16;
+
+    Ok(parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            let mut chunk = vec![0u8; HEADER_SIZE + part.len()];
+            BigEndian::write_u32(&mut chunk[0..4], id);
+            BigEndian::write_u16(&mut chunk[4..6], i as u16);
+            BigEndian::write_u16(&mut chunk[6..8], total);
+            chunk[8] = part.len() as u8;
+            chunk[HEADER_SIZE..].copy_from_slice(part);
+            chunk
+        })
+        .collect())
+}
+
+// Parses a (decrypted) chunk into (id, index, total, payload), or None if it is malformed
+fn parse(chunk: &[u8]) -> Option<(u32, u16, u16, &[u8])> {
+    if chunk.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let id = BigEndian::read_u32(&chunk[0..4]);
+    let index = BigEndian::read_u16(&chunk[4..6]);
+    let total = BigEndian::read_u16(&chunk[6..8]);
+    let len = chunk[8] as usize;
+
+    if total == 0 || index >= total || len > CHUNK_PAYLOAD || HEADER_SIZE + len > chunk.len() {
+        return None;
+    }
+
+    Some((id, index, total, &chunk[HEADER_SIZE..HEADER_SIZE + len]))
+}
+
+/// Puts the chunks of large messages back together as they are retrieved, which may take
+/// several rounds (e.g., when the sender spreads a message's chunks over rounds). Chunks may
+/// come in any order; those of messages that are not complete yet are kept until they are.
+#[derive(Default)]
+pub struct Reassembler {
+    // (sender, id) -> (total, index -> payload)
+    partial: BTreeMap<(String, u32), (u16, BTreeMap<u16, Vec<u8>>)>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler::default()
+    }
+
+    /// Adds a chunk (as retrieved, padding included) sent by `sender`, and returns the
+    /// message once this was its last missing chunk. Malformed chunks, and chunks that
+    /// disagree with the others of their message on the number of chunks, are dropped.
+    pub fn add(&mut self, sender: &str, chunk: &[u8]) -> Option<LargeMessage> {
+        let (id, index, total, payload) = match parse(chunk) {
+            Some(parsed) => parsed,
+            None => return None,
+        };
+
+        let key = (sender.to_string(), id);
+
+        let complete = {
+            let entry = self.partial
+                .entry(key.clone())
+                .or_insert_with(|| (total, BTreeMap::new()));
+
+            if entry.0 != total {
+                return None;
+            }
+
+            entry.1.insert(index, payload.to_vec());
+            entry.1.len() == total as usize
+        };
+
+        if complete {
+            self.partial.remove(&key).map(|(total, chunks)| message(key, total, chunks))
+        } else {
+            None
+        }
+    }
+
+    /// Returns (and forgets) the messages some of whose chunks are still missing, with what
+    /// was retrieved of them, in order of sender and id.
+    pub fn take_incomplete(&mut self) -> Vec<LargeMessage> {
+        let partial = ::std::mem::replace(&mut self.partial, BTreeMap::new());
+
+        partial
+            .into_iter()
+            .map(|(key, (total, chunks))| message(key, total, chunks))
+            .collect()
+    }
+}
+
+// The message (complete or not) made up of the given chunks
+fn message(key: (String, u32), total: u16, chunks: BTreeMap<u16, Vec<u8>>) -> LargeMessage {
+    let missing = (0..total).filter(|i| !chunks.contains_key(i)).collect();
+    let data = chunks.into_iter().flat_map(|(_, payload)| payload).collect();
+
+    LargeMessage {
+        sender: key.0,
+        id: key.1,
+        data: data,
+        missing: missing,
+    }
+}
+
+/// Puts chunks (as split by `split`, from each sender) back together. Chunks may come in any
+/// order; a message some of whose chunks are missing is returned with what was retrieved of
+/// it (see `LargeMessage::missing`). Messages come in order of sender and id. Every entry
+/// must be a chunk: retrieved messages only say whether they are through `retr_large`.
+pub fn reassemble(chunks: Vec<(String, Vec<u8>)>) -> Vec<LargeMessage> {
+    let mut reassembler = Reassembler::new();
+    let mut whole = Vec::new();
+
+    for (sender, chunk) in chunks {
+        if let Some(msg) = reassembler.add(&sender, &chunk) {
+            whole.push(msg);
+        }
+    }
+
+    whole.extend(reassembler.take_incomplete());
+    whole.sort_by(|a, b| (&a.sender, a.id).cmp(&(&b.sender, b.id)));
+    whole
+}
+
+impl<'a> PungClient<'a> {
+    /// Sends a message of any length (up to `MAX_LARGE_SIZE`) to `recipient` as
+    /// ceil(len / `CHUNK_PAYLOAD`) messages, which must fit within the send rate. The
+    /// recipient gets it back with `retr_large`. Returns the id of the message.
+    pub fn send_large(
+        &mut self,
+        recipient: &str,
+        msg: &[u8],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<u32, PungError> {
+        if !self.peers.contains_key(recipient) {
+            return Err(PungError::InvalidPeer("Invalid recipient name".to_string()));
+        }
+
+        let id = self.rng.borrow_mut().gen_range(1, u32::max_value());
+        let chunks = split(msg, id)?;
+
+        let outgoing: Vec<(&str, &[u8], u32)> =
+            chunks.iter().map(|chunk| (recipient, &chunk[..], 0)).collect();
+
+        self.send_tuples(&outgoing, FRAME_CHUNK, scope, port)?;
+        Ok(id)
+    }
+
+    /// Retrieves messages like `retr_attributed` and puts the chunks of those sent with
+    /// `send_large` back together. Each chunk is a message, so a peer must appear in
+    /// `peer_names` once for every chunk that it sent. Messages sent with `send` come first,
+    /// as they are and as complete messages with id 0, followed by the large messages whose
+    /// last chunk was retrieved by this call. The chunks of a large message that is not
+    /// complete yet are kept by the client until later calls (e.g., in later rounds) retrieve
+    /// the rest of it (see `take_incomplete_large`).
+    pub fn retr_large(
+        &self,
+        peer_names: &[&str],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<LargeMessage>, PungError> {
+        let mut whole = Vec::new();
+        let mut large = Vec::new();
+
+        for (sender, frame, msg) in self.retr_framed(peer_names, scope, port)? {
+            if frame == FRAME_CHUNK {
+                if let Some(msg) = self.large.borrow_mut().add(&sender, &msg) {
+                    large.push(msg);
+                }
+            } else {
+                whole.push(LargeMessage {
+                    sender: sender,
+                    id: 0,
+                    data: msg,
+                    missing: Vec::new(),
+                });
+            }
+        }
+
+        whole.extend(large);
+        Ok(whole)
+    }
+
+    /// Returns (and forgets) the large messages of which `retr_large` retrieved some chunks
+    /// but not all, with what was retrieved of them (see `Reassembler::take_incomplete`).
+    pub fn take_incomplete_large(&self) -> Vec<LargeMessage> {
+        self.large.borrow_mut().take_incomplete()
+    }
+}
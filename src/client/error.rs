@@ -0,0 +1,115 @@
+// Errors returned by the public PungClient methods (see PungError).
+
+use capnp;
+use capnp::Error;
+use proto::ErrorCode;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Why a client call failed. The server reports every failure as a string, which starts with
+/// a `proto::ErrorCode` when the failure is one of the kinds below; the variant is taken from
+/// the code, and `Display` shows the rest of the string, so logs read as they always have.
+#[derive(Debug)]
+pub enum PungError {
+    /// The request carried more tuples or queries than the client's rate allows.
+    RateExceeded(String),
+
+    /// The peer (or recipient) is not one of the client's peers.
+    InvalidPeer(String),
+
+    /// The client is not registered, or has not synced for the current round.
+    NotSynchronized(String),
+
+    /// The request is for another phase or round than the server is in.
+    WrongPhase(String),
+
+    /// A PIR answer did not decode into the requested tuple (see `is_corrupted`).
+    Corrupted(String),
+
+    /// The server's answer does not fit the client's schemes, e.g., the server uses another
+    /// RetScheme or OptScheme than the client (see `is_scheme_mismatch`).
+    SchemeMismatch(String),
+
+    /// Any other failed request (e.g., an invalid configuration).
+    Failed(String),
+
+    /// The RPC itself did not go through, e.g., the connection is gone (see
+    /// `is_disconnected`).
+    Transport(capnp::Error),
+}
+
+impl PungError {
+    /// Returns the server's (or client's) description of the failure.
+    pub fn description(&self) -> &str {
+        match *self {
+            PungError::RateExceeded(ref s)
+            | PungError::InvalidPeer(ref s)
+            | PungError::NotSynchronized(ref s)
+            | PungError::WrongPhase(ref s)
+            | PungError::Corrupted(ref s)
+            | PungError::SchemeMismatch(ref s)
+            | PungError::Failed(ref s) => s,
+            PungError::Transport(ref e) => &e.description,
+        }
+    }
+}
+
+impl fmt::Display for PungError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl error::Error for PungError {
+    fn description(&self) -> &str {
+        PungError::description(self)
+    }
+}
+
+impl From<Error> for PungError {
+    fn from(e: Error) -> PungError {
+        if e.kind != capnp::ErrorKind::Failed {
+            return PungError::Transport(e);
+        }
+
+        let (code, description) = match ErrorCode::parse(&e.description) {
+            Some((code, rest)) => (Some(code), rest.to_string()),
+            None => (None, e.description),
+        };
+
+        match code {
+            Some(ErrorCode::RateExceeded) => PungError::RateExceeded(description),
+            Some(ErrorCode::InvalidPeer) => PungError::InvalidPeer(description),
+            Some(ErrorCode::NotSynchronized) => PungError::NotSynchronized(description),
+            Some(ErrorCode::WrongPhase) => PungError::WrongPhase(description),
+            Some(ErrorCode::Corrupted) => PungError::Corrupted(description),
+            Some(ErrorCode::SchemeMismatch) => PungError::SchemeMismatch(description),
+            None => PungError::Failed(description),
+        }
+    }
+}
+
+// Connecting to the server fails with an I/O error
+impl From<io::Error> for PungError {
+    fn from(e: io::Error) -> PungError {
+        PungError::Transport(Error::from(e))
+    }
+}
+
+// Lets code that still deals in capnp::Error (e.g., a gj event loop) use `?` on client calls.
+// The code goes along, so converting back gives the same variant.
+impl From<PungError> for Error {
+    fn from(e: PungError) -> Error {
+        match e {
+            PungError::RateExceeded(s) => ErrorCode::RateExceeded.error(s),
+            PungError::InvalidPeer(s) => ErrorCode::InvalidPeer.error(s),
+            PungError::NotSynchronized(s) => ErrorCode::NotSynchronized.error(s),
+            PungError::WrongPhase(s) => ErrorCode::WrongPhase.error(s),
+            PungError::Corrupted(s) => ErrorCode::Corrupted.error(s),
+            PungError::SchemeMismatch(s) => ErrorCode::SchemeMismatch.error(s),
+            PungError::Failed(s) => Error::failed(s),
+            PungError::Transport(e) => e,
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Lookup of labels in the collections of a round's buckets (see PungClient::retr).
+
+use std::collections::BTreeMap;
+
+use util;
+use util::bloomfilter::Bloom;
+
+// What the client knows about the labels of a collection
+enum Filter {
+    Labels(Vec<Vec<u8>>), // the collection's labels, sorted (Explicit)
+    Bloom(Bloom, u64),    // filter of (index, label) pairs and length of the collection (Bloom)
+}
+
+/// Locates labels in the collections of a round's buckets. It is built once per round from
+/// the label mappings (Explicit) or bloom filters (Bloom) returned by the server, and it
+/// uses the best lookup available for each: a binary search over sorted labels, or a probe
+/// of the bloom filter at every index of the collection.
+#[derive(Default)]
+pub struct LabelIndex {
+    filters: BTreeMap<(usize, usize), Filter>, // (bucket, collection) -> filter
+}
+
+impl LabelIndex {
+    pub fn new() -> LabelIndex {
+        LabelIndex {
+            filters: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the labels of a collection, which must be sorted (as the server returns them).
+    pub fn insert_labels(&mut self, bucket: usize, collection: usize, labels: Vec<Vec<u8>>) {
+        self.filters.insert((bucket, collection), Filter::Labels(labels));
+    }
+
+    /// Adds the bloom filter of a collection of `len` tuples.
+    pub fn insert_bloom(&mut self, bucket: usize, collection: usize, bloom: Bloom, len: u64) {
+        self.filters.insert((bucket, collection), Filter::Bloom(bloom, len));
+    }
+
+    /// Returns the number of tuples in a collection, or `None` if it was never added.
+    pub fn len(&self, bucket: usize, collection: usize) -> Option<u64> {
+        self.filters.get(&(bucket, collection)).map(|filter| match *filter {
+            Filter::Labels(ref labels) => labels.len() as u64,
+            Filter::Bloom(_, len) => len,
+        })
+    }
+
+    /// Returns the index of `label` in a collection, or `None` if it is not there (or the
+    /// collection was never added). Bloom filters can yield false positives, so the tuple
+    /// at the returned index may still have a different label.
+    pub fn index_of(&self, bucket: usize, collection: usize, label: &[u8]) -> Option<u64> {
+        match self.filters.get(&(bucket, collection)) {
+            Some(&Filter::Labels(ref labels)) => util::get_index(labels, label),
+            Some(&Filter::Bloom(ref bloom, len)) => util::get_idx_bloom(bloom, label, len),
+            None => None,
+        }
+    }
+}
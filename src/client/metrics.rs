@@ -0,0 +1,113 @@
+// Bandwidth accounting of a client's requests (see PungClient::set_metrics).
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The request for which bytes were uploaded or downloaded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum RpcKind {
+    /// Tuples sent, and the bucket information that comes back
+    Send,
+
+    /// Label mappings (Explicit), whether requested or piggybacked on a PIR answer
+    Labels,
+
+    /// Bloom filters (Bloom), whether requested or piggybacked on a PIR answer
+    Bloom,
+
+    /// PIR queries and answers
+    Pir,
+}
+
+/// Receives the number of bytes that a client uploads and downloads for each request. The
+/// counts are those of the payloads (tuples, queries, answers, labels, filters) plus the
+/// fixed-size fields of the request, not of the Cap'n Proto messages that carry them.
+pub trait PungMetrics {
+    fn record_upload(&self, kind: RpcKind, bytes: usize);
+
+    /// `uncompressed` is what `bytes` would have been without compression (see
+    /// `PungClient::set_compression`), or `bytes` if the response was not compressed.
+    fn record_download(&self, kind: RpcKind, bytes: usize, uncompressed: usize);
+}
+
+/// Discards every measurement (the default).
+pub struct NoMetrics;
+
+impl PungMetrics for NoMetrics {
+    fn record_upload(&self, _kind: RpcKind, _bytes: usize) {}
+
+    fn record_download(&self, _kind: RpcKind, _bytes: usize, _uncompressed: usize) {}
+}
+
+// So that whoever set the metrics keeps a handle with which to read them
+impl<M: PungMetrics> PungMetrics for Rc<M> {
+    fn record_upload(&self, kind: RpcKind, bytes: usize) {
+        (**self).record_upload(kind, bytes)
+    }
+
+    fn record_download(&self, kind: RpcKind, bytes: usize, uncompressed: usize) {
+        (**self).record_download(kind, bytes, uncompressed)
+    }
+}
+
+/// Bytes transferred for one kind of request (see `CountingMetrics`).
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Totals {
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub downloaded_uncompressed: usize,
+}
+
+/// Adds up the bytes transferred for each kind of request. To read the totals while a
+/// client uses it, give the client an `Rc<CountingMetrics>` and keep a clone of it.
+#[derive(Default)]
+pub struct CountingMetrics {
+    totals: RefCell<HashMap<RpcKind, Totals>>,
+}
+
+impl CountingMetrics {
+    pub fn new() -> CountingMetrics {
+        CountingMetrics::default()
+    }
+
+    /// Returns the totals of the given kind of request.
+    pub fn get(&self, kind: RpcKind) -> Totals {
+        self.totals
+            .borrow()
+            .get(&kind)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Returns the totals across all kinds of requests.
+    pub fn total(&self) -> Totals {
+        self.totals
+            .borrow()
+            .values()
+            .fold(Totals::default(), |acc, t| Totals {
+                uploaded: acc.uploaded + t.uploaded,
+                downloaded: acc.downloaded + t.downloaded,
+                downloaded_uncompressed: acc.downloaded_uncompressed + t.downloaded_uncompressed,
+            })
+    }
+
+    /// Starts counting from zero again (e.g., at the start of every round).
+    pub fn reset(&self) {
+        self.totals.borrow_mut().clear();
+    }
+}
+
+impl PungMetrics for CountingMetrics {
+    fn record_upload(&self, kind: RpcKind, bytes: usize) {
+        self.totals.borrow_mut().entry(kind).or_insert_with(Totals::default).uploaded += bytes;
+    }
+
+    fn record_download(&self, kind: RpcKind, bytes: usize, uncompressed: usize) {
+        let mut totals = self.totals.borrow_mut();
+        let entry = totals.entry(kind).or_insert_with(Totals::default);
+
+        entry.downloaded += bytes;
+        entry.downloaded_uncompressed += uncompressed;
+    }
+}
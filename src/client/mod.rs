@@ -13,19 +13,53 @@ use gj;
 use gjio; // asynchronous IO libraries
 
 use pir::pir_client::PirClient;
+use proto::ErrorCode;
+use pung_capnp;
 use pung_capnp::pung_rpc;
+use pung_capnp::Piggyback;
 
 use rand;
 use rand::Rng;
+use rand::SeedableRng;
+use std::cell::RefCell;
+use std::cmp;
 use std::cmp::Ordering;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::mem;
 use std::net::ToSocketAddrs;
+use std::time::{Duration, Instant};
 
 use util;
 use util::bloomfilter;
 
+pub use self::error::PungError;
+
+pub mod assign;
+pub mod chunk;
+pub mod error;
+pub mod index;
+pub mod metrics;
 pub mod pcrypto;
+pub mod session;
+
+/// Number of alias labels derived (or, for cover tuples, drawn) under aliasing before giving up
+/// on finding one that falls in a different bucket than the original label. With 2 buckets,
+/// each attempt fails with probability 1/2, so this is only reached when there is a single
+/// bucket.
+pub const MAX_ALIAS_ATTEMPTS: u64 = 64;
+
+/// Length (in bytes) of the longest message that can be sent. Every message is sent behind a
+/// frame byte that says what it is (see `seal`), which takes one byte of the tuple's
+/// plaintext (`pcrypto::MESSAGE_SIZE`).
+pub const MESSAGE_SIZE: usize = pcrypto::MESSAGE_SIZE - 1;
+
+// Frame bytes: a message sent with send (or round_trip, or SendSession::add), or a chunk of
+// a message sent with send_large (see chunk.rs)
+const FRAME_MESSAGE: u8 = 0;
+const FRAME_CHUNK: u8 = 1;
 
 struct PungPeer {
     uid_self: u64,
@@ -43,6 +77,10 @@ impl PungPeer {
     }
 }
 
+// (peer, label) pairs to retrieve from each bucket, indexed by bucket. Retrievals go through
+// buckets in order, so this must not be a map that could be iterated in any other order.
+type BucketLabels<'a> = Vec<Vec<(&'a PungPeer, Vec<u8>)>>;
+
 // information about a bucket. Number of tuples in the bucket, and lmid
 struct BucketInfo {
     num: u64,
@@ -63,6 +101,91 @@ impl BucketInfo {
     }
 }
 
+// The PIR requests that retrieve the tuples of some systematic collections of a hybrid 4 or 8
+// bucket (see plan_h4_targets), which can be in flight along with those of other buckets, and
+// how each tuple is rebuilt from the answers.
+struct PartPlan {
+    // (bucket, collection, level, idx, len) of each request (see pir_retr_many)
+    requests: Vec<(usize, u32, u32, u64, u64)>,
+
+    // Request whose answer is each part's share of the tuples (None if it is a zero tuple)
+    shares: Vec<Option<usize>>,
+
+    // Parts of each target
+    target_parts: Vec<Vec<usize>>,
+}
+
+impl PartPlan {
+    fn new(num_parts: usize) -> PartPlan {
+        PartPlan {
+            requests: Vec::with_capacity(num_parts),
+            shares: vec![None; num_parts],
+            target_parts: Vec::new(),
+        }
+    }
+
+    // Adds the request for index `idx` of part (collection) `part` of the bucket, where `idx`
+    // is an index in the target collection and `len` is the part's length. A part can be one
+    // element shorter than the target (see `util::h4_part_len`), in which case the other parts
+    // already yield the tuple: fetch anything from this part and use a zero tuple instead.
+    // Empty parts (of buckets with fewer tuples than parts) have no PIR database and are not
+    // requested at all; like the lengths of the parts, that only depends on the bucket's size.
+    fn add_part(
+        &mut self,
+        bucket: usize,
+        part: usize,
+        idx: u64,
+        len: u64,
+        rng: &mut rand::ChaChaRng,
+    ) -> Result<(), Error> {
+        // Anything beyond the end of the part means the index was mapped to the wrong part
+        // (e.g., the server's layout does not match what it told us)
+        if idx > len {
+            return Err(Error::failed(format!(
+                "Index {} is out of range for part {} of length {}",
+                idx,
+                part,
+                len
+            )));
+        }
+
+        if len == 0 {
+            return Ok(());
+        } else if idx == len {
+            let tmp_idx = rng.next_u64() % len;
+            self.requests.push((bucket, part as u32, 0, tmp_idx, len));
+        } else {
+            self.shares[part] = Some(self.requests.len());
+            self.requests.push((bucket, part as u32, 0, idx, len));
+        }
+
+        Ok(())
+    }
+
+    // Creates each target's tuple by XORing its parts together, given the answers to the
+    // requests (in order)
+    fn rebuild(&self, answers: &[db::PungTuple]) -> Vec<db::PungTuple> {
+        self.target_parts
+            .iter()
+            .map(|parts| {
+                let mut tuple = db::PungTuple::default();
+
+                for &part in parts {
+                    if let Some(i) = self.shares[part] {
+                        tuple = &tuple ^ &answers[i];
+                    }
+                }
+
+                tuple
+            })
+            .collect()
+    }
+}
+
+/// A Pung client. It is neither `Send` nor `Sync`: its RPC connection belongs to the event
+/// loop of the thread that created it, and its PIR client wraps a C++ object that is not
+/// thread-safe. To run several clients concurrently (e.g., for load testing), give each
+/// thread its own event loop and create (and use) its clients there.
 pub struct PungClient<'a> {
     id: u64, // id to register with service
     name: &'a str,
@@ -70,20 +193,231 @@ pub struct PungClient<'a> {
     ret_rate: u32, // roughly same as # of buckets
 
     conn: pung_rpc::Client,
+    // connections to the server's other workers and our (retrieval-only) ids on them, in
+    // order of shard (see ClientConfig::workers)
+    helpers: Vec<(pung_rpc::Client, u64)>,
 
     round: u64,
     buckets: Vec<BucketInfo>, // Information about buckets for this round
+    history: VecDeque<(u64, Vec<BucketInfo>)>, // Bucket information of past rounds (oldest first)
+    history_len: usize,                        // Number of past rounds kept in history
 
     ret_scheme: db::RetScheme, // retrieval scheme
     opt_scheme: db::OptScheme, // optimization scheme
 
     peers: HashMap<&'a str, PungPeer>,
+    dh_key: pcrypto::DhKeyPair, // key pair listed in the server's key directory (see add_peer)
 
     pir_handler: PirClient<'a>,
-    partitions: Vec<Vec<u8>>, // Static partitioning of label space
+    partitions: util::Partitioning, // Static partitioning of label space
+
+    // Mapping between collection and encoding recipe (i.e., which pieces to xor together).
+    // Pieces are sorted so that they are always requested in the same order.
+    h4_mappings: HashMap<usize, [BTreeSet<usize>; 4]>,
+    h8_mappings: HashMap<usize, Vec<BTreeSet<usize>>>, // same for hybrid 8 (see util::h8_mappings)
+
+    compression: bool, // whether responses are run-length compressed (negotiated at register)
+    piggyback: bool,   // whether labels or blooms come with the first retrieval of a round
+    verify_answers: bool, // whether PIR answers are checked against the server's checksums
+    bloom_hashes: Option<u32>, // hash functions of the server's bloom filters (see register)
+    bloom_fp: f64,             // false positive rate of the server's bloom filters (likewise)
+    alpha: Option<u64>,        // PIR aggregation of every level, if set (likewise)
+
+    aead: pcrypto::AeadAlgorithm, // AEAD used for peers added from now on
+    label_prf: pcrypto::LabelPrf, // PRF from which all labels are derived
+
+    cover_rates: Option<(u32, u32)>, // (send, retrieval) volume kept regardless of activity
+
+    assigner: Box<assign::BucketAssigner>, // picks one of the two buckets of a label (aliasing)
+    metrics: Box<metrics::PungMetrics>,     // told how many bytes each request transfers
+
+    // (bucket, collection, level, length) of the PIR queries of the last retrieval, in order
+    queries: RefCell<Vec<(usize, u32, u32, u64)>>,
+    // labels of the last retrieval whose presence was checked (found or not)
+    checked: RefCell<HashSet<Vec<u8>>>,
+    // message number of each label scheduled for the last retrieval (see msg_index)
+    msg_indices: RefCell<HashMap<Vec<u8>, u64>>,
+    // chunks of large messages retrieved so far whose other chunks are still missing
+    large: RefCell<chunk::Reassembler>,
+
+    // source of decoy indices, dummy secrets, and cover tuples (seeded from the OS unless
+    // the client was created with with_seed)
+    rng: RefCell<rand::ChaChaRng>,
+}
+
+
+/// Configuration of a `PungClient`. `ClientConfig::new` fills in defaults for everything
+/// but the client's name and the server's address, so clients are usually configured with
+/// `ClientConfig { send_rate: 2, ..ClientConfig::new(name, address) }`.
+#[derive(Clone)]
+pub struct ClientConfig<'a> {
+    /// Name of the client (used to order peers, see `add_peer`)
+    pub name: &'a str,
+
+    /// Address of the server (host:port)
+    pub address: String,
+
+    /// Messages sent per round (default 1)
+    pub send_rate: u32,
+
+    /// Messages retrieved per round, which is also the number of buckets (default 1)
+    pub ret_rate: u32,
+
+    /// Maximum PIR recursion depth (default 1)
+    pub depth: u64,
+
+    /// PIR aggregation of every level, which must match the server's (default: whatever the
+    /// server uses, which it tells the client when it registers). See `util::pir_alpha`.
+    pub alpha: Option<u64>,
+
+    /// Retrieval scheme, which must match the server's (default Explicit)
+    pub ret_scheme: db::RetScheme,
+
+    /// Optimization scheme, which must match the server's (default Normal)
+    pub opt_scheme: db::OptScheme,
+
+    /// Whether to ask for compressed responses (see `set_compression`)
+    pub compression: bool,
+
+    /// Whether to piggyback filters on retrievals (see `set_piggyback`)
+    pub piggyback: bool,
+
+    /// Whether to verify PIR answers (see `set_verify_answers`)
+    pub verify_answers: bool,
+
+    /// AEAD used with peers (see `set_aead`)
+    pub aead: pcrypto::AeadAlgorithm,
+
+    /// PRF from which labels are derived, which must match the peers' (default HMAC-SHA256)
+    pub label_prf: pcrypto::LabelPrf,
+
+    /// Cover (send, retrieval) rates, if any (see `set_cover_rates`)
+    pub cover_rates: Option<(u32, u32)>,
+
+    /// Addresses of other workers of the same server (default none), over which PIR
+    /// requests are spread: those for bucket b go to worker b % (1 + `workers.len()`),
+    /// where worker 0 is `address`. Everything else (e.g., sends) goes to `address`. Which
+    /// worker gets a request only depends on its bucket, and every bucket is queried every
+    /// round, so each worker sees the same requests whatever messages are retrieved.
+    pub workers: Vec<String>,
+}
+
+impl<'a> ClientConfig<'a> {
+    pub fn new(name: &'a str, address: &str) -> ClientConfig<'a> {
+        ClientConfig {
+            name: name,
+            address: address.to_string(),
+            send_rate: 1,
+            ret_rate: 1,
+            depth: 1,
+            alpha: None,
+            ret_scheme: db::RetScheme::Explicit,
+            opt_scheme: db::OptScheme::Normal,
+            compression: false,
+            piggyback: false,
+            verify_answers: false,
+            aead: pcrypto::AeadAlgorithm::default(),
+            label_prf: pcrypto::LabelPrf::default(),
+            cover_rates: None,
+            workers: Vec::new(),
+        }
+    }
+}
+
+/// Builds a `PungClient` one setting at a time, e.g.,
+/// `PungClientBuilder::new().name("alice").address(addr).send_rate(2).build(scope, port)`.
+/// Settings that are not given keep the defaults of `ClientConfig::new`, except for the name
+/// and the address, without which `build` fails.
+#[derive(Clone)]
+pub struct PungClientBuilder<'a> {
+    config: ClientConfig<'a>,
+}
+
+impl<'a> PungClientBuilder<'a> {
+    pub fn new() -> PungClientBuilder<'a> {
+        PungClientBuilder {
+            config: ClientConfig::new("", ""),
+        }
+    }
+
+    /// Starts from the given configuration (e.g., to change a few of its settings).
+    pub fn with_config(config: ClientConfig<'a>) -> PungClientBuilder<'a> {
+        PungClientBuilder { config: config }
+    }
+
+    pub fn name(mut self, name: &'a str) -> PungClientBuilder<'a> {
+        self.config.name = name;
+        self
+    }
+
+    pub fn address(mut self, address: &str) -> PungClientBuilder<'a> {
+        self.config.address = address.to_string();
+        self
+    }
+
+    pub fn send_rate(mut self, send_rate: u32) -> PungClientBuilder<'a> {
+        self.config.send_rate = send_rate;
+        self
+    }
+
+    pub fn ret_rate(mut self, ret_rate: u32) -> PungClientBuilder<'a> {
+        self.config.ret_rate = ret_rate;
+        self
+    }
+
+    pub fn depth(mut self, depth: u64) -> PungClientBuilder<'a> {
+        self.config.depth = depth;
+        self
+    }
+
+    /// Requires the server to use `alpha` as the PIR aggregation (see `ClientConfig::alpha`).
+    pub fn alpha(mut self, alpha: u64) -> PungClientBuilder<'a> {
+        self.config.alpha = Some(alpha);
+        self
+    }
+
+    pub fn ret_scheme(mut self, ret_scheme: db::RetScheme) -> PungClientBuilder<'a> {
+        self.config.ret_scheme = ret_scheme;
+        self
+    }
+
+    pub fn opt_scheme(mut self, opt_scheme: db::OptScheme) -> PungClientBuilder<'a> {
+        self.config.opt_scheme = opt_scheme;
+        self
+    }
+
+    /// Adds a worker over which to spread PIR requests (see `ClientConfig::workers`).
+    pub fn worker(mut self, address: &str) -> PungClientBuilder<'a> {
+        self.config.workers.push(address.to_string());
+        self
+    }
+
+    /// Returns the configuration built so far.
+    pub fn config(&self) -> &ClientConfig<'a> {
+        &self.config
+    }
+
+    /// Connects to the server (see `PungClient::with_config`). Invalid settings, addresses
+    /// that do not resolve, and servers that cannot be reached are all reported as errors.
+    pub fn build(
+        self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<PungClient<'a>, PungError> {
+        if self.config.name.is_empty() {
+            return Err(PungError::Failed("The client has no name".to_string()));
+        } else if self.config.address.is_empty() {
+            return Err(PungError::Failed("No server address was given".to_string()));
+        }
+
+        PungClient::with_config(self.config, scope, port)
+    }
+}
 
-    // Mapping between collection and encoding recipe (i.e., which pieces to xor together)
-    h4_mappings: HashMap<usize, [HashSet<usize>; 4]>,
+impl<'a> Default for PungClientBuilder<'a> {
+    fn default() -> PungClientBuilder<'a> {
+        PungClientBuilder::new()
+    }
 }
 
 
@@ -93,6 +427,10 @@ macro_rules! h_set {
 
 
 impl<'a> PungClient<'a> {
+    /// Connects to the server at `address`. Panics if the address does not resolve, the
+    /// connection fails, or the configuration is invalid; `PungClientBuilder::build` returns
+    /// an error instead.
+    #[deprecated(note = "use PungClient::with_config or PungClientBuilder")]
     pub fn new(
         name: &'a str,
         address: &str,
@@ -104,42 +442,80 @@ impl<'a> PungClient<'a> {
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
     ) -> PungClient<'a> {
-        let addr = match address.to_socket_addrs() {
-            Ok(mut v) => match v.next() {
-                Some(a) => a,
-                None => panic!("Error: Address iterator is empty."),
-            },
-
-            Err(e) => panic!("Error parsing address: {:?}", e),
+        let config = ClientConfig {
+            send_rate: send_rate,
+            ret_rate: ret_rate,
+            depth: depth,
+            ret_scheme: ret_scheme,
+            opt_scheme: opt_scheme,
+            ..ClientConfig::new(name, address)
         };
 
-        let network = port.get_network();
+        match PungClient::with_config(config, scope, port) {
+            Ok(c) => c,
+            Err(e) => panic!("Error creating the client: {}", e),
+        }
+    }
 
-        let address = network.get_tcp_address(addr);
-        let stream = match address.connect().wait(scope, port) {
-            Ok(s) => s,
-            Err(e) => panic!("Error connecting to addr: {:?}", e),
-        };
+    /// Connects to the server given in `config`. The client still has to `register` (after
+    /// adding its peers) before it can send or retrieve.
+    pub fn with_config(
+        config: ClientConfig<'a>,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<PungClient<'a>, PungError> {
+        let ClientConfig {
+            name,
+            address,
+            send_rate,
+            ret_rate,
+            depth,
+            alpha,
+            ret_scheme,
+            opt_scheme,
+            compression,
+            piggyback,
+            verify_answers,
+            aead,
+            label_prf,
+            cover_rates,
+            workers,
+        } = config;
+
+        if send_rate == 0 {
+            return Err(PungError::Failed("Invalid send rate (0)".to_string()));
+        } else if ret_rate == 0 {
+            return Err(PungError::Failed("Invalid retrieval rate (0)".to_string()));
+        } else if alpha == Some(0) {
+            return Err(PungError::Failed("Invalid PIR alpha (0)".to_string()));
+        } else if label_prf.output_bytes() != db::LABEL_SIZE {
+            return Err(PungError::Failed(
+                "Label PRF output does not match the label size".to_string(),
+            ));
+        } else if let Err(e) = db::check_config(ret_scheme, opt_scheme, depth) {
+            return Err(PungError::Failed(e.to_string()));
+        }
 
-        let mut reader_options: capnp::message::ReaderOptions = Default::default();
-        reader_options.traversal_limit_in_words(300 * 1024 * 1024);
+        let conn = connect(&address, scope, port)?;
 
-        let network = Box::new(twoparty::VatNetwork::new(
-            stream.clone(),
-            stream,
-            rpc_twoparty_capnp::Side::Client,
-            Default::default(),
-        ));
+        // Other workers only get (our share of) PIR requests, so we register with them later
+        let mut helpers = Vec::with_capacity(workers.len());
 
-        // Initialize RPC client
-        let mut rpc_system = RpcSystem::new(network, None);
+        for worker in &workers {
+            helpers.push((connect(worker, scope, port)?, 0));
+        }
 
         // Initialize static partitions of label space
-        let mut partitions: Vec<Vec<u8>> = Vec::with_capacity(ret_rate as usize);
+        let partitions = match util::Partitioning::new(ret_rate as usize) {
+            Ok(p) => p,
+            Err(e) => return Err(PungError::Failed(e.to_string())),
+        };
 
-        for i in 0..ret_rate as usize {
-            partitions.push(util::label_marker(i, ret_rate as usize));
-        }
+        // Parameters are set for each query (see update_params_with_depth)
+        let pir_handler = match PirClient::new(1, 1, 1, depth) {
+            Ok(handler) => handler,
+            Err(e) => return Err(PungError::Failed(e.to_string())),
+        };
 
         // Initialize h4 mapping
         let mut h4_mappings = HashMap::new();
@@ -185,37 +561,130 @@ impl<'a> PungClient<'a> {
             );
         }
 
-        PungClient {
+        // Initialize h8 mapping
+        let mut h8_mappings = HashMap::new();
+
+        if opt_scheme == db::OptScheme::Hybrid8 {
+            h8_mappings.extend(util::h8_mappings().into_iter().enumerate());
+        }
+
+        let mut rng = match rand::OsRng::new() {
+            Ok(mut os_rng) => os_rng.gen::<rand::ChaChaRng>(),
+            Err(e) => return Err(PungError::Failed(format!("OS randomness is unavailable: {}", e))),
+        };
+
+        let dh_key = pcrypto::DhKeyPair::generate(&mut rng);
+
+        let mut client = PungClient {
             id: 0,
             name: name,
             send_rate: send_rate,
             ret_rate: ret_rate,
             round: 0,
             buckets: Vec::with_capacity(ret_rate as usize),
-            conn: rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server),
+            history: VecDeque::new(),
+            history_len: 0,
+            conn: conn,
+            helpers: helpers,
             ret_scheme: ret_scheme,
             opt_scheme: opt_scheme,
             peers: HashMap::new(),
-            pir_handler: PirClient::new(1, 1, 1, depth),
+            dh_key: dh_key,
+            pir_handler: pir_handler,
             partitions: partitions,
             h4_mappings: h4_mappings,
+            h8_mappings: h8_mappings,
+            compression: compression,
+            piggyback: piggyback,
+            verify_answers: verify_answers,
+            bloom_hashes: None,
+            bloom_fp: db::BLOOM_FP,
+            alpha: alpha,
+            aead: aead,
+            label_prf: label_prf,
+            cover_rates: None,
+            assigner: Box::new(assign::LeastFull),
+            metrics: Box::new(metrics::NoMetrics),
+            queries: RefCell::new(Vec::new()),
+            checked: RefCell::new(HashSet::new()),
+            msg_indices: RefCell::new(HashMap::new()),
+            large: RefCell::new(chunk::Reassembler::new()),
+            rng: RefCell::new(rng),
+        };
+
+        if let Some((cover_send_rate, cover_ret_rate)) = cover_rates {
+            client.set_cover_rates(cover_send_rate, cover_ret_rate)?;
         }
+
+        Ok(client)
+    }
+
+    /// Like `with_config`, but the client's randomness (decoy PIR indices, dummy labels, and
+    /// cover tuples) is derived from `seed`, so that it is the same on every run. This is
+    /// for reproducible tests and experiments only: anyone who knows the seed can tell the
+    /// client's dummy requests apart from its real ones.
+    pub fn with_seed(
+        config: ClientConfig<'a>,
+        seed: u64,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<PungClient<'a>, PungError> {
+        let client = PungClient::with_config(config, scope, port)?;
+
+        let seed = [seed as u32, (seed >> 32) as u32];
+        *client.rng.borrow_mut() = rand::ChaChaRng::from_seed(&seed);
+
+        Ok(client)
     }
 
     pub fn get_round(&self) -> u64 {
         self.round
     }
 
+    /// Returns the (bucket, collection, level, length) of every PIR query issued by the last
+    /// `retr` (or `retr_stream`), in the order they were sent, where the length is the number
+    /// of tuples queried over. This order depends only on the round's buckets and the peers
+    /// retrieved from, never on the order of a hash map.
+    pub fn last_queries(&self) -> Vec<(usize, u32, u32, u64)> {
+        self.queries.borrow().clone()
+    }
+
     pub fn inc_round(&mut self, val: u64) {
+        if self.history_len > 0 && !self.buckets.is_empty() {
+            let capacity = self.ret_rate as usize;
+            let buckets = mem::replace(&mut self.buckets, Vec::with_capacity(capacity));
+            self.history.push_back((self.round, buckets));
+
+            while self.history.len() > self.history_len {
+                self.history.pop_front();
+            }
+        }
+
         self.round += val;
         self.buckets.clear();
     }
 
+    /// Keeps the bucket information of the given number of past rounds so that messages
+    /// sent during those rounds can still be retrieved with `retr_round`. The server must
+    /// retain at least as many rounds (see --retain-rounds).
+    pub fn set_history(&mut self, rounds: usize) {
+        self.history_len = rounds;
+
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+    }
+
     /// Adds a peer. A unique id between peer and `self` is derived
     /// based on the names (lexicographically smaller name gets 0,
-    /// the other gets 1).
-    pub fn add_peer(&mut self, peer: &'a str, secret: &[u8]) {
-        let keys = pcrypto::derive_keys(secret);
+    /// the other gets 1). Labels are derived from these ids, so both
+    /// clients must use the exact same names for each other (see `peer_uids`).
+    /// A client may also add itself (e.g., for notes to self, or for several devices that
+    /// share a name and secret). Both of its uids are then 0, so the labels under which it
+    /// sends messages to itself are those under which it retrieves them.
+    /// The secret is shared out of band (see `add_peer` to agree on one through the server).
+    pub fn add_peer_with_secret(&mut self, peer: &'a str, secret: &[u8]) {
+        let keys = pcrypto::derive_keys(secret, self.aead);
 
         if self.name < peer {
             self.peers.insert(peer, PungPeer::new(0, 1, keys));
@@ -226,40 +695,268 @@ impl<'a> PungClient<'a> {
         }
     }
 
+    /// Adds a peer like `add_peer_with_secret`, but with a secret agreed on with the peer's
+    /// public key, which is fetched from the server's key directory (see `pcrypto::dh_agree`).
+    /// Both clients must have registered (which uploads their public keys) before either adds
+    /// the other. The server learns who looks up whose key, and could hand out keys of its
+    /// own, so this is only as trustworthy as the server's directory.
+    pub fn add_peer(
+        &mut self,
+        peer: &'a str,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(), PungError> {
+        let peer_key = self.fetch_key(peer, scope, port)?;
+
+        let secret = match pcrypto::dh_agree(&self.dh_key, &peer_key) {
+            Ok(s) => s,
+            Err(e) => return Err(PungError::InvalidPeer(format!("{}: {}", peer, e.description))),
+        };
+
+        self.add_peer_with_secret(peer, &secret);
+        Ok(())
+    }
+
+    /// Returns the public key that `peer` registered with the server (see `add_peer`).
+    pub fn fetch_key(
+        &self,
+        peer: &str,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<u8>, PungError> {
+        let mut key_request = self.conn.get_key_request();
+        key_request.get().set_name(peer);
+
+        let response = key_request.send().promise.wait(scope, port)?;
+        Ok(response.get()?.get_public_key()?.to_vec())
+    }
+
+    /// Returns the public key that this client lists in the server's key directory.
+    pub fn public_key(&self) -> &[u8] {
+        self.dh_key.public_key()
+    }
+
+    /// Replaces this client's key pair. The new public key is uploaded at the next `sync`;
+    /// peers that were already added keep the secrets they agreed on, so only peers added
+    /// afterwards (on both sides) agree on secrets with the new key.
+    pub fn rotate_dh_key(&mut self) {
+        self.dh_key = pcrypto::DhKeyPair::generate(&mut *self.rng.borrow_mut());
+    }
+
+    /// Replaces the keys shared with `peer` by keys derived from `new_secret`, keeping the
+    /// uids that `add_peer` assigned (and the AEAD). Both peers must rotate before the same
+    /// round: messages sent under the old keys cannot be retrieved with the new ones and
+    /// vice versa.
+    pub fn rotate_peer_secret(
+        &mut self,
+        peer: &str,
+        new_secret: &[u8],
+    ) -> Result<(), PungError> {
+        match self.peers.get_mut(peer) {
+            Some(p) => {
+                p.keys = pcrypto::derive_keys(new_secret, p.keys.aead);
+                Ok(())
+            }
+            None => Err(PungError::InvalidPeer("Invalid peer name".to_string())),
+        }
+    }
+
+    /// Returns the (own uid, peer's uid) pair that `add_peer` assigned to `peer`, or `None`
+    /// if it is not a peer. Two clients agree on the assignment if and only if one's pair is
+    /// the other's reversed: messages are sent under the peer's uid and retrieved under ours.
+    pub fn peer_uids(&self, peer: &str) -> Option<(u64, u64)> {
+        self.peers.get(peer).map(|p| (p.uid_self, p.uid_peer))
+    }
+
     /// Sets up a fake peer with which to encrypt messages that are meant to be sent to nobody
     pub fn init_dummy_peer(&mut self) {
         let mut secret = [0u8; 256];
-        let mut rng = rand::ChaChaRng::new_unseeded();
-        rng.fill_bytes(&mut secret);
+        self.rng.borrow_mut().fill_bytes(&mut secret);
 
-        let keys = pcrypto::derive_keys(&secret);
+        let keys = pcrypto::derive_keys(&secret, self.aead);
         self.peers.insert("dummy", PungPeer::new(0, 0, keys));
     }
 
+    /// Asks the server to compress bloom filters, label mappings, and PIR answers.
+    /// Compression is negotiated during `register`, so this must be called before it.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// Sets the AEAD with which messages to and from peers are encrypted (both peers must
+    /// use the same one). Keys are bound to it when peers are added, so this must be called
+    /// before `init_dummy_peer` and `add_peer`.
+    pub fn set_aead(&mut self, aead: pcrypto::AeadAlgorithm) {
+        self.aead = aead;
+    }
+
+    /// Asks for the label mapping (Explicit) or bloom filters (Bloom) together with the first
+    /// PIR answer of each round rather than in a separate RPC, which saves a round trip.
+    /// Since the first query is then issued before seeing the filters, it uses one of the
+    /// bucket's dummy slots, and `retr` fails (rather than lose a message) if that bucket has
+    /// a message for every retrieval. This only applies to the Normal and Aliasing schemes.
+    pub fn set_piggyback(&mut self, enabled: bool) {
+        self.piggyback = enabled;
+    }
+
+    /// Asks the server for the checksum of every tuple in each queried collection (or tree
+    /// level) and checks the decoded PIR answers against them, so that a corrupted answer is
+    /// reported (see `is_corrupted`) rather than mistaken for a missing message. This reveals
+    /// nothing about the queries but adds 4 bytes per tuple to every answer, so it is for
+    /// debugging only and servers reject it unless started with --debug.
+    pub fn set_verify_answers(&mut self, enabled: bool) {
+        self.verify_answers = enabled;
+    }
+
+    /// Changes the number of messages sent per round. The server learns the new rate on the
+    /// next `sync`, which applies it to the round returned unless tuples were already sent
+    /// for it (and from the following round otherwise). This saves registering again, but
+    /// the server sees every change: a rate that follows the client's actual volume reveals
+    /// when it is more or less active. Cover rates (see `set_cover_rates`) hide this since
+    /// only the cover send rate is reported, which the new rate may then not exceed.
+    pub fn set_send_rate(&mut self, rate: u32) -> Result<(), PungError> {
+        if rate == 0 {
+            return Err(PungError::Failed("Invalid send rate (0)".to_string()));
+        }
+
+        if let Some((cover_send_rate, _)) = self.cover_rates {
+            if rate > cover_send_rate {
+                return Err(PungError::Failed(
+                    "Cover rates must be at least the real rates".to_string(),
+                ));
+            }
+        }
+
+        self.send_rate = rate;
+        Ok(())
+    }
+
+    /// Sends and retrieves at fixed rates that may exceed the real ones so that the volume
+    /// of traffic does not reveal how many messages are actually exchanged. Every send then
+    /// carries exactly `cover_send_rate` tuples (real ones padded with random ones, so all of
+    /// a round's messages must go in one send), and retrievals are made as if
+    /// `cover_ret_rate` labels were being retrieved. The server is told these rates during
+    /// `register`, so this must be called before it.
+    pub fn set_cover_rates(
+        &mut self,
+        cover_send_rate: u32,
+        cover_ret_rate: u32,
+    ) -> Result<(), PungError> {
+        if cover_send_rate < self.send_rate || cover_ret_rate < self.ret_rate {
+            return Err(PungError::Failed(
+                "Cover rates must be at least the real rates".to_string(),
+            ));
+        }
+
+        self.cover_rates = Some((cover_send_rate, cover_ret_rate));
+        Ok(())
+    }
+
+    /// Sets the policy that decides from which of its two buckets each message is retrieved
+    /// under aliasing (`assign::LeastFull` by default).
+    pub fn set_bucket_assigner(&mut self, assigner: Box<assign::BucketAssigner>) {
+        self.assigner = assigner;
+    }
+
+    /// Sets where the number of bytes that each request uploads and downloads is reported
+    /// (nowhere by default). See `metrics::CountingMetrics` to add them up.
+    pub fn set_metrics(&mut self, metrics: Box<metrics::PungMetrics>) {
+        self.metrics = metrics;
+    }
+
     /// Register with the server and receive a client id
     pub fn register(
         &mut self,
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<u64, Error> {
+    ) -> Result<u64, PungError> {
         let mut reg_request = self.conn.register_request();
-        reg_request.get().set_rate(self.send_rate);
+        reg_request.get().set_compress(self.compression);
+
+        // The server checks that it partitions labels as we do (ret_rate is our bucket count)
+        reg_request.get().set_buckets(self.ret_rate);
+
+        // Our entry in the server's key directory (see add_peer)
+        reg_request.get().set_name(self.name);
+        reg_request.get().set_public_key(self.dh_key.public_key());
+
+        let (rate, ret_rate) = match self.cover_rates {
+            Some((cover_send_rate, cover_ret_rate)) => (cover_send_rate, cover_ret_rate),
+            None => (self.send_rate, self.ret_rate),
+        };
+
+        // With other workers, this one only expects retrievals of the buckets of shard 0
+        let shards = self.helpers.len() as u32 + 1;
+
+        reg_request.get().set_rate(rate);
+        reg_request.get().set_ret_rate(ret_rate);
+        reg_request.get().set_shards(shards);
 
         let response = reg_request.send().promise.wait(scope, port)?;
         let id: u64 = response.get()?.get_id();
 
+        // Server tells us whether it will compress its responses
+        self.compression = response.get()?.get_compress();
+
+        // Bloom filters can only be read with the server's number of hash functions
+        self.bloom_hashes = match response.get()?.get_bloom_hashes() {
+            0 => None,
+            k => Some(k),
+        };
+
+        // Servers that predate configurable rates do not send one
+        let bloom_fp = response.get()?.get_bloom_fp();
+
+        self.bloom_fp = if db::check_bloom_fp(bloom_fp).is_ok() {
+            bloom_fp
+        } else {
+            db::BLOOM_FP
+        };
+
+        // Answers can only be decoded with the server's alpha, so a configured one must match
+        let alpha = match response.get()?.get_alpha() {
+            0 => None,
+            a => Some(a),
+        };
+
+        if self.alpha.is_some() && self.alpha != alpha {
+            return Err(PungError::Failed(format!(
+                "PIR alpha {:?} does not match the server's ({:?})",
+                self.alpha,
+                alpha
+            )));
+        }
+
+        self.alpha = alpha;
+
+        // The other workers only answer the PIR requests of their shard (see
+        // ClientConfig::workers), so they need neither our name nor our public key
+        for (i, helper) in self.helpers.iter_mut().enumerate() {
+            let mut helper_request = helper.0.register_request();
+            helper_request.get().set_compress(self.compression);
+            helper_request.get().set_buckets(self.ret_rate);
+            helper_request.get().set_rate(rate);
+            helper_request.get().set_ret_rate(ret_rate);
+            helper_request.get().set_shard(i as u32 + 1);
+            helper_request.get().set_shards(shards);
+
+            let response = helper_request.send().promise.wait(scope, port)?;
+            helper.1 = response.get()?.get_id();
+        }
+
         self.id = id;
         Ok(id)
     }
 
 
-    // This is just to make testing and data collection easier
+    // This is just to make testing and data collection easier. The server only accepts it
+    // when started with --allow-change-extra and no sends are in flight.
     pub fn extra(
         &self,
         extra: u64,
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<(), Error> {
+    ) -> Result<(), PungError> {
         let mut extra_request = self.conn.change_extra_request();
         extra_request.get().set_extra(extra);
 
@@ -268,133 +965,481 @@ impl<'a> PungClient<'a> {
         if response.get()?.get_success() {
             Ok(())
         } else {
-            Err(Error::failed("Failed to change extra tuples.".to_string()))
+            Err(PungError::Failed("Failed to change extra tuples.".to_string()))
         }
     }
 
-    /// End connection with the server.
-    pub fn close(&self, scope: &gj::WaitScope, port: &mut gjio::EventPort) -> Result<(), Error> {
-        let mut close_request = self.conn.close_request();
-        close_request.get().set_id(self.id);
+    /// Asks the server whether a tuple with `label` is in `bucket` of the current round and,
+    /// if so, returns its (collection, index). This reveals the label to the server, so it
+    /// is only meant for debugging and the server must be started with `--debug`.
+    pub fn debug_lookup(
+        &self,
+        bucket: usize,
+        label: &[u8],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Option<(usize, u64)>, PungError> {
+        let mut lookup_request = self.conn.debug_lookup_request();
+        lookup_request.get().set_bucket(bucket as u32);
+        lookup_request.get().set_label(label);
 
-        let response = close_request.send().promise.wait(scope, port)?;
-        let success = response.get()?.get_success();
+        let response = lookup_request.send().promise.wait(scope, port)?;
+        let reader = response.get()?;
 
-        if success {
-            Ok(())
+        if reader.get_found() {
+            Ok(Some((reader.get_collection() as usize, reader.get_index())))
         } else {
-            Err(Error::failed("Failed to unregister.".to_string()))
+            Ok(None)
         }
     }
 
+    /// Downloads every tuple sent to `bucket` during the current round, e.g., to check what
+    /// a round's sends produced. **This is not private**: the server learns which bucket is
+    /// dumped and sends it in the clear, so it is only meant for debugging and the server
+    /// must be started with `--debug`.
+    pub fn retr_bucket_dump(
+        &self,
+        bucket: usize,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<db::PungTuple>, PungError> {
+        let mut dump_request = self.conn.debug_dump_request();
+        dump_request.get().set_bucket(bucket as u32);
 
-    /// Sync with server to obtain next available round number
-    pub fn sync(&mut self, scope: &gj::WaitScope, port: &mut gjio::EventPort) -> Result<(), Error> {
-        let mut sync_request = self.conn.sync_request();
-        sync_request.get().set_id(self.id);
+        let response = dump_request.send().promise.wait(scope, port)?;
+        let tuple_list = response.get()?.get_tuples()?;
 
-        let response = sync_request.send().promise.wait(scope, port)?;
-        let new_round = response.get()?.get_round();
+        let mut tuples = Vec::with_capacity(tuple_list.len() as usize);
 
-        if self.round <= new_round {
-            self.round = new_round;
-            Ok(())
-        } else {
-            Err(Error::failed(
-                "Invalid round number returned by server".to_string(),
-            ))
-        }
-    }
+        for i in 0..tuple_list.len() {
+            let data = tuple_list.get(i)?;
 
-    fn max_retries(&self) -> u32 {
-        match self.opt_scheme {
-            db::OptScheme::Normal => retry_bound!(self.ret_rate),
-            db::OptScheme::Aliasing => retry_bound!(self.ret_rate, 2),
-            db::OptScheme::Hybrid2 => retry_bound!(self.ret_rate, 2) / 2,
-            db::OptScheme::Hybrid4 => 1,
+            if data.len() != db::TUPLE_SIZE {
+                return Err(PungError::Failed("Dumped tuple has an invalid size".to_string()));
+            }
+
+            tuples.push(db::PungTuple::new(data));
         }
+
+        Ok(tuples)
     }
 
-    /// Send a tuple (or set of tuples) to the server
-    pub fn send(
-        &mut self,
-        recipient: &str,
-        msgs: &mut Vec<Vec<u8>>,
+    /// Asks the server, for each entry of `peer_names` (as given to `retr`), whether the
+    /// message is in the current round. The labels are derived anew rather than taken from
+    /// a retrieval, so that the answer does not depend on the code under test (see
+    /// `retr_self_check`). Like `debug_lookup`, this reveals the labels to the server and
+    /// needs a server started with `--debug`.
+    pub fn debug_present(
+        &self,
+        peer_names: &[&str],
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<u64, Error> {
-        if !self.peers.contains_key(&recipient) {
-            return Err(Error::failed("Invalid recipient name".to_string()));
-        } else if msgs.is_empty() {
-            return Err(Error::failed("No messages were provided".to_string()));
-        }
+    ) -> Result<Vec<bool>, PungError> {
+        let mut peer_count: HashMap<&str, u64> = HashMap::new();
+        let mut present = Vec::with_capacity(peer_names.len());
 
-        let peer = &self.peers[recipient];
-        let mut send_request = self.conn.send_request();
-        send_request.get().set_id(self.id);
-        send_request.get().set_round(self.round);
+        for peer_name in peer_names {
+            let peer = match self.peers.get(peer_name) {
+                Some(p) => p,
+                None => return Err(PungError::InvalidPeer("Invalid peer name".to_string())),
+            };
 
-        {
-            let mut tuple_list = send_request.get().init_tuples(msgs.len() as u32);
-            let mut idx: u32 = 0;
-            let mut measurement_byte_count = 0;
+            let count = peer_count.entry(peer_name).or_insert(0);
 
-            for msg in msgs.drain(..) {
-                let (mut c, mut mac) = pcrypto::encrypt(&peer.keys.k_e[..], self.round, &msg[..]);
+            // Aliased tuples are stored under both of their labels, so the first one will do
+            let label = pcrypto::gen_label_with(
+                self.label_prf,
+                &peer.keys.k_l[..],
+                self.round,
+                peer.uid_self,
+                *count,
+                0,
+            );
 
-                let mut tuple = pcrypto::gen_label(
-                    &peer.keys.k_l[..],
-                    self.round,
-                    peer.uid_peer,
-                    idx as u64,
-                    0,
-                );
+            *count += 1;
 
-                // If we are using aliasing, generate an extra label
-                // and make sure it falls in a separate bucket
-                if self.opt_scheme >= db::OptScheme::Aliasing {
-                    let bucket_idx = util::bucket_idx(&tuple, &self.partitions);
-
-                    let mut label_alias = pcrypto::gen_label(
-                        &peer.keys.k_l2[..],
-                        self.round,
-                        peer.uid_peer,
-                        idx as u64,
-                        0,
-                    );
-
-                    let mut bucket_alias_idx = util::bucket_idx(&label_alias, &self.partitions);
-
-                    let mut collision_count = 1; // count collisions of labels to the same bucket
-
-                    while bucket_idx == bucket_alias_idx {
-                        label_alias = pcrypto::gen_label(
-                            &peer.keys.k_l2[..],
-                            self.round,
-                            peer.uid_peer,
-                            idx as u64,
-                            collision_count,
-                        );
+            let bucket = self.partitions.bucket_of(&label);
+            present.push(self.debug_lookup(bucket, &label, scope, port)?.is_some());
+        }
 
-                        bucket_alias_idx = util::bucket_idx(&label_alias, &self.partitions);
-                        collision_count += 1;
-                    }
+        Ok(present)
+    }
+
+    /// Checks that the server is alive and returns the round-trip time of the check. This
+    /// does not require registering and does not affect the server's state.
+    pub fn ping(
+        &self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Duration, PungError> {
+        let nonce = rand::thread_rng().next_u64();
 
-                    // Postcondtion: the two labels fall in different buckets
+        let mut ping_request = self.conn.ping_request();
+        ping_request.get().set_nonce(nonce);
 
-                    tuple.append(&mut label_alias);
-                }
+        let start = Instant::now();
+        let response = ping_request.send().promise.wait(scope, port)?;
+        let elapsed = start.elapsed();
+
+        if response.get()?.get_nonce() != nonce {
+            return Err(PungError::Failed("Server echoed the wrong nonce".to_string()));
+        }
+
+        Ok(elapsed)
+    }
+
+    /// End connection with the server.
+    pub fn close(
+        &self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(), PungError> {
+        let mut close_request = self.conn.close_request();
+        close_request.get().set_id(self.id);
+
+        let response = close_request.send().promise.wait(scope, port)?;
+        let mut success = response.get()?.get_success();
+
+        for &(ref conn, id) in &self.helpers {
+            let mut close_request = conn.close_request();
+            close_request.get().set_id(id);
+
+            let response = close_request.send().promise.wait(scope, port)?;
+            success &= response.get()?.get_success();
+        }
+
+        if success {
+            Ok(())
+        } else {
+            Err(PungError::Failed("Failed to unregister.".to_string()))
+        }
+    }
+
+
+    /// Obtains statistics collected by the server.
+    pub fn stats(
+        &self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<util::stats::Stats, PungError> {
+        let stats_request = self.conn.stats_request();
+        let response = stats_request.send().promise.wait(scope, port)?;
+        let timing_list = response.get()?.get_pir_timings()?;
+
+        let mut stats = util::stats::Stats::default();
+        stats.tuples = response.get()?.get_tuples();
+        stats.unencoded_tuples = response.get()?.get_unencoded_tuples();
+        stats.duplicate_labels = response.get()?.get_duplicate_labels();
+
+        for timing in timing_list.iter() {
+            let histogram = timing.get_histogram()?;
+
+            stats.pir_timings.push(util::stats::PirTiming {
+                bucket_size: timing.get_bucket_size(),
+                alpha: timing.get_alpha(),
+                level: timing.get_level(),
+                latency: util::stats::Histogram {
+                    count: timing.get_count(),
+                    total_usec: timing.get_total_usec(),
+                    buckets: histogram.iter().collect(),
+                },
+            });
+        }
+
+        Ok(stats)
+    }
+
+    /// Obtains the server's current round and phase, number of registered clients, and
+    /// database size. This does not require registering and can be called in any phase.
+    pub fn status(
+        &self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<util::stats::ServerStatus, PungError> {
+        let status_request = self.conn.status_request();
+        let response = status_request.send().promise.wait(scope, port)?;
+        let status = response.get()?;
+
+        let phase = match status.get_phase() {
+            Ok(pung_capnp::Phase::Sending) => util::stats::Phase::Sending,
+            Ok(pung_capnp::Phase::Receiving) => util::stats::Phase::Receiving,
+            Err(_) => return Err(PungError::Failed("Server reported an invalid phase".to_string())),
+        };
+
+        Ok(util::stats::ServerStatus {
+            round: status.get_round(),
+            phase: phase,
+            clients: status.get_clients(),
+            tuples: status.get_tuples(),
+            total_dbs: status.get_total_dbs(),
+        })
+    }
+
+    /// Sync with server to obtain next available round number. This also tells the server
+    /// the current send rate (see `set_send_rate`) and public key (see `rotate_dh_key`).
+    pub fn sync(
+        &mut self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(), PungError> {
+        let mut sync_request = self.conn.sync_request();
+        sync_request.get().set_id(self.id);
+        sync_request.get().set_public_key(self.dh_key.public_key());
+
+        match self.cover_rates {
+            Some((cover_send_rate, _)) => sync_request.get().set_rate(cover_send_rate),
+            None => sync_request.get().set_rate(self.send_rate),
+        }
+
+        let response = sync_request.send().promise.wait(scope, port)?;
+        let new_round = response.get()?.get_round();
+
+        if self.round <= new_round {
+            self.round = new_round;
+            Ok(())
+        } else {
+            Err(PungError::Failed(
+                "Invalid round number returned by server".to_string(),
+            ))
+        }
+    }
+
+    fn max_retries(&self) -> u32 {
+        let rate = match self.cover_rates {
+            Some((_, cover_ret_rate)) => cover_ret_rate,
+            None => self.ret_rate,
+        };
+
+        util::max_retries(self.opt_scheme, rate as u64)
+    }
+
+    /// Send a tuple (or set of tuples) to the server (see `send_session` to add messages one
+    /// at a time instead). With cover traffic (see `set_cover_rates`), `msgs` may be empty:
+    /// the request is then made of cover tuples only, so that a client with nothing to say
+    /// still takes part in the round.
+    pub fn send(
+        &mut self,
+        recipient: &str,
+        msgs: &mut Vec<Vec<u8>>,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<u64, PungError> {
+        self.send_with_ttl(recipient, msgs, &[], scope, port)
+    }
+
+    /// Like `send`, but `ttls[i]` is the number of rounds (including this one) during which
+    /// `msgs[i]` can be retrieved from a server that retains past rounds. A TTL of 0 (or an
+    /// empty `ttls`) keeps messages for the server's whole retention window.
+    pub fn send_with_ttl(
+        &mut self,
+        recipient: &str,
+        msgs: &mut Vec<Vec<u8>>,
+        ttls: &[u32],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<u64, PungError> {
+        if !self.peers.contains_key(&recipient) {
+            return Err(PungError::InvalidPeer("Invalid recipient name".to_string()));
+        } else if msgs.is_empty() && self.cover_rates.is_none() {
+            // With cover traffic, an empty send is all cover tuples (see send)
+            return Err(PungError::Failed("No messages were provided".to_string()));
+        } else if !ttls.is_empty() && ttls.len() != msgs.len() {
+            return Err(PungError::Failed("Number of TTLs does not match messages".to_string()));
+        }
+
+        let total_tuples = {
+            let outgoing: Vec<(&str, &[u8], u32)> = msgs
+                .iter()
+                .enumerate()
+                .map(|(i, msg)| (recipient, &msg[..], ttls.get(i).cloned().unwrap_or(0)))
+                .collect();
+
+            self.send_tuples(&outgoing, FRAME_MESSAGE, scope, port)?
+        };
+
+        msgs.clear();
+
+        Ok(total_tuples)
+    }
+
+    /// Sends messages to (possibly) several peers, retrieves messages from the given peers,
+    /// and moves on to the next round. This is what a client typically does every round.
+    /// Like `send`, this waits for the send phase to end, and the client must have synced
+    /// before its first round.
+    pub fn round_trip(
+        &mut self,
+        outgoing: &[(&str, &[u8])],
+        incoming: &[&str],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<Vec<u8>>, PungError> {
+        if outgoing.is_empty() {
+            return Err(PungError::Failed("No messages were provided".to_string()));
+        } else if outgoing.iter().any(|&(peer, _)| !self.peers.contains_key(peer)) {
+            return Err(PungError::InvalidPeer("Invalid recipient name".to_string()));
+        }
+
+        let outgoing: Vec<(&str, &[u8], u32)> =
+            outgoing.iter().map(|&(peer, msg)| (peer, msg, 0)).collect();
+
+        self.send_tuples(&outgoing, FRAME_MESSAGE, scope, port)?;
+        let messages = self.retr(incoming, scope, port)?;
+        self.inc_round(1);
+
+        Ok(messages)
+    }
+
+    // Sends (recipient, message, ttl) triples as a single send request. Messages to each
+    // recipient are numbered from 0 (in order), which is how recipients derive their labels.
+    // Every message is sent under the given frame (see seal).
+    fn send_tuples(
+        &mut self,
+        outgoing: &[(&str, &[u8], u32)],
+        frame: u8,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<u64, Error> {
+        let mut sealed = Vec::with_capacity(outgoing.len());
+
+        {
+            let mut counts: HashMap<&str, u64> = HashMap::new(); // messages per recipient
+            let mut encryptor = pcrypto::Encryptor::new(); // reused for all messages
+
+            for &(recipient, msg, ttl) in outgoing {
+                let idx = {
+                    let count = counts.entry(recipient).or_insert(0);
+                    *count += 1;
+                    *count - 1
+                };
+
+                let peer = &self.peers[recipient];
+                let tuple = self.seal(peer, idx, frame, msg, &mut encryptor)?;
+                sealed.push((tuple, ttl));
+            }
+        }
+
+        self.send_sealed(&sealed, scope, port)
+    }
+
+    // Builds the tuple of message number `idx` to `peer`: its label (followed by its alias
+    // label under aliasing) and the encrypted message. The message is encrypted behind the
+    // frame byte (FRAME_MESSAGE or FRAME_CHUNK), so that recipients can tell what it is
+    // regardless of its contents; retr_labels strips it off again.
+    fn seal(
+        &self,
+        peer: &PungPeer,
+        idx: u64,
+        frame: u8,
+        msg: &[u8],
+        encryptor: &mut pcrypto::Encryptor,
+    ) -> Result<Vec<u8>, Error> {
+        if msg.len() > MESSAGE_SIZE {
+            return Err(Error::failed(format!(
+                "Message of {} bytes is longer than the maximum ({})",
+                msg.len(),
+                MESSAGE_SIZE
+            )));
+        }
+
+        let mut framed = Vec::with_capacity(msg.len() + 1);
+        framed.push(frame);
+        framed.extend_from_slice(msg);
+
+        let mut tuple = pcrypto::gen_label_with(
+            self.label_prf,
+            &peer.keys.k_l[..],
+            self.round,
+            peer.uid_peer,
+            idx,
+            0,
+        );
+
+        // If we are using aliasing, generate an extra label
+        // and make sure it falls in a separate bucket
+        if self.opt_scheme >= db::OptScheme::Aliasing {
+            let bucket_idx = self.partitions.bucket_of(&tuple);
+            let (mut label_alias, _) =
+                self.alias_label(&peer.keys.k_l2[..], peer.uid_peer, idx, bucket_idx)?;
+
+            // Postcondtion: the two labels fall in different buckets
+
+            tuple.append(&mut label_alias);
+        }
+
+        // Encrypt the message into the rest of the tuple: (cipher, mac)
+        let header_len = tuple.len();
+        tuple.resize(header_len + db::CIPHER_SIZE + db::MAC_SIZE, 0);
+
+        {
+            let (c, mac) = tuple[header_len..].split_at_mut(db::CIPHER_SIZE);
+            let (k_e, aead) = (&peer.keys.k_e[..], peer.keys.aead);
+            encryptor.encrypt_into(aead, k_e, self.round, peer.uid_peer, idx, &framed, c, mac);
+        }
+
+        Ok(tuple)
+    }
+
+    // Sends already sealed (tuple, ttl) pairs (see `seal`) as a single send request, padded
+    // with cover tuples up to the cover send rate (if any). Returns the number of tuples in
+    // the round, and keeps the round's bucket information for retrievals.
+    fn send_sealed(
+        &mut self,
+        sealed: &[(Vec<u8>, u32)],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<u64, Error> {
+        // Random tuples that pad the request up to the cover rate (if any)
+        let num_cover = match self.cover_rates {
+            Some((cover_send_rate, _)) if sealed.len() > cover_send_rate as usize => {
+                return Err(Error::failed(
+                    "More messages than the cover send rate".to_string(),
+                ));
+            }
+
+            Some((cover_send_rate, _)) => cover_send_rate as usize - sealed.len(),
+            None => 0,
+        };
+
+        let num_tuples = sealed.len() + num_cover;
 
-                tuple.append(&mut c);
-                tuple.append(&mut mac);
+        let mut send_request = self.conn.send_request();
+        send_request.get().set_id(self.id);
+        send_request.get().set_round(self.round);
+
+        // TTLs of 0 are the default, so they need not be sent
+        let send_ttls = sealed.iter().any(|&(_, ttl)| ttl != 0);
+
+        if send_ttls {
+            let mut ttl_list = send_request.get().init_ttls(num_tuples as u32);
+
+            for (i, &(_, ttl)) in sealed.iter().enumerate() {
+                ttl_list.set(i as u32, ttl);
+            }
+        }
 
+        {
+            let mut tuple_list = send_request.get().init_tuples(num_tuples as u32);
+            let mut measurement_byte_count = 0;
+
+            for (i, &(ref tuple, _)) in sealed.iter().enumerate() {
+                measurement_byte_count += tuple.len();
+                tuple_list.set(i as u32, &tuple[..]);
+            }
+
+            let mut rng = self.rng.borrow_mut();
+
+            for i in sealed.len()..num_tuples {
+                let tuple = self.cover_tuple(&mut *rng)?;
                 measurement_byte_count += tuple.len();
+                tuple_list.set(i as u32, &tuple[..]);
+            }
 
-                tuple_list.set(idx as u32, &tuple[..]);
-                idx += 1;
+            // 4 bytes per TTL (if any)
+            if send_ttls {
+                measurement_byte_count += num_tuples * 4;
             }
 
-            println!("Upload (send rpc) {} bytes", measurement_byte_count + 16);
+            self.metrics.record_upload(metrics::RpcKind::Send, measurement_byte_count + 16);
         }
 
         // get RPC response which contains total number of tuples and lmids
@@ -405,11 +1450,11 @@ impl<'a> PungClient<'a> {
         let response = res_ptr.get()?;
 
         let buckets_num = response.get_num_messages()?;
-        assert_eq!(buckets_num.len(), self.ret_rate);
+        check_list_count(buckets_num.len(), self.ret_rate, "bucket count")?;
 
         if self.opt_scheme == db::OptScheme::Hybrid2 {
             let buckets_lmid = response.get_min_labels()?;
-            assert_eq!(buckets_num.len(), buckets_lmid.len());
+            check_list_count(buckets_lmid.len(), buckets_num.len(), "min label")?;
 
             for i in 0..buckets_num.len() {
                 self.buckets.push(BucketInfo {
@@ -422,20 +1467,22 @@ impl<'a> PungClient<'a> {
 
             // This accounts for: 8 bytes (64 bits) for each bucket number entry
             // and the Lmid label
-            println!(
-                "Download (send rpc) {} bytes",
-                (buckets_num.len() * 8) + (buckets_lmid.len() * db::LABEL_SIZE as u32)
-            );
-        } else if self.opt_scheme == db::OptScheme::Hybrid4 {
+            let bytes =
+                buckets_num.len() as usize * 8 + buckets_lmid.len() as usize * db::LABEL_SIZE;
+            self.metrics.record_download(metrics::RpcKind::Send, bytes, bytes);
+        } else if self.opt_scheme >= db::OptScheme::Hybrid4 {
+            // Delimeters per bucket: 3 for hybrid 4 and 7 for hybrid 8
+            let per_bucket = util::label_collections(self.opt_scheme).len() as u32 - 1;
+
             let buckets_lmid = response.get_min_labels()?;
-            assert_eq!(buckets_num.len() * 3, buckets_lmid.len()); // delimeters per bucket
+            check_list_count(buckets_lmid.len(), buckets_num.len() * per_bucket, "min label")?;
 
             for i in 0..buckets_num.len() {
-                let mut lmid = Vec::with_capacity(3);
+                let mut lmid = Vec::with_capacity(per_bucket as usize);
 
-                for j in 0..3 {
+                for j in 0..per_bucket {
                     // collections
-                    lmid.push(buckets_lmid.get(3 * i + j)?.to_vec());
+                    lmid.push(buckets_lmid.get(per_bucket * i + j)?.to_vec());
                 }
 
                 self.buckets.push(BucketInfo {
@@ -446,11 +1493,10 @@ impl<'a> PungClient<'a> {
             }
 
             // This accounts for: 8 bytes (64 bits) for each bucket number entry
-            // and the 3 Lmid labels per bucket
-            println!(
-                "Download (send rpc) {} bytes",
-                (buckets_num.len() * 8) + (buckets_lmid.len() * db::LABEL_SIZE as u32)
-            );
+            // and the 3 (or 7) Lmid labels per bucket
+            let bytes =
+                buckets_num.len() as usize * 8 + buckets_lmid.len() as usize * db::LABEL_SIZE;
+            self.metrics.record_download(metrics::RpcKind::Send, bytes, bytes);
         } else {
             for i in 0..buckets_num.len() {
                 self.buckets.push(BucketInfo {
@@ -461,28 +1507,112 @@ impl<'a> PungClient<'a> {
             }
 
             // 8 bytes (64 bits) for each bucket number entry
-            println!("Download (send rpc) {} bytes", buckets_num.len() * 8);
+            let bytes = buckets_num.len() as usize * 8;
+            self.metrics.record_download(metrics::RpcKind::Send, bytes, bytes);
         }
 
         Ok(total_tuples)
     }
 
+    // Derives the alias label of message `msg_num` (under aliasing) along with its bucket,
+    // which must differ from `bucket_idx` (the bucket of the original label). Labels that
+    // collide are re-derived with an increasing counter, so senders and recipients agree on
+    // the alias. Fails if there are too few buckets for the labels to ever differ.
+    fn alias_label(
+        &self,
+        key: &[u8],
+        uid: u64,
+        msg_num: u64,
+        bucket_idx: usize,
+    ) -> Result<(Vec<u8>, usize), Error> {
+        if self.partitions.num_buckets() < 2 {
+            return Err(Error::failed("Aliasing requires at least 2 buckets".to_string()));
+        }
+
+        for collisions in 0..MAX_ALIAS_ATTEMPTS {
+            let label_alias =
+                pcrypto::gen_label_with(self.label_prf, key, self.round, uid, msg_num, collisions);
+            let bucket_idx_alias = self.partitions.bucket_of(&label_alias);
+
+            if bucket_idx_alias != bucket_idx {
+                return Ok((label_alias, bucket_idx_alias));
+            }
+        }
+
+        Err(Error::failed(
+            "Could not derive an alias label in a different bucket".to_string(),
+        ))
+    }
+
+    // A tuple that looks like a real one to the server but that nobody will retrieve: its
+    // label(s), ciphertext, and MAC are random. Under aliasing the two labels are kept in
+    // separate buckets, as they are for real tuples, which fails (like alias_label) if there
+    // is a single bucket or no alias is found within MAX_ALIAS_ATTEMPTS.
+    fn cover_tuple<R: Rng>(&self, rng: &mut R) -> Result<Vec<u8>, Error> {
+        let mut label = vec![0u8; db::LABEL_SIZE];
+        rng.fill_bytes(&mut label);
+
+        let mut tuple = label.clone();
+
+        if self.opt_scheme >= db::OptScheme::Aliasing {
+            if self.partitions.num_buckets() < 2 {
+                return Err(Error::failed("Aliasing requires at least 2 buckets".to_string()));
+            }
+
+            let bucket_idx = self.partitions.bucket_of(&label);
+            let mut label_alias = vec![0u8; db::LABEL_SIZE];
+            let mut found = false;
+
+            for _ in 0..MAX_ALIAS_ATTEMPTS {
+                rng.fill_bytes(&mut label_alias);
+
+                if self.partitions.bucket_of(&label_alias) != bucket_idx {
+                    found = true;
+                    break;
+                }
+            }
+
+            if !found {
+                return Err(Error::failed(
+                    "Could not pick an alias label in a different bucket".to_string(),
+                ));
+            }
+
+            tuple.extend_from_slice(&label_alias);
+        }
+
+        let mut rest = vec![0u8; db::CIPHER_SIZE + db::MAC_SIZE];
+        rng.fill_bytes(&mut rest);
+        tuple.append(&mut rest);
+
+        Ok(tuple)
+    }
+
     // Given a list of peers from whom to retrieve a message, derive the label(s) and build
     // a list of labels for each bucket. Output maps from bucket to list of (peer, label).
-    // Peer object is needed to decrypt file once it has been retrieved.
+    // Peer object is needed to decrypt file once it has been retrieved. Also returns the
+    // label scheduled for each entry of peer_names, in order.
     fn schedule(
         &'a self,
         peer_names: &[&'a str],
-    ) -> Result<HashMap<usize, Vec<(&'a PungPeer, Vec<u8>)>>, Error> {
+    ) -> Result<(BucketLabels<'a>, Vec<Vec<u8>>), Error> {
         // bucket_id -> [(peer, label)]
-        let mut bucket_map: HashMap<usize, Vec<(&'a PungPeer, Vec<u8>)>> = HashMap::new();
+        let mut bucket_map: BucketLabels<'a> =
+            (0..self.partitions.num_buckets()).map(|_| Vec::new()).collect();
         // maps from peer name to which message this is (first, second, third, etc.)
         let mut peer_count: HashMap<&str, u64> = HashMap::new();
+        // number of labels scheduled for each bucket so far
+        let mut loads = vec![0; self.partitions.num_buckets()];
+        // label scheduled for each peer name
+        let mut requested = Vec::with_capacity(peer_names.len());
+        // message number of each label, to decrypt what is retrieved (see msg_index)
+        let mut indices = self.msg_indices.borrow_mut();
+        indices.clear();
 
         // Go through each peer, get labels and see to which bucket they map
         for peer_name in peer_names {
             if !self.peers.contains_key(peer_name) {
-                return Err(Error::failed("Invalid peer name".to_string()));
+                return Err(ErrorCode::InvalidPeer.error("Invalid peer name"));
             }
 
             // get peer object for this sender
@@ -492,96 +1622,99 @@ impl<'a> PungClient<'a> {
             let count = peer_count.entry(peer_name).or_insert(0);
 
             // get mailbox label for this peer/count
-            let label =
-                pcrypto::gen_label(&peer.keys.k_l[..], self.round, peer.uid_self, *count, 0);
+            let label = pcrypto::gen_label_with(
+                self.label_prf,
+                &peer.keys.k_l[..],
+                self.round,
+                peer.uid_self,
+                *count,
+                0,
+            );
 
             // find out on which bucket this label falls
-            let bucket_idx = util::bucket_idx(&label, &self.partitions);
+            let bucket_idx = self.partitions.bucket_of(&label);
 
             // Add (peer, label) to the bucket map. If there are collisions, append it to list
             // If there is aliasing, derive second label too
 
             if self.opt_scheme >= db::OptScheme::Aliasing {
-                let mut collisions = 0; // Number of collisions found so far
-                let mut label_alias = pcrypto::gen_label(
-                    &peer.keys.k_l2[..],
-                    self.round,
-                    peer.uid_self,
-                    *count,
-                    collisions,
-                );
-                let mut bucket_idx_alias = util::bucket_idx(&label_alias, &self.partitions);
-
-                // Derive a different label if there are collisions (must ensure labels map to
-                // different buckets)
-                while bucket_idx == bucket_idx_alias {
-                    collisions += 1;
-                    label_alias = pcrypto::gen_label(
-                        &peer.keys.k_l2[..],
-                        self.round,
-                        peer.uid_self,
-                        *count,
-                        collisions,
-                    );
-                    bucket_idx_alias = util::bucket_idx(&label_alias, &self.partitions);
-                }
-
-                // Lenghts of the buckets
-                let len1 = if let Some(bucket) = bucket_map.get(&bucket_idx) {
-                    bucket.len()
-                } else {
-                    0
-                };
+                let (label_alias, bucket_idx_alias) =
+                    self.alias_label(&peer.keys.k_l2[..], peer.uid_self, *count, bucket_idx)?;
 
-                let len2 = if let Some(bucket) = bucket_map.get(&bucket_idx_alias) {
-                    bucket.len()
-                } else {
-                    0
-                };
+                // Let the assigner pick the bucket (the least full one by default)
+                let candidates = [bucket_idx, bucket_idx_alias];
 
-                // Add label to the least full bucket
-                if len1 < len2 {
-                    let bucket_entry = bucket_map.entry(bucket_idx).or_insert_with(Vec::new);
-                    bucket_entry.push((peer, label));
+                if self.assigner.assign(&candidates, &loads) == 0 {
+                    requested.push(label.clone());
+                    indices.insert(label.clone(), *count);
+                    bucket_map[bucket_idx].push((peer, label));
+                    loads[bucket_idx] += 1;
                 } else {
-                    let bucket_entry = bucket_map.entry(bucket_idx_alias).or_insert_with(Vec::new);
-                    bucket_entry.push((peer, label_alias));
+                    requested.push(label_alias.clone());
+                    indices.insert(label_alias.clone(), *count);
+                    bucket_map[bucket_idx_alias].push((peer, label_alias));
+                    loads[bucket_idx_alias] += 1;
                 }
             } else {
-                let bucket_entry = bucket_map.entry(bucket_idx).or_insert_with(Vec::new);
-                bucket_entry.push((peer, label));
+                requested.push(label.clone());
+                indices.insert(label.clone(), *count);
+                bucket_map[bucket_idx].push((peer, label));
+                loads[bucket_idx] += 1;
             }
 
             *count += 1; // update # messages from this peer
         }
 
-        Ok(bucket_map)
+        Ok((bucket_map, requested))
+    }
+
+    /// Returns how many of the labels of `peer_names` (as given to `retr`) would be
+    /// retrieved from each bucket this round. No requests are sent.
+    pub fn bucket_loads(&self, peer_names: &[&str]) -> Result<Vec<usize>, PungError> {
+        let (bucket_map, _) = self.schedule(peer_names)?;
+
+        Ok(bucket_map.iter().map(|labels| labels.len()).collect())
+    }
+
+    /// Returns the buckets (in increasing order) from which the labels of `peer_names` (as
+    /// given to `retr`) would be retrieved this round, i.e., where their messages are if they
+    /// were sent. No requests are sent. `retr` probes every bucket regardless (see
+    /// `probed_buckets`), so the server cannot tell these apart from the others.
+    pub fn planned_buckets(&self, peer_names: &[&str]) -> Result<Vec<usize>, PungError> {
+        let loads = self.bucket_loads(peer_names)?;
+
+        Ok((0..loads.len()).filter(|&bucket| loads[bucket] > 0).collect())
+    }
+
+    /// Returns the buckets that `retr` probes, which are all of them whatever the peers.
+    pub fn probed_buckets(&self) -> Vec<usize> {
+        (0..self.partitions.num_buckets()).collect()
     }
 
 
+    // Returns the message number of a label scheduled for the current retrieval, from which
+    // (along with the round and the peer's uid) its nonce is derived (see pcrypto::decrypt).
+    fn msg_index(&self, label: &[u8]) -> u64 {
+        self.msg_indices.borrow().get(label).cloned().unwrap_or(0)
+    }
+
     fn next_label(
         &'a self,
-        bucket_map: &mut HashMap<usize, Vec<(&'a PungPeer, Vec<u8>)>>,
+        bucket_map: &mut BucketLabels<'a>,
         bucket: usize,
         dummy: &'a PungPeer,
         dummy_count: &mut u64,
     ) -> (&'a PungPeer, Vec<u8>) {
-        match bucket_map.remove(&bucket) {
-            Some(mut v) => {
-                // this is a vector of (peer, label)
-                let t = v.pop().unwrap();
-
-                // re-insert vector if there are any labels left
-                if !v.is_empty() {
-                    bucket_map.insert(bucket, v);
-                }
-
+        match bucket_map[bucket].pop() {
+            Some(t) => {
+                self.checked.borrow_mut().insert(t.1.clone());
                 t
             }
 
             None => {
                 // Request for this bucket will have to be a dummy one
-                let label = pcrypto::gen_label(
+                let label = pcrypto::gen_label_with(
+                    self.label_prf,
                     &dummy.keys.k_l[..],
                     self.round,
                     dummy.uid_self,
@@ -594,98 +1727,317 @@ impl<'a> PungClient<'a> {
         }
     }
 
-    // Returns a map of bucket -> (collection -> [labels])
+    // Picks the (peer, label) for the first retrieval of a round when the filters come with
+    // it. Its index is chosen before seeing the filters, so this is always a dummy label,
+    // which takes one of the bucket's retrievals (the order of retrievals within a bucket is
+    // invisible to the server). A bucket with a real label for every retrieval has none to
+    // spare: rather than spending one of them on a random index, and losing that message, this
+    // is an error.
+    fn piggyback_label(
+        &'a self,
+        bucket_map: &BucketLabels<'a>,
+        bucket: usize,
+        retries: u32,
+        dummy: &'a PungPeer,
+        dummy_count: &mut u64,
+    ) -> Result<(&'a PungPeer, Vec<u8>), Error> {
+        let real = bucket_map[bucket].len();
+
+        if real >= retries as usize {
+            return Err(Error::failed(format!(
+                "Bucket {} has {} messages to retrieve, but piggybacking leaves room for {}",
+                bucket,
+                real,
+                retries - 1
+            )));
+        }
+
+        let label = pcrypto::gen_label_with(
+            self.label_prf,
+            &dummy.keys.k_l[..],
+            self.round,
+            dummy.uid_self,
+            *dummy_count,
+            0,
+        );
+        *dummy_count += 1;
+
+        Ok((dummy, label))
+    }
+
+    // Checks that the collections of every bucket (whose sizes follow from the number of
+    // tuples returned by send) agree with the split computed by util::collection_len and,
+    // if given, with the explicit labels returned by the server. This runs before any PIR
+    // request so that an encode/decode mismatch surfaces as a clear error rather than as
+    // bogus PIR answers.
+    fn check_collections(
+        &self,
+        explicit_labels: Option<&index::LabelIndex>,
+    ) -> Result<(), Error> {
+        let num_collections = util::label_collections(self.opt_scheme).len() as u32;
+
+        for (bucket, info) in self.buckets.iter().enumerate() {
+            let num = info.num_tuples();
+            let lens: Vec<u64> = (0..num_collections)
+                .map(|i| util::collection_len(num, i, num_collections))
+                .collect();
+
+            // Collections partition the bucket and only the first ones get an extra tuple
+            if lens.iter().sum::<u64>() != num || lens.windows(2).any(|w| w[0] < w[1]) {
+                return Err(Error::failed(format!(
+                    "Bucket {} with {} tuples is split into collections of {:?} tuples",
+                    bucket, num, lens
+                )));
+            }
+
+            // There is one lmid per collection after the first (none when there is only one)
+            let lmids = info.get_lmids();
+
+            if num_collections > 1 && lmids.len() as u32 != num_collections - 1 {
+                return Err(Error::failed(format!(
+                    "Bucket {} has {} mid labels but {} were expected",
+                    bucket,
+                    lmids.len(),
+                    num_collections - 1
+                )));
+            }
+
+            // An lmid is missing exactly when its collection is empty
+            for (i, lmid) in lmids.iter().enumerate() {
+                if lmid.is_empty() != (lens[i + 1] == 0) {
+                    return Err(Error::failed(format!(
+                        "Bucket {} collection {} should have {} tuples but its mid label is {}",
+                        bucket,
+                        i + 1,
+                        lens[i + 1],
+                        if lmid.is_empty() { "missing" } else { "present" }
+                    )));
+                }
+            }
+
+            if let Some(label_index) = explicit_labels {
+                for (c, len) in lens.iter().enumerate() {
+                    let actual = label_index.len(bucket, c);
+
+                    if actual != Some(*len) {
+                        return Err(Error::failed(format!(
+                            "Bucket {} collection {} has {:?} labels but {} were expected",
+                            bucket, c, actual, len
+                        )));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns an index of the labels of every bucket's meaningful collections
     fn get_explicit_labels(
         &self,
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<HashMap<usize, HashMap<usize, Vec<Vec<u8>>>>, Error> {
+    ) -> Result<index::LabelIndex, Error> {
         let mut map_request = self.conn.get_mapping_request();
         map_request.get().set_round(self.round);
+        map_request.get().set_id(self.id);
 
-        // RPC is 8 bytes
-        println!("Upload (explicit label rpc) {} bytes", 8);
+        // RPC is 16 bytes
+        self.metrics.record_upload(metrics::RpcKind::Labels, 16);
 
         let response = map_request.send().promise.wait(scope, port)?;
+        let reader = response.get()?;
 
-        if !response.get()?.has_labels() {
-            return Err(Error::failed(
-                "Empty label mapping returned by server".to_string(),
-            ));
-        }
+        let labels = if reader.has_labels() {
+            Some(reader.get_labels()?)
+        } else {
+            None
+        };
 
-        // This is a list(list(label)) = list(list([u8]))
-        let collection_list = response.get()?.get_labels()?;
-        let mut response_idx = 0;
+        let packed = if reader.has_packed() {
+            Some(reader.get_packed()?)
+        } else {
+            None
+        };
+
+        self.decode_labels(labels, packed)
+    }
 
+    // Decodes the label mapping returned by the server (as labels, or packed if compression
+    // is in use) into an index of the labels of each bucket's meaningful collections
+    fn decode_labels(
+        &self,
+        labels: Option<capnp::list_list::Reader<capnp::data_list::Owned>>,
+        packed: Option<capnp::data_list::Reader>,
+    ) -> Result<index::LabelIndex, Error> {
         // index of collection(s) within a bucket containing meaningful labels
         let meaningful_labels: Vec<usize> = util::label_collections(self.opt_scheme);
 
-        let mut label_map: HashMap<usize, HashMap<usize, Vec<Vec<u8>>>> = HashMap::new();
+        // (bucket, collection, labels) in the order in which the server returns them
+        let mut label_lists: Vec<(usize, usize, Vec<Vec<u8>>)> = Vec::new();
 
         let mut download_measurement = 0;
+        let mut raw_measurement = 0;
+        let mut response_idx = 0;
 
-        for bucket_idx in 0..self.buckets.len() {
-            let bucket_map = label_map.entry(bucket_idx).or_insert_with(HashMap::new);
+        // One list of labels per meaningful collection of each bucket
+        let expected_lists = (self.buckets.len() * meaningful_labels.len()) as u32;
+
+        if self.compression {
+            // This is a list(compressed concatenation of labels)
+            let packed_list = match packed {
+                Some(p) => p,
+                None => {
+                    return Err(Error::failed(
+                        "Empty label mapping returned by server".to_string(),
+                    ))
+                }
+            };
 
-            for collection_idx in &meaningful_labels {
-                let collection_vec = bucket_map.entry(*collection_idx).or_insert_with(Vec::new);
+            check_list_count(packed_list.len(), expected_lists, "label list")?;
 
-                // This is the returned list(label) = list([u8])
-                let label_list = collection_list.get(response_idx)?;
+            for bucket_idx in 0..self.buckets.len() {
+                for collection_idx in &meaningful_labels {
+                    let packed = packed_list.get(response_idx)?;
+                    let labels = match util::rle::decompress(packed) {
+                        Ok(v) => v,
+                        Err(e) => return Err(Error::failed(e.to_string())),
+                    };
 
-                for i in 0..label_list.len() {
-                    collection_vec.push(label_list.get(i).unwrap().to_vec());
-                    download_measurement += db::LABEL_SIZE;
-                }
+                    if labels.len() % db::LABEL_SIZE != 0 {
+                        return Err(Error::failed("Invalid packed label list".to_string()));
+                    }
 
-                response_idx += 1;
+                    let collection_vec = labels.chunks(db::LABEL_SIZE).map(|l| l.to_vec());
+                    label_lists.push((bucket_idx, *collection_idx, collection_vec.collect()));
+
+                    download_measurement += packed.len();
+                    raw_measurement += labels.len();
+                    response_idx += 1;
+                }
+            }
+        } else {
+            // This is a list(list(label)) = list(list([u8]))
+            let collection_list = match labels {
+                Some(l) => l,
+                None => {
+                    return Err(Error::failed(
+                        "Empty label mapping returned by server".to_string(),
+                    ))
+                }
+            };
+
+            check_list_count(collection_list.len(), expected_lists, "label list")?;
+
+            for bucket_idx in 0..self.buckets.len() {
+                for collection_idx in &meaningful_labels {
+                    let mut collection_vec = Vec::new();
+
+                    // This is the returned list(label) = list([u8])
+                    let label_list = collection_list.get(response_idx)?;
+
+                    for i in 0..label_list.len() {
+                        collection_vec.push(label_list.get(i).unwrap().to_vec());
+                        download_measurement += db::LABEL_SIZE;
+                    }
+
+                    label_lists.push((bucket_idx, *collection_idx, collection_vec));
+                    response_idx += 1;
+                }
             }
+
+            raw_measurement = download_measurement;
         }
 
-        println!(
-            "Download (explicit label rpc) {} bytes",
-            download_measurement
-        );
+        self.metrics
+            .record_download(metrics::RpcKind::Labels, download_measurement, raw_measurement);
+
+        // Explicit retrieval binary searches the labels, so they must be sorted. A server
+        // that uses the Tree scheme, for example, returns them in BST order instead.
+        let mut label_index = index::LabelIndex::new();
 
-        Ok(label_map)
+        for (bucket, collection, labels) in label_lists {
+            if labels.iter().any(|label| label.len() != db::LABEL_SIZE) {
+                return Err(ErrorCode::SchemeMismatch.error(format!(
+                    "Scheme mismatch: bucket {} collection {} has malformed labels",
+                    bucket, collection
+                )));
+            }
+
+            let sorted = labels
+                .windows(2)
+                .all(|w| util::label_cmp(&w[0][..], &w[1][..]) != Ordering::Greater);
+
+            if !sorted {
+                return Err(ErrorCode::SchemeMismatch.error(format!(
+                    "Scheme mismatch: labels of bucket {} collection {} are not sorted",
+                    bucket, collection
+                )));
+            }
+
+            label_index.insert_labels(bucket, collection, labels);
+        }
+
+        Ok(label_index)
     }
 
 
-    // Returns a bloom filter that encodes the labels
+    // Returns an index of the bloom filters that encode the labels
     fn get_bloom_filter(
         &self,
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<HashMap<usize, HashMap<usize, bloomfilter::Bloom>>, Error> {
+    ) -> Result<index::LabelIndex, Error> {
         let mut bloom_request = self.conn.get_bloom_request();
         bloom_request.get().set_round(self.round);
+        bloom_request.get().set_id(self.id);
 
-        // RPC is 8 bytes
-        println!("Upload (bloom filter rpc) {} bytes", 8);
+        // RPC is 16 bytes
+        self.metrics.record_upload(metrics::RpcKind::Bloom, 16);
 
         let response = bloom_request.send().promise.wait(scope, port)?;
+        let reader = response.get()?;
 
-        if !response.get()?.has_blooms() {
-            return Err(Error::failed(
-                "Empty bloom map returned by server".to_string(),
-            ));
-        }
+        let blooms = if reader.has_blooms() {
+            Some(reader.get_blooms()?)
+        } else {
+            None
+        };
+
+        self.decode_blooms(blooms)
+    }
 
+    // Decodes the bloom filters returned by the server into an index of the filters of each
+    // bucket's meaningful collections
+    fn decode_blooms(
+        &self,
+        blooms: Option<capnp::data_list::Reader>,
+    ) -> Result<index::LabelIndex, Error> {
         // This is a list(bit_vec)
-        let bit_vec_list = response.get()?.get_blooms()?;
+        let bit_vec_list = match blooms {
+            Some(b) => b,
+            None => {
+                return Err(Error::failed(
+                    "Empty bloom map returned by server".to_string(),
+                ))
+            }
+        };
 
         let mut response_idx = 0;
 
         // index of collection(s) within a bucket containing meaningful labels
         let meaningful_labels: Vec<usize> = util::label_collections(self.opt_scheme);
 
-        let mut bloom_map: HashMap<usize, HashMap<usize, bloomfilter::Bloom>> = HashMap::new();
+        let mut bloom_index = index::LabelIndex::new();
 
         let mut download_measurement = 0;
+        let mut raw_measurement = 0;
+
+        // One filter per meaningful collection of each bucket
+        let expected_lists = (self.buckets.len() * meaningful_labels.len()) as u32;
+        check_list_count(bit_vec_list.len(), expected_lists, "bloom filter")?;
 
         for bucket_idx in 0..self.buckets.len() {
-            let bucket_map = bloom_map.entry(bucket_idx).or_insert_with(HashMap::new);
             let num_tuples = self.buckets[bucket_idx].num_tuples();
 
 
@@ -697,110 +2049,229 @@ impl<'a> PungClient<'a> {
                     meaningful_labels.len() as u32,
                 );
 
-                // This is the returned bit_vec
-                let bit_vec = bit_vec_list.get(response_idx)?;
+                // This is the returned bit_vec (possibly compressed)
+                let wire_vec = bit_vec_list.get(response_idx)?;
+
+                let decompressed;
+                let bit_vec: &[u8] = if self.compression {
+                    decompressed = match util::rle::decompress(wire_vec) {
+                        Ok(v) => v,
+                        Err(e) => return Err(Error::failed(e.to_string())),
+                    };
+                    &decompressed[..]
+                } else {
+                    wire_vec
+                };
 
-                download_measurement += bit_vec.len();
+                download_measurement += wire_vec.len();
+                raw_measurement += bit_vec.len();
+
+                // Filters are sized for the collection, unless the server does not use the
+                // Bloom scheme (in which case it returns placeholder filters)
+                let expected_len =
+                    bloomfilter::Bloom::compute_bitmap_size(t_num as usize, self.bloom_fp);
+
+                if bit_vec.len() != expected_len {
+                    return Err(ErrorCode::SchemeMismatch.error(format!(
+                        "Scheme mismatch: bloom filter of bucket {} collection {} has {} bytes \
+                         but {} were expected",
+                        bucket_idx,
+                        collection_idx,
+                        bit_vec.len(),
+                        expected_len
+                    )));
+                }
 
                 // Create a bloom filter from bit vector
-                let mut bloom = bloomfilter::Bloom::new_for_fp_rate(t_num as usize, db::BLOOM_FP);
+                let mut bloom =
+                    db::new_bloom_with_fp(t_num as usize, self.bloom_fp, self.bloom_hashes);
                 bloom.from_bytes(bit_vec);
 
                 // Insert bloom filter
-                bucket_map.insert(*collection_idx, bloom);
+                bloom_index.insert_bloom(bucket_idx, *collection_idx, bloom, t_num);
 
                 response_idx += 1;
             }
         }
 
-        println!("Download (bloom filter rpc) {} bytes", download_measurement);
+        self.metrics
+            .record_download(metrics::RpcKind::Bloom, download_measurement, raw_measurement);
 
-        Ok(bloom_map)
+        Ok(bloom_index)
     }
 
     // Retrieves a message (or set of messages) form the server based on bucket_map
     fn retr_normal(
         &'a self,
-        mut bucket_map: HashMap<usize, Vec<(&'a PungPeer, Vec<u8>)>>,
+        mut bucket_map: BucketLabels<'a>,
+        on_message: &mut FnMut(&[u8], Vec<u8>),
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<Vec<Vec<u8>>, Error> {
+    ) -> Result<(), Error> {
         let retries = self.max_retries();
         let dummy = &self.peers["dummy"];
         let mut dummy_count = 0;
-        let mut rng = rand::ChaChaRng::new_unseeded();
-        let mut messages: Vec<Vec<u8>> = Vec::new();
+        let mut rng = self.rng.borrow_mut();
 
         match self.ret_scheme {
             db::RetScheme::Explicit => {
-                // Get labels explicitly
-                let explicit_labels = self.get_explicit_labels(scope, port)?;
+                // Get labels explicitly (unless they come with the first retrieval)
+                let mut explicit_labels = if self.piggyback {
+                    None
+                } else {
+                    Some(self.get_explicit_labels(scope, port)?)
+                };
 
                 for _ in 0..retries {
-                    for bucket in 0..self.partitions.len() {
-                        // Get next label to retrieve
-                        let (peer, label) =
-                            self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
+                    // Retrievals whose labels are known are independent of each other, so
+                    // they are all made at once (see pir_retr_many)
+                    let mut found = Vec::new();
+                    let mut batch = Vec::with_capacity(self.partitions.num_buckets());
+                    let mut requests = Vec::with_capacity(self.partitions.num_buckets());
 
+                    for bucket in 0..self.partitions.num_buckets() {
                         // Number of elements in bucket
                         let num = self.buckets[bucket].num_tuples();
 
-                        // Get labels of collection 0 (which is the entire bucket)
-                        let labels = &explicit_labels[&bucket][&0];
-                        assert_eq!(num, labels.len() as u64);
+                        // Empty buckets have no PIR database (and nothing to find), so the
+                        // filters come with the first retrieval from a bucket that has one
+                        if num == 0 {
+                            continue;
+                        }
+
+                        if explicit_labels.is_none() {
+                            // Get a label whose index does not depend on the labels
+                            let (peer, label) = self.piggyback_label(
+                                &bucket_map,
+                                bucket,
+                                retries,
+                                dummy,
+                                &mut dummy_count,
+                            )?;
+
+                            // Get a tuple using PIR along with the labels
+                            let idx = rng.next_u64() % num;
+                            let (t, labels) = self.pir_retr_mapping(bucket, idx, num, scope, port)?;
+                            explicit_labels = Some(labels);
+
+                            found.push((peer, label, t));
+                        } else {
+                            // Get next label to retrieve
+                            let (peer, label) =
+                                self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
+
+                            // Collection 0 is the entire bucket
+                            let labels = explicit_labels.as_ref().unwrap();
+                            assert_eq!(Some(num), labels.len(bucket, 0));
+
+                            // Get index of label if available or random otherwise
+                            let idx = some_or_random!(labels.index_of(bucket, 0, &label), rng, num);
+
+                            batch.push((peer, label));
+                            requests.push((bucket, 0, 0, idx, num));
+                        }
+                    }
 
-                        // Get index of label if available or random otherwise
-                        let idx = some_or_random!(util::get_index(labels, &label), rng, num);
+                    // Get the tuples using PIR to retrieve
+                    let tuples = self.pir_retr_many(&requests, scope, port)?;
 
-                        // Get a tuple using PIR to retrieve
-                        let t = self.pir_retr(bucket, 0, 0, idx, num, scope, port)?;
+                    for ((peer, label), t) in batch.into_iter().zip(tuples) {
+                        found.push((peer, label, t));
+                    }
 
+                    for (peer, label, t) in found {
                         if t.label() == &label[..] {
                             // decrypt ciphertext using shared key and insert it into message list
                             let m = pcrypto::decrypt(
+                                peer.keys.aead,
                                 &peer.keys.k_e[..],
                                 self.round,
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
                                 t.cipher(),
                                 t.mac()
                             )?;
-                            messages.push(m);
+                            on_message(&label[..], m);
                         }
                     }
                 }
             }
 
             db::RetScheme::Bloom => {
-                // Get bloom filter
-                let bloom_filters = self.get_bloom_filter(scope, port)?;
+                // Get bloom filter (unless it comes with the first retrieval)
+                let mut bloom_filters = if self.piggyback {
+                    None
+                } else {
+                    Some(self.get_bloom_filter(scope, port)?)
+                };
 
                 for _ in 0..retries {
-                    for bucket in 0..self.partitions.len() {
-                        // Get next label to retrieve
-                        let (peer, label) =
-                            self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
+                    // As with Explicit, retrievals whose labels are known are made at once
+                    let mut found = Vec::new();
+                    let mut batch = Vec::with_capacity(self.partitions.num_buckets());
+                    let mut requests = Vec::with_capacity(self.partitions.num_buckets());
 
+                    for bucket in 0..self.partitions.num_buckets() {
                         // Number of elements in bucket
                         let num = self.buckets[bucket].num_tuples();
 
-                        // Get bloom filter of collection 0 (entire bucket)
-                        let bloom = &bloom_filters[&bucket][&0];
+                        // Empty buckets are skipped as with Explicit
+                        if num == 0 {
+                            continue;
+                        }
+
+                        if bloom_filters.is_none() {
+                            // Get a label whose index does not depend on the bloom filter
+                            let (peer, label) = self.piggyback_label(
+                                &bucket_map,
+                                bucket,
+                                retries,
+                                dummy,
+                                &mut dummy_count,
+                            )?;
+
+                            // Get a tuple using PIR along with the bloom filters
+                            let idx = rng.next_u64() % num;
+                            let (t, blooms) = self.pir_retr_bloom(bucket, idx, num, scope, port)?;
+                            bloom_filters = Some(blooms);
+
+                            found.push((peer, label, t));
+                        } else {
+                            // Get next label to retrieve
+                            let (peer, label) =
+                                self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
+
+                            // Bloom filter of collection 0 (entire bucket)
+                            let blooms = bloom_filters.as_ref().unwrap();
+
+                            // Get index of label if available or random otherwise
+                            let idx = some_or_random!(blooms.index_of(bucket, 0, &label), rng, num);
+
+                            batch.push((peer, label));
+                            requests.push((bucket, 0, 0, idx, num));
+                        }
+                    }
 
-                        // Get index of label if available or random otherwise
-                        let idx =
-                            some_or_random!(util::get_idx_bloom(bloom, &label, num), rng, num);
+                    // Get the tuples using PIR to retrieve
+                    let tuples = self.pir_retr_many(&requests, scope, port)?;
 
-                        // Get a tuple using PIR to retrieve
-                        let t = self.pir_retr(bucket, 0, 0, idx, num, scope, port)?;
+                    for ((peer, label), t) in batch.into_iter().zip(tuples) {
+                        found.push((peer, label, t));
+                    }
 
+                    for (peer, label, t) in found {
                         if t.label() == &label[..] {
                             // decrypt ciphertext using shared key and insert it into message list
                             let m = pcrypto::decrypt(
+                                peer.keys.aead,
                                 &peer.keys.k_e[..],
                                 self.round,
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
                                 t.cipher(),
                                 t.mac()
                             )?;
-                            messages.push(m);
+                            on_message(&label[..], m);
                         }
                     }
                 }
@@ -808,57 +2279,76 @@ impl<'a> PungClient<'a> {
 
             db::RetScheme::Tree => {
                 for _ in 0..retries {
-                    for bucket in 0..self.partitions.len() {
-                        // Get next label
-                        let (peer, label) =
-                            self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
+                    // Get next label of each bucket
+                    let mut label_list = Vec::with_capacity(self.partitions.num_buckets());
 
-                        // Number of elemnets in bucket
-                        let num = self.buckets[bucket].num_tuples();
+                    for bucket in 0..self.partitions.num_buckets() {
+                        label_list.push(
+                            self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count),
+                        );
+                    }
 
-                        // Perform bst retrieval
-                        let result =
-                            self.bst_retr(&label[..], bucket, 0, num, &mut rng, scope, port)?;
+                    // (bucket, label, number of elements in bucket) of each search
+                    let searches: Vec<(usize, &[u8], u64)> = label_list
+                        .iter()
+                        .enumerate()
+                        .map(|(bucket, &(_, ref label))| {
+                            (bucket, &label[..], self.buckets[bucket].num_tuples())
+                        })
+                        .collect();
 
+                    // Perform bst retrieval in every bucket at once
+                    let results = self.bst_retr(&searches, &mut rng, scope, port)?;
+
+                    for (&(peer, ref label), result) in label_list.iter().zip(results) {
                         if let Some(t) = result {
                             // decrypt ciphertext using shared key and insert it into message list
                             let m = pcrypto::decrypt(
+                                peer.keys.aead,
                                 &peer.keys.k_e[..],
                                 self.round,
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
                                 t.cipher(),
                                 t.mac()
                             )?;
-                            messages.push(m);
+                            on_message(&label[..], m);
                         }
                     }
                 }
             }
         }
 
-        Ok(messages)
+        Ok(())
     }
 
 
     fn retr_hybrid2(
         &'a self,
-        mut bucket_map: HashMap<usize, Vec<(&'a PungPeer, Vec<u8>)>>,
+        mut bucket_map: BucketLabels<'a>,
+        on_message: &mut FnMut(&[u8], Vec<u8>),
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<Vec<Vec<u8>>, Error> {
+    ) -> Result<(), Error> {
         let retries = self.max_retries();
         let dummy = &self.peers["dummy"];
         let mut dummy_count = 0;
-        let mut rng = rand::ChaChaRng::new_unseeded();
-        let mut messages: Vec<Vec<u8>> = Vec::new();
+        let mut rng = self.rng.borrow_mut();
 
 
         match self.ret_scheme {
             db::RetScheme::Explicit => {
                 // Get labels explicitly
                 let explicit_labels = self.get_explicit_labels(scope, port)?;
+                self.check_collections(Some(&explicit_labels))?;
 
                 for _ in 0..retries {
-                    for bucket in 0..self.partitions.len() {
+                    // The requests to every bucket are independent of each other, so they are
+                    // all made at once (see pir_retr_many)
+                    let mut found = Vec::with_capacity(2 * self.partitions.num_buckets());
+                    let mut requests = Vec::with_capacity(3 * self.partitions.num_buckets());
+
+                    for bucket in 0..self.partitions.num_buckets() {
                         // Get 2 labels to retrieve
                         let (peer1, label1) =
                             self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
@@ -872,118 +2362,87 @@ impl<'a> PungClient<'a> {
                         let cmp1 = util::label_cmp(&label1[..], lmid);
                         let cmp2 = util::label_cmp(&label2[..], lmid);
 
-                        // Get explicit labels for collections 0 and 1
-                        let col0 = &explicit_labels[&bucket][&0];
-                        let col1 = &explicit_labels[&bucket][&1];
-
                         // number of elements in collections 0 and 2
                         let len0 = util::collection_len(num, 0, 2) as u64;
                         // number of elements in collections 1
                         let len1 = util::collection_len(num, 1, 2) as u64;
 
-                        assert_eq!(col0.len() as u64, len0);
-                        assert_eq!(col1.len() as u64, len1);
+                        assert_eq!(explicit_labels.len(bucket, 0), Some(len0));
+                        assert_eq!(explicit_labels.len(bucket, 1), Some(len1));
                         assert!(len0 >= len1);
 
+                        // Index of a label in collection 0 or 1 (if available)
+                        let index_of = |c, label: &[u8]| explicit_labels.index_of(bucket, c, label);
+
+                        // Index requested from collections 0, 1, and 2, and the collections
+                        // whose tuples XOR into the tuple of each label.
                         // "_" stands for "greater than or equal" in this case
-                        let (t1, t2) = match (cmp1, cmp2) {
+                        let (idx, parts1, parts2) = match (cmp1, cmp2) {
                             // Case 1: both labels fall in collection 0
                             (Ordering::Less, Ordering::Less) => {
-                                let idx1 =
-                                    some_or_random!(util::get_index(col0, &label1), rng, len0);
-                                let idx2 =
-                                    some_or_random!(util::get_index(col0, &label2), rng, len0);
+                                let idx1 = some_or_random!(index_of(0, &label1), rng, len0);
+                                let idx2 = some_or_random!(index_of(0, &label2), rng, len0);
 
-                                let t1 = self.pir_retr(bucket, 0, 0, idx1, len0, scope, port)?;
-                                let t2 = self.pir_retr(bucket, 1, 0, idx2, len1, scope, port)?;
-                                let t3 = self.pir_retr(bucket, 2, 0, idx2, len0, scope, port)?;
-
-                                (t1, (&t2 ^ &t3))
+                                ([idx1, idx2, idx2], vec![0], vec![1, 2])
                             }
 
                             // Case 2: label 1 is in collection 0, and label 2 in collection 1
                             (Ordering::Less, _) => {
-                                let idx1 =
-                                    some_or_random!(util::get_index(col0, &label1), rng, len0);
-                                let idx2 =
-                                    some_or_random!(util::get_index(col1, &label2), rng, len1);
-
-                                let t1 = self.pir_retr(bucket, 0, 0, idx1, len0, scope, port)?;
-                                let t2 = self.pir_retr(bucket, 1, 0, idx2, len1, scope, port)?;
-
-                                // fake request
-                                self.pir_retr(
-                                    bucket,
-                                    2,
-                                    0,
-                                    rng.next_u64() % len0,
-                                    len0,
-                                    scope,
-                                    port
-                                )?;
+                                let idx1 = some_or_random!(index_of(0, &label1), rng, len0);
+                                let idx2 = some_or_random!(index_of(1, &label2), rng, len1);
 
-                                (t1, t2)
+                                // fake request to collection 2
+                                ([idx1, idx2, rng.next_u64() % len0], vec![0], vec![1])
                             }
 
                             // Case 3: label 1 is in collection 1, and label 2 in collection 0
                             (_, Ordering::Less) => {
-                                let idx1 =
-                                    some_or_random!(util::get_index(col1, &label1), rng, len1);
-                                let idx2 =
-                                    some_or_random!(util::get_index(col0, &label2), rng, len0);
-
-                                let t2 = self.pir_retr(bucket, 0, 0, idx2, len0, scope, port)?;
-                                let t1 = self.pir_retr(bucket, 1, 0, idx1, len1, scope, port)?;
-
-                                // fake request
-                                self.pir_retr(
-                                    bucket,
-                                    2,
-                                    0,
-                                    rng.next_u64() % len0,
-                                    len0,
-                                    scope,
-                                    port
-                                )?;
+                                let idx1 = some_or_random!(index_of(1, &label1), rng, len1);
+                                let idx2 = some_or_random!(index_of(0, &label2), rng, len0);
 
-                                (t1, t2)
+                                // fake request to collection 2
+                                ([idx2, idx1, rng.next_u64() % len0], vec![1], vec![0])
                             }
 
                             // Case 4: both labels fall in collection 1
                             (_, _) => {
-                                let idx1 =
-                                    some_or_random!(util::get_index(col1, &label1), rng, len1);
-                                let idx2 =
-                                    some_or_random!(util::get_index(col1, &label2), rng, len1);
-
-                                let t1 = self.pir_retr(bucket, 0, 0, idx1, len0, scope, port)?;
-                                let t2 = self.pir_retr(bucket, 1, 0, idx2, len1, scope, port)?;
-                                let t3 = self.pir_retr(bucket, 2, 0, idx1, len0, scope, port)?;
+                                let idx1 = some_or_random!(index_of(1, &label1), rng, len1);
+                                let idx2 = some_or_random!(index_of(1, &label2), rng, len1);
 
-                                ((&t1 ^ &t3), t2)
+                                ([idx1, idx2, idx1], vec![0, 2], vec![1])
                             }
                         };
 
-                        if t1.label() == &label1[..] {
-                            // decrypt ciphertext using shared key and insert it into message list
-                            let m = pcrypto::decrypt(
-                                &peer1.keys.k_e[..],
-                                self.round,
-                                t1.cipher(),
-                                t1.mac()
-                            )?;
-                            messages.push(m);
+                        let first = requests.len();
+                        requests.push((bucket, 0, 0, idx[0], len0));
+                        requests.push((bucket, 1, 0, idx[1], len1));
+                        requests.push((bucket, 2, 0, idx[2], len0));
+
+                        found.push((peer1, label1, first, parts1));
+                        found.push((peer2, label2, first, parts2));
+                    }
+
+                    let tuples = self.pir_retr_many(&requests, scope, port)?;
+
+                    for (peer, label, first, parts) in found {
+                        let mut t = db::PungTuple::default();
+
+                        for part in parts {
+                            t = &t ^ &tuples[first + part];
                         }
 
-                        if t2.label() == &label2[..] {
+                        if t.label() == &label[..] {
                             // decrypt ciphertext using shared key and insert it into message list
                             let m = pcrypto::decrypt(
-                                &peer2.keys.k_e[..],
+                                peer.keys.aead,
+                                &peer.keys.k_e[..],
                                 self.round,
-                                t2.cipher(),
-                                t2.mac()
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
+                                t.cipher(),
+                                t.mac()
                             )?;
-                            messages.push(m);
+                            on_message(&label[..], m);
                         }
                     }
                 }
@@ -992,9 +2451,14 @@ impl<'a> PungClient<'a> {
             db::RetScheme::Bloom => {
                 // Get bloom filters
                 let bloom_filters = self.get_bloom_filter(scope, port)?;
+                self.check_collections(None)?;
 
                 for _ in 0..retries {
-                    for bucket in 0..self.partitions.len() {
+                    // As with Explicit, the requests to every bucket are made at once
+                    let mut found = Vec::with_capacity(2 * self.partitions.num_buckets());
+                    let mut requests = Vec::with_capacity(3 * self.partitions.num_buckets());
+
+                    for bucket in 0..self.partitions.num_buckets() {
                         // Get 2 labels to retrieve
                         let (peer1, label1) =
                             self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
@@ -1008,147 +2472,99 @@ impl<'a> PungClient<'a> {
                         let cmp1 = util::label_cmp(&label1[..], lmid);
                         let cmp2 = util::label_cmp(&label2[..], lmid);
 
-                        // Get bloom filter for collections 0 and 1
-                        let b0 = &bloom_filters[&bucket][&0];
-                        let b1 = &bloom_filters[&bucket][&1];
-
                         // number of elements in collections 0 and 2
                         let len0 = util::collection_len(num, 0, 2) as u64;
                         // number of elements in collections 1
                         let len1 = util::collection_len(num, 1, 2) as u64;
                         assert!(len0 >= len1);
 
+                        // Index of a label in collection 0 or 1 (if the bloom filter has it)
+                        let index_of = |c, label: &[u8]| bloom_filters.index_of(bucket, c, label);
+
+                        // Index requested from collections 0, 1, and 2, and the collections
+                        // whose tuples XOR into the tuple of each label.
                         // "_" stands for "greater than or equal" in this case
-                        let (t1, t2) = match (cmp1, cmp2) {
+                        let (idx, parts1, parts2) = match (cmp1, cmp2) {
                             // Case 1: both labels fall in collection 0
                             (Ordering::Less, Ordering::Less) => {
-                                let idx1 = some_or_random!(
-                                    util::get_idx_bloom(b0, &label1, len0),
-                                    rng,
-                                    len0
-                                );
-                                let idx2 = some_or_random!(
-                                    util::get_idx_bloom(b0, &label2, len0),
-                                    rng,
-                                    len0
-                                );
-
-                                let t1 = self.pir_retr(bucket, 0, 0, idx1, len0, scope, port)?;
-                                let t2 = self.pir_retr(bucket, 1, 0, idx2, len1, scope, port)?;
-                                let t3 = self.pir_retr(bucket, 2, 0, idx2, len0, scope, port)?;
-
-                                (t1, (&t2 ^ &t3))
+                                let idx1 = some_or_random!(index_of(0, &label1), rng, len0);
+                                let idx2 = some_or_random!(index_of(0, &label2), rng, len0);
+
+                                ([idx1, idx2, idx2], vec![0], vec![1, 2])
                             }
 
                             // Case 2: label 1 is in collection 0, and label 2 in collection 1
                             (Ordering::Less, _) => {
-                                let idx1 = some_or_random!(
-                                    util::get_idx_bloom(b0, &label1, len0),
-                                    rng,
-                                    len0
-                                );
-                                let idx2 = some_or_random!(
-                                    util::get_idx_bloom(b1, &label2, len1),
-                                    rng,
-                                    len1
-                                );
-
-                                let t1 = self.pir_retr(bucket, 0, 0, idx1, len0, scope, port)?;
-                                let t2 = self.pir_retr(bucket, 1, 0, idx2, len1, scope, port)?;
-
-                                // fake request
-                                self.pir_retr(
-                                    bucket,
-                                    2,
-                                    0,
-                                    rng.next_u64() % len0,
-                                    len0,
-                                    scope,
-                                    port
-                                )?;
+                                let idx1 = some_or_random!(index_of(0, &label1), rng, len0);
+                                let idx2 = some_or_random!(index_of(1, &label2), rng, len1);
 
-                                (t1, t2)
+                                // fake request to collection 2
+                                ([idx1, idx2, rng.next_u64() % len0], vec![0], vec![1])
                             }
 
                             // Case 3: label 1 is in collection 1, and label 2 in collection 0
                             (_, Ordering::Less) => {
-                                let idx1 = some_or_random!(
-                                    util::get_idx_bloom(b1, &label1, len1),
-                                    rng,
-                                    len1
-                                );
-                                let idx2 = some_or_random!(
-                                    util::get_idx_bloom(b0, &label2, len0),
-                                    rng,
-                                    len0
-                                );
-
-                                let t2 = self.pir_retr(bucket, 0, 0, idx2, len0, scope, port)?;
-                                let t1 = self.pir_retr(bucket, 1, 0, idx1, len1, scope, port)?;
-
-                                // fake request
-                                self.pir_retr(
-                                    bucket,
-                                    2,
-                                    0,
-                                    rng.next_u64() % len0,
-                                    len0,
-                                    scope,
-                                    port
-                                )?;
+                                let idx1 = some_or_random!(index_of(1, &label1), rng, len1);
+                                let idx2 = some_or_random!(index_of(0, &label2), rng, len0);
 
-                                (t1, t2)
+                                // fake request to collection 2
+                                ([idx2, idx1, rng.next_u64() % len0], vec![1], vec![0])
                             }
 
                             // Case 4: both labels fall in collection 1
                             (_, _) => {
-                                let idx1 = some_or_random!(
-                                    util::get_idx_bloom(b1, &label1, len1),
-                                    rng,
-                                    len1
-                                );
-                                let idx2 = some_or_random!(
-                                    util::get_idx_bloom(b1, &label2, len1),
-                                    rng,
-                                    len1
-                                );
-
-                                let t1 = self.pir_retr(bucket, 0, 0, idx1, len0, scope, port)?;
-                                let t2 =self.pir_retr(bucket, 1, 0, idx2, len1, scope, port)?;
-                                let t3 = self.pir_retr(bucket, 2, 0, idx1, len0, scope, port)?;
-
-                                ((&t1 ^ &t3), t2)
+                                let idx1 = some_or_random!(index_of(1, &label1), rng, len1);
+                                let idx2 = some_or_random!(index_of(1, &label2), rng, len1);
+
+                                ([idx1, idx2, idx1], vec![0, 2], vec![1])
                             }
                         };
 
-                        if t1.label() == &label1[..] {
-                            // decrypt ciphertext using shared key and insert it into message list
-                            let m = pcrypto::decrypt(
-                                &peer1.keys.k_e[..],
-                                self.round,
-                                t1.cipher(),
-                                t1.mac()
-                            )?;
-                            messages.push(m);
+                        let first = requests.len();
+                        requests.push((bucket, 0, 0, idx[0], len0));
+                        requests.push((bucket, 1, 0, idx[1], len1));
+                        requests.push((bucket, 2, 0, idx[2], len0));
+
+                        found.push((peer1, label1, first, parts1));
+                        found.push((peer2, label2, first, parts2));
+                    }
+
+                    let tuples = self.pir_retr_many(&requests, scope, port)?;
+
+                    for (peer, label, first, parts) in found {
+                        let mut t = db::PungTuple::default();
+
+                        for part in parts {
+                            t = &t ^ &tuples[first + part];
                         }
 
-                        if t2.label() == &label2[..] {
+                        if t.label() == &label[..] {
                             // decrypt ciphertext using shared key and insert it into message list
                             let m = pcrypto::decrypt(
-                                &peer2.keys.k_e[..],
+                                peer.keys.aead,
+                                &peer.keys.k_e[..],
                                 self.round,
-                                t2.cipher(),
-                                t2.mac()
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
+                                t.cipher(),
+                                t.mac()
                             )?;
-                            messages.push(m);
+                            on_message(&label[..], m);
                         }
                     }
                 }
             }
 
             db::RetScheme::Tree => {
+                self.check_collections(None)?;
+
                 for _ in 0..retries {
-                    for bucket in 0..self.partitions.len() {
+                    // Labels to retrieve from each bucket, and (bucket, collection lengths,
+                    // (label, collection, parts)) of each bucket's searches
+                    let mut label_lists = Vec::with_capacity(self.partitions.num_buckets());
+                    let mut searches = Vec::with_capacity(self.partitions.num_buckets());
+
+                    for bucket in 0..self.partitions.num_buckets() {
                         // Get 2 labels to retrieve
                         let (peer1, label1) =
                             self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count);
@@ -1159,737 +2575,1501 @@ impl<'a> PungClient<'a> {
                         let num = self.buckets[bucket].num_tuples();
                         let lmid = self.buckets[bucket].get_lmid(0);
 
-                        // Compare labels to lmid
-                        let cmp1 = util::label_cmp(&label1[..], lmid);
-                        let cmp2 = util::label_cmp(&label2[..], lmid);
-
                         // number of elements in collections 0 and 2
                         let len0 = util::collection_len(num, 0, 2) as u64;
                         // number of elements in collections 1
                         let len1 = util::collection_len(num, 1, 2) as u64;
 
-                        // "_" stands for ">="
-                        let (t1, t2) = match (cmp1, cmp2) {
-                            // Case 1: both labels fall in collection 0
-                            (Ordering::Less, Ordering::Less) => {
-                                let t1 = self.bst_retr(
-                                    &label1[..],
-                                    bucket,
-                                    0,
-                                    len0,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
-
-                                let t2 = self.bst_joint_retr(
-                                    &label2[..],
-                                    bucket,
-                                    1,
-                                    len1,
-                                    len0,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
-
-                                (t1, t2)
-                            }
-
-                            // Case 2: label 1 is in collection 0, and label 2 in collection 1
-                            (Ordering::Less, _) => {
-                                let t1 = self.bst_retr(
-                                    &label1[..],
-                                    bucket,
-                                    0,
-                                    len0,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
-
-                                let t2 = self.bst_retr(
-                                    &label2[..],
-                                    bucket,
-                                    1,
-                                    len1,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
-
-                                // Generate dummy label
-                                let dummy = pcrypto::gen_label(
-                                    &dummy.keys.k_l[..],
-                                    self.round,
-                                    dummy.uid_self,
-                                    dummy_count,
-                                    0,
-                                );
-                                dummy_count += 1;
-
-                                self.bst_retr(
-                                    &dummy[..],
-                                    bucket,
-                                    2,
-                                    len0,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
+                        // Collection in which each label falls (0 if below lmid, 1 otherwise)
+                        let collection_of = |label: &[u8]| match util::label_cmp(label, lmid) {
+                            Ordering::Less => 0,
+                            _ => 1,
+                        };
 
+                        let c1 = collection_of(&label1[..]);
+                        let c2 = collection_of(&label2[..]);
 
-                                (t1, t2)
-                            }
+                        // Label 1 is searched for directly. So is label 2 if it falls in the
+                        // other collection, and otherwise in the XOR of that collection with
+                        // collection 2 (which is collection 0 XOR collection 1).
+                        let parts2 = if c1 != c2 { vec![c2] } else { vec![1 - c2, 2] };
 
-                            // Case 3: label 1 is in collection 1, and label 2 in collection 0
-                            (_, Ordering::Less) => {
-                                let t2 = self.bst_retr(
-                                    &label2[..],
-                                    bucket,
-                                    0,
-                                    len0,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
+                        let targets = vec![
+                            (label1.clone(), c1, vec![c1]),
+                            (label2.clone(), c2, parts2),
+                        ];
 
-                                let t1 = self.bst_retr(
-                                    &label1[..],
-                                    bucket,
-                                    1,
-                                    len1,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
+                        searches.push((bucket, vec![len0, len1, len0], targets));
+                        label_lists.push(vec![(peer1, label1), (peer2, label2)]);
+                    }
 
-                                // Generate dummy label
-                                let dummy = pcrypto::gen_label(
-                                    &dummy.keys.k_l[..],
+                    // The requests are the same in all four cases, and the trees of every
+                    // bucket are descended together (see bst_coded_retr)
+                    let results = self.bst_coded_retr(&searches, &mut rng, scope, port)?;
+
+                    for (label_list, tuples) in label_lists.iter().zip(results) {
+                        for (&(peer, ref label), tuple) in label_list.iter().zip(tuples) {
+                            if let Some(t) = tuple {
+                                // decrypt using shared key and insert into message list
+                                let m = pcrypto::decrypt(
+                                    peer.keys.aead,
+                                    &peer.keys.k_e[..],
                                     self.round,
-                                    dummy.uid_self,
-                                    dummy_count,
-                                    0,
-                                );
-                                dummy_count += 1;
-
-                                self.bst_retr(
-                                    &dummy[..],
-                                    bucket,
-                                    2,
-                                    len0,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
-
-                                (t1, t2)
-                            }
-
-                            // Case 4: both labels fall in collection 1
-                            //
-                            // XXX: As written this may leak information since joint retrieval
-                            // requests from 0 and 2 and then bst_retr gets from 1.
-                            // To fix this one needs to request from 0, 1, 2 (or in parallel).
-                            // This leads to slightly more gross code.
-                            // Performance-wise this should be no different though.
-                            (_, _) => {
-                                let t2 = self.bst_joint_retr(
-                                    &label2[..],
-                                    bucket,
-                                    0,
-                                    len0,
-                                    len0,
-                                    &mut rng,
-                                    scope,
-                                    port
-                                )?;
-
-                                let t1 = self.bst_retr(
-                                    &label1[..],
-                                    bucket,
-                                    1,
-                                    len1,
-                                    &mut rng,
-                                    scope,
-                                    port
+                                    peer.uid_self,
+                                    self.msg_index(&label[..]),
+                                    t.cipher(),
+                                    t.mac()
                                 )?;
-
-                                (t1, t2)
+                                on_message(&label[..], m);
                             }
-                        };
-
-                        if let Some(t) = t1 {
-                            // decrypt ciphertext 1 using shared key and insert it into message list
-                            let m = pcrypto::decrypt(
-                                &peer1.keys.k_e[..],
-                                self.round,
-                                t.cipher(),
-                                t.mac()
-                            )?;
-                            messages.push(m);
-                        }
-
-                        if let Some(t) = t2 {
-                            // decrypt ciphertext 2 using shared key and insert it into message list
-                            let m = pcrypto::decrypt(
-                                &peer2.keys.k_e[..],
-                                self.round,
-                                t.cipher(),
-                                t.mac()
-                            )?;
-                            messages.push(m);
                         }
                     }
                 }
             }
         }
 
-        Ok(messages)
+        Ok(())
     }
 
 
     fn retr_hybrid4(
         &'a self,
-        mut bucket_map: HashMap<usize, Vec<(&'a PungPeer, Vec<u8>)>>,
+        mut bucket_map: BucketLabels<'a>,
+        on_message: &mut FnMut(&[u8], Vec<u8>),
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<Vec<Vec<u8>>, Error> {
+    ) -> Result<(), Error> {
         let dummy = &self.peers["dummy"];
         let mut dummy_count = 0;
-        let mut rng = rand::ChaChaRng::new_unseeded();
-        let mut messages: Vec<Vec<u8>> = Vec::new();
+        let mut rng = self.rng.borrow_mut();
 
 
         match self.ret_scheme {
-            // XXX: The function below probes all collections (as it should) but it does
-            // so in an order that is dependent on the labels of interest to the user.
-            // This can likely leak information. The solution is to retrieve from the collections
-            // in a fixed order (e.g., 0, 1, 2,..., 8) and then put the tuples together afterwards.
-            // However, that requires much grosser looking code and its performance is the same
-            // as the scheme below. We leave it to be fixed later.
+            // Every collection is requested once and in a fixed order (see plan_h4_targets),
+            // so the requests do not depend on which labels are of interest to the user.
             db::RetScheme::Explicit => {
                 // Get labels explicitly
                 let explicit_labels = self.get_explicit_labels(scope, port)?;
+                self.check_collections(Some(&explicit_labels))?;
 
-                for bucket in 0..self.partitions.len() {
-                    // Available collections
-                    let mut available: HashSet<usize> = (0..9).collect();
+                // Labels to retrieve from each bucket, and how to retrieve their tuples
+                let mut label_lists = Vec::with_capacity(self.partitions.num_buckets());
+                let mut plans = Vec::with_capacity(self.partitions.num_buckets());
 
+                for bucket in 0..self.partitions.num_buckets() {
                     // Get 4 (peer, label) to retrieve
                     let mut label_list = Vec::with_capacity(4);
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
 
-                    let lmids = self.buckets[bucket].get_lmids();
-                    let bucket_labels = &explicit_labels[&bucket];
+                    for _ in 0..4 {
+                        label_list.push(self.next_label(
+                            &mut bucket_map,
+                            bucket,
+                            dummy,
+                            &mut dummy_count,
+                        ));
+                    }
 
-                    for &(peer, ref label) in &label_list {
-                        let mut c_i = 3; // last collection
+                    let lmids = self.buckets[bucket].get_lmids();
 
-                        // Find out in which of the systematic collections does this label fall
-                        for (i, lmid) in lmids.iter().enumerate() {
-                            if util::label_cmp(&label[..], &lmid[..]) == Ordering::Less {
-                                c_i = i;
-                                break;
-                            }
-                        }
+                    // (collection, index) of the tuple of each label
+                    let mut targets = Vec::with_capacity(4);
 
+                    for &(_, ref label) in &label_list {
+                        let c_i = h4_collection(lmids, &label[..]);
 
-                        // Get labels and index of tuple in the target collection (0, 1, 2 or 3)
-                        let c_labels = bucket_labels.get(&c_i).unwrap();
+                        // Get index of tuple in the target collection (0, 1, 2 or 3)
+                        let c_num = explicit_labels.len(bucket, c_i).unwrap();
                         let idx = some_or_random!(
-                            util::get_index(c_labels, &label),
+                            explicit_labels.index_of(bucket, c_i, &label),
                             rng,
-                            c_labels.len() as u64
+                            c_num
                         );
 
-                        for parts in &self.h4_mappings[&c_i] {
-                            let res = available.is_superset(parts);
-
-                            if res {
-                                // All needed parts are available
-
-                                let mut tuple = db::PungTuple::default();
-
-                                for part in parts {
-                                    // Remove parts from available set
-                                    available.remove(part);
-
-
-                                    let len = if *part == 4 || *part == 6 || *part == 8 {
-                                        bucket_labels.get(&0).unwrap().len() as u64
-                                    } else if *part == 5 {
-                                        bucket_labels.get(&2).unwrap().len() as u64
-                                    } else if *part == 7 {
-                                        bucket_labels.get(&1).unwrap().len() as u64
-                                    } else {
-                                        bucket_labels.get(part).unwrap().len() as u64
-                                    };
-
-                                    assert!(idx < len);
-
-                                    // Create the tuple by requesting parts and XORING them together
-                                    tuple ^= self.pir_retr(
-                                        bucket,
-                                        *part as u32,
-                                        0,
-                                        idx,
-                                        len,
-                                        scope,
-                                        port
-                                    )?;
-                                }
-
-                                if tuple.label() == &label[..] {
-                                    //decrypt using shared key and insert into message list
-                                    let m = pcrypto::decrypt(
-                                        &peer.keys.k_e[..],
-                                        self.round,
-                                        tuple.cipher(),
-                                        tuple.mac()
-                                    )?;
-                                    messages.push(m);
-                                }
-
-                                break;
-                            }
-                        }
+                        targets.push((c_i, idx));
                     }
 
+                    plans.push(self.plan_h4_targets(bucket, &targets, &mut rng)?);
+                    label_lists.push(label_list);
+                }
 
-                    // Once all labels have been retrieved, retrieve from the remaining collections
-                    for part in &available {
-                        let len = if *part == 4 || *part == 6 || *part == 8 {
-                            bucket_labels.get(&0).unwrap().len() as u64
-                        } else if *part == 5 {
-                            bucket_labels.get(&2).unwrap().len() as u64
-                        } else if *part == 7 {
-                            bucket_labels.get(&1).unwrap().len() as u64
-                        } else {
-                            bucket_labels.get(part).unwrap().len() as u64
-                        };
-
-                        let idx = rng.next_u64() % len;
+                // The requests to every bucket are made at once (see retr_planned)
+                let tuples = self.retr_planned(&plans, scope, port)?;
 
-                        self.pir_retr(bucket, *part as u32, 0, idx, len, scope, port)?;
+                for (label_list, tuples) in label_lists.iter().zip(tuples) {
+                    for (&(peer, ref label), tuple) in label_list.iter().zip(tuples) {
+                        if tuple.label() == &label[..] {
+                            //decrypt using shared key and insert into message list
+                            let m = pcrypto::decrypt(
+                                peer.keys.aead,
+                                &peer.keys.k_e[..],
+                                self.round,
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
+                                tuple.cipher(),
+                                tuple.mac()
+                            )?;
+                            on_message(&label[..], m);
+                        }
                     }
                 }
             }
 
-            // XXX: The function below probes all collections (as it should) but it does
-            // so in an order that is dependent on the labels of interest to the user.
-            // This can likely leak information. The solution is to retrieve from the collections
-            // in a fixed order (e.g., 0, 1, 2,..., 8) and then put the tuples together afterwards.
-            // However, that requires much grosser looking code and its performance is the same
-            // as the scheme below. We leave it to be fixed later.
+            // As with Explicit, collections are requested in a fixed order
             db::RetScheme::Bloom => {
                 // Get labels explicitly
                 let bloom_filters = self.get_bloom_filter(scope, port)?;
+                self.check_collections(None)?;
 
-                for bucket in 0..self.partitions.len() {
-                    // Available collections
-                    let mut available: HashSet<usize> = (0..9).collect();
+                // Labels to retrieve from each bucket, and how to retrieve their tuples
+                let mut label_lists = Vec::with_capacity(self.partitions.num_buckets());
+                let mut plans = Vec::with_capacity(self.partitions.num_buckets());
 
+                for bucket in 0..self.partitions.num_buckets() {
                     // Get 4 (peer, label) to retrieve
                     let mut label_list = Vec::with_capacity(4);
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
-                    label_list.push(self.next_label(
-                        &mut bucket_map,
-                        bucket,
-                        dummy,
-                        &mut dummy_count,
-                    ));
+
+                    for _ in 0..4 {
+                        label_list.push(self.next_label(
+                            &mut bucket_map,
+                            bucket,
+                            dummy,
+                            &mut dummy_count,
+                        ));
+                    }
 
                     let lmids = self.buckets[bucket].get_lmids();
-                    let bucket_blooms = &bloom_filters[&bucket];
                     let num = self.buckets[bucket].num_tuples();
 
-                    for &(peer, ref label) in &label_list {
-                        let mut c_i = 3; // last collection
-
-                        // Find out in which of the systematic collections does this label fall
-                        for (i, lmid) in lmids.iter().enumerate() {
-                            if util::label_cmp(&label[..], &lmid[..]) == Ordering::Less {
-                                c_i = i;
-                                break;
-                            }
-                        }
+                    // (collection, index) of the tuple of each label
+                    let mut targets = Vec::with_capacity(4);
 
+                    for &(_, ref label) in &label_list {
+                        let c_i = h4_collection(lmids, &label[..]);
 
-                        // Get labels and index of tuple in the target collection (0, 1, 2 or 3)
+                        // Get index of tuple in the target collection (0, 1, 2 or 3)
                         let c_num = util::collection_len(num, c_i as u32, 4);
-                        let c_bloom = bucket_blooms.get(&c_i).unwrap();
                         let idx = some_or_random!(
-                            util::get_idx_bloom(c_bloom, &label, c_num),
+                            bloom_filters.index_of(bucket, c_i, &label),
                             rng,
                             c_num
                         );
 
-                        for parts in &self.h4_mappings[&c_i] {
-                            let res = available.is_superset(parts);
-
-                            if res {
-                                // All needed parts are available
-
-                                let mut tuple = db::PungTuple::default();
-
-                                for part in parts {
-                                    // Remove parts from available set
-                                    available.remove(part);
-
-
-                                    let len = if *part == 4 || *part == 6 || *part == 8 {
-                                        util::collection_len(num, 0, 4)
-                                    } else if *part == 5 {
-                                        util::collection_len(num, 2, 4)
-                                    } else if *part == 7 {
-                                        util::collection_len(num, 1, 4)
-                                    } else {
-                                        util::collection_len(num, *part as u32, 4)
-                                    };
-
-                                    assert!(idx < len || idx == len);
-
-                                    // The index is not in this part (but it is in the other parts)
-                                    // Just fetch anything from this part and ignore the result
-                                    if idx == len {
-                                        let tmp_idx = rng.next_u64() % (len as u64);
-                                        self.pir_retr(
-                                            bucket,
-                                            *part as u32,
-                                            0,
-                                            tmp_idx,
-                                            len,
-                                            scope,
-                                            port
-                                        )?;
-                                    } else {
-                                        //Create tuple by requesting part and XORING to prior parts
-                                        tuple ^= self.pir_retr(
-                                            bucket,
-                                            *part as u32,
-                                            0,
-                                            idx,
-                                            len,
-                                            scope,
-                                            port
-                                        )?;
-                                    }
-                                }
-
-                                if tuple.label() == &label[..] {
-                                    // decrypt using shared key and insert into message list
-                                    let m = pcrypto::decrypt(
-                                        &peer.keys.k_e[..],
-                                        self.round,
-                                        tuple.cipher(),
-                                        tuple.mac()
-                                    )?;
-                                    messages.push(m);
-                                }
-
-                                break;
-                            }
-                        }
+                        targets.push((c_i, idx));
                     }
 
+                    plans.push(self.plan_h4_targets(bucket, &targets, &mut rng)?);
+                    label_lists.push(label_list);
+                }
 
-                    // Once all labels have been retrieved, retrieve from the remaining collections
-                    for part in &available {
-                        let len = if *part == 4 || *part == 6 || *part == 8 {
-                            util::collection_len(num, 0, 4)
-                        } else if *part == 5 {
-                            util::collection_len(num, 2, 4)
-                        } else if *part == 7 {
-                            util::collection_len(num, 1, 4)
-                        } else {
-                            util::collection_len(num, *part as u32, 4)
-                        };
-
-                        let idx = rng.next_u64() % len;
+                // The requests to every bucket are made at once (see retr_planned)
+                let tuples = self.retr_planned(&plans, scope, port)?;
 
-                        self.pir_retr(bucket, *part as u32, 0, idx, len, scope, port)?;
+                for (label_list, tuples) in label_lists.iter().zip(tuples) {
+                    for (&(peer, ref label), tuple) in label_list.iter().zip(tuples) {
+                        if tuple.label() == &label[..] {
+                            // decrypt using shared key and insert into message list
+                            let m = pcrypto::decrypt(
+                                peer.keys.aead,
+                                &peer.keys.k_e[..],
+                                self.round,
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
+                                tuple.cipher(),
+                                tuple.mac()
+                            )?;
+                            on_message(&label[..], m);
+                        }
                     }
                 }
             }
 
-            // TODO, FIXME: Previous implementation was horribly inefficient and leaked information.
-            // A re-write is work in progress.
-            db::RetScheme::Tree => unimplemented!(),
-        }
+            // Each label is searched for in the tree of its systematic collection, whose
+            // nodes are rebuilt from the parts of one of its mappings (see bst_coded_retr)
+            db::RetScheme::Tree => {
+                self.check_collections(None)?;
 
-        Ok(messages)
-    }
+                // Labels to retrieve from each bucket, and (bucket, part lengths, (label,
+                // collection, parts)) of each bucket's searches
+                let mut label_lists = Vec::with_capacity(self.partitions.num_buckets());
+                let mut searches = Vec::with_capacity(self.partitions.num_buckets());
 
+                for bucket in 0..self.partitions.num_buckets() {
+                    // Available collections
+                    let mut available: BTreeSet<usize> = (0..9).collect();
 
+                    // Get 4 (peer, label) to retrieve
+                    let mut label_list = Vec::with_capacity(4);
 
-    // Retrieves a tuple from the server given a bucket, collection, level, and index
-    fn pir_retr(
-        &self,
-        bucket: usize,
-        collection: u32,
-        level: u32,
-        idx: u64,
-        len: u64,
-        scope: &gj::WaitScope,
-        port: &mut gjio::EventPort,
-    ) -> Result<db::PungTuple, Error> {
-        // set up PIR handler
-        // compute ideal alpha
-        let alpha = util::get_alpha(len);
-        self.pir_handler
-            .update_params(db::TUPLE_SIZE as u64, len, alpha);
+                    for _ in 0..4 {
+                        label_list.push(self.next_label(
+                            &mut bucket_map,
+                            bucket,
+                            dummy,
+                            &mut dummy_count,
+                        ));
+                    }
 
-        // Create PIR request
-        let query = self.pir_handler.gen_query(idx);
-        let mut request = self.conn.retr_request();
-        request.get().set_id(self.id);
-        request.get().set_round(self.round);
-        request.get().set_bucket(bucket as u32);
-        request.get().set_collection(collection);
-        request.get().set_level(level);
-        request.get().set_query(query.query);
-        request.get().set_qnum(query.num);
+                    let lmids = self.buckets[bucket].get_lmids();
+                    let num = self.buckets[bucket].num_tuples();
 
-        println!("Upload (pir) {} bytes", 32 + query.query.len());
+                    let part_lens: Vec<u64> =
+                        (0..9).map(|part| util::h4_part_len(num, part)).collect();
 
-        // Send request to the server and get response
-        let response = request.send().promise.wait(scope, port)?;
+                    // (label, collection, parts) of each label's search
+                    let mut targets = Vec::with_capacity(4);
 
-        // Extract PIR answer from response
-        let answer: &[u8] = response.get()?.get_answer()?;
-        let a_num: u64 = response.get()?.get_anum();
+                    for &(_, ref label) in &label_list {
+                        let c_i = h4_collection(lmids, &label[..]);
 
-        if answer.len() == 0 || a_num == 0 {
-            return Err(Error::failed("Invalid PIR answer returned.".to_string()));
-        }
+                        let parts = self.h4_mappings[&c_i]
+                            .iter()
+                            .find(|parts| available.is_superset(parts))
+                            .expect("batch code covers any 4 collections");
 
-        // Decode answer to get tuple
-        let decoded = self.pir_handler.decode_answer(answer, a_num);
+                        for part in parts {
+                            available.remove(part);
+                        }
 
-        println!("Download (pir) {} bytes", 8 + answer.len());
+                        targets.push((label.clone(), c_i, parts.iter().cloned().collect()));
+                    }
 
-        Ok(db::PungTuple::new(decoded.result))
-    }
+                    searches.push((bucket, part_lens, targets));
+                    label_lists.push(label_list);
+                }
 
-    // Retrieves a tuple using only a label by searching on the server
-    fn bst_retr(
-        &self,
-        label: &[u8],
+                // The trees of every bucket are descended together
+                let results = self.bst_coded_retr(&searches, &mut rng, scope, port)?;
+
+                for (label_list, tuples) in label_lists.iter().zip(results) {
+                    for (&(peer, ref label), result) in label_list.iter().zip(tuples) {
+                        if let Some(tuple) = result {
+                            // decrypt using shared key and insert into message list
+                            let m = pcrypto::decrypt(
+                                peer.keys.aead,
+                                &peer.keys.k_e[..],
+                                self.round,
+                                peer.uid_self,
+                                self.msg_index(&label[..]),
+                                tuple.cipher(),
+                                tuple.mac()
+                            )?;
+                            on_message(&label[..], m);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+
+
+    // Plans the retrieval, for each (collection, index) in `targets` (at most 4), of the
+    // tuple at that index of that systematic collection of a hybrid 4 bucket. Each tuple is
+    // rebuilt from the parts of the first of its collection's mappings whose parts are all
+    // still unused. Every part is then requested exactly once and in order (0 to 8), those
+    // that no target needs at a random index, so that the requests are the same whatever the
+    // targets.
+    fn plan_h4_targets(
+        &self,
         bucket: usize,
-        collection: u32,
-        num: u64,
+        targets: &[(usize, u64)],
         rng: &mut rand::ChaChaRng,
+    ) -> Result<PartPlan, Error> {
+        let num = self.buckets[bucket].num_tuples();
+
+        // Available collections
+        let mut available: BTreeSet<usize> = (0..9).collect();
+
+        // Index requested from each part (if any target needs it)
+        let mut plan = PartPlan::new(9);
+        let mut requested: Vec<Option<u64>> = vec![None; 9];
+
+        for &(c_i, idx) in targets {
+            let parts = self.h4_mappings[&c_i]
+                .iter()
+                .find(|parts| available.is_superset(parts))
+                .expect("batch code covers any 4 collections");
+
+            for &part in parts {
+                available.remove(&part);
+                requested[part] = Some(idx);
+            }
+
+            plan.target_parts.push(parts.iter().cloned().collect());
+        }
+
+        for part in 0..9 {
+            let len = util::h4_part_len(num, part);
+
+            // Parts that no target needs are fetched at a random index (if they have any)
+            let idx = match requested[part] {
+                Some(idx) => idx,
+                None if len > 0 => rng.next_u64() % len,
+                None => 0,
+            };
+
+            plan.add_part(bucket, part, idx, len, rng)?;
+        }
+
+        Ok(plan)
+    }
+
+    // Retrieves the tuples of every plan (see PartPlan), with the requests of all of them in
+    // flight at once
+    fn retr_planned(
+        &self,
+        plans: &[PartPlan],
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<Option<db::PungTuple>, Error> {
-        let tree_height = util::tree_height(num);
-        let mut idx = 0; // first index is 0
-        let mut len = 1; // first level has 1 entry
-        let mut result: Option<db::PungTuple> = None;
+    ) -> Result<Vec<Vec<db::PungTuple>>, Error> {
+        let requests: Vec<(usize, u32, u32, u64, u64)> =
+            plans.iter().flat_map(|plan| plan.requests.iter().cloned()).collect();
+
+        let answers = self.pir_retr_many(&requests, scope, port)?;
+        let mut start = 0;
+
+        Ok(plans
+            .iter()
+            .map(|plan| {
+                let end = start + plan.requests.len();
+                let tuples = plan.rebuild(&answers[start..end]);
+                start = end;
+                tuples
+            })
+            .collect())
+    }
 
-        // Request level by level
-        for h in 0..tree_height {
-            let tuple = self.pir_retr(bucket, collection, h, idx, len, scope, port)?;
-
-            if result.is_none() {
-                if tuple.gt(label) {
-                    // if L* < L
-                    idx *= 2;
-                } else if tuple.lt(label) {
-                    // if L* > L
-                    idx = (2 * idx) + 1;
-                } else {
-                    result = Some(tuple);
+    // Like retr_hybrid4, but retrieves up to 8 labels per bucket from the 27 collections of a
+    // hybrid 8 bucket (see util::h8_mappings).
+    fn retr_hybrid8(
+        &'a self,
+        mut bucket_map: BucketLabels<'a>,
+        on_message: &mut FnMut(&[u8], Vec<u8>),
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(), Error> {
+        let dummy = &self.peers["dummy"];
+        let mut dummy_count = 0;
+        let mut rng = self.rng.borrow_mut();
+
+        // Labels (explicit or bloom filters) are fetched once for every bucket
+        let label_index = match self.ret_scheme {
+            db::RetScheme::Explicit => {
+                let labels = self.get_explicit_labels(scope, port)?;
+                self.check_collections(Some(&labels))?;
+                Some(labels)
+            }
+
+            db::RetScheme::Bloom => {
+                let filters = self.get_bloom_filter(scope, port)?;
+                self.check_collections(None)?;
+                Some(filters)
+            }
+
+            db::RetScheme::Tree => {
+                self.check_collections(None)?;
+                None
+            }
+        };
+
+        // Labels to retrieve from each bucket, and the systematic collection (0 to 7) of each
+        let mut label_lists = Vec::with_capacity(self.partitions.num_buckets());
+        let mut collections = Vec::with_capacity(self.partitions.num_buckets());
+
+        for bucket in 0..self.partitions.num_buckets() {
+            // Get 8 (peer, label) to retrieve
+            let mut label_list = Vec::with_capacity(8);
+
+            for _ in 0..8 {
+                label_list.push(self.next_label(&mut bucket_map, bucket, dummy, &mut dummy_count));
+            }
+
+            let lmids = self.buckets[bucket].get_lmids();
+
+            let bucket_collections: Vec<usize> = label_list
+                .iter()
+                .map(|&(_, ref label)| h4_collection(lmids, &label[..]))
+                .collect();
+
+            label_lists.push(label_list);
+            collections.push(bucket_collections);
+        }
+
+        // The requests to every bucket are made at once
+        let tuples: Vec<Vec<Option<db::PungTuple>>> = match self.ret_scheme {
+            // Every collection is requested once and in a fixed order (see plan_h8_targets)
+            db::RetScheme::Explicit | db::RetScheme::Bloom => {
+                let label_index = label_index.as_ref().expect("labels were fetched");
+                let mut plans = Vec::with_capacity(self.partitions.num_buckets());
+
+                for (bucket, label_list) in label_lists.iter().enumerate() {
+                    let num = self.buckets[bucket].num_tuples();
+                    let mut targets = Vec::with_capacity(8);
+
+                    for (&(_, ref label), &c_i) in label_list.iter().zip(&collections[bucket]) {
+                        // Get index of tuple in the target collection
+                        let pos = label_index.index_of(bucket, c_i, &label);
+                        let c_num = util::collection_len(num, c_i as u32, 8);
+                        let idx = some_or_random!(pos, rng, c_num);
+
+                        targets.push((c_i, idx));
+                    }
+
+                    plans.push(self.plan_h8_targets(bucket, &targets, &mut rng)?);
                 }
+
+                self.retr_planned(&plans, scope, port)?
+                    .into_iter()
+                    .map(|tuples| tuples.into_iter().map(Some).collect())
+                    .collect()
             }
 
-            len = 2u64.pow(h + 1);
+            // Each label is searched for in the tree of its systematic collection, and the
+            // trees of every bucket are descended together (see bst_coded_retr)
+            db::RetScheme::Tree => {
+                let available: BTreeSet<usize> = (0..27).collect();
+                let mut searches = Vec::with_capacity(self.partitions.num_buckets());
 
-            // if next round is the last
-            if h + 1 == tree_height - 1 {
-                len = num - (len - 1);
+                for (bucket, label_list) in label_lists.iter().enumerate() {
+                    let num = self.buckets[bucket].num_tuples();
+
+                    let part_lens: Vec<u64> =
+                        (0..27).map(|part| util::h8_part_len(num, part)).collect();
+
+                    let assigned = h8_assign(&self.h8_mappings, &collections[bucket], &available)
+                        .expect("batch code covers any 8 collections");
+
+                    // (label, collection, parts) of each label's search
+                    let targets: Vec<(Vec<u8>, usize, Vec<usize>)> = label_list
+                        .iter()
+                        .zip(&collections[bucket])
+                        .zip(assigned)
+                        .map(|((&(_, ref label), &c_i), parts)| {
+                            (label.clone(), c_i, parts.iter().cloned().collect())
+                        })
+                        .collect();
+
+                    searches.push((bucket, part_lens, targets));
+                }
+
+                self.bst_coded_retr(&searches, &mut rng, scope, port)?
+            }
+        };
+
+        for (label_list, tuples) in label_lists.iter().zip(tuples) {
+            for (&(peer, ref label), tuple) in label_list.iter().zip(tuples) {
+                let tuple = match tuple {
+                    Some(tuple) => tuple,
+                    None => continue,
+                };
+
+                if tuple.label() != &label[..] {
+                    continue;
+                }
+
+                // decrypt using shared key and insert into message list
+                let m = pcrypto::decrypt(
+                    peer.keys.aead,
+                    &peer.keys.k_e[..],
+                    self.round,
+                    peer.uid_self,
+                    self.msg_index(&label[..]),
+                    tuple.cipher(),
+                    tuple.mac()
+                )?;
+                on_message(&label[..], m);
             }
+        }
+
+        Ok(())
+    }
+
+    // Like plan_h4_targets, but for (up to 8) collections of a hybrid 8 bucket, whose 27 parts
+    // are requested in order. The mappings are picked with h8_assign, since taking the first
+    // available mapping of each target in turn could leave a later one without any.
+    fn plan_h8_targets(
+        &self,
+        bucket: usize,
+        targets: &[(usize, u64)],
+        rng: &mut rand::ChaChaRng,
+    ) -> Result<PartPlan, Error> {
+        let num = self.buckets[bucket].num_tuples();
+
+        let collections: Vec<usize> = targets.iter().map(|&(c_i, _)| c_i).collect();
+        let available: BTreeSet<usize> = (0..27).collect();
+        let target_parts = h8_assign(&self.h8_mappings, &collections, &available)
+            .expect("batch code covers any 8 collections");
+
+        // Index requested from each part (if any target needs it)
+        let mut plan = PartPlan::new(27);
+        let mut requested: Vec<Option<u64>> = vec![None; 27];
 
-            if idx >= len || result.is_some() {
-                idx = rng.next_u64() % len;
+        for (&(_, idx), parts) in targets.iter().zip(&target_parts) {
+            for &part in *parts {
+                requested[part] = Some(idx);
             }
+
+            plan.target_parts.push(parts.iter().cloned().collect());
+        }
+
+        for part in 0..27 {
+            let len = util::h8_part_len(num, part);
+
+            // Parts that no target needs are fetched at a random index (if they have any)
+            let idx = match requested[part] {
+                Some(idx) => idx,
+                None if len > 0 => rng.next_u64() % len,
+                None => 0,
+            };
+
+            plan.add_part(bucket, part, idx, len, rng)?;
         }
 
-        Ok(result)
+        Ok(plan)
+    }
+
+    // Retrieves a tuple from collection 0 of a bucket along with the label mapping
+    fn pir_retr_mapping(
+        &self,
+        bucket: usize,
+        idx: u64,
+        len: u64,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(db::PungTuple, index::LabelIndex), Error> {
+        let (tuple, response) =
+            self.pir_retr_with(bucket, 0, 0, idx, len, Piggyback::Mapping, scope, port)?;
+
+        let reader = response.get()?;
+
+        let labels = if reader.has_labels() {
+            Some(reader.get_labels()?)
+        } else {
+            None
+        };
+
+        let packed = if reader.has_packed() {
+            Some(reader.get_packed()?)
+        } else {
+            None
+        };
+
+        let label_index = self.decode_labels(labels, packed)?;
+
+        Ok((tuple, label_index))
     }
 
+    // Retrieves a tuple from collection 0 of a bucket along with the bloom filters
+    fn pir_retr_bloom(
+        &self,
+        bucket: usize,
+        idx: u64,
+        len: u64,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(db::PungTuple, index::LabelIndex), Error> {
+        let (tuple, response) =
+            self.pir_retr_with(bucket, 0, 0, idx, len, Piggyback::Bloom, scope, port)?;
+
+        let reader = response.get()?;
+
+        let blooms = if reader.has_blooms() {
+            Some(reader.get_blooms()?)
+        } else {
+            None
+        };
 
-    fn bst_joint_retr(
+        let bloom_index = self.decode_blooms(blooms)?;
+
+        Ok((tuple, bloom_index))
+    }
+
+    // Retrieves a tuple from the server given a bucket, collection, level, and index, and asks
+    // the server to piggyback filters on the answer. Returns the server's response from which
+    // the filters can be read.
+    fn pir_retr_with(
         &self,
-        label: &[u8],
         bucket: usize,
         collection: u32,
-        num: u64,
-        num2: u64, // num of items in collection 2
-        rng: &mut rand::ChaChaRng,
+        level: u32,
+        idx: u64,
+        len: u64,
+        piggyback: Piggyback,
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
-    ) -> Result<Option<db::PungTuple>, Error> {
-        assert!(num2 == num || num2 == num + 1);
+    ) -> Result<(db::PungTuple, capnp::capability::Response<pung_rpc::retr_results::Owned>), Error> {
+        let request = self.pir_request(bucket, collection, level, idx, len, piggyback);
 
-        let tree_height = util::tree_height(num);
-        let tree_height2 = util::tree_height(num2);
+        // Send request to the server and get response
+        let response = request.wait(scope, port)?;
+        let tuple = self.pir_answer(&response, idx, len)?;
 
-        let mut idx = 0; // first index is 0
-        let mut len = 1; // first level has 1 entry
-        let mut result: Option<db::PungTuple> = None;
+        Ok((tuple, response))
+    }
 
-        // Request level by level
-        for h in 0..tree_height - 1 {
-            let t1 = self.pir_retr(bucket, collection, h, idx, len, scope, port)?;
-            let t2 = self.pir_retr(bucket, 2, h, idx, len, scope, port)?;
-
-            let tuple = &t1 ^ &t2;
-
-            if result.is_none() {
-                if tuple.gt(label) {
-                    // if L* < L
-                    idx *= 2;
-                } else if tuple.lt(label) {
-                    // if L* > L
-                    idx = (2 * idx) + 1;
-                } else {
-                    result = Some(tuple);
-                }
+    // Retrieves a tuple with each of the given (bucket, collection, level, idx, len) PIR
+    // requests, which are all in flight at once (and spread over the workers, if any)
+    fn pir_retr_many(
+        &self,
+        requests: &[(usize, u32, u32, u64, u64)],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<db::PungTuple>, Error> {
+        let promises: Vec<_> = requests
+            .iter()
+            .map(|&(bucket, collection, level, idx, len)| {
+                self.pir_request(bucket, collection, level, idx, len, Piggyback::None)
+            })
+            .collect();
+
+        let responses = gj::Promise::all(promises.into_iter()).wait(scope, port)?;
+
+        requests
+            .iter()
+            .zip(responses.iter())
+            .map(|(&(_, _, _, idx, len), response)| self.pir_answer(response, idx, len))
+            .collect()
+    }
+
+    // Connection and id with which to retrieve from a bucket (see ClientConfig::workers)
+    fn worker_for(&self, bucket: usize) -> (&pung_rpc::Client, u64) {
+        match bucket % (self.helpers.len() + 1) {
+            0 => (&self.conn, self.id),
+            shard => {
+                let (ref conn, id) = self.helpers[shard - 1];
+                (conn, id)
             }
+        }
+    }
+
+    // Sets up the PIR handler for a collection of len tuples (the server derives alpha and
+    // depth the same way)
+    fn pir_params(&self, len: u64) {
+        let alpha = util::pir_alpha(len, self.alpha);
+        let depth = util::get_depth(len, self.pir_handler.depth());
+        self.pir_handler
+            .update_params_with_depth(db::TUPLE_SIZE as u64, len, alpha, depth);
+    }
 
-            len = 2u64.pow(h + 1);
+    // Sends a PIR request for tuple idx of a level of a collection with len tuples
+    fn pir_request(
+        &self,
+        bucket: usize,
+        collection: u32,
+        level: u32,
+        idx: u64,
+        len: u64,
+        piggyback: Piggyback,
+    ) -> gj::Promise<capnp::capability::Response<pung_rpc::retr_results::Owned>, Error> {
+        self.pir_params(len);
+
+        // Create PIR request
+        let query = match self.pir_handler.gen_query(idx) {
+            Ok(query) => query,
+            Err(e) => return gj::Promise::err(Error::failed(e.to_string())),
+        };
+        let (conn, id) = self.worker_for(bucket);
+
+        let mut request = conn.retr_request();
+        request.get().set_id(id);
+        request.get().set_round(self.round);
+        request.get().set_bucket(bucket as u32);
+        request.get().set_collection(collection);
+        request.get().set_level(level);
+        request.get().set_query(query.query);
+        request.get().set_qnum(query.num);
+        request.get().set_piggyback(piggyback);
+        request.get().set_verify(self.verify_answers);
+
+        self.queries.borrow_mut().push((bucket, collection, level, len));
+
+        self.metrics.record_upload(metrics::RpcKind::Pir, 32 + query.query.len());
+
+        request.send().promise
+    }
+
+    // Decodes the answer to a PIR request (see pir_request) into the tuple
+    fn pir_answer(
+        &self,
+        response: &capnp::capability::Response<pung_rpc::retr_results::Owned>,
+        idx: u64,
+        len: u64,
+    ) -> Result<db::PungTuple, Error> {
+        let reader = response.get()?;
+        self.pir_decode(reader.get_answer()?, reader.get_anum(), reader.get_checksums()?, idx, len)
+    }
+
+    // Decodes a PIR answer (as sent, i.e., compressed if negotiated) with a_num ciphertexts
+    // for tuple idx of a level with len tuples. checksums are only read if verifying answers.
+    fn pir_decode(
+        &self,
+        wire_answer: &[u8],
+        a_num: u64,
+        checksums: capnp::primitive_list::Reader<u32>,
+        idx: u64,
+        len: u64,
+    ) -> Result<db::PungTuple, Error> {
+        // Other requests may have been made (for other lengths and indices) in the meantime
+        self.pir_params(len);
+
+        let tuple = {
+            // Decompress the answer if needed
+            let decompressed;
+            let answer: &[u8] = if self.compression {
+                decompressed = match util::rle::decompress(wire_answer) {
+                    Ok(v) => v,
+                    Err(e) => return Err(Error::failed(e.to_string())),
+                };
+                &decompressed[..]
+            } else {
+                wire_answer
+            };
 
-            if result.is_some() {
-                idx = rng.next_u64() % len;
+            if answer.len() == 0 || a_num == 0 {
+                return Err(Error::failed("Invalid PIR answer returned.".to_string()));
             }
+
+            // A wrongly-sized answer would decode into a bogus tuple
+            if let Err(e) = self.pir_handler.check_answer(answer, a_num) {
+                return Err(Error::failed(e.to_string()));
+            }
+
+            // Decode answer to get tuple
+            let decoded = match self.pir_handler.decode_answer_for(idx, answer, a_num) {
+                Ok(decoded) => decoded,
+                Err(e) => return Err(Error::failed(e.to_string())),
+            };
+
+            self.metrics
+                .record_download(metrics::RpcKind::Pir, 8 + wire_answer.len(), 8 + answer.len());
+
+            decode_tuple(decoded.result)?
+        };
+
+        if self.verify_answers {
+            let checksums: Vec<u32> = (0..checksums.len()).map(|i| checksums.get(i)).collect();
+            verify_answer(&tuple, &checksums, idx)?;
         }
 
-        // Last level has 3 special cases.
+        Ok(tuple)
+    }
+
+    // Retrieves a tuple with each of the given (collection, level, idx, len) PIR requests to
+    // each bucket, with a single retrBatch request per bucket, all of which are in flight at
+    // once. The requests to a bucket must not depend on each other's answers, e.g., they are
+    // for the same level of different collections. Buckets without requests are skipped.
+    fn pir_retr_batches(
+        &self,
+        batches: &[(usize, Vec<(u32, u32, u64, u64)>)],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<Vec<db::PungTuple>>, Error> {
+        let promises: Vec<_> = batches
+            .iter()
+            .filter(|&&(_, ref requests)| !requests.is_empty())
+            .map(|&(bucket, ref requests)| self.pir_batch_request(bucket, requests))
+            .collect();
+
+        let responses = gj::Promise::all(promises.into_iter()).wait(scope, port)?;
+        let mut responses = responses.iter();
+
+        batches
+            .iter()
+            .map(|&(_, ref requests)| if requests.is_empty() {
+                Ok(Vec::new())
+            } else {
+                let response = responses.next().expect("one response per nonempty batch");
+                self.pir_batch_answers(response, requests)
+            })
+            .collect()
+    }
+
+    // Sends the (collection, level, idx, len) PIR requests to a bucket as one retrBatch request
+    fn pir_batch_request(
+        &self,
+        bucket: usize,
+        requests: &[(u32, u32, u64, u64)],
+    ) -> gj::Promise<capnp::capability::Response<pung_rpc::retr_batch_results::Owned>, Error> {
+        let (conn, id) = self.worker_for(bucket);
+
+        let mut request = conn.retr_batch_request();
+        request.get().set_id(id);
+        request.get().set_round(self.round);
+        request.get().set_bucket(bucket as u32);
+        request.get().set_verify(self.verify_answers);
+
+        {
+            let mut queries = request.get().init_queries(requests.len() as u32);
+
+            for (i, &(collection, level, idx, len)) in requests.iter().enumerate() {
+                self.pir_params(len);
+                let query = match self.pir_handler.gen_query(idx) {
+                    Ok(query) => query,
+                    Err(e) => return gj::Promise::err(Error::failed(e.to_string())),
+                };
 
-        let h = tree_height - 1;
-        len = num - (len - 1);
+                let mut entry = queries.borrow().get(i as u32);
+                entry.set_collection(collection);
+                entry.set_level(level);
+                entry.set_query(query.query);
+                entry.set_qnum(query.num);
 
-        // Case 1 and 2: both collections have the same number of elements on all shared levels
-        if num == num2 || tree_height < tree_height2 {
-            if idx >= len || result.is_some() {
-                idx = rng.next_u64() % len;
+                self.queries.borrow_mut().push((bucket, collection, level, len));
+                self.metrics.record_upload(metrics::RpcKind::Pir, 16 + query.query.len());
             }
+        }
+
+        self.metrics.record_upload(metrics::RpcKind::Pir, 16);
+
+        request.send().promise
+    }
+
+    // Decodes the answers to a retrBatch request (see pir_batch_request) into the tuples
+    fn pir_batch_answers(
+        &self,
+        response: &capnp::capability::Response<pung_rpc::retr_batch_results::Owned>,
+        requests: &[(u32, u32, u64, u64)],
+    ) -> Result<Vec<db::PungTuple>, Error> {
+        let answers = response.get()?.get_answers()?;
+
+        if answers.len() as usize != requests.len() {
+            return Err(Error::failed("Wrong number of PIR answers returned.".to_string()));
+        }
 
-            let t1 = self.pir_retr(bucket, collection, h, idx, len, scope, port)?;
-            let t2 = self.pir_retr(bucket, 2, h, idx, len, scope, port)?;
+        requests
+            .iter()
+            .enumerate()
+            .map(|(i, &(_, _, idx, len))| {
+                let answer = answers.get(i as u32);
+                let checksums = answer.get_checksums()?;
+                self.pir_decode(answer.get_answer()?, answer.get_anum(), checksums, idx, len)
+            })
+            .collect()
+    }
 
-            if result.is_none() {
-                let tuple = &t1 ^ &t2;
+    // Retrieves a tuple using only a label by searching on the server, for each (bucket,
+    // label, num) of `searches`, where num is the number of tuples in the bucket. Each level
+    // is one round trip, since the index requested at a level depends on the answer at the
+    // one above (unlike bst_coded_retr, whose collections' levels are batched), but the
+    // requests of all buckets at a level are in flight at once (see pir_retr_many). Fetching
+    // several levels ahead would take more retrievals than the server expects of the client.
+    fn bst_retr(
+        &self,
+        searches: &[(usize, &[u8], u64)],
+        rng: &mut rand::ChaChaRng,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<Option<db::PungTuple>>, Error> {
+        let heights: Vec<u32> =
+            searches.iter().map(|&(_, _, num)| util::tree_height(num)).collect();
+        let height = heights.iter().cloned().max().unwrap_or(0);
+
+        // (index, length) of the level requested next in each tree. The first level has 1
+        // entry, so the first index is 0.
+        let mut next: Vec<(u64, u64)> = vec![(0, 1); searches.len()];
+        let mut results: Vec<Option<db::PungTuple>> = vec![None; searches.len()];
+
+        // Request level by level
+        for h in 0..height {
+            // Trees that have a level h
+            let active: Vec<usize> = (0..searches.len()).filter(|&i| h < heights[i]).collect();
+
+            let requests: Vec<(usize, u32, u32, u64, u64)> = active
+                .iter()
+                .map(|&i| (searches[i].0, 0, h, next[i].0, next[i].1))
+                .collect();
+
+            let tuples = self.pir_retr_many(&requests, scope, port)?;
+
+            for (&i, tuple) in active.iter().zip(tuples) {
+                let (_, label, num) = searches[i];
+                let mut idx = next[i].0;
+
+                if results[i].is_none() {
+                    if tuple.gt(label) {
+                        // if L* < L
+                        idx *= 2;
+                    } else if tuple.lt(label) {
+                        // if L* > L
+                        idx = (2 * idx) + 1;
+                    } else {
+                        results[i] = Some(tuple);
+                    }
+                }
+
+                let mut len = 2u64.pow(h + 1);
+
+                // if next round is the last
+                if h + 1 == heights[i] - 1 {
+                    len = num - (len - 1);
+                }
 
-                if tuple.label() == label {
-                    result = Some(tuple);
+                if idx >= len || results[i].is_some() {
+                    idx = rng.next_u64() % len;
                 }
+
+                next[i] = (idx, len);
             }
+        }
+
+        Ok(results)
+    }
 
 
-            // Case 2: collection 2 has 1 extra node which causes the tree to have 1 more level
-            if tree_height < tree_height2 {
-                len = num2 - (len - 1);
-                assert_eq!(len, 1);
+    // Searches, for each (bucket, part_lens, targets) of `searches`, for each (label,
+    // collection, parts) of `targets` in the tree of that systematic collection of a hybrid
+    // (2, 4, or 8) bucket, where collection p has `part_lens[p]` tuples. Every collection is a
+    // BST array, and parity collections are the XOR of systematic ones position by position,
+    // so XORing the given parts at a position yields the collection's node there (a part that
+    // is too short to have that position contributes nothing). The trees are descended
+    // together: every level of every collection is requested exactly once, level by level and
+    // in order of collection within a level, at a random index if no search needs it, so that
+    // the requests are the same whichever labels are searched for. A level is requested from
+    // every bucket at once (see pir_retr_batches).
+    fn bst_coded_retr(
+        &self,
+        searches: &[(usize, Vec<u64>, Vec<(Vec<u8>, usize, Vec<usize>)>)],
+        rng: &mut rand::ChaChaRng,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<Vec<Option<db::PungTuple>>>, Error> {
+        // Position (in the BST array) of the next node of each search. A search ends when its
+        // label is found or cannot be in the collection.
+        let mut positions: Vec<Vec<Option<u64>>> = searches
+            .iter()
+            .map(|&(_, ref part_lens, ref targets)| {
+                targets
+                    .iter()
+                    .map(|&(_, c_i, _)| if part_lens[c_i] > 0 { Some(0) } else { None })
+                    .collect()
+            })
+            .collect();
+
+        let mut results: Vec<Vec<Option<db::PungTuple>>> = searches
+            .iter()
+            .map(|&(_, _, ref targets)| vec![None; targets.len()])
+            .collect();
+
+        let height = searches
+            .iter()
+            .flat_map(|&(_, ref part_lens, _)| part_lens.iter())
+            .map(|&len| util::tree_height(len))
+            .max()
+            .unwrap_or(0);
+
+        for h in 0..height {
+            let first = 2u64.pow(h) - 1; // position of the level's first node
+
+            // Index within level h that each part of each bucket is asked for (if any)
+            let mut requested: Vec<Vec<Option<u64>>> = Vec::with_capacity(searches.len());
+
+            // The level of every collection of a bucket is requested in a single round trip
+            let mut batches: Vec<(usize, Vec<(u32, u32, u64, u64)>)> =
+                Vec::with_capacity(searches.len());
+
+            for (&(bucket, ref part_lens, ref targets), search) in searches.iter().zip(&positions) {
+                let mut wanted: Vec<Option<u64>> = vec![None; part_lens.len()];
+
+                for (&(_, _, ref parts), pos) in targets.iter().zip(search) {
+                    if let Some(pos) = *pos {
+                        for &part in parts {
+                            if pos < part_lens[part] {
+                                wanted[part] = Some(pos - first);
+                            }
+                        }
+                    }
+                }
 
-                // This is pretty wasteful :(. Optimization is to just fetch it normally
-                let tuple = self.pir_retr(bucket, 2, h + 1, 0, 1, scope, port)?;
+                let mut batch = Vec::new();
 
-                if result.is_none() && tuple.label() == label {
-                    result = Some(tuple);
+                for (part, &len) in part_lens.iter().enumerate() {
+                    if len <= first {
+                        continue; // this collection's tree has no level h
+                    }
+
+                    let level_len = cmp::min(2u64.pow(h), len - first);
+
+                    let idx = match wanted[part] {
+                        Some(idx) => idx,
+                        None => rng.next_u64() % level_len,
+                    };
+
+                    batch.push((part as u32, h, idx, level_len));
                 }
+
+                requested.push(wanted);
+                batches.push((bucket, batch));
             }
 
-        // Case 3: collections differ in 1 element on a shared level
-        } else {
-            let mut idx2 = idx;
-            let len2 = len + 1;
+            let answers = self.pir_retr_batches(&batches, scope, port)?;
 
-            if idx >= len || result.is_some() {
-                idx = rng.next_u64() % len;
-            }
+            for (s, tuples) in answers.into_iter().enumerate() {
+                let (_, ref part_lens, ref targets) = searches[s];
+                let mut fetched: Vec<Option<db::PungTuple>> = vec![None; part_lens.len()];
 
-            if idx2 >= len2 || result.is_some() {
-                idx2 = rng.next_u64() % len2;
-            }
+                for (&(part, _, _, _), tuple) in batches[s].1.iter().zip(tuples) {
+                    if requested[s][part as usize].is_some() {
+                        fetched[part as usize] = Some(tuple);
+                    }
+                }
 
-            let t1 = self.pir_retr(bucket, collection, h, idx, len, scope, port)?;
-            let t2 = self.pir_retr(bucket, 2, h, idx2, len2, scope, port)?;
+                // Rebuild each search's node at this level and move down its tree
+                for (i, &(ref label, c_i, ref parts)) in targets.iter().enumerate() {
+                    let pos = match positions[s][i] {
+                        Some(pos) => pos,
+                        None => continue,
+                    };
 
-            if result.is_none() {
-                // If the same node was not fetched, then just use t2. Otherwise use combination
-                let tuple = if idx != idx2 { t2 } else { &t1 ^ &t2 };
+                    let mut tuple = db::PungTuple::default();
 
-                if tuple.label() == label {
-                    result = Some(tuple);
+                    for &part in parts {
+                        if let Some(ref t) = fetched[part] {
+                            tuple = &tuple ^ t;
+                        }
+                    }
+
+                    let next = if tuple.gt(&label[..]) {
+                        2 * pos + 1 // left child
+                    } else if tuple.lt(&label[..]) {
+                        2 * pos + 2 // right child
+                    } else {
+                        results[s][i] = Some(tuple);
+                        positions[s][i] = None;
+                        continue;
+                    };
+
+                    positions[s][i] = if next < part_lens[c_i] { Some(next) } else { None };
                 }
             }
         }
 
-        Ok(result)
+        Ok(results)
+    }
+
+    /// Retrieves messages from the given peers that were sent during `round`, which is
+    /// either the current round or one of the past rounds kept in history (see `set_history`).
+    /// The client must have sent during that round, since that is how it learns the layout
+    /// of the server's buckets.
+    pub fn retr_round(
+        &mut self,
+        peer_names: &[&str],
+        round: u64,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<Vec<u8>>, PungError> {
+        if round == self.round {
+            return self.retr(peer_names, scope, port);
+        }
+
+        let pos = match self.history.iter().position(|&(r, _)| r == round) {
+            Some(p) => p,
+            None => return Err(PungError::Failed("Round is not in history".to_string())),
+        };
+
+        // Labels, keys, and requests are all derived from the current round and its buckets,
+        // so temporarily switch to the past round.
+        let current_round = self.round;
+        self.round = round;
+        mem::swap(&mut self.buckets, &mut self.history[pos].1);
+
+        let result = self.retr(peer_names, scope, port);
+
+        mem::swap(&mut self.buckets, &mut self.history[pos].1);
+        self.round = current_round;
+
+        result
     }
 
+    /// Retrieves the messages sent this round by the peers in `peer_names` (one retrieval per
+    /// entry). Messages are returned as the bytes that were sent, padded with zeros to
+    /// `MESSAGE_SIZE`; they are arbitrary bytes, not necessarily text.
     pub fn retr(
         &self,
         peer_names: &[&str],
         scope: &gj::WaitScope,
         port: &mut gjio::EventPort,
+    ) -> Result<Vec<Vec<u8>>, PungError> {
+        let mut messages: Vec<Vec<u8>> = Vec::new();
+        self.retr_stream(peer_names, |m| messages.push(m), scope, port)?;
+
+        Ok(messages)
+    }
+
+    /// Like `retr`, but hands each message to `on_message` as soon as it is decrypted
+    /// instead of returning all of them once the round's retrievals are done. The PIR
+    /// requests issued are exactly the same.
+    pub fn retr_stream<F>(
+        &self,
+        peer_names: &[&str],
+        mut on_message: F,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(), PungError>
+    where
+        F: FnMut(Vec<u8>),
+    {
+        self.retr_labels(peer_names, &mut |_, _, m| on_message(m), scope, port)?;
+        Ok(())
+    }
+
+    /// Like `retr`, but pairs each message with the name of the peer (as given in
+    /// `peer_names`) under whose keys it was decrypted, so that messages from several peers
+    /// can be told apart. Messages come in the same order as from `retr`, and only found
+    /// messages are returned: dummy retrievals and peers that sent nothing yield no entry.
+    pub fn retr_attributed(
+        &self,
+        peer_names: &[&str],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<(String, Vec<u8>)>, PungError> {
+        Ok(self.retr_framed(peer_names, scope, port)?
+            .into_iter()
+            .map(|(peer, _, m)| (peer, m))
+            .collect())
+    }
+
+    // Like retr_attributed, but also returns the frame under which each message was sent
+    // (see seal), for retr_large to tell chunks apart
+    fn retr_framed(
+        &self,
+        peer_names: &[&str],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<(String, u8, Vec<u8>)>, Error> {
+        let mut found: Vec<(Vec<u8>, u8, Vec<u8>)> = Vec::new();
+
+        let labels = self.retr_labels(
+            peer_names,
+            &mut |label, frame, m| found.push((label.to_vec(), frame, m)),
+            scope,
+            port,
+        )?;
+
+        // The k-th requested label is that of the k-th peer name
+        let senders: HashMap<Vec<u8>, &str> =
+            labels.into_iter().zip(peer_names.iter().cloned()).collect();
+
+        Ok(found
+            .into_iter()
+            .filter_map(|(label, frame, m)| {
+                senders.get(&label).map(|peer| (peer.to_string(), frame, m))
+            })
+            .collect())
+    }
+
+    /// Like `retr_attributed`, but retrieves from every peer that was added (see `add_peer`),
+    /// so that the caller need not know in advance who sent something this round. Each peer
+    /// gets one retrieval (the first message it sent this round) and the rest of the
+    /// retrieval rate goes to dummy requests, so there may be at most `ret_rate` peers.
+    pub fn retr_all(
+        &self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<(String, Vec<u8>)>, PungError> {
+        // In order of name, so that which bucket each peer gets does not depend on the map
+        let mut peer_names: Vec<&str> =
+            self.peers.keys().cloned().filter(|&name| name != "dummy").collect();
+        peer_names.sort();
+
+        if peer_names.len() as u32 > self.ret_rate {
+            return Err(PungError::Failed(format!(
+                "{} peers exceed the retrieval rate ({})",
+                peer_names.len(),
+                self.ret_rate
+            )));
+        }
+
+        self.retr_attributed(&peer_names[..], scope, port)
+    }
+
+    /// Like `retr`, but with one outcome per entry of `peer_names`, in the same order (the
+    /// k-th entry for a peer is the k-th message it sent this round). A peer that sent no
+    /// such message yields `RetrOutcome::NotPresent` rather than being left out, so that
+    /// every requested message is accounted for. Failed retrievals are still errors.
+    pub fn retr_outcomes(
+        &self,
+        peer_names: &[&str],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<Vec<RetrOutcome>, PungError> {
+        let mut found: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+
+        let labels = self.retr_labels(
+            peer_names,
+            &mut |label, _, m| {
+                found.insert(label.to_vec(), m);
+            },
+            scope,
+            port,
+        )?;
+
+        let checked = self.checked.borrow();
+
+        Ok(labels
+            .into_iter()
+            .map(|label| match found.remove(&label) {
+                Some(m) => RetrOutcome::Found(m),
+                None if checked.contains(&label) => RetrOutcome::NotPresent,
+                None => RetrOutcome::NotRetrieved,
+            })
+            .collect())
+    }
+
+    /// Like `retr_outcomes`, but also cross-checks the outcomes against the server: before
+    /// retrieving, it asks the server which of the messages are there (see `debug_present`).
+    /// Returns the outcomes along with the indices of `peer_names` whose message is on the
+    /// server but was not retrieved (see `missed_messages`), which should always be empty.
+    /// This is for diagnosing silent retrieval failures only: it reveals every label to the
+    /// server, which must be started with `--debug`.
+    pub fn retr_self_check(
+        &self,
+        peer_names: &[&str],
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<(Vec<RetrOutcome>, Vec<usize>), PungError> {
+        // The server moves on to the next round once everyone has retrieved, so ask first
+        let present = self.debug_present(peer_names, scope, port)?;
+        let outcomes = self.retr_outcomes(peer_names, scope, port)?;
+        let missed = missed_messages(&outcomes, &present);
+
+        Ok((outcomes, missed))
+    }
+
+    // Retrieves the messages of peer_names, handing each to on_message along with the label
+    // under which it was found and the frame under which it was sent (see seal), which is
+    // stripped off the message. Returns the label requested for each peer name.
+    fn retr_labels(
+        &self,
+        peer_names: &[&str],
+        on_message: &mut FnMut(&[u8], u8, Vec<u8>),
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
     ) -> Result<Vec<Vec<u8>>, Error> {
         if peer_names.len() as u32 > self.ret_rate {
-            return Err(Error::failed("Number of peers exceeds rate".to_string()));
+            return Err(ErrorCode::RateExceeded.error("Number of peers exceeds rate"));
         }
 
-        let bucket_map = self.schedule(peer_names)?;
+        let (bucket_map, labels) = self.schedule(peer_names)?;
+        self.queries.borrow_mut().clear();
+        self.checked.borrow_mut().clear();
+
+        let on_message = &mut |label: &[u8], mut m: Vec<u8>| {
+            let frame = m.remove(0);
+            on_message(label, frame, m)
+        };
 
         match self.opt_scheme {
             db::OptScheme::Normal | db::OptScheme::Aliasing => {
-                self.retr_normal(bucket_map, scope, port)
+                self.retr_normal(bucket_map, on_message, scope, port)?
             }
-            db::OptScheme::Hybrid2 => self.retr_hybrid2(bucket_map, scope, port),
-            db::OptScheme::Hybrid4 => self.retr_hybrid4(bucket_map, scope, port),
+            db::OptScheme::Hybrid2 => self.retr_hybrid2(bucket_map, on_message, scope, port)?,
+            db::OptScheme::Hybrid4 => self.retr_hybrid4(bucket_map, on_message, scope, port)?,
+            db::OptScheme::Hybrid8 => self.retr_hybrid8(bucket_map, on_message, scope, port)?,
         }
+
+        Ok(labels)
+    }
+}
+
+/// The outcome of retrieving one message (see `PungClient::retr_outcomes`).
+pub enum RetrOutcome {
+    /// The decrypted message.
+    Found(Vec<u8>),
+
+    /// The peer did not send this message: its label is not in the round's database.
+    NotPresent,
+
+    /// The message could not be looked for this round, e.g., because more messages mapped to
+    /// its bucket than there are retrievals per bucket. Retrieving it again may find it.
+    NotRetrieved,
+}
+
+
+/// Returns the indices of the messages that are on the server (as given by
+/// `PungClient::debug_present`) but whose retrieval did not find them, i.e., silent
+/// retrieval failures.
+pub fn missed_messages(outcomes: &[RetrOutcome], present: &[bool]) -> Vec<usize> {
+    outcomes
+        .iter()
+        .zip(present)
+        .enumerate()
+        .filter(|&(_, (outcome, &on_server))| match *outcome {
+            RetrOutcome::Found(_) => false,
+            _ => on_server,
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Returns whether an error returned by a `PungClient` means that the connection to the
+/// server is gone (e.g., the server shut down or closed it), as opposed to a failed request.
+/// This goes by the error's kind only: a connection lost in the middle of a response fails
+/// that request as any I/O error would, and the following requests as disconnected.
+/// A client cannot be used after it has been disconnected.
+pub fn is_disconnected(error: &PungError) -> bool {
+    match *error {
+        PungError::Transport(ref e) => e.kind == capnp::ErrorKind::Disconnected,
+        _ => false,
+    }
+}
+
+/// Returns whether an error returned by a `PungClient` means that the server turned the
+/// request away because the client made too many requests lately (see
+/// `ServerConfig::rpc_limit`). The same request can be made again after a while.
+pub fn is_overloaded(error: &PungError) -> bool {
+    match *error {
+        PungError::Transport(ref e) => e.kind == capnp::ErrorKind::Overloaded,
+        _ => false,
+    }
+}
+
+/// Turns the result of decoding a PIR answer into a tuple. A result that is not exactly one
+/// tuple long (e.g., the client and server disagree on the PIR parameters) is an error
+/// rather than a panic.
+pub fn decode_tuple(result: &[u8]) -> Result<db::PungTuple, PungError> {
+    if result.len() != db::TUPLE_SIZE {
+        Err(PungError::Failed(format!(
+            "Invalid PIR result: decoded {} bytes instead of a {}-byte tuple",
+            result.len(),
+            db::TUPLE_SIZE
+        )))
+    } else {
+        Ok(db::PungTuple::new(result))
+    }
+}
+
+/// Checks a tuple decoded from a PIR answer for index `idx` against the checksums of the
+/// queried tuples (see `set_verify_answers`). A tuple that passes is genuine, so if its label
+/// is not the one sought then the message is simply not there.
+pub fn verify_answer(
+    tuple: &db::PungTuple,
+    checksums: &[u32],
+    idx: u64,
+) -> Result<(), PungError> {
+    if idx >= checksums.len() as u64 {
+        Err(PungError::Corrupted(format!(
+            "Corrupted PIR answer: no checksum for index {} ({} tuples)",
+            idx,
+            checksums.len()
+        )))
+    } else if tuple.checksum() != checksums[idx as usize] {
+        Err(PungError::Corrupted(format!(
+            "Corrupted PIR answer: checksum mismatch at index {}",
+            idx
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns whether an error returned by a `PungClient` means that a PIR answer did not decode
+/// into the requested tuple (only detected when answers are verified).
+pub fn is_corrupted(error: &PungError) -> bool {
+    match *error {
+        PungError::Corrupted(_) => true,
+        _ => false,
+    }
+}
+
+/// Returns whether an error returned by a `PungClient` means that the server's answer does
+/// not fit the client's schemes (e.g., the client and server use different OptSchemes).
+pub fn is_scheme_mismatch(error: &PungError) -> bool {
+    match *error {
+        PungError::SchemeMismatch(_) => true,
+        _ => false,
+    }
+}
+
+// Connects to the server (or one of its workers) at `address` (host:port)
+fn connect(
+    address: &str,
+    scope: &gj::WaitScope,
+    port: &mut gjio::EventPort,
+) -> Result<pung_rpc::Client, PungError> {
+    let addr = match address.to_socket_addrs() {
+        Ok(mut v) => match v.next() {
+            Some(a) => a,
+            None => return Err(PungError::Failed("Address iterator is empty".to_string())),
+        },
+
+        Err(e) => return Err(PungError::Failed(format!("Error parsing address: {:?}", e))),
+    };
+
+    let network = port.get_network();
+
+    let address = network.get_tcp_address(addr);
+    let stream = address.connect().wait(scope, port)?;
+
+    let mut reader_options: capnp::message::ReaderOptions = Default::default();
+    reader_options.traversal_limit_in_words(300 * 1024 * 1024);
+
+    let network = Box::new(twoparty::VatNetwork::new(
+        stream.clone(),
+        stream,
+        rpc_twoparty_capnp::Side::Client,
+        Default::default(),
+    ));
+
+    // Initialize RPC client
+    let mut rpc_system = RpcSystem::new(network, None);
+    Ok(rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server))
+}
+
+// Returns in which of the systematic collections (0 to 3) of a hybrid 4 bucket `label` falls,
+// given the lowest label of collections 1 to 3 (see `Bucket::mid_labels`). The same goes for
+// the collections 0 to 7 of a hybrid 8 bucket, given the lowest label of collections 1 to 7.
+fn h4_collection(lmids: &[Vec<u8>], label: &[u8]) -> usize {
+    for (i, lmid) in lmids.iter().enumerate() {
+        if util::label_cmp(label, &lmid[..]) == Ordering::Less {
+            return i;
+        }
+    }
+
+    lmids.len() // last collection
+}
+
+// Picks, for each of the (at most 8) systematic collections in `targets`, one of its mappings
+// such that no two of the picked mappings share a part. Trying the mappings of each target in
+// order and backtracking when a later target has none left always succeeds, as the hybrid 8
+// code can serve any 8 collections (possibly the same one several times).
+fn h8_assign<'m>(
+    mappings: &'m HashMap<usize, Vec<BTreeSet<usize>>>,
+    targets: &[usize],
+    available: &BTreeSet<usize>,
+) -> Option<Vec<&'m BTreeSet<usize>>> {
+    let (&c_i, rest) = match targets.split_first() {
+        Some(split) => split,
+        None => return Some(Vec::new()),
+    };
+
+    for parts in &mappings[&c_i] {
+        if !available.is_superset(parts) {
+            continue;
+        }
+
+        let remaining: BTreeSet<usize> = available.difference(parts).cloned().collect();
+
+        if let Some(mut assigned) = h8_assign(mappings, rest, &remaining) {
+            assigned.insert(0, parts);
+            return Some(assigned);
+        }
+    }
+
+    None
+}
+
+// Checks that the server returned as many filters (label lists or bloom filters), bucket
+// counts, or min labels as the client's schemes (and rates) expect. Servers running another
+// OptScheme return a different number.
+fn check_list_count(len: u32, expected: u32, kind: &str) -> Result<(), Error> {
+    if len != expected {
+        Err(ErrorCode::SchemeMismatch.error(format!(
+            "Scheme mismatch: server returned {} {}s but {} were expected",
+            len, kind, expected
+        )))
+    } else {
+        Ok(())
     }
 }
@@ -6,15 +6,24 @@ use byteorder::{BigEndian, WriteBytesExt};
 use capnp::Error;
 
 use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::blake2b::Blake2b;
+use crypto::blake2s::Blake2s;
 use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
 use crypto::digest::Digest;
 use crypto::hkdf;
 use crypto::hmac;
-use crypto::mac::Mac;
+use crypto::mac::{Mac, MacResult};
 use crypto::sha2::Sha256;
 
 use db;
 
+use proto::DH_KEY_SIZE;
+
+use rand::Rng;
+
 use std::io::Cursor;
 use std::iter::repeat;
 use std::mem;
@@ -34,6 +43,105 @@ macro_rules! create_nonce {
     };
 }
 
+/// AEAD used to encrypt messages. Both use 256-bit keys and produce a 128-bit tag
+/// (`db::MAC_SIZE`) and a ciphertext as long as the padded message (`db::CIPHER_SIZE`),
+/// so tuples have the same layout either way. Peers must use the same algorithm.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AeadAlgorithm {
+    ChaCha20Poly1305,
+    AesGcm, // faster on hardware with AES-NI
+}
+
+impl Default for AeadAlgorithm {
+    fn default() -> AeadAlgorithm {
+        AeadAlgorithm::ChaCha20Poly1305
+    }
+}
+
+impl AeadAlgorithm {
+    // Nonce of message number `idx` sent under `uid` during `round`. Keys are shared by both
+    // peers and used for every message between them, so nonces are made of all three (as
+    // labels are) and no two messages are encrypted under the same one. Uids are 0 or 1 and
+    // a client sends fewer than 2^16 messages to a peer in a round, so both take 16 bits.
+    // That leaves AES-GCM's 96-bit nonces 64 bits for the round, and ChaCha20Poly1305's
+    // 64-bit nonces 32 bits (i.e., 2^32 rounds).
+    fn nonce(&self, round: u64, uid: u64, idx: u64) -> Vec<u8> {
+        assert!(uid <= u16::max_value() as u64 && idx <= u16::max_value() as u64);
+        let (uid, idx) = (uid as u16, idx as u16);
+
+        match *self {
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                assert!(round <= u32::max_value() as u64);
+                let round = round as u32;
+                create_nonce!(round, uid, idx)
+            }
+            AeadAlgorithm::AesGcm => create_nonce!(round, uid, idx),
+        }
+    }
+
+    // Sets up the cryptosystem for the given key and message (see nonce)
+    fn encryptor(&self, key: &[u8], round: u64, uid: u64, idx: u64) -> Box<AeadEncryptor> {
+        let nonce = self.nonce(round, uid, idx);
+
+        match *self {
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                Box::new(ChaCha20Poly1305::new(key, &nonce[..], &[0; 0]))
+            }
+            AeadAlgorithm::AesGcm => {
+                Box::new(AesGcm::new(KeySize::KeySize256, key, &nonce[..], &[0; 0]))
+            }
+        }
+    }
+
+    // Like encryptor, but for decryption
+    fn decryptor(&self, key: &[u8], round: u64, uid: u64, idx: u64) -> Box<AeadDecryptor> {
+        let nonce = self.nonce(round, uid, idx);
+
+        match *self {
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                Box::new(ChaCha20Poly1305::new(key, &nonce[..], &[0; 0]))
+            }
+            AeadAlgorithm::AesGcm => {
+                Box::new(AesGcm::new(KeySize::KeySize256, key, &nonce[..], &[0; 0]))
+            }
+        }
+    }
+}
+
+/// PRF from which labels are derived. All of them are keyed with 256-bit keys, and their
+/// output must be exactly `db::LABEL_SIZE` bytes long. Peers must use the same PRF.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LabelPrf {
+    HmacSha256,
+    Blake2b, // keyed BLAKE2b, faster than HMAC on 64-bit platforms
+    Blake2s, // keyed BLAKE2s, faster than HMAC on 32-bit platforms
+}
+
+impl Default for LabelPrf {
+    fn default() -> LabelPrf {
+        LabelPrf::HmacSha256
+    }
+}
+
+impl LabelPrf {
+    /// Returns the length (in bytes) of the labels derived with this PRF.
+    pub fn output_bytes(&self) -> usize {
+        match *self {
+            LabelPrf::HmacSha256 => Sha256::new().output_bytes(),
+            LabelPrf::Blake2b | LabelPrf::Blake2s => db::LABEL_SIZE,
+        }
+    }
+
+    // Sets up the PRF for the given key
+    fn prf(&self, key: &[u8]) -> Box<Mac> {
+        match *self {
+            LabelPrf::HmacSha256 => Box::new(hmac::Hmac::new(Sha256::new(), key)),
+            LabelPrf::Blake2b => Box::new(Blake2b::new_keyed(db::LABEL_SIZE, key)),
+            LabelPrf::Blake2s => Box::new(Blake2s::new_keyed(db::LABEL_SIZE, key)),
+        }
+    }
+}
+
 /// Cryptographic keys
 pub struct PungKeys {
     /// Key 1 used for label generation
@@ -44,11 +152,18 @@ pub struct PungKeys {
 
     /// Key used for message encryption
     pub k_e: Vec<u8>,
+
+    /// Key used to authenticate client-side framing that lives outside the AEAD
+    pub k_mac: Vec<u8>,
+
+    /// AEAD with which `k_e` is used
+    pub aead: AeadAlgorithm,
 }
 
 /// Derives a pair of keys from a given secret. This function ensures the secret's randomness
-/// is uniformly distributed prior to generating the keys.
-pub fn derive_keys(secret: &[u8]) -> PungKeys {
+/// is uniformly distributed prior to generating the keys. The encryption key is meant to be
+/// used with `aead`.
+pub fn derive_keys(secret: &[u8], aead: AeadAlgorithm) -> PungKeys {
     let digest = Sha256::new();
     let len = digest.output_bytes();
 
@@ -59,18 +174,20 @@ pub fn derive_keys(secret: &[u8]) -> PungKeys {
     // an attacker may have partial information (e.g., knows the j bit is 1).
     hkdf::hkdf_extract(digest, &[0; 0], secret, &mut prk[..]);
 
-    // Serves as a contiguous buffer for all 3 keys
-    let mut okm: Vec<u8> = repeat(0).take(len * 3).collect();
+    // Serves as a contiguous buffer for all 4 keys
+    let mut okm: Vec<u8> = repeat(0).take(len * 4).collect();
 
     // Fills in the buffer with cryptographic key material
     hkdf::hkdf_expand(Sha256::new(), &prk[..], &[0; 0], &mut okm[..]);
 
-    // Splits the buffer into the three keys.
+    // Splits the buffer into the four keys.
     let mut k_l = okm.split_off(len);
-    let k_l2 = k_l.split_off(len);
+    let mut k_l2 = k_l.split_off(len);
+    let k_mac = k_l2.split_off(len);
 
     assert_eq!(k_l.len(), len);
     assert_eq!(k_l2.len(), len);
+    assert_eq!(k_mac.len(), len);
     assert_eq!(okm.len(), len);
 
     // Key lenghts are 256-bits each.
@@ -78,14 +195,29 @@ pub fn derive_keys(secret: &[u8]) -> PungKeys {
         k_l: k_l,
         k_l2: k_l2,
         k_e: okm,
+        k_mac: k_mac,
+        aead: aead,
     }
 }
 
 /// Generates a Pung label from a round and a uid using a PRF keyed with
 /// the label key.
 pub fn gen_label(key: &[u8], round: u64, uid: u64, msg_num: u64, iter: u64) -> Vec<u8> {
+    gen_label_with(LabelPrf::HmacSha256, key, round, uid, msg_num, iter)
+}
+
+/// Like `gen_label`, but with the given PRF instead of HMAC-SHA256.
+pub fn gen_label_with(
+    label_prf: LabelPrf,
+    key: &[u8],
+    round: u64,
+    uid: u64,
+    msg_num: u64,
+    iter: u64,
+) -> Vec<u8> {
     // Create PRF instance
-    let mut prf = hmac::Hmac::new(Sha256::new(), &key[..]);
+    let mut prf = label_prf.prf(&key[..]);
+    assert_eq!(prf.output_bytes(), db::LABEL_SIZE);
 
     let input: Vec<u8> = create_nonce!(round, uid, msg_num, iter);
 
@@ -99,36 +231,107 @@ pub fn gen_label(key: &[u8], round: u64, uid: u64, msg_num: u64, iter: u64) -> V
     output
 }
 
-/// Encrypts a message under the given round with the encryption key.
-pub fn encrypt(key: &[u8], round: u64, message: &[u8]) -> (Vec<u8>, Vec<u8>) {
-    assert!(message.len() <= MESSAGE_SIZE);
-
-    let nonce: Vec<u8> = create_nonce!(round);
+/// Computes an HMAC-SHA256 tag over `data`. This is meant for authenticating
+/// client-side framing that is not covered by the AEAD (it is unused by default).
+pub fn mac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = hmac::Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result().code().to_vec()
+}
 
-    // Sets up cryptosystem for the current round
-    let mut ae = ChaCha20Poly1305::new(key, &nonce[..], &[0; 0]);
+/// Verifies an HMAC-SHA256 tag produced by `mac`. The comparison is constant time.
+pub fn verify_mac(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    let mut hmac = hmac::Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result() == MacResult::new(tag)
+}
 
-    // Performs the encryption
+/// Encrypts message number `idx` sent under `uid` during `round` (the same `uid` and
+/// `idx` from which its label is derived, see `gen_label`) with the encryption key.
+pub fn encrypt(
+    aead: AeadAlgorithm,
+    key: &[u8],
+    round: u64,
+    uid: u64,
+    idx: u64,
+    message: &[u8],
+) -> (Vec<u8>, Vec<u8>) {
     let mut c: Vec<u8> = repeat(0).take(MESSAGE_SIZE).collect();
-    let mut mac: Vec<u8> = repeat(0).take(16).collect(); // 128-bit tag
+    let mut mac: Vec<u8> = repeat(0).take(db::MAC_SIZE).collect(); // 128-bit tag
 
-    // Pad message
-    let mut padded_message: Vec<u8> = repeat(0).take(MESSAGE_SIZE).collect();
-    padded_message[0..message.len()].clone_from_slice(message);
-
-    ae.encrypt(&padded_message[..], &mut c[..], &mut mac[..]);
+    Encryptor::new().encrypt_into(aead, key, round, uid, idx, message, &mut c[..], &mut mac[..]);
 
     (c, mac)
 }
 
+/// Encrypts messages like `encrypt`, but into buffers provided by the caller and with a
+/// padding buffer that is reused across calls. This avoids allocating for every message
+/// when encrypting a batch of them (e.g., a client's messages for a round).
+pub struct Encryptor {
+    padded_message: Vec<u8>,
+}
+
+impl Encryptor {
+    pub fn new() -> Encryptor {
+        Encryptor {
+            padded_message: repeat(0).take(MESSAGE_SIZE).collect(),
+        }
+    }
+
+    /// Encrypts a message like `encrypt`. The ciphertext is written to `c` (which must be
+    /// `MESSAGE_SIZE` bytes long) and the tag to `mac` (which must be `db::MAC_SIZE` bytes
+    /// long).
+    pub fn encrypt_into(
+        &mut self,
+        aead: AeadAlgorithm,
+        key: &[u8],
+        round: u64,
+        uid: u64,
+        idx: u64,
+        message: &[u8],
+        c: &mut [u8],
+        mac: &mut [u8],
+    ) {
+        assert!(message.len() <= MESSAGE_SIZE);
+        assert_eq!(c.len(), MESSAGE_SIZE);
+        assert_eq!(mac.len(), db::MAC_SIZE);
+
+        // Sets up cryptosystem for this message
+        let mut ae = aead.encryptor(key, round, uid, idx);
+
+        // Pad message (clearing whatever a previous message left behind)
+        self.padded_message[0..message.len()].clone_from_slice(message);
+
+        for b in &mut self.padded_message[message.len()..] {
+            *b = 0;
+        }
+
+        // Performs the encryption
+        ae.encrypt(&self.padded_message[..], c, mac);
+    }
+}
+
+impl Default for Encryptor {
+    fn default() -> Encryptor {
+        Encryptor::new()
+    }
+}
+
 /// Decrypts and verifies the authenticity of a ciphertext and returns
-/// the corresponding message or an error.
-pub fn decrypt(key: &[u8], round: u64, c: &[u8], mac: &[u8]) -> Result<Vec<u8>, Error> {
+/// the corresponding message or an error. `round`, `uid`, and `idx` must be those with which
+/// the message was encrypted (see `encrypt`).
+pub fn decrypt(
+    aead: AeadAlgorithm,
+    key: &[u8],
+    round: u64,
+    uid: u64,
+    idx: u64,
+    c: &[u8],
+    mac: &[u8],
+) -> Result<Vec<u8>, Error> {
     assert_eq!(c.len(), MESSAGE_SIZE);
 
-    let nonce: Vec<u8> = create_nonce!(round);
-
-    let mut ae = ChaCha20Poly1305::new(key, &nonce[..], &[0; 0]);
+    let mut ae = aead.decryptor(key, round, uid, idx);
 
     // Performs the decryption
     let mut msg: Vec<u8> = repeat(0).take(c.len()).collect();
@@ -141,3 +344,59 @@ pub fn decrypt(key: &[u8], round: u64, c: &[u8], mac: &[u8]) -> Result<Vec<u8>,
         Ok(msg)
     }
 }
+
+/// X25519 key pair with which a client agrees on secrets with its peers (see `dh_agree`).
+/// The public half is what the client uploads to the server's key directory.
+pub struct DhKeyPair {
+    secret: [u8; DH_KEY_SIZE],
+    public: [u8; DH_KEY_SIZE],
+}
+
+impl DhKeyPair {
+    /// Generates a fresh key pair. `rng` must be cryptographically secure.
+    pub fn generate<R: Rng>(rng: &mut R) -> DhKeyPair {
+        let mut secret = [0u8; DH_KEY_SIZE];
+        rng.fill_bytes(&mut secret);
+
+        DhKeyPair {
+            secret: secret,
+            public: curve25519_base(&secret),
+        }
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        &self.public
+    }
+}
+
+/// Agrees on a secret with the owner of `peer_public`, who gets the same secret by calling
+/// this with its own key pair and our public key. The secret is meant to be passed to
+/// `derive_keys` (which extracts its randomness), so it is the X25519 output followed by
+/// both public keys (in sorted order, so that both peers list them alike), which binds it
+/// to the keys it was agreed with. Fails if `peer_public` is not a valid public key, or is
+/// one (e.g., of small order) for which the X25519 output is all zeros.
+pub fn dh_agree(own: &DhKeyPair, peer_public: &[u8]) -> Result<Vec<u8>, Error> {
+    if peer_public.len() != DH_KEY_SIZE {
+        return Err(Error::failed("Invalid public key length".to_string()));
+    }
+
+    let shared = curve25519(&own.secret, peer_public);
+
+    // Checks for all zeros in constant time
+    if shared.iter().fold(0u8, |acc, b| acc | b) == 0 {
+        return Err(Error::failed("Invalid public key (small order)".to_string()));
+    }
+
+    let (lo, hi) = if &own.public[..] < peer_public {
+        (&own.public[..], peer_public)
+    } else {
+        (peer_public, &own.public[..])
+    };
+
+    let mut secret = Vec::with_capacity(DH_KEY_SIZE * 3);
+    secret.extend_from_slice(&shared);
+    secret.extend_from_slice(lo);
+    secret.extend_from_slice(hi);
+
+    Ok(secret)
+}
@@ -0,0 +1,114 @@
+// Sends whose messages are added one at a time (see PungClient::send_session).
+
+use gj;
+use gjio;
+use std::collections::HashMap;
+
+use super::pcrypto;
+use super::{PungClient, PungError, FRAME_CHUNK, FRAME_MESSAGE};
+
+/// A send request to which messages are added one at a time, e.g., as they are generated.
+/// Each message is encrypted into its tuple as soon as it is added, so that generating the
+/// next messages overlaps with encrypting the previous ones. The request is only built by
+/// `finish`: Cap'n Proto lists are allocated with their final length, which is not known
+/// until then, so the sealed tuples are kept until `finish` copies them into the request.
+/// Dropping a session without finishing it sends nothing.
+pub struct SendSession<'c, 'a: 'c> {
+    client: &'c mut PungClient<'a>,
+    sealed: Vec<(Vec<u8>, u32)>,  // (tuple, ttl) of each message added so far
+    counts: HashMap<String, u64>, // messages per recipient
+    encryptor: pcrypto::Encryptor,
+}
+
+impl<'a> PungClient<'a> {
+    /// Starts a send whose messages are added with `SendSession::add`. Finishing it sends
+    /// exactly what `send` (or `round_trip`) would send for the same messages, in the order
+    /// they were added.
+    pub fn send_session<'c>(&'c mut self) -> SendSession<'c, 'a> {
+        SendSession {
+            client: self,
+            sealed: Vec::new(),
+            counts: HashMap::new(),
+            encryptor: pcrypto::Encryptor::new(),
+        }
+    }
+}
+
+impl<'c, 'a> SendSession<'c, 'a> {
+    /// Encrypts a message to `peer` and adds it to the request.
+    pub fn add(&mut self, peer: &str, msg: &[u8]) -> Result<(), PungError> {
+        self.add_with_ttl(peer, msg, 0)
+    }
+
+    /// Like `add`, but the message is evicted `ttl` rounds (including this one) after it
+    /// is sent, if the server retains rounds (see `send_with_ttl`).
+    pub fn add_with_ttl(
+        &mut self,
+        peer: &str,
+        msg: &[u8],
+        ttl: u32,
+    ) -> Result<(), PungError> {
+        self.add_framed(peer, FRAME_MESSAGE, msg, ttl)
+    }
+
+    /// Adds a chunk of a large message (see `chunk::split`) to `peer`, who gets it back
+    /// through `retr_large`. Unlike `send_large`, this lets the chunks of a message be spread
+    /// over several rounds and share a request with other messages.
+    pub fn add_chunk(&mut self, peer: &str, chunk: &[u8]) -> Result<(), PungError> {
+        self.add_framed(peer, FRAME_CHUNK, chunk, 0)
+    }
+
+    fn add_framed(
+        &mut self,
+        peer: &str,
+        frame: u8,
+        msg: &[u8],
+        ttl: u32,
+    ) -> Result<(), PungError> {
+        if !self.client.peers.contains_key(peer) {
+            return Err(PungError::InvalidPeer("Invalid recipient name".to_string()));
+        }
+
+        if let Some((cover_send_rate, _)) = self.client.cover_rates {
+            if self.sealed.len() >= cover_send_rate as usize {
+                return Err(PungError::Failed(
+                    "More messages than the cover send rate".to_string(),
+                ));
+            }
+        }
+
+        // Messages to each recipient are numbered from 0, as in PungClient::send_tuples
+        let idx = self.counts.get(peer).cloned().unwrap_or(0);
+
+        let tuple = self.client
+            .seal(&self.client.peers[peer], idx, frame, msg, &mut self.encryptor)?;
+
+        self.sealed.push((tuple, ttl));
+        self.counts.insert(peer.to_string(), idx + 1);
+
+        Ok(())
+    }
+
+    /// Returns the number of messages added so far.
+    pub fn len(&self) -> usize {
+        self.sealed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sealed.is_empty()
+    }
+
+    /// Sends the messages added so far (see `PungClient::send`). As with `send`, there must
+    /// be at least one unless cover traffic is on.
+    pub fn finish(
+        self,
+        scope: &gj::WaitScope,
+        port: &mut gjio::EventPort,
+    ) -> Result<u64, PungError> {
+        if self.sealed.is_empty() && self.client.cover_rates.is_none() {
+            return Err(PungError::Failed("No messages were provided".to_string()));
+        }
+
+        Ok(self.client.send_sealed(&self.sealed, scope, port)?)
+    }
+}
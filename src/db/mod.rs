@@ -1,6 +1,12 @@
 //! This module contains the collection of Pung's messages.
 
+use rand;
+use rand::Rng;
+use rayon::prelude::*;
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::mem;
 use std::rc::Rc;
 use std::slice;
 use util;
@@ -19,12 +25,42 @@ pub const MAC_SIZE: usize = 16;
 /// Size of a Pung tuple (sum of label, cipher, and mac).
 pub const TUPLE_SIZE: usize = LABEL_SIZE + CIPHER_SIZE + MAC_SIZE;
 
-/// False positive probability for bloom filter
+/// Default false positive probability for bloom filter (see `Database::set_bloom_fp`)
 pub const BLOOM_FP: f64 = 0.00001;
 
+/// Creates an empty bloom filter for a collection of `len` tuples, sized for `BLOOM_FP`. It
+/// uses `hashes` hash functions if given (the optimal number otherwise), so clients and
+/// servers must agree on it to build the same filters (see `Database::set_bloom_hashes`).
+pub fn new_bloom(len: usize, hashes: Option<u32>) -> util::bloomfilter::Bloom {
+    new_bloom_with_fp(len, BLOOM_FP, hashes)
+}
+
+/// Like `new_bloom`, but sized for a false positive probability of `fp` (see
+/// `Database::set_bloom_fp`).
+pub fn new_bloom_with_fp(len: usize, fp: f64, hashes: Option<u32>) -> util::bloomfilter::Bloom {
+    match hashes {
+        Some(k) => {
+            let size = util::bloomfilter::Bloom::compute_bitmap_size(len, fp);
+            util::bloomfilter::Bloom::new_with_k_num(size, k)
+        }
+        None => util::bloomfilter::Bloom::new_for_fp_rate(len, fp),
+    }
+}
+
+/// Checks that `fp` can be the false positive probability of bloom filters, i.e., that it
+/// is in (0, 1).
+pub fn check_bloom_fp(fp: f64) -> Result<(), &'static str> {
+    if fp > 0.0 && fp < 1.0 {
+        Ok(())
+    } else {
+        Err("Bloom filter false positive rate must be in (0, 1)")
+    }
+}
+
 /// Type of retrieval scheme. Explicit retrieval has a single level, tree retrieval
 /// constructs a complete binary search tree.
-#[derive(PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum RetScheme {
     Explicit,
     Bloom,
@@ -33,12 +69,54 @@ pub enum RetScheme {
 
 
 /// Type of optimization for retrieval scheme.
-#[derive(PartialEq, Eq, PartialOrd, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum OptScheme {
     Normal,   // No optimization
     Aliasing, // Storing messages under two labels
     Hybrid2,  // Hybrid with batch codes (supports 2 collisions per bucket)
     Hybrid4,  // Hybrid with batch codes (supports 4 collisions per bucket)
+    Hybrid8,  // Hybrid with batch codes (supports 8 collisions per bucket)
+}
+
+/// Checks that buckets can be laid out (and retrieved from) with the given retrieval
+/// scheme, optimization scheme, and PIR depth. `Bucket::new` and `Database::new` reject
+/// any combination this rejects, so servers and clients can check their configuration
+/// with it at startup. Every pair of schemes is currently supported, so only the depth can
+/// be rejected.
+pub fn check_config(
+    _ret_scheme: RetScheme,
+    _opt_scheme: OptScheme,
+    depth: u64,
+) -> Result<(), &'static str> {
+    if depth == 0 {
+        Err("PIR depth must be at least 1")
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Why `Bucket::encode` refused to encode a bucket. The bucket is left as it was.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodeError {
+    /// The bucket was already encoded (and not cleared since)
+    AlreadyEncoded,
+
+    /// A collection other than the first one holds tuples before encoding (tuples are only
+    /// ever pushed to the first collection, which encoding then spreads around)
+    UnexpectedTuples(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::AlreadyEncoded => write!(f, "bucket is already encoded"),
+            EncodeError::UnexpectedTuples(i) => {
+                write!(f, "collection {} holds tuples before encoding", i)
+            }
+        }
+    }
 }
 
 
@@ -48,7 +126,28 @@ pub struct PungTuple {
     pub data: [u8; TUPLE_SIZE],
 }
 
+/// Read-only summary of how `Bucket::encode` laid out one of a bucket's collections.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectionSummary {
+    /// Number of tuples in the collection
+    pub len: usize,
+
+    /// Whether the collection holds real tuples (i.e., it has meaningful labels)
+    pub is_systematic: bool,
+
+    /// Whether the collection holds XORed (encoded) tuples
+    pub is_parity: bool,
+
+    /// Label of the first tuple as stored (the root of the tree for Tree retrieval)
+    pub first_label: Option<Vec<u8>>,
+
+    /// Delimiter label for this collection (only for systematic collections other than 0
+    /// under the hybrid schemes)
+    pub lmid: Option<Vec<u8>>,
+}
+
 mod tuple;
+mod persist;
 pub mod bst;
 
 use db::bst::BSTOrder;
@@ -58,12 +157,24 @@ pub type DatabasePtr = Rc<RefCell<Database<'static>>>;
 
 pub struct Database<'a> {
     buckets: Vec<Bucket<'a>>,
+    partitions: util::Partitioning, // bucket of each label (built once, see push_batch)
 }
 
+// A bucket handed to another thread to be encoded. Buckets are not Send only because of
+// their PIR servers (see PirServer), which encoding never touches; encode_each only hands
+// out buckets that have none.
+struct EncodeJob<'b, 'a: 'b>(&'b mut Bucket<'a>);
+
+unsafe impl<'b, 'a: 'b> Send for EncodeJob<'b, 'a> {}
+
 pub struct Bucket<'a> {
     collections: Vec<Collection<'a>>,
     opt_scheme: OptScheme,
     ret_scheme: RetScheme,
+    ttls: HashMap<Vec<u8>, u32>, // label -> TTL (in rounds) of tuples that have one
+    bloom_hashes: Option<u32>, // hash functions of the bloom filters (optimal if None)
+    encoded: bool,             // whether encode was called since the bucket was last cleared
+    duplicates: usize,         // tuples whose label another tuple has (see duplicate_labels)
 }
 
 /// A collection made up of [`PungTuples`] (struct.`PungTuple`.html).
@@ -76,26 +187,31 @@ pub struct Collection<'a> {
     ret_scheme: RetScheme,
     pir_dbs: Vec<PirServer<'a>>,
     depth: u64,
+    alpha: Option<u64>, // PIR aggregation of every level, if not derived from its size
     bloom: util::bloomfilter::Bloom,
+    bloom_fp: f64, // false positive probability for which the bloom filter is sized
 }
 
 impl<'a> Database<'a> {
+    /// Creates an empty database with `buckets` buckets, or an error if there are no buckets
+    /// or the schemes and depth are not a supported combination (see `check_config`).
     pub fn new(
         ret_scheme: RetScheme,
         opt_scheme: OptScheme,
         buckets: usize,
         depth: u64,
-    ) -> Database<'a> {
+    ) -> Result<Database<'a>, &'static str> {
         let mut db = Database {
             buckets: Vec::new(),
+            partitions: util::Partitioning::new(buckets)?,
         };
 
         for _ in 0..buckets {
-            let bucket = Bucket::new(ret_scheme, opt_scheme, depth);
+            let bucket = Bucket::new(ret_scheme, opt_scheme, depth)?;
             db.buckets.push(bucket);
         }
 
-        db
+        Ok(db)
     }
 
     /// Total number of subcollections in the database
@@ -126,11 +242,59 @@ impl<'a> Database<'a> {
         self.len() == 0
     }
 
+    /// Total number of tuples in the database excluding those added by encoding
+    #[inline]
+    pub fn unencoded_len(&self) -> usize {
+        let mut count = 0;
+
+        for bucket in &self.buckets {
+            count += bucket.unencoded_len();
+        }
+
+        count
+    }
+
+    /// Number of tuples whose label collides with another's in the same bucket (see
+    /// `Bucket::duplicate_labels`).
+    #[inline]
+    pub fn duplicate_labels(&self) -> usize {
+        self.buckets.iter().map(|b| b.duplicate_labels()).sum()
+    }
+
+    /// Ratio between the tuples stored and the tuples sent (e.g., about 1.5 for Hybrid2,
+    /// 2.25 for Hybrid4, and 3.375 for Hybrid8 once encoded). This is 1 for an empty database.
+    pub fn encoding_overhead_ratio(&self) -> f64 {
+        let unencoded = self.unencoded_len();
+
+        if unencoded == 0 {
+            1.0
+        } else {
+            self.len() as f64 / unencoded as f64
+        }
+    }
+
+    #[inline]
+    pub fn ret_scheme(&self) -> RetScheme {
+        self.buckets[0].ret_scheme
+    }
+
     #[inline]
     pub fn opt_scheme(&self) -> OptScheme {
         self.buckets[0].opt_scheme()
     }
 
+    /// False positive probability of the bloom filters (see `set_bloom_fp`).
+    #[inline]
+    pub fn bloom_fp(&self) -> f64 {
+        self.buckets.first().map_or(BLOOM_FP, |b| b.collections[0].bloom_fp)
+    }
+
+    /// PIR aggregation of every level, if one was set (see `set_alpha`).
+    #[inline]
+    pub fn alpha(&self) -> Option<u64> {
+        self.buckets.first().and_then(|b| b.collections[0].alpha)
+    }
+
     /// Total number of buckets in the database
     #[inline]
     pub fn num_buckets(&self) -> usize {
@@ -142,6 +306,11 @@ impl<'a> Database<'a> {
         self.buckets.iter()
     }
 
+    #[inline]
+    pub fn get_buckets_mut(&mut self) -> slice::IterMut<Bucket<'a>> {
+        self.buckets.iter_mut()
+    }
+
     #[inline]
     pub fn get_bucket(&self, id: usize) -> &'a Bucket {
         &self.buckets[id]
@@ -159,38 +328,173 @@ impl<'a> Database<'a> {
         }
     }
 
+    /// Uses `hashes` hash functions in the bloom filters of every bucket instead of the
+    /// optimal number for `BLOOM_FP`. The filters keep their size, so this trades their
+    /// false positive rate for the cost of checking them (for experiments). Clients must use
+    /// the same number, which the server tells them when they register.
+    pub fn set_bloom_hashes(&mut self, hashes: Option<u32>) {
+        for bucket in &mut self.buckets {
+            bucket.set_bloom_hashes(hashes);
+        }
+    }
+
+    /// Sizes the bloom filters of every bucket for a false positive probability of `fp`
+    /// instead of `BLOOM_FP`, or returns an error if `fp` is not in (0, 1). Larger filters
+    /// cost clients more to download, but fewer false positives waste fewer PIR requests.
+    /// Clients must use the same rate, which the server tells them when they register.
+    pub fn set_bloom_fp(&mut self, fp: f64) -> Result<(), &'static str> {
+        check_bloom_fp(fp)?;
+
+        for bucket in &mut self.buckets {
+            bucket.set_bloom_fp(fp)?;
+        }
+
+        Ok(())
+    }
+
+    /// Uses `alpha` as the PIR aggregation of every level set up from now on (see
+    /// `util::pir_alpha`) instead of deriving it from the level's size, or returns an error if
+    /// `alpha` is 0. Clients must use the same alpha, which the server tells them when they
+    /// register.
+    pub fn set_alpha(&mut self, alpha: Option<u64>) -> Result<(), &'static str> {
+        if alpha == Some(0) {
+            return Err("PIR alpha must be at least 1");
+        }
+
+        for bucket in &mut self.buckets {
+            bucket.set_alpha(alpha);
+        }
+
+        Ok(())
+    }
+
+    /// Moves the contents of the database out, leaving behind an empty database with the
+    /// same configuration. This is how the server retains past rounds.
+    pub fn take(&mut self) -> Database<'a> {
+        let mut buckets = Vec::with_capacity(self.buckets.len());
+
+        for bucket in &mut self.buckets {
+            buckets.push(bucket.take());
+        }
+
+        Database {
+            buckets: buckets,
+            partitions: self.partitions.clone(),
+        }
+    }
+
     #[inline]
     pub fn push(&mut self, bucket_id: usize, tuple: PungTuple) {
         self.buckets[bucket_id].push(tuple);
     }
 
+    /// Adds a tuple that expires `ttl` rounds after the round in which it was sent
+    /// (0 means it never expires). See `evict_expired`.
     #[inline]
-    pub fn encode(&mut self) {
+    pub fn push_with_ttl(&mut self, bucket_id: usize, tuple: PungTuple, ttl: u32) {
+        self.buckets[bucket_id].push_with_ttl(tuple, ttl);
+    }
+
+    /// Adds each tuple of a batch to the bucket its label falls in (the same assignment as
+    /// clients, see `util::Partitioning`, which the database builds once when it is created).
+    /// The batch is split up first and then appended to each bucket at once, which is faster
+    /// than pushing tuples one at a time.
+    pub fn push_batch(&mut self, tuples: Vec<PungTuple>) {
+        self.push_batch_with_ttl(tuples.into_iter().map(|tuple| (tuple, 0)));
+    }
+
+    /// Like `push_batch`, but each tuple comes with its TTL (see `push_with_ttl`).
+    pub fn push_batch_with_ttl<I>(&mut self, tuples: I)
+    where
+        I: IntoIterator<Item = (PungTuple, u32)>,
+    {
+        let mut batches: Vec<Vec<_>> = (0..self.buckets.len()).map(|_| Vec::new()).collect();
+
+        for (tuple, ttl) in tuples {
+            batches[self.partitions.bucket_of(tuple.label())].push((tuple, ttl));
+        }
+
+        for (bucket, batch) in self.buckets.iter_mut().zip(batches) {
+            bucket.push_batch_with_ttl(batch);
+        }
+    }
+
+    /// Evicts the tuples whose TTL has elapsed given that the database is `age` rounds old.
+    /// Returns the number of evicted tuples, or an error if PIR could not be set up again for
+    /// some bucket (see `Bucket::evict_expired`). The other buckets are evicted regardless.
+    pub fn evict_expired(&mut self, age: u32) -> Result<usize, &'static str> {
+        let mut count = 0;
+        let mut failed = None;
+
         for bucket in &mut self.buckets {
-            bucket.encode();
+            match bucket.evict_expired(age) {
+                Ok(evicted) => count += evicted,
+                Err(e) => failed = Some(e),
+            }
+        }
+
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(count),
+        }
+    }
+
+    /// Encodes every bucket (see `Bucket::encode`), returning the first error if any bucket
+    /// cannot be encoded. The other buckets are encoded regardless (see `encode_each`).
+    #[inline]
+    pub fn encode(&mut self) -> Result<(), EncodeError> {
+        self.encode_each().into_iter().collect()
+    }
+
+    /// Encodes every bucket (see `Bucket::encode`) and returns each bucket's outcome.
+    /// Buckets are independent, so they are encoded in parallel on rayon's thread pool; the
+    /// calling (timely worker) thread waits for them. Every bucket is laid out exactly as if
+    /// it were encoded on its own.
+    pub fn encode_each(&mut self) -> Vec<Result<(), EncodeError>> {
+        // Buckets with PIR servers cannot leave this thread (they are encoded already anyway)
+        if self.buckets.iter().any(|b| b.has_pir_dbs()) {
+            return self.buckets.iter_mut().map(|b| b.encode()).collect();
         }
+
+        let mut jobs: Vec<EncodeJob> = self.buckets.iter_mut().map(EncodeJob).collect();
+        jobs.par_iter_mut().map(|job| job.0.encode()).collect()
     }
 
+    /// Sets up PIR for every bucket, or returns an error if the PIR library fails to.
     #[inline]
-    pub fn pir_setup(&mut self) {
+    pub fn pir_setup(&mut self) -> Result<(), &'static str> {
         for bucket in &mut self.buckets {
-            bucket.pir_setup();
+            bucket.pir_setup()?;
         }
+
+        Ok(())
     }
 }
 
 impl<'a> Bucket<'a> {
-    pub fn new(ret_scheme: RetScheme, opt_scheme: OptScheme, depth: u64) -> Bucket<'a> {
+    /// Creates an empty bucket, or an error if the schemes and depth are not a supported
+    /// combination (see `check_config`).
+    pub fn new(
+        ret_scheme: RetScheme,
+        opt_scheme: OptScheme,
+        depth: u64,
+    ) -> Result<Bucket<'a>, &'static str> {
+        check_config(ret_scheme, opt_scheme, depth)?;
+
         let mut b = Bucket {
             collections: Vec::new(),
             opt_scheme: opt_scheme,
             ret_scheme: ret_scheme,
+            ttls: HashMap::new(),
+            bloom_hashes: None,
+            encoded: false,
+            duplicates: 0,
         };
 
         // Default is 1 collection
         b.collections.push(Collection::new(ret_scheme, depth));
 
-        // Hybrid 2 adds 2 more collections, Hybrid 4 adds 8 more
+        // Hybrid 2 adds 2 more collections, Hybrid 4 adds 8 more, and Hybrid 8 adds 26 more
         if opt_scheme == OptScheme::Hybrid2 {
             b.collections.push(Collection::new(ret_scheme, depth));
             b.collections.push(Collection::new(ret_scheme, depth));
@@ -198,9 +502,13 @@ impl<'a> Bucket<'a> {
             for _ in 0..8 {
                 b.collections.push(Collection::new(ret_scheme, depth));
             }
+        } else if opt_scheme == OptScheme::Hybrid8 {
+            for _ in 0..26 {
+                b.collections.push(Collection::new(ret_scheme, depth));
+            }
         }
 
-        b
+        Ok(b)
     }
 
     #[inline]
@@ -244,19 +552,30 @@ impl<'a> Bucket<'a> {
     }
 
 
-    #[inline]
-    pub fn unencoded_len(&self) -> usize {
-        let mut count = self.collections[0].len();
+    /// Returns the (collection, index) of the tuple with the given label among the
+    /// collections that hold sent tuples (i.e., not the encoded ones), if there is one.
+    /// This is a linear scan meant for debugging.
+    pub fn find_by_label(&self, label: &[u8]) -> Option<(usize, usize)> {
+        for i in util::label_collections(self.opt_scheme) {
+            let pos = self.collections[i]
+                .get_tuples()
+                .position(|t| t.label() == label);
 
-        if self.opt_scheme == OptScheme::Hybrid2 {
-            count += self.collections[1].len();
-        } else if self.opt_scheme == OptScheme::Hybrid4 {
-            count += self.collections[1].len();
-            count += self.collections[2].len();
-            count += self.collections[3].len();
+            if let Some(idx) = pos {
+                return Some((i, idx));
+            }
         }
 
-        count
+        None
+    }
+
+    #[inline]
+    pub fn unencoded_len(&self) -> usize {
+        // Only the systematic collections hold tuples that were sent
+        util::label_collections(self.opt_scheme)
+            .into_iter()
+            .map(|i| self.collections[i].len())
+            .sum()
     }
 
     #[inline]
@@ -269,6 +588,10 @@ impl<'a> Bucket<'a> {
         for collection in &mut self.collections {
             collection.clear();
         }
+
+        self.ttls.clear();
+        self.encoded = false;
+        self.duplicates = 0;
     }
 
     #[inline]
@@ -276,6 +599,50 @@ impl<'a> Bucket<'a> {
         self.opt_scheme
     }
 
+    // Whether PIR was set up for any collection (see pir_setup)
+    fn has_pir_dbs(&self) -> bool {
+        self.collections.iter().any(|c| !c.pir_dbs.is_empty())
+    }
+
+    // Moves the contents out, leaving an empty bucket with the same configuration
+    fn take(&mut self) -> Bucket<'a> {
+        let depth = self.collections[0].depth;
+        let mut empty = Bucket::new(self.ret_scheme, self.opt_scheme, depth)
+            .expect("configuration was checked when the bucket was created");
+        empty.bloom_hashes = self.bloom_hashes;
+        empty
+            .set_bloom_fp(self.collections[0].bloom_fp)
+            .expect("rate was checked when it was set");
+        empty.set_alpha(self.collections[0].alpha);
+        mem::replace(self, empty)
+    }
+
+    /// Sets the number of hash functions of the bucket's bloom filters (see
+    /// `Database::set_bloom_hashes`).
+    pub fn set_bloom_hashes(&mut self, hashes: Option<u32>) {
+        self.bloom_hashes = hashes;
+    }
+
+    /// Sets the false positive probability of the bloom filters of every collection of the
+    /// bucket (see `Database::set_bloom_fp`).
+    pub fn set_bloom_fp(&mut self, fp: f64) -> Result<(), &'static str> {
+        check_bloom_fp(fp)?;
+
+        for collection in &mut self.collections {
+            collection.set_bloom_fp(fp);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the PIR aggregation of every collection of the bucket (see
+    /// `Database::set_alpha`).
+    pub fn set_alpha(&mut self, alpha: Option<u64>) {
+        for collection in &mut self.collections {
+            collection.set_alpha(alpha);
+        }
+    }
+
     // Pushes always go to the 0'th colletion. Encoding takes care of spreading them around
     #[inline]
     pub fn push(&mut self, tuple: PungTuple) {
@@ -283,10 +650,97 @@ impl<'a> Bucket<'a> {
     }
 
     #[inline]
-    pub fn encode(&mut self) {
+    pub fn push_with_ttl(&mut self, tuple: PungTuple, ttl: u32) {
+        if ttl > 0 {
+            self.ttls.insert(tuple.label().to_vec(), ttl);
+        }
+
+        self.push(tuple);
+    }
+
+    /// Pushes (tuple, ttl) pairs, reserving space for all of them up front.
+    pub fn push_batch_with_ttl(&mut self, batch: Vec<(PungTuple, u32)>) {
+        self.collections[0].reserve(batch.len());
+
+        for (tuple, ttl) in batch {
+            self.push_with_ttl(tuple, ttl);
+        }
+    }
+
+    /// Evicts the tuples whose TTL is at most `age` rounds and rebuilds the (encoded) bucket.
+    /// An evicted tuple is replaced with random contents under a label that differs only
+    /// in its last byte, so the tuple keeps its position and the layout that clients learned
+    /// during the send phase (sizes and lmids) stays the same. Returns the number of evicted
+    /// tuples, or an error if PIR (which is set up again for the new contents) cannot be.
+    pub fn evict_expired(&mut self, age: u32) -> Result<usize, &'static str> {
+        let expired: HashSet<Vec<u8>> = self.ttls
+            .iter()
+            .filter(|&(_, &ttl)| ttl <= age)
+            .map(|(label, _)| label.clone())
+            .collect();
+
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        for label in &expired {
+            self.ttls.remove(label);
+        }
+
+        // Real tuples live in the collections with meaningful labels
+        let mut tuples = Vec::with_capacity(self.unencoded_len());
+
+        for i in util::label_collections(self.opt_scheme) {
+            tuples.extend(self.collections[i].split_off(0));
+        }
+
+        for collection in &mut self.collections {
+            collection.clear();
+        }
+
+        self.encoded = false;
+        self.duplicates = 0;
+
+        let mut rng = rand::thread_rng();
+
+        for mut tuple in tuples {
+            if expired.contains(tuple.label()) {
+                tuple.data[LABEL_SIZE - 1] ^= 0xff;
+                rng.fill_bytes(&mut tuple.data[LABEL_SIZE..]);
+            }
+
+            self.push(tuple);
+        }
+
+        self.encode().expect("the bucket was just cleared");
+
+        self.pir_setup()?;
+
+        Ok(expired.len())
+    }
+
+    /// Lays out the tuples pushed to the bucket for retrieval: sorts them and, depending on
+    /// the schemes, splits them into collections, encodes (XORs) those, and builds trees or
+    /// bloom filters. Encoding a bucket twice (without clearing it in between) or one whose
+    /// tuples are not all in its first collection is an error, and leaves it untouched.
+    pub fn encode(&mut self) -> Result<(), EncodeError> {
+        if self.encoded {
+            return Err(EncodeError::AlreadyEncoded);
+        }
+
+        if let Some(i) = (1..self.collections.len()).find(|&i| !self.collections[i].is_empty()) {
+            return Err(EncodeError::UnexpectedTuples(i));
+        }
+
+        self.encoded = true;
+
         // Sort collection
         self.collections[0].sort();
 
+        // Tuples whose labels collide are all kept (see Collection::sort), but only one of
+        // them can be found by label, so they are counted for the server to report
+        self.duplicates = self.collections[0].duplicate_labels();
+
         if (self.opt_scheme == OptScheme::Normal || self.opt_scheme == OptScheme::Aliasing)
             && self.ret_scheme == RetScheme::Tree
         {
@@ -294,9 +748,9 @@ impl<'a> Bucket<'a> {
         } else if (self.opt_scheme == OptScheme::Normal || self.opt_scheme == OptScheme::Aliasing)
             && self.ret_scheme == RetScheme::Bloom
         {
-            self.collections[0].set_bloom();
+            self.collections[0].set_bloom(self.bloom_hashes);
         } else if self.opt_scheme == OptScheme::Hybrid2 {
-            assert_eq!(self.collections.len(), 3);
+            debug_assert_eq!(self.collections.len(), 3);
 
             let len = self.len();
 
@@ -306,7 +760,7 @@ impl<'a> Bucket<'a> {
             // Setup the second collection with the remaining items
             self.collections[1].set_contents(tuples);
 
-            assert!(
+            debug_assert!(
                 self.collections[0].len() == self.collections[1].len()
                     || self.collections[0].len() == self.collections[1].len() + 1
             );
@@ -316,8 +770,8 @@ impl<'a> Bucket<'a> {
                 self.collections[0].as_bst_array();
                 self.collections[1].as_bst_array();
             } else if self.ret_scheme == RetScheme::Bloom {
-                self.collections[0].set_bloom();
-                self.collections[1].set_bloom();
+                self.collections[0].set_bloom(self.bloom_hashes);
+                self.collections[1].set_bloom(self.bloom_hashes);
             }
 
 
@@ -339,9 +793,9 @@ impl<'a> Bucket<'a> {
 
             self.collections[2].set_contents(xor_tuples);
 
-            assert_eq!(self.collections[0].len(), self.collections[2].len());
+            debug_assert_eq!(self.collections[0].len(), self.collections[2].len());
         } else if self.opt_scheme == OptScheme::Hybrid4 {
-            assert_eq!(self.collections.len(), 9);
+            debug_assert_eq!(self.collections.len(), 9);
 
             let mut len = self.len();
 
@@ -370,7 +824,7 @@ impl<'a> Bucket<'a> {
                 }
             } else if self.ret_scheme == RetScheme::Bloom {
                 for i in 0..4 {
-                    self.collections[i].set_bloom();
+                    self.collections[i].set_bloom(self.bloom_hashes);
                 }
             }
 
@@ -400,12 +854,73 @@ impl<'a> Bucket<'a> {
 
             // Check the right numbers are present
             for i in 0..4 {
-                assert_eq!(
+                debug_assert_eq!(
                     self.collections[i].len() as u64,
                     util::collection_len(self.unencoded_len() as u64, i as u32, 4)
                 );
             }
+        } else if self.opt_scheme == OptScheme::Hybrid8 {
+            debug_assert_eq!(self.collections.len(), 27);
+
+            let len = self.len() as u64;
+
+            // Split collection 0 (which has all the tuples) into 8 consecutive runs, as halving
+            // it three times would (see util::collection_len), starting from the last one
+            let lens: Vec<u64> = (0..8).map(|i| util::collection_len(len, i, 8)).collect();
+            let mut start: u64 = lens.iter().sum();
+
+            for i in (1..8).rev() {
+                start -= lens[i];
+                let tuples = self.collections[0].split_off(start as usize);
+                self.collections[i].set_contents(tuples);
+            }
+
+            // If we are doing BST retrieval, convert to BSTs
+            if self.ret_scheme == RetScheme::Tree {
+                for i in 0..8 {
+                    self.collections[i].as_bst_array();
+                }
+            } else if self.ret_scheme == RetScheme::Bloom {
+                for i in 0..8 {
+                    self.collections[i].set_bloom(self.bloom_hashes);
+                }
+            }
+
+            // Encode (XOR) collections as the plan says. As with Hybrid4, the first collection
+            // of each pair can be one tuple longer than the second, which then has nothing to
+            // XOR with its last tuple.
+            for (i, (c1, c2)) in util::h8_plan().into_iter().enumerate() {
+                let mut collection_i: Vec<PungTuple> = self.collections[c1]
+                    .get_tuples()
+                    .zip(self.collections[c2].get_tuples())
+                    .map(|(a, b)| a ^ b)
+                    .collect();
+
+                if collection_i.len() != self.collections[c1].len() {
+                    collection_i.push(
+                        self.collections[c1]
+                            .get_tuple(self.collections[c1].len() - 1)
+                            .clone(),
+                    );
+                }
+
+                self.collections[i + 8].set_contents(collection_i);
+            }
+
+            for i in 0..8 {
+                debug_assert_eq!(self.collections[i].len() as u64, lens[i]);
+            }
         }
+
+        Ok(())
+    }
+
+    /// Number of tuples in the bucket that have the same label as another of its tuples (not
+    /// counting the first of each label), as of the last `encode`. Retrieval by label finds
+    /// only one of the tuples that share a label, so the others waste their slots.
+    #[inline]
+    pub fn duplicate_labels(&self) -> usize {
+        self.duplicates
     }
 
     #[inline]
@@ -434,10 +949,12 @@ impl<'a> Bucket<'a> {
             };
 
             vec![lmid]
-        } else if self.opt_scheme == OptScheme::Hybrid4 {
-            let mut lmids = Vec::with_capacity(3);
+        } else if self.opt_scheme >= OptScheme::Hybrid4 {
+            // The lowest label of each systematic collection but the first
+            let systematic = util::label_collections(self.opt_scheme).len();
+            let mut lmids = Vec::with_capacity(systematic - 1);
 
-            for i in 1..4 {
+            for i in 1..systematic {
                 let lmid = match self.ret_scheme {
                     RetScheme::Explicit | RetScheme::Bloom => {
                         // lmid is the first element
@@ -469,13 +986,46 @@ impl<'a> Bucket<'a> {
         }
     }
 
+    /// Describes how the tuples of this bucket are distributed across its collections.
+    /// This is meant for debugging the hybrid encodings.
+    pub fn layout_summary(&self) -> Vec<CollectionSummary> {
+        let systematic = util::label_collections(self.opt_scheme);
+        let lmids = self.mid_labels();
+
+        self.collections
+            .iter()
+            .enumerate()
+            .map(|(i, collection)| {
+                let is_systematic = systematic.contains(&i);
+
+                // lmids are only defined for systematic collections 1, 2, and 3
+                let lmid = if is_systematic && i > 0 && i <= lmids.len() && !lmids[i - 1].is_empty()
+                {
+                    Some(lmids[i - 1].clone())
+                } else {
+                    None
+                };
+
+                CollectionSummary {
+                    len: collection.len(),
+                    is_systematic: is_systematic,
+                    is_parity: !is_systematic,
+                    first_label: collection.get_first().map(|t| t.label().to_vec()),
+                    lmid: lmid,
+                }
+            })
+            .collect()
+    }
+
     #[inline]
-    pub fn pir_setup(&mut self) {
+    pub fn pir_setup(&mut self) -> Result<(), &'static str> {
         for collection in &mut self.collections {
             if !collection.is_empty() {
-                collection.pir_setup();
+                collection.pir_setup()?;
             }
         }
+
+        Ok(())
     }
 }
 
@@ -488,7 +1038,9 @@ impl<'a> Collection<'a> {
             ret_scheme: ret_scheme,
             pir_dbs: Vec::new(),
             depth: depth,
+            alpha: None,
             bloom: util::bloomfilter::Bloom::new(1, 1),
+            bloom_fp: BLOOM_FP,
         }
     }
 
@@ -516,6 +1068,19 @@ impl<'a> Collection<'a> {
         self.len() == 0
     }
 
+    /// Number of tuples in the collection whose label is also that of an earlier tuple (in
+    /// whatever order the collection is laid out).
+    pub fn duplicate_labels(&self) -> usize {
+        let mut seen = HashSet::with_capacity(self.set.len());
+        self.set.iter().filter(|t| !seen.insert(t.label())).count()
+    }
+
+    /// Whether any two tuples of the collection have the same label.
+    #[inline]
+    pub fn has_duplicate_labels(&self) -> bool {
+        self.duplicate_labels() > 0
+    }
+
     /// Returns the number of levels in the tree representing a bucket's collection
     #[inline]
     pub fn num_levels(&self) -> usize {
@@ -532,6 +1097,12 @@ impl<'a> Collection<'a> {
         self.set.push(tuple)
     }
 
+    /// Reserves space for at least `additional` more tuples.
+    #[inline]
+    pub fn reserve(&mut self, additional: usize) {
+        self.set.reserve(additional)
+    }
+
     #[inline]
     pub fn get_first(&'a self) -> Option<&'a PungTuple> {
         self.set.first()
@@ -548,13 +1119,36 @@ impl<'a> Collection<'a> {
     }
 
     #[inline]
+    /// Sizes the bloom filters built from now on (see `set_bloom`) for a false positive
+    /// probability of `fp`, which must be in (0, 1) (see `check_bloom_fp`).
+    #[inline]
+    pub fn set_bloom_fp(&mut self, fp: f64) {
+        debug_assert!(check_bloom_fp(fp).is_ok());
+        self.bloom_fp = fp;
+    }
+
+    #[inline]
+    pub fn bloom_fp(&self) -> f64 {
+        self.bloom_fp
+    }
+
+    /// Uses `alpha` (if any) as the PIR aggregation of the levels set up from now on (see
+    /// `pir_setup`).
+    #[inline]
+    pub fn set_alpha(&mut self, alpha: Option<u64>) {
+        debug_assert!(alpha != Some(0));
+        self.alpha = alpha;
+    }
+
     pub fn set_contents(&mut self, collection: Vec<PungTuple>) {
         self.set = collection;
     }
 
 
-    pub fn set_bloom(&mut self) {
-        let mut bloom = util::bloomfilter::Bloom::new_for_fp_rate(self.len(), BLOOM_FP);
+    /// Builds the bloom filter of the collection's (index, label) pairs, with `hashes` hash
+    /// functions if given (see `new_bloom_with_fp`).
+    pub fn set_bloom(&mut self, hashes: Option<u32>) {
+        let mut bloom = new_bloom_with_fp(self.len(), self.bloom_fp, hashes);
 
         for (i, t) in self.set.iter().enumerate() {
             bloom.set((i, t.label()));
@@ -576,7 +1170,9 @@ impl<'a> Collection<'a> {
 
     #[inline]
     pub fn sort(&mut self) {
-        self.set.sort();
+        // Tuples with the same label (which is unlikely but possible) are all kept, in an
+        // order that does not depend on the order in which they arrived
+        self.set.sort_by(|a, b| a.full_cmp(b));
     }
 
     /// Changes the ordering of tuples in the collection to one that mirrors
@@ -588,42 +1184,63 @@ impl<'a> Collection<'a> {
         }
     }
 
-    pub fn pir_setup(&mut self) {
-        let depth = self.depth;
+    /// Sets up a PIR database for each level. Like alpha (unless one was set, see
+    /// `util::pir_alpha`), the recursion depth of each level depends on its size (see
+    /// `util::get_depth`), up to the collection's depth. Returns an
+    /// error (leaving the collection with no PIR database, so that queries to it are refused)
+    /// if the PIR library fails to set up one.
+    pub fn pir_setup(&mut self) -> Result<(), &'static str> {
+        let max_depth = self.depth;
 
         let levels = self.num_levels();
         let mut pir_dbs = Vec::with_capacity(levels);
+        self.pir_dbs.clear();
 
         for i in 0..levels {
-            let level: &[PungTuple] = self.get_level(i);
-            let alpha = util::get_alpha(level.len() as u64);
-            pir_dbs.push(PirServer::new(level, alpha, depth));
+            let level: &[PungTuple] = self.get_level(i).expect("level is within the tree");
+            let alpha = util::pir_alpha(level.len() as u64, self.alpha);
+            let depth = util::get_depth(level.len() as u64, max_depth);
+            pir_dbs.push(PirServer::new(level, alpha, depth)?);
         }
 
         self.pir_dbs = pir_dbs;
+        Ok(())
     }
 
+    /// Returns the PIR handler of a particular level or an error if the level does not exist
+    /// or PIR was not set up for it (see `pir_setup`).
     #[inline]
-    pub fn pir_handler(&self, level: usize) -> &PirServer {
-        &self.pir_dbs[level as usize]
+    pub fn pir_handler(&self, level: usize) -> Result<&PirServer, &'static str> {
+        match self.pir_dbs.get(level) {
+            Some(handler) => Ok(handler),
+            None if level < self.num_levels() => Err("PIR is unavailable for this collection"),
+            None => Err("PIR handler for the requested level does not exist"),
+        }
     }
 
-    /// Gets all the Tuples at a particular level in the BST representation.
+    /// Gets all the Tuples at a particular level in the BST representation. Returns an error
+    /// if the level is beyond the tree (or beyond the single level for Explicit and Bloom).
     #[inline]
-    pub fn get_level(&'a self, level: usize) -> &'a [PungTuple] {
+    pub fn get_level(&'a self, level: usize) -> Result<&'a [PungTuple], &'static str> {
+        if level >= self.num_levels() {
+            return Err("Requested level is beyond the collection's tree");
+        }
+
         if self.ret_scheme == RetScheme::Explicit || self.ret_scheme == RetScheme::Bloom {
-            &self.set[..]
+            Ok(&self.set[..])
         } else {
             let min = (2u64.pow(level as u32) - 1) as usize;
             let mut max = (2u64.pow(level as u32 + 1) - 1) as usize;
 
-            assert!(min < self.set.len());
+            if min >= self.set.len() {
+                return Err("Requested level is beyond the collection's tree");
+            }
 
             if max > self.set.len() {
                 max = self.set.len();
             }
 
-            &self.set[min..max]
+            Ok(&self.set[min..max])
         }
     }
 
@@ -0,0 +1,130 @@
+// Saving a database to a file and loading it back (see Database::save_to).
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use util;
+
+use super::{Database, OptScheme, PungTuple, RetScheme, TUPLE_SIZE};
+
+// Layout of the file: MAGIC, the number of hash functions of the bloom filters (u32, 0 for
+// the optimal number), their false positive rate (f64), and the number of buckets (u32).
+// Then, for each bucket, its state (STATE_*, u8), its number of tuples (u64), and each tuple
+// followed by its TTL (u32, 0 for none). Numbers are big endian.
+const MAGIC: &[u8; 8] = b"PUNGDB01";
+
+const STATE_UNENCODED: u8 = 0;
+const STATE_ENCODED: u8 = 1;
+const STATE_PIR: u8 = 2; // encoded, and PIR was set up
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+impl<'a> Database<'a> {
+    /// Writes the tuples of every bucket (with their TTLs) to `path`, along with whether each
+    /// bucket was encoded and set up for PIR, so that `load_from` can rebuild the database
+    /// (e.g., after the server restarts). Only the tuples that were pushed are written: the
+    /// layout that encoding derives from them, and the PIR databases (which hold pointers
+    /// into C++), are rebuilt on load.
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        let hashes = self.buckets.first().and_then(|b| b.bloom_hashes);
+
+        out.write_all(MAGIC)?;
+        out.write_u32::<BigEndian>(hashes.unwrap_or(0))?;
+        out.write_f64::<BigEndian>(self.bloom_fp())?;
+        out.write_u32::<BigEndian>(self.buckets.len() as u32)?;
+
+        for bucket in &self.buckets {
+            let state = if bucket.has_pir_dbs() {
+                STATE_PIR
+            } else if bucket.encoded {
+                STATE_ENCODED
+            } else {
+                STATE_UNENCODED
+            };
+
+            out.write_u8(state)?;
+            out.write_u64::<BigEndian>(bucket.unencoded_len() as u64)?;
+
+            // Pushed tuples live in the collections with meaningful labels
+            for i in util::label_collections(bucket.opt_scheme) {
+                for tuple in bucket.collections[i].get_tuples() {
+                    let ttl = bucket.ttls.get(tuple.label()).cloned().unwrap_or(0);
+                    out.write_all(&tuple.to_binary())?;
+                    out.write_u32::<BigEndian>(ttl)?;
+                }
+            }
+        }
+
+        out.flush()
+    }
+
+    /// Reads a database written by `save_to` and lays it out with the given schemes and
+    /// depth: the tuples are pushed again, and buckets that were encoded (and set up for PIR)
+    /// are encoded (and set up) again. A bucket is laid out exactly as it was, since encoding
+    /// does not depend on the order in which tuples were pushed.
+    pub fn load_from(
+        path: &Path,
+        ret_scheme: RetScheme,
+        opt_scheme: OptScheme,
+        depth: u64,
+    ) -> io::Result<Database<'a>> {
+        let mut input = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(invalid("Not a saved Pung database"));
+        }
+
+        let hashes = input.read_u32::<BigEndian>()?;
+        let fp = input.read_f64::<BigEndian>()?;
+        let buckets = input.read_u32::<BigEndian>()? as usize;
+
+        let mut dbase = Database::new(ret_scheme, opt_scheme, buckets, depth).map_err(invalid)?;
+
+        if hashes > 0 {
+            dbase.set_bloom_hashes(Some(hashes));
+        }
+
+        dbase.set_bloom_fp(fp).map_err(invalid)?;
+
+        let mut data = [0u8; TUPLE_SIZE];
+
+        for bucket in &mut dbase.buckets {
+            let state = input.read_u8()?;
+
+            if state > STATE_PIR {
+                return Err(invalid("Invalid bucket state"));
+            }
+
+            let len = input.read_u64::<BigEndian>()?;
+
+            for _ in 0..len {
+                input.read_exact(&mut data)?;
+                let ttl = input.read_u32::<BigEndian>()?;
+                bucket.push_with_ttl(PungTuple::new(&data), ttl);
+            }
+
+            if state >= STATE_ENCODED {
+                bucket.encode().map_err(|e| invalid(&e.to_string()))?;
+            }
+
+            if state == STATE_PIR {
+                bucket.pir_setup().map_err(invalid)?;
+            }
+        }
+
+        // Anything after the last bucket means this is not what save_to wrote
+        if input.read(&mut data)? > 0 {
+            return Err(invalid("Trailing data after the last bucket"));
+        }
+
+        Ok(dbase)
+    }
+}
@@ -1,5 +1,14 @@
 use abomonation::Abomonation;
+use byteorder::{BigEndian, ByteOrder, NativeEndian};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+#[cfg(feature = "serialize")]
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+#[cfg(feature = "serialize")]
+use serde::{Serialize, Serializer};
 use std::cmp::Ordering;
+#[cfg(feature = "serialize")]
+use std::fmt;
 use std::io::Write;
 use std::ops::BitXor;
 use std::ops::BitXorAssign;
@@ -8,17 +17,25 @@ use super::{PungTuple, CIPHER_SIZE, LABEL_SIZE, TUPLE_SIZE};
 use util;
 
 impl PungTuple {
-    /// Creates a Pung tuple from a binary stream ([u8]).
+    /// Creates a Pung tuple from a binary stream ([u8]). Panics if `data` is not a tuple, so
+    /// data that comes from a client should go through `try_new` instead.
     pub fn new(data: &[u8]) -> PungTuple {
-        assert!(data.len() == TUPLE_SIZE);
+        PungTuple::try_new(data).unwrap()
+    }
 
-        PungTuple {
+    /// Creates a Pung tuple from a binary stream ([u8]), which must be `TUPLE_SIZE` bytes long.
+    pub fn try_new(data: &[u8]) -> Result<PungTuple, &'static str> {
+        if data.len() != TUPLE_SIZE {
+            return Err("Invalid tuple length");
+        }
+
+        Ok(PungTuple {
             data: {
                 let mut x = [0; TUPLE_SIZE];
                 x.clone_from_slice(data);
                 x
             },
-        }
+        })
     }
 
     pub fn default() -> PungTuple {
@@ -35,25 +52,15 @@ impl PungTuple {
     }
 
     /// Less-than compares a Pung tuple and some label.
-    // XXX: This is slightly faster than self.label < label, but uses unsafe casts and assumes
-    // (without checking) that label is a valid label (32 bytes).
     #[inline]
     pub fn lt(&self, label: &[u8]) -> bool {
-        unsafe {
-            (&*(self.label() as *const [u8] as *const [u64; 4]))
-                < (&*(label as *const [u8] as *const [u64; 4]))
-        }
+        util::label_cmp(self.label(), label) == Ordering::Less
     }
 
     /// Greater-than compares a Pung tuple and some label.
-    //XXX: This is slightly faster than self.label > label, but uses unsafe casts and assumes
-    // (without checking) that label is a valid label (32 bytes).
     #[inline]
     pub fn gt(&self, label: &[u8]) -> bool {
-        unsafe {
-            (&*(self.label() as *const [u8] as *const [u64; 4]))
-                > (&*(label as *const [u8] as *const [u64; 4]))
-        }
+        util::label_cmp(self.label(), label) == Ordering::Greater
     }
 
     #[inline]
@@ -72,43 +79,76 @@ impl PungTuple {
     pub fn mac(&self) -> &[u8] {
         &self.data[LABEL_SIZE + CIPHER_SIZE..]
     }
+
+    /// Compares all of two tuples (`==` only compares their labels).
+    #[inline]
+    pub fn full_eq(&self, other: &PungTuple) -> bool {
+        self.data[..] == other.data[..]
+    }
+
+    /// Orders tuples by label and then by the rest of their contents (`Ord` only compares
+    /// their labels). Sorting with this places tuples whose labels collide in the same order
+    /// no matter in which order they were pushed.
+    #[inline]
+    pub fn full_cmp(&self, other: &PungTuple) -> Ordering {
+        match util::label_cmp(self.label(), other.label()) {
+            Ordering::Equal => self.data[LABEL_SIZE..].cmp(&other.data[LABEL_SIZE..]),
+            ord => ord,
+        }
+    }
+
+    /// Returns a 32-bit checksum of the whole tuple (the first 4 bytes of its SHA-256 hash),
+    /// with which clients verify PIR answers when debugging (see `set_verify_answers`).
+    pub fn checksum(&self) -> u32 {
+        let mut hasher = Sha256::new();
+        let mut digest = [0u8; 32];
+
+        hasher.input(&self.data);
+        hasher.result(&mut digest);
+
+        BigEndian::read_u32(&digest[..4])
+    }
+}
+
+// XORs src into dst a u64 word at a time (TUPLE_SIZE is not a multiple of 8, so the last
+// few bytes are XORed one at a time). Encoding XORs every pair of tuples of a Hybrid plan,
+// and clients XOR every node they rebuild, so this is on the hot path of both.
+#[inline]
+fn xor_into(dst: &mut [u8; TUPLE_SIZE], src: &[u8; TUPLE_SIZE]) {
+    let words = TUPLE_SIZE / 8;
+
+    for i in 0..words {
+        let (start, end) = (i * 8, (i + 1) * 8);
+        let word =
+            NativeEndian::read_u64(&dst[start..end]) ^ NativeEndian::read_u64(&src[start..end]);
+        NativeEndian::write_u64(&mut dst[start..end], word);
+    }
+
+    for (d, s) in dst[words * 8..].iter_mut().zip(&src[words * 8..]) {
+        *d ^= *s;
+    }
 }
 
 impl<'a> BitXor for &'a PungTuple {
     type Output = PungTuple;
 
     fn bitxor(self, other: &PungTuple) -> PungTuple {
-        assert_eq!(self.data.len(), other.data.len());
-
         let mut xored_tuple = self.clone();
-
-        for i in 0..self.data.len() {
-            xored_tuple.data[i] ^= other.data[i];
-        }
-
+        xor_into(&mut xored_tuple.data, &other.data);
         xored_tuple
     }
 }
 
 impl BitXorAssign for PungTuple {
     fn bitxor_assign(&mut self, other: PungTuple) {
-        assert_eq!(self.data.len(), other.data.len());
-
-        let len = self.data.len();
-
-        for i in 0..len {
-            self.data[i] ^= other.data[i];
-        }
+        xor_into(&mut self.data, &other.data);
     }
 }
 
 impl PartialEq for PungTuple {
     #[inline]
     fn eq(&self, other: &PungTuple) -> bool {
-        unsafe {
-            (&*(self.label() as *const [u8] as *const [u64; 4]))
-                .eq(&*(other.label() as *const [u8] as *const [u64; 4]))
-        }
+        self.label() == other.label()
     }
 }
 
@@ -158,3 +198,53 @@ impl Abomonation for PungTuple {
         Some(bytes)
     }
 }
+
+// A tuple is serialized as its TUPLE_SIZE bytes. Formats without a notion of bytes (e.g.,
+// JSON) write them as a sequence, so either is accepted back.
+#[cfg(feature = "serialize")]
+impl Serialize for PungTuple {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.data)
+    }
+}
+
+#[cfg(feature = "serialize")]
+impl<'de> Deserialize<'de> for PungTuple {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PungTuple, D::Error> {
+        struct TupleVisitor;
+
+        impl<'de> Visitor<'de> for TupleVisitor {
+            type Value = PungTuple;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{} bytes", TUPLE_SIZE)
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<PungTuple, E> {
+                PungTuple::try_new(bytes).map_err(|_| E::invalid_length(bytes.len(), &self))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PungTuple, A::Error> {
+                let mut tuple = PungTuple::default();
+                let mut len = 0;
+
+                while let Some(byte) = seq.next_element::<u8>()? {
+                    if len == TUPLE_SIZE {
+                        return Err(de::Error::invalid_length(len + 1, &self));
+                    }
+
+                    tuple.data[len] = byte;
+                    len += 1;
+                }
+
+                if len != TUPLE_SIZE {
+                    return Err(de::Error::invalid_length(len, &self));
+                }
+
+                Ok(tuple)
+            }
+        }
+
+        deserializer.deserialize_bytes(TupleVisitor)
+    }
+}
@@ -13,6 +13,14 @@ extern crate crypto;
 #[macro_use]
 extern crate gj;
 extern crate gjio;
+#[macro_use]
+extern crate log;
+extern crate rayon;
+#[cfg(feature = "serialize")]
+extern crate serde;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
 extern crate timely;
 extern crate timely_communication;
 
@@ -27,3 +35,4 @@ pub mod server;
 pub mod client;
 pub mod db;
 pub mod pir;
+pub mod proto;
@@ -109,6 +109,14 @@ PungPIRClient::
 {
 }
 
+// Replies are decoded for the index of the last query generated unless told otherwise (e.g.,
+// when several queries are in flight at once)
+void PungPIRClient::
+setChosenIdx(uint64_t chosen_idx)
+{
+  this->lastChosenIdx = chosen_idx;
+}
+
 uint64_t PungPIRClient::
 generateQuery(uint64_t chosen_idx, vector<char*>* q)
 {
@@ -180,6 +188,25 @@ processReply(char* r, uint64_t len, uint64_t len_element, uint64_t *rlen)
   return result;
 }
 
+// Size of the reply that a server with the same parameters sends back: each level of
+// recursion turns every ciphertext of the previous level into as many ciphertexts as it
+// takes to absorb it.
+void PungPIRClient::
+expectedReply(uint64_t *rlen, uint64_t *rnum)
+{
+  PublicParameters& pp = crypto->getPublicParameters();
+
+  uint64_t num = ceil((double) this->maxFileBytesize * params.alpha * 8 / pp.getAbsorptionBitsize(0));
+
+  for (unsigned int i=1; i<params.d; i++)
+  {
+    num *= ceil((double) pp.getCiphBitsizeFromRecLvl(i) / pp.getAbsorptionBitsize(i));
+  }
+
+  *rnum = num;
+  *rlen = num * (pp.getCiphBitsizeFromRecLvl(params.d) / 8);
+}
+
 //--------------------------------------------------------------------------------
 //
 //// methods to be called from Rust
@@ -253,6 +280,18 @@ cpp_client_process_reply(void* pir, char* r, uint64_t len_total_bytes, uint64_t
   return ((PungPIRClient*) pir)->processReply(r, len_total_bytes, len_element, rlen_total_bytes);
 }
 
+void
+cpp_client_expected_reply(void* pir, uint64_t* rlen_total_bytes, uint64_t* rnum_logical_entries)
+{
+  ((PungPIRClient*) pir)->expectedReply(rlen_total_bytes, rnum_logical_entries);
+}
+
+void
+cpp_client_set_chosen_idx(void* pir, uint64_t chosen_idx)
+{
+  ((PungPIRClient*) pir)->setChosenIdx(chosen_idx);
+}
+
 void 
 cpp_client_free(void *pir)
 {
@@ -147,6 +147,8 @@ class PungPIRClient
     void updateDBParams(PIRParameters p, uint64_t);
     uint64_t generateQuery(uint64_t, vector<char*>*);
     char* processReply(char* r, uint64_t len, uint64_t len_element, uint64_t *rlen);
+    void expectedReply(uint64_t *rlen, uint64_t *rnum);
+    void setChosenIdx(uint64_t chosen_idx);
 };
 
 
@@ -165,5 +167,7 @@ extern "C" {
   char* cpp_server_process_query(void* pir, char* q, uint64_t len_query_total_bytes, uint64_t num_query_slots, uint64_t* rlen_response_total_bytes, uint64_t* rnum_response_slots);
   char* cpp_client_process_reply(void* pir, char* r, uint64_t len_response_total_bytes, uint64_t num_response_slots, uint64_t* rlen_answer_total_bytes);
   void cpp_client_update_db_params(void* pir, uint64_t len_db_total_bytes, uint64_t num_db_entries, uint64_t alpha, uint64_t d);
+  void cpp_client_expected_reply(void* pir, uint64_t* rlen_response_total_bytes, uint64_t* rnum_response_slots);
+  void cpp_client_set_chosen_idx(void* pir, uint64_t chosen_idx);
 }
 #endif
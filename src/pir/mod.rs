@@ -1,4 +1,5 @@
 use libc;
+use std::slice;
 
 // Functions from C++ shim
 //#[link(name = "gomp")]
@@ -26,6 +27,21 @@ pub struct PirResult<'a> {
 }
 
 
+// Takes a buffer that the C++ shim allocated (and that is freed when the query, answer, or
+// result holding it is dropped). A failed call returns no buffer or an empty one, which is
+// freed here rather than handed out.
+unsafe fn take_buffer<'a>(ptr: *mut u8, len: u64) -> Result<&'a mut [u8], &'static str> {
+    if ptr.is_null() {
+        Err("PIR library returned no buffer")
+    } else if len == 0 {
+        cpp_buffer_free(ptr as *mut libc::c_void);
+        Err("PIR library returned an empty buffer")
+    } else {
+        Ok(slice::from_raw_parts_mut(ptr, len as usize))
+    }
+}
+
+
 impl<'a> Drop for PirQuery<'a> {
     fn drop(&mut self) {
         unsafe {
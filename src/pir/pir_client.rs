@@ -1,7 +1,7 @@
 use libc;
-use std::slice;
+use std::marker::PhantomData;
 
-use super::{PirQuery, PirResult};
+use super::{take_buffer, PirQuery, PirResult};
 
 // Functions from C++ shim
 // #[link(name = "gomp")]
@@ -41,12 +41,23 @@ extern "C" {
         alpha: u64,
         depth: u64,
     );
+
+    fn cpp_client_expected_reply(
+        client: *const libc::c_void,
+        a_len: *mut u64,
+        a_num: *mut u64,
+    );
+
+    fn cpp_client_set_chosen_idx(client: *const libc::c_void, index: u64);
 }
 
 
+/// Handle to a C++ PIR client. Like `PirServer`, it is neither `Send` nor `Sync` since the
+/// C++ object is not thread-safe.
 pub struct PirClient<'a> {
     client: &'a mut libc::c_void,
     depth: u64,
+    _not_send: PhantomData<*mut libc::c_void>, // `&mut c_void` alone would be Send + Sync
 }
 
 impl<'a> Drop for PirClient<'a> {
@@ -58,39 +69,94 @@ impl<'a> Drop for PirClient<'a> {
 }
 
 impl<'a> PirClient<'a> {
-    pub fn new(size: u64, num: u64, alpha: u64, depth: u64) -> PirClient<'a> {
-        let client_ptr: &'a mut libc::c_void =
-            unsafe { &mut *(cpp_client_setup(size * num, num, alpha, depth)) };
+    /// Creates a PIR client for `num` elements of `size` bytes, or an error if the PIR library
+    /// could not set one up.
+    pub fn new(size: u64, num: u64, alpha: u64, depth: u64) -> Result<PirClient<'a>, &'static str> {
+        let client_ptr = unsafe { cpp_client_setup(size * num, num, alpha, depth) };
 
-        PirClient {
-            client: client_ptr,
-            depth: depth,
+        if client_ptr.is_null() {
+            return Err("PIR library could not set up a client");
         }
+
+        Ok(PirClient {
+            client: unsafe { &mut *client_ptr },
+            depth: depth,
+            _not_send: PhantomData,
+        })
     }
 
     pub fn update_params(&self, size: u64, num: u64, alpha: u64) {
+        self.update_params_with_depth(size, num, alpha, self.depth);
+    }
+
+    /// Like `update_params`, but with the given recursion depth instead of the one the
+    /// client was created with (see `util::get_depth`).
+    pub fn update_params_with_depth(&self, size: u64, num: u64, alpha: u64, depth: u64) {
         unsafe {
-            cpp_client_update_db_params(self.client, size * num, num, alpha, self.depth);
+            cpp_client_update_db_params(self.client, size * num, num, alpha, depth);
         }
     }
 
-    pub fn gen_query(&self, index: u64) -> PirQuery<'a> {
+    /// Returns the recursion depth the client was created with.
+    #[inline]
+    pub fn depth(&self) -> u64 {
+        self.depth
+    }
+
+    /// Generates a query for the element at `index`, or returns an error if the PIR library
+    /// fails to.
+    pub fn gen_query(&self, index: u64) -> Result<PirQuery<'a>, &'static str> {
         let mut q_len: u64 = 0;
         let mut q_num: u64 = 0;
 
         let query: &'a mut [u8] = unsafe {
             let ptr = cpp_client_generate_query(self.client, index, &mut q_len, &mut q_num);
-            slice::from_raw_parts_mut(ptr as *mut u8, q_len as usize)
+            take_buffer(ptr, q_len)?
         };
 
-        PirQuery {
+        Ok(PirQuery {
             query: query,
             num: q_num,
+        })
+    }
+
+
+    /// Returns the length (in bytes) of the answers that a server with the client's current
+    /// parameters produces.
+    pub fn expected_answer_len(&self) -> u64 {
+        self.expected_answer().0
+    }
+
+    // (length, number of ciphertexts) of answers for the current parameters
+    fn expected_answer(&self) -> (u64, u64) {
+        let mut a_len: u64 = 0;
+        let mut a_num: u64 = 0;
+
+        unsafe {
+            cpp_client_expected_reply(self.client, &mut a_len, &mut a_num);
         }
+
+        (a_len, a_num)
     }
 
+    /// Returns an error if an answer does not have the size (in bytes and in number of
+    /// ciphertexts) expected for the client's current parameters. Decoding such an answer
+    /// would yield garbage, e.g., when client and server disagree on the parameters.
+    pub fn check_answer(&self, answer: &[u8], a_num: u64) -> Result<(), &'static str> {
+        let (len, num) = self.expected_answer();
+
+        if answer.len() as u64 != len {
+            Err("PIR answer does not have the expected length")
+        } else if a_num != num {
+            Err("PIR answer does not have the expected number of ciphertexts")
+        } else {
+            Ok(())
+        }
+    }
 
-    pub fn decode_answer(&self, answer: &[u8], a_num: u64) -> PirResult<'a> {
+    /// Decodes an answer to the last query generated, or returns an error if the PIR library
+    /// fails to (see `check_answer` to reject answers that it cannot decode).
+    pub fn decode_answer(&self, answer: &[u8], a_num: u64) -> Result<PirResult<'a>, &'static str> {
         let mut r_len: u64 = 0;
 
         let result: &'a mut [u8] = unsafe {
@@ -101,9 +167,24 @@ impl<'a> PirClient<'a> {
                 a_num,
                 &mut r_len,
             );
-            slice::from_raw_parts_mut(ptr as *mut u8, r_len as usize)
+            take_buffer(ptr, r_len)?
         };
 
-        PirResult { result: result }
+        Ok(PirResult { result: result })
+    }
+
+    /// Like `decode_answer`, but for an answer to a query for `index` rather than to the last
+    /// query generated (the parameters must still be those of the query).
+    pub fn decode_answer_for(
+        &self,
+        index: u64,
+        answer: &[u8],
+        a_num: u64,
+    ) -> Result<PirResult<'a>, &'static str> {
+        unsafe {
+            cpp_client_set_chosen_idx(self.client, index);
+        }
+
+        self.decode_answer(answer, a_num)
     }
 }
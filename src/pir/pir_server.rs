@@ -1,7 +1,12 @@
 use libc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::mem;
 use std::slice;
-use super::PirAnswer;
+use std::time::{Duration, Instant};
+use super::{take_buffer, PirAnswer};
+use super::pir_client::PirClient;
 
 // functions from C++ PungPIR shim
 //#[link(name = "gomp")]
@@ -30,8 +35,29 @@ extern "C" {
     fn cpp_server_free(server: *mut libc::c_void);
 }
 
+// Candidate parameters explored by the autotuner
+const AUTOTUNE_ALPHAS: [u64; 7] = [1, 2, 4, 8, 16, 32, 64];
+const AUTOTUNE_DEPTHS: [u64; 2] = [1, 2];
+const AUTOTUNE_TRIALS: u64 = 3;
+
+thread_local! {
+    // (collection length, tuple size) -> (alpha, depth)
+    static AUTOTUNE_CACHE: RefCell<HashMap<(u64, u64), (u64, u64)>> = RefCell::new(HashMap::new());
+
+    // (element size, number of elements, alpha, depth) -> (query length, query num)
+    static QUERY_CACHE: RefCell<HashMap<(u64, u64, u64, u64), (u64, u64)>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Handle to a C++ PIR server. It is neither `Send` nor `Sync`: the C++ object is not
+/// thread-safe, so each timely worker builds its own (see `db::Database`).
 pub struct PirServer<'a> {
     server: &'a mut libc::c_void,
+    elem_size: u64,
+    num: u64,
+    alpha: u64,
+    depth: u64,
+    _not_send: PhantomData<*mut libc::c_void>, // `&mut c_void` alone would be Send + Sync
 }
 
 impl<'a> Drop for PirServer<'a> {
@@ -43,21 +69,94 @@ impl<'a> Drop for PirServer<'a> {
 }
 
 impl<'a> PirServer<'a> {
-    pub fn new<T>(collection: &[T], alpha: u64, depth: u64) -> PirServer<'a> {
-        let server_ptr: &'a mut libc::c_void = unsafe {
-            &mut *(cpp_server_setup(
-                (collection.len() * mem::size_of::<T>()) as u64,
-                collection.as_ptr() as *const u8,
-                collection.len() as u64,
-                alpha,
-                depth,
-            ))
+    /// Creates a PIR server over the elements of `collection`, or an error if the PIR library
+    /// could not set one up.
+    pub fn new<T>(collection: &[T], alpha: u64, depth: u64) -> Result<PirServer<'a>, &'static str> {
+        let len = collection.len() * mem::size_of::<T>();
+        let data = unsafe { slice::from_raw_parts(collection.as_ptr() as *const u8, len) };
+
+        PirServer::from_raw(data, collection.len() as u64, alpha, depth)
+    }
+
+    /// Creates a PIR server over `num` equally-sized elements laid out contiguously in `data`.
+    pub fn from_raw(
+        data: &[u8],
+        num: u64,
+        alpha: u64,
+        depth: u64,
+    ) -> Result<PirServer<'a>, &'static str> {
+        let server_ptr = unsafe {
+            cpp_server_setup(data.len() as u64, data.as_ptr(), num, alpha, depth)
         };
 
-        PirServer { server: server_ptr }
+        if server_ptr.is_null() {
+            return Err("PIR library could not set up a server");
+        }
+
+        Ok(PirServer {
+            server: unsafe { &mut *server_ptr },
+            elem_size: if num > 0 { data.len() as u64 / num } else { 0 },
+            num: num,
+            alpha: alpha,
+            depth: depth,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Returns the recursion depth of this server's PIR database.
+    #[inline]
+    pub fn depth(&self) -> u64 {
+        self.depth
+    }
+
+    /// Returns the aggregation (alpha) of this server's PIR database.
+    #[inline]
+    pub fn alpha(&self) -> u64 {
+        self.alpha
+    }
+
+    /// Returns the (length, number of ciphertexts) of the queries that clients generate for
+    /// this server's parameters, or an error if a client could not be set up to find out.
+    /// Results are cached per parameters (and thread).
+    pub fn expected_query(&self) -> Result<(u64, u64), &'static str> {
+        let key = (self.elem_size, self.num, self.alpha, self.depth);
+
+        if let Some(expected) = QUERY_CACHE.with(|cache| cache.borrow().get(&key).cloned()) {
+            return Ok(expected);
+        }
+
+        // The size of a query does not depend on the requested index
+        let client = PirClient::new(self.elem_size, self.num, self.alpha, self.depth)?;
+        let query = client.gen_query(0)?;
+        let expected = (query.query.len() as u64, query.num);
+
+        QUERY_CACHE.with(|cache| cache.borrow_mut().insert(key, expected));
+
+        Ok(expected)
     }
 
-    pub fn gen_answer(&self, query: &[u8], q_num: u64) -> PirAnswer<'a> {
+    /// Returns an error if a query is more than `factor` times larger (in bytes or in number
+    /// of ciphertexts) than the queries clients generate (see `expected_query`). This is meant
+    /// to reject oversized queries before they are processed.
+    pub fn check_query(&self, query: &[u8], q_num: u64, factor: f64) -> Result<(), &'static str> {
+        if self.num == 0 {
+            return Err("PIR query targets an empty collection");
+        }
+
+        let (len, num) = self.expected_query()?;
+
+        if query.len() as f64 > len as f64 * factor {
+            Err("PIR query is larger than expected")
+        } else if q_num as f64 > num as f64 * factor {
+            Err("PIR query has more ciphertexts than expected")
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Answers a query, or returns an error if the PIR library fails to (e.g., on a malformed
+    /// query).
+    pub fn gen_answer(&self, query: &[u8], q_num: u64) -> Result<PirAnswer<'a>, &'static str> {
         let mut a_len: u64 = 0;
         let mut a_num: u64 = 0;
 
@@ -70,12 +169,115 @@ impl<'a> PirServer<'a> {
                 &mut a_len,
                 &mut a_num,
             );
-            slice::from_raw_parts_mut(ptr as *mut u8, a_len as usize)
+            take_buffer(ptr, a_len)?
         };
 
-        PirAnswer {
+        Ok(PirAnswer {
             answer: answer,
             num: a_num,
+        })
+    }
+
+    /// Picks the (alpha, depth) pair with the lowest per-request latency (query generation,
+    /// answer, and decoding) for a collection of `collection_len` elements of `tuple_size`
+    /// bytes by running a quick micro-benchmark. Results are cached per size (and thread).
+    ///
+    /// Note that clients must use the same parameters to decode answers, so the result
+    /// has to be communicated to (or derived identically by) clients.
+    pub fn autotune(collection_len: u64, tuple_size: u64) -> (u64, u64) {
+        assert!(collection_len > 0 && tuple_size > 0);
+
+        let key = (collection_len, tuple_size);
+
+        if let Some(params) = AUTOTUNE_CACHE.with(|cache| cache.borrow().get(&key).cloned()) {
+            return params;
+        }
+
+        // Contents do not affect the cost of PIR
+        let data = vec![0u8; (collection_len * tuple_size) as usize];
+
+        let mut best = (1, 1);
+        let mut best_time: Option<Duration> = None;
+
+        for &depth in &AUTOTUNE_DEPTHS {
+            for &alpha in &AUTOTUNE_ALPHAS {
+                if !valid_params(collection_len, alpha, depth) {
+                    continue;
+                }
+
+                // Parameters that the PIR library fails on are not candidates
+                let elapsed = match bench_params(&data, collection_len, tuple_size, alpha, depth) {
+                    Ok(elapsed) => elapsed,
+                    Err(_) => continue,
+                };
+
+                if best_time.map_or(true, |t| elapsed < t) {
+                    best = (alpha, depth);
+                    best_time = Some(elapsed);
+                }
+            }
+        }
+
+        AUTOTUNE_CACHE.with(|cache| cache.borrow_mut().insert(key, best));
+
+        best
+    }
+}
+
+// Whether XPIR accepts the given parameters (the C++ shim exits on invalid ones)
+fn valid_params(num: u64, alpha: u64, depth: u64) -> bool {
+    if alpha > num {
+        return false;
+    }
+
+    match depth {
+        1 => true,
+        2 => {
+            // Entries must be a multiple of alpha and factor into two dimensions (the shim
+            // picks the smallest factor >= sqrt(x) as one of them).
+            if num % alpha != 0 {
+                return false;
+            }
+
+            let x = num / alpha;
+            let mut n = (x as f64).sqrt().ceil() as u64;
+
+            while n < x && x % n != 0 {
+                n += 1;
+            }
+
+            x / n >= 2
         }
+        _ => false,
     }
 }
+
+// Returns the fastest of a few query/answer/decode runs with the given parameters
+fn bench_params(
+    data: &[u8],
+    num: u64,
+    tuple_size: u64,
+    alpha: u64,
+    depth: u64,
+) -> Result<Duration, &'static str> {
+    let server = PirServer::from_raw(data, num, alpha, depth)?;
+    let client = PirClient::new(tuple_size, num, alpha, depth)?;
+
+    let mut best: Option<Duration> = None;
+
+    for i in 0..AUTOTUNE_TRIALS {
+        let start = Instant::now();
+
+        let query = client.gen_query(i % num)?;
+        let answer = server.gen_answer(query.query, query.num)?;
+        client.decode_answer(answer.answer, answer.num)?;
+
+        let elapsed = start.elapsed();
+
+        if best.map_or(true, |t| elapsed < t) {
+            best = Some(elapsed);
+        }
+    }
+
+    Ok(best.unwrap())
+}
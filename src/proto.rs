@@ -0,0 +1,57 @@
+// Definitions that clients and servers share on top of the RPC schema (schema/pung.capnp):
+// the codes with which failures are tagged and the size of the keys in the key directory.
+
+use capnp::Error;
+
+/// Length (in bytes) of X25519 public and secret keys (see `client::pcrypto::DhKeyPair`),
+/// which is the length of the keys in the server's key directory.
+pub const DH_KEY_SIZE: usize = 32;
+
+/// The kinds of failure that the server (or client) tells apart from the rest (see
+/// `client::PungError`). A failure of one of these kinds is created with `error`, which starts
+/// its description with the code's tag (e.g., "[rate-exceeded] Send rate exceeded.").
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    RateExceeded,
+    InvalidPeer,
+    NotSynchronized,
+    WrongPhase,
+    Corrupted,
+    SchemeMismatch,
+}
+
+const ERROR_CODES: [ErrorCode; 6] = [
+    ErrorCode::RateExceeded,
+    ErrorCode::InvalidPeer,
+    ErrorCode::NotSynchronized,
+    ErrorCode::WrongPhase,
+    ErrorCode::Corrupted,
+    ErrorCode::SchemeMismatch,
+];
+
+impl ErrorCode {
+    fn tag(&self) -> &'static str {
+        match *self {
+            ErrorCode::RateExceeded => "[rate-exceeded] ",
+            ErrorCode::InvalidPeer => "[invalid-peer] ",
+            ErrorCode::NotSynchronized => "[not-synchronized] ",
+            ErrorCode::WrongPhase => "[wrong-phase] ",
+            ErrorCode::Corrupted => "[corrupted] ",
+            ErrorCode::SchemeMismatch => "[scheme-mismatch] ",
+        }
+    }
+
+    /// Returns a failure of this kind described by `description`.
+    pub fn error<T: AsRef<str>>(&self, description: T) -> Error {
+        Error::failed(format!("{}{}", self.tag(), description.as_ref()))
+    }
+
+    /// Splits the description of a failure created with `error` into its code and the rest of
+    /// the description. Returns None for any other description.
+    pub fn parse(description: &str) -> Option<(ErrorCode, &str)> {
+        ERROR_CODES
+            .iter()
+            .find(|code| description.starts_with(code.tag()))
+            .map(|code| (*code, &description[code.tag().len()..]))
+    }
+}
@@ -0,0 +1,210 @@
+// Per-connection view of the RPC server, which keeps track of whether the connection has
+// synced yet (see ServerConfig::max_pending) and of the clients registered through it (so
+// that they can be forgotten once it disconnects, see PungRpc::disconnect, and so that their
+// calls count against their rate limit, see ServerConfig::rpc_limit).
+
+use capnp::Error;
+use gj;
+
+use pung_capnp::pung_rpc;
+use pung_capnp::pung_rpc::{ChangeExtraParams, ChangeExtraResults, CloseParams, CloseResults,
+                           DebugDumpParams, DebugDumpResults, DebugLookupParams, DebugLookupResults,
+                           GetBloomParams, GetBloomResults, GetKeyParams, GetKeyResults,
+                           GetMappingParams, GetMappingResults, PingParams, PingResults,
+                           RegisterParams, RegisterResults, RetrBatchParams, RetrBatchResults,
+                           RetrParams, RetrResults, SendParams, SendResults, StatsParams,
+                           StatsResults, StatusParams, StatusResults, SyncParams, SyncResults};
+use pung_capnp::pung_rpc::Server;
+
+use server::rate_limit::Caller;
+use server::rpc::PungRpc;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// Whether a connection is still pending (i.e., it has not synced yet), and the number of
+/// pending connections it counts towards.
+#[derive(Clone)]
+pub struct Pending {
+    pending: Rc<Cell<bool>>,
+    count: Rc<Cell<usize>>,
+}
+
+impl Pending {
+    /// Counts a new connection as pending.
+    pub fn new(count: Rc<Cell<usize>>) -> Pending {
+        count.set(count.get() + 1);
+
+        Pending {
+            pending: Rc::new(Cell::new(true)),
+            count: count,
+        }
+    }
+
+    /// Stops counting the connection as pending. Only the first call has any effect, so that
+    /// a connection that syncs and later disconnects frees its slot once.
+    pub fn resolve(&self) {
+        if self.pending.get() {
+            self.pending.set(false);
+            self.count.set(self.count.get() - 1);
+        }
+    }
+}
+
+/// The RPC server as seen by one connection. Every call is forwarded to the shared
+/// `PungRpc`; the first successful sync takes the connection out of the pending ones, and
+/// every successful registration adds its id to `registered`. Calls other than sends and
+/// retrievals are first counted against the rate limit of the first client registered
+/// through the connection, or of the connection itself (numbered `connection`) until then.
+pub struct ConnectionRpc {
+    rpc: Rc<RefCell<PungRpc>>,
+    pending: Pending,
+    registered: Rc<RefCell<Vec<u64>>>,
+    connection: u64,
+}
+
+impl ConnectionRpc {
+    pub fn new(
+        rpc: Rc<RefCell<PungRpc>>,
+        pending: Pending,
+        registered: Rc<RefCell<Vec<u64>>>,
+        connection: u64,
+    ) -> ConnectionRpc {
+        ConnectionRpc {
+            rpc: rpc,
+            pending: pending,
+            registered: registered,
+            connection: connection,
+        }
+    }
+
+    fn throttle(&self) -> Result<(), Error> {
+        let caller = match self.registered.borrow().first() {
+            Some(&id) => Caller::Client(id),
+            None => Caller::Connection(self.connection),
+        };
+
+        self.rpc.borrow_mut().throttle(caller)
+    }
+}
+
+impl pung_rpc::Server for ConnectionRpc {
+    fn register(&mut self, params: RegisterParams, res: RegisterResults) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        // Registration is answered right away, so the id it hands out is the next one
+        let id = self.rpc.borrow().next_id();
+        let registered = self.registered.clone();
+
+        self.rpc.borrow_mut().register(params, res).map(move |()| {
+            registered.borrow_mut().push(id);
+            Ok(())
+        })
+    }
+
+    fn sync(&mut self, params: SyncParams, res: SyncResults) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        let pending = self.pending.clone();
+
+        self.rpc.borrow_mut().sync(params, res).map(move |()| {
+            pending.resolve();
+            Ok(())
+        })
+    }
+
+    fn close(&mut self, params: CloseParams, res: CloseResults) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().close(params, res)
+    }
+
+    fn change_extra(
+        &mut self,
+        params: ChangeExtraParams,
+        res: ChangeExtraResults,
+    ) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().change_extra(params, res)
+    }
+
+    fn debug_lookup(
+        &mut self,
+        params: DebugLookupParams,
+        res: DebugLookupResults,
+    ) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().debug_lookup(params, res)
+    }
+
+    fn debug_dump(
+        &mut self,
+        params: DebugDumpParams,
+        res: DebugDumpResults,
+    ) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().debug_dump(params, res)
+    }
+
+    fn ping(&mut self, params: PingParams, res: PingResults) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().ping(params, res)
+    }
+
+    fn get_key(&mut self, params: GetKeyParams, res: GetKeyResults) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().get_key(params, res)
+    }
+
+    fn stats(&mut self, params: StatsParams, res: StatsResults) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().stats(params, res)
+    }
+
+    fn status(&mut self, params: StatusParams, res: StatusResults) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().status(params, res)
+    }
+
+    fn get_mapping(
+        &mut self,
+        params: GetMappingParams,
+        res: GetMappingResults,
+    ) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().get_mapping(params, res)
+    }
+
+    fn get_bloom(
+        &mut self,
+        params: GetBloomParams,
+        res: GetBloomResults,
+    ) -> gj::Promise<(), Error> {
+        pry!(self.throttle());
+
+        self.rpc.borrow_mut().get_bloom(params, res)
+    }
+
+    fn send(&mut self, params: SendParams, res: SendResults) -> gj::Promise<(), Error> {
+        self.rpc.borrow_mut().send(params, res)
+    }
+
+    fn retr(&mut self, params: RetrParams, res: RetrResults) -> gj::Promise<(), Error> {
+        self.rpc.borrow_mut().retr(params, res)
+    }
+
+    fn retr_batch(
+        &mut self,
+        params: RetrBatchParams,
+        res: RetrBatchResults,
+    ) -> gj::Promise<(), Error> {
+        self.rpc.borrow_mut().retr_batch(params, res)
+    }
+}
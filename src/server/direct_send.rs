@@ -0,0 +1,43 @@
+//! Builds each round's database without timely dataflow, for servers with a single worker
+//! (see `server::run_direct`). There is no other worker to broadcast tuples to, so they are
+//! kept until the round ends and then pushed to the database in one batch.
+
+use db;
+use server::send_dataflow;
+use server::timely_shim::{SendFulfillerList, SendPath};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub struct DirectSend {
+    dbase: db::DatabasePtr,
+    tuples: Vec<(db::PungTuple, u32)>, // (tuple, ttl) sent during the current round
+    fulfillers: SendFulfillerList,
+}
+
+impl DirectSend {
+    pub fn new(dbase: db::DatabasePtr) -> DirectSend {
+        DirectSend {
+            dbase: dbase,
+            tuples: Vec::new(),
+            fulfillers: Rc::new(RefCell::new(Vec::new())),
+        }
+    }
+}
+
+impl SendPath for DirectSend {
+    fn send(&mut self, tuple: (db::PungTuple, u32)) {
+        self.tuples.push(tuple);
+    }
+
+    fn fulfillers(&self) -> SendFulfillerList {
+        self.fulfillers.clone()
+    }
+
+    fn finish_round(&mut self, _round: u64) {
+        let db = &mut self.dbase.borrow_mut();
+
+        // Add tuples to the database, each to the bucket its label falls in
+        db.push_batch_with_ttl(self.tuples.drain(..));
+        send_dataflow::build_round(db, &mut self.fulfillers.borrow_mut());
+    }
+}
@@ -0,0 +1,91 @@
+// Saving the key directory (client name -> public key) to a file and loading it back, so that
+// it outlives the server (see ServerConfig::key_file).
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Layout of the file: MAGIC and the number of entries (u32). Then, for each entry (in order
+// of name), the length of the name (u32), the name (UTF-8), the length of the key (u32), and
+// the key. Numbers are big endian.
+const MAGIC: &[u8; 8] = b"PUNGKEY1";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Writes `keys` to `path`. The file is written next to it first and then renamed, so that a
+/// crash midway leaves the previous directory in place rather than a truncated one.
+pub fn save(keys: &HashMap<String, Vec<u8>>, path: &Path) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+
+    {
+        let mut out = BufWriter::new(File::create(&tmp)?);
+
+        let mut names: Vec<&String> = keys.keys().collect();
+        names.sort();
+
+        out.write_all(MAGIC)?;
+        out.write_u32::<BigEndian>(names.len() as u32)?;
+
+        for name in names {
+            let key = &keys[name];
+
+            out.write_u32::<BigEndian>(name.len() as u32)?;
+            out.write_all(name.as_bytes())?;
+            out.write_u32::<BigEndian>(key.len() as u32)?;
+            out.write_all(key)?;
+        }
+
+        out.flush()?;
+    }
+
+    fs::rename(&tmp, path)
+}
+
+/// Reads a key directory written by `save`.
+pub fn load(path: &Path) -> io::Result<HashMap<String, Vec<u8>>> {
+    let mut input = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; 8];
+    input.read_exact(&mut magic)?;
+
+    if &magic != MAGIC {
+        return Err(invalid("Not a saved Pung key directory"));
+    }
+
+    let entries = input.read_u32::<BigEndian>()?;
+    let mut keys = HashMap::new();
+
+    for _ in 0..entries {
+        let name = read_field(&mut input)?;
+        let name = String::from_utf8(name).map_err(|_| invalid("Name is not valid UTF-8"))?;
+        let key = read_field(&mut input)?;
+
+        if keys.insert(name, key).is_some() {
+            return Err(invalid("Duplicate name in the key directory"));
+        }
+    }
+
+    // Anything after the last entry means this is not what save wrote
+    if input.read(&mut [0u8; 1])? > 0 {
+        return Err(invalid("Trailing data after the last entry"));
+    }
+
+    Ok(keys)
+}
+
+// Reads a length-prefixed field. The length is checked against what is left of the file
+// rather than trusted for an allocation up front.
+fn read_field<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let len = input.read_u32::<BigEndian>()? as u64;
+    let mut field = Vec::new();
+
+    if input.by_ref().take(len).read_to_end(&mut field)? as u64 != len {
+        return Err(invalid("Truncated key directory"));
+    }
+
+    Ok(field)
+}
@@ -4,17 +4,47 @@
 //! [timely dataflow] (../../timely/index.html).
 //!
 //! #RPC interface
-//! The Pung server exposes four RPC calls:
+//! The Pung server exposes the following RPC calls (see schema/pung.capnp):
 //!
 //! **register**: allows clients to register with the Pung server.
 //!
-//! **sync**: allows clients to obtain the current round number and to create or update their
-//! Diffie-Hellman public component and retrieval rate.
+//! **sync**: allows clients to obtain the current round number and to update their send rate.
 //!
 //! **send**: allows clients to send a list of [PungTuples](../db/struct.PungTuple.html).
 //!
 //! **retr**: allows clients to send a retrieval request and obtain a
-//! [PungTuple](../db/struct.PungTuple.html).
+//! [PungTuple](../db/struct.PungTuple.html). When `debug` is set, clients may also ask for
+//! the checksums of the queried tuples to verify the PIR answer.
+//!
+//! **getMapping**: returns the labels of the current round's buckets (Explicit), packed if
+//! the client registered with compression. retr can piggyback them on its answer instead.
+//!
+//! **getBloom**: like getMapping, but returns a Bloom filter of each bucket's labels (Bloom).
+//!
+//! **close**: unregisters a client.
+//!
+//! **changeExtra**: changes the number of extra tuples the server adds to every round. This
+//! is a test hook, only available when `allow_change_extra` is set (`--allow-change-extra`).
+//!
+//! **stats**: reports statistics about the server (e.g., PIR answer latencies when built
+//! with the `pir-timing` feature).
+//!
+//! **debugLookup**: reports whether a label is in a bucket. This is *not* private and is
+//! only available when `debug` is set (`--debug`).
+//!
+//! **debugDump**: returns all tuples sent to a bucket. Like debugLookup, this is *not*
+//! private and is only available when `debug` is set.
+//!
+//! **ping**: echoes a nonce, along with the server's clock, so that clients can check that
+//! the server is alive.
+//!
+//! **getKey**: returns the public key that a client registered under a given name.
+//!
+//! **status**: reports the current round and phase, the number of registered clients, and
+//! the size of the current round's database.
+//!
+//! **retrBatch**: like retr, but answers several PIR queries to one bucket (e.g., a level of
+//! every collection of a bucket) in a single round trip. Each query counts as a retrieval.
 
 use capnp;
 use capnp_rpc;
@@ -28,7 +58,11 @@ use gjio;
 use pung_capnp::pung_rpc;
 
 use std;
+use std::cell::{Cell, RefCell};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
 
 // Naiad
 use timely::dataflow::scopes::root::Root;
@@ -36,19 +70,93 @@ use timely_communication::allocator::generic::Generic;
 
 pub mod timely_shim;
 pub mod send_dataflow;
+pub mod direct_send;
+pub mod key_dir;
 mod rpc;
+mod connection;
+mod rate_limit;
 mod reaper;
 
+pub use self::rate_limit::RpcLimit;
+
 use db;
+use server::connection::ConnectionRpc;
 use server::rpc::PungRpc;
 
+/// Default bound on the size of retrieval queries, as a multiple of the size of the queries
+/// that clients generate for the targeted collection (see `ServerConfig`).
+pub const DEFAULT_MAX_QUERY_FACTOR: f64 = 2.0;
+
+// Number of client connections that are open and that are pending (i.e., have not synced
+// yet), and the most that may be (see `ServerConfig::max_connections` and `max_pending`)
+#[derive(Clone)]
+struct ConnectionLimit {
+    open: Rc<Cell<usize>>,
+    max: Option<usize>,
+    pending: Rc<Cell<usize>>,
+    max_pending: Option<usize>,
+}
+
+impl ConnectionLimit {
+    // Takes up a slot for a new connection, or returns None if there are none left (or too
+    // many connections are pending). The slot is given back when the guard is dropped (i.e.,
+    // when the connection's task completes); the pending slot also when the connection syncs.
+    fn acquire(&self) -> Option<ConnectionGuard> {
+        if let Some(max) = self.max {
+            if self.open.get() >= max {
+                return None;
+            }
+        }
+
+        if let Some(max_pending) = self.max_pending {
+            if self.pending.get() >= max_pending {
+                return None;
+            }
+        }
+
+        self.open.set(self.open.get() + 1);
+
+        Some(ConnectionGuard {
+            open: self.open.clone(),
+            pending: connection::Pending::new(self.pending.clone()),
+        })
+    }
+}
+
+struct ConnectionGuard {
+    open: Rc<Cell<usize>>,
+    pending: connection::Pending,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.open.set(self.open.get() - 1);
+        self.pending.resolve();
+    }
+}
+
 fn accept_loop(
     listener: gjio::SocketListener,
     mut task_set: gj::TaskSet<(), capnp::Error>,
-    conn: pung_rpc::Client,
+    rpc: Rc<RefCell<PungRpc>>,
+    limit: ConnectionLimit,
 ) -> gj::Promise<(), std::io::Error> {
     // Accept an incoming connection
     listener.accept().then(move |stream| {
+        // Refuse connections beyond the limits by closing them right away
+        let guard = match limit.acquire() {
+            Some(guard) => guard,
+            None => {
+                warn!(
+                    "Refusing connection: {} connections are open ({} pending)",
+                    limit.open.get(),
+                    limit.pending.get()
+                );
+                drop(stream);
+                return accept_loop(listener, task_set, rpc, limit);
+            }
+        };
+
         let mut reader_options: capnp::message::ReaderOptions = Default::default();
         reader_options.traversal_limit_in_words(300 * 1024 * 1024);
 
@@ -61,17 +169,207 @@ fn accept_loop(
         );
         let disconnect_promise = network.on_disconnect();
 
-        // Clone connection for each client, and create rpc context
-        let rpc_context = RpcSystem::new(Box::new(network), Some(conn.clone().client));
+        // Every client gets its own view of the shared RPC server, which notices when it syncs
+        // and which clients register through it
+        let connection = rpc.borrow_mut().open_connection();
+        let registered = Rc::new(RefCell::new(Vec::new()));
+        let server = ConnectionRpc::new(
+            rpc.clone(),
+            guard.pending.clone(),
+            registered.clone(),
+            connection,
+        );
+        let conn = pung_rpc::ToClient::new(server).from_server::<capnp_rpc::Server>();
+
+        // Create rpc context
+        let rpc_context = RpcSystem::new(Box::new(network), Some(conn.client));
+
+        // Clients registered through the connection are forgotten once it disconnects
+        // (cleanly or not), so that the round does not wait for them
+        let departed = rpc.clone();
+        let disconnect_promise = disconnect_promise.map_else(move |result| {
+            let mut rpc = departed.borrow_mut();
+            rpc.close_connection(connection);
+
+            for &id in registered.borrow().iter() {
+                rpc.disconnect(id);
+            }
+
+            result
+        });
 
-        // Add the rpc conext + connection to the set of tasks
-        task_set.add(disconnect_promise.attach(rpc_context));
+        // Add the rpc conext + connection to the set of tasks (the connection's slot is
+        // freed once it disconnects)
+        task_set.add(disconnect_promise.attach((rpc_context, guard)));
 
         // Go back to accepting other connections
-        accept_loop(listener, task_set, conn)
+        accept_loop(listener, task_set, rpc, limit)
     })
 }
 
+// How often the server checks whether the send phase has gone on for too long (see
+// `ServerConfig::round_timeout`)
+const ROUND_TIMEOUT_CHECK_MS: u64 = 100;
+
+// Ends the send phase of rounds that exceed the round timeout, checking periodically
+fn round_timeout_loop(
+    timer: gjio::Timer,
+    rpc: Rc<RefCell<PungRpc>>,
+) -> gj::Promise<(), std::io::Error> {
+    timer.after_delay(Duration::from_millis(ROUND_TIMEOUT_CHECK_MS)).then(move |()| {
+        rpc.borrow_mut().check_round_timeout();
+        round_timeout_loop(timer, rpc)
+    })
+}
+
+/// Configuration of a Pung server (see src/bin/server.rs for the corresponding flags).
+/// `ServerConfig::new` fills in defaults for everything but the schemes and the number of
+/// buckets.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Retrieval scheme
+    pub ret_scheme: db::RetScheme,
+
+    /// Optimization scheme
+    pub opt_scheme: db::OptScheme,
+
+    /// Number of buckets
+    pub buckets: usize,
+
+    /// Maximum PIR recursion depth (default 1)
+    pub depth: u64,
+
+    /// PIR aggregation of every level (default: derived from each level's size), which must
+    /// be at least 1. Clients learn it when they register (see `db::Database::set_alpha`).
+    pub alpha: Option<u64>,
+
+    /// Random tuples added to every round (default 0)
+    pub extra_tuples: usize,
+
+    /// Whether clients may change `extra_tuples` (testing only)
+    pub allow_change_extra: bool,
+
+    /// Whether non-private debugging RPCs (and verified retrievals) are allowed
+    /// (diagnostics only)
+    pub debug: bool,
+
+    /// Whether to corrupt every PIR answer, so that retrievals silently fail to find what they
+    /// look for (testing only, e.g., of `PungClient::retr_self_check`)
+    #[cfg(feature = "fault-injection")]
+    pub corrupt_answers: bool,
+
+    /// Messages the server waits for before ending the send phase (default 1)
+    pub min_messages: u32,
+
+    /// If > 0, the fixed number of clients that take part in every round
+    pub expected_clients: u32,
+
+    /// Past rounds kept for late retrievals (default 0)
+    pub retain_rounds: u32,
+
+    /// Retrieval queries that are more than this many times larger than the queries clients
+    /// generate for the targeted collection are rejected without being processed
+    pub max_query_factor: f64,
+
+    /// How many rounds ahead of the current one clients may send for (default 1). Sends for
+    /// future rounds are queued until the round starts; sends for rounds further ahead, and
+    /// sends beyond a client's send rate for a queued round, are rejected, which bounds the
+    /// queue.
+    pub send_lookahead: u64,
+
+    /// Seed of the extra tuples. They are drawn from OS randomness unless a seed is given,
+    /// in which case they are the same every time (for reproducible experiments, at the
+    /// cost of privacy).
+    pub tuple_seed: Option<u64>,
+
+    /// Most client connections open at once (default unlimited). Further connections are
+    /// closed as soon as they are accepted, until some of the open ones close.
+    pub max_connections: Option<usize>,
+
+    /// Most connections that have not synced yet (default unlimited). Like connections over
+    /// `max_connections`, further connections are closed as soon as they are accepted, which
+    /// bounds the resources that clients can hold without ever taking part in a round.
+    pub max_pending: Option<usize>,
+
+    /// Hash functions of the bloom filters (default: the optimal number for `db::BLOOM_FP`).
+    /// Clients learn it when they register (see `db::Database::set_bloom_hashes`).
+    pub bloom_hashes: Option<u32>,
+
+    /// False positive probability of the bloom filters (default `db::BLOOM_FP`), which must
+    /// be in (0, 1). Clients learn it when they register (see `db::Database::set_bloom_fp`).
+    pub bloom_fp: f64,
+
+    /// File to which the layout of the database (bucket and collection sizes, first labels,
+    /// and lmids) is appended at the end of every send phase (default none). This *leaks*
+    /// the labels of the round and is only meant for debugging.
+    pub dump_db_after_round: Option<PathBuf>,
+
+    /// Longest the send phase of a round may last, counted from the first tuple sent in it
+    /// (default none: the server waits for every client). Once it elapses, the tuples that
+    /// clients have yet to send are replaced with random ones and the round moves on to the
+    /// receive phase, so that a client that stops sending cannot stall everyone else.
+    pub round_timeout: Option<Duration>,
+
+    /// How often each client may call the server (default unlimited). Calls beyond the limit
+    /// fail with an `overloaded` error. Sends and retrievals are bounded by the send and
+    /// retrieval rates instead, so they are not counted.
+    pub rpc_limit: Option<RpcLimit>,
+
+    /// File in which the key directory (the public keys that clients register) is kept
+    /// (default none: keys only live in memory). It is loaded when the server starts, if it
+    /// exists, and rewritten whenever a key changes, so that peers can still look up keys
+    /// registered before a restart (see `key_dir`).
+    pub key_file: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn new(
+        ret_scheme: db::RetScheme,
+        opt_scheme: db::OptScheme,
+        buckets: usize,
+    ) -> ServerConfig {
+        ServerConfig {
+            ret_scheme: ret_scheme,
+            opt_scheme: opt_scheme,
+            buckets: buckets,
+            depth: 1,
+            alpha: None,
+            extra_tuples: 0,
+            allow_change_extra: false,
+            debug: false,
+            #[cfg(feature = "fault-injection")]
+            corrupt_answers: false,
+            min_messages: 1,
+            expected_clients: 0,
+            retain_rounds: 0,
+            max_query_factor: DEFAULT_MAX_QUERY_FACTOR,
+            send_lookahead: 1,
+            tuple_seed: None,
+            max_connections: None,
+            max_pending: None,
+            bloom_hashes: None,
+            bloom_fp: db::BLOOM_FP,
+            dump_db_after_round: None,
+            round_timeout: None,
+            rpc_limit: None,
+            key_file: None,
+        }
+    }
+
+    /// Creates an empty database laid out as configured, or an error if the schemes and
+    /// depth are not a supported combination (see `db::check_config`), the bloom filters'
+    /// false positive rate is invalid (see `db::check_bloom_fp`), or alpha is 0.
+    pub fn database<'a>(&self) -> Result<db::Database<'a>, &'static str> {
+        let mut dbase =
+            db::Database::new(self.ret_scheme, self.opt_scheme, self.buckets, self.depth)?;
+        dbase.set_bloom_hashes(self.bloom_hashes);
+        dbase.set_bloom_fp(self.bloom_fp)?;
+        dbase.set_alpha(self.alpha)?;
+
+        Ok(dbase)
+    }
+}
+
 /// Launches an RPC server that interfaces with timely dataflow via
 /// a [shim layer](timely_shim/index.html) that captures inputs and outputs.
 /// The RPC server is in charge of passing inputs to timely dataflow via
@@ -80,16 +378,43 @@ fn accept_loop(
 /// The RPC server is also required to instruct the timely worker to
 /// perform computational steps on the provided data via calls to step in
 /// [timely::dataflow::scopes::root::Root](../../timely/dataflow/scopes/root/struct.Root.html).
-pub fn run_rpc(
+///
+/// This only returns if the server cannot start, e.g., because `addr` is taken or the key file
+/// (see `ServerConfig::key_file`) cannot be loaded.
+pub fn run(
     addr: SocketAddr,
     worker: Root<Generic>,
     send: timely_shim::SendHandler,
     dbase: db::DatabasePtr,
-    extra_tuples: usize,
-    min_messages: u32,
-    opt_scheme: db::OptScheme,
-) {
-    // Event-loop for RPC. This never returns.
+    config: ServerConfig,
+) -> Result<(), capnp::Error> {
+    let send = timely_shim::TimelySend {
+        handler: send,
+        worker: worker,
+    };
+
+    serve(addr, Box::new(send), dbase, config)
+}
+
+/// Like `run`, but without timely dataflow: tuples go straight into `dbase`. This avoids
+/// the overhead of the dataflow when there is a single worker (and thus nothing to
+/// broadcast), which is all that this supports. Clients see no difference.
+pub fn run_direct(
+    addr: SocketAddr,
+    dbase: db::DatabasePtr,
+    config: ServerConfig,
+) -> Result<(), capnp::Error> {
+    let send = direct_send::DirectSend::new(dbase.clone());
+    serve(addr, Box::new(send), dbase, config)
+}
+
+fn serve(
+    addr: SocketAddr,
+    send: Box<timely_shim::SendPath>,
+    dbase: db::DatabasePtr,
+    config: ServerConfig,
+) -> Result<(), capnp::Error> {
+    // Event-loop for RPC. This only returns if the server fails to start.
 
     gj::EventLoop::top_level(move |wait_scope| -> Result<(), capnp::Error> {
         // create event port
@@ -100,21 +425,52 @@ pub fn run_rpc(
         // create a listener for Pung's RPC server
         let listener = address.listen()?;
 
-        // instance of the pung RPC server
-        let connection = pung_rpc::ToClient::new(PungRpc::new(
-            worker,
-            send,
-            dbase,
-            extra_tuples,
-            min_messages,
-            opt_scheme,
-        )).from_server::<capnp_rpc::Server>();
+        // instance of the pung RPC server, shared by all connections
+        let rpc = Rc::new(RefCell::new(PungRpc::new(send, dbase, &config)?));
 
         // defines a set that holds all promises ("tasks") and a destructor in case they go awry
-        let task_set = gj::TaskSet::new(Box::new(reaper::Reaper));
+        let mut task_set = gj::TaskSet::new(Box::new(reaper::Reaper));
+
+        if config.round_timeout.is_some() {
+            let timeouts = round_timeout_loop(event_port.get_timer(), rpc.clone());
+            task_set.add(timeouts.map_err(capnp::Error::from));
+        }
+
+        let limit = ConnectionLimit {
+            open: Rc::new(Cell::new(0)),
+            max: config.max_connections,
+            pending: Rc::new(Cell::new(0)),
+            max_pending: config.max_pending,
+        };
 
-        accept_loop(listener, task_set, connection).wait(wait_scope, &mut event_port)?;
+        accept_loop(listener, task_set, rpc, limit).wait(wait_scope, &mut event_port)?;
 
         Ok(())
-    }).expect("top level error running server RPC");
+    })
+}
+
+/// Like `run`, but with the configuration given as separate arguments (the rest of it is
+/// `ServerConfig`'s defaults).
+#[deprecated(note = "use server::run with a ServerConfig")]
+pub fn run_rpc(
+    addr: SocketAddr,
+    worker: Root<Generic>,
+    send: timely_shim::SendHandler,
+    dbase: db::DatabasePtr,
+    extra_tuples: usize,
+    min_messages: u32,
+    opt_scheme: db::OptScheme,
+) {
+    let (ret_scheme, buckets) = {
+        let db = dbase.borrow();
+        (db.ret_scheme(), db.num_buckets())
+    };
+
+    let config = ServerConfig {
+        extra_tuples: extra_tuples,
+        min_messages: min_messages,
+        ..ServerConfig::new(ret_scheme, opt_scheme, buckets)
+    };
+
+    run(addr, worker, send, dbase, config).expect("top level error running server RPC");
 }
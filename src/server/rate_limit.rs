@@ -0,0 +1,93 @@
+// Per-client limits on how often the server may be called (see ServerConfig::rpc_limit).
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// How often each client may call the server: every client (or, before it registers, every
+/// connection) has a bucket of `burst` tokens, each call takes one, and tokens come back at
+/// `refill_per_sec` per second. Sends and retrievals are not counted, since the send and
+/// retrieval rates already bound them every round.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RpcLimit {
+    /// Calls that a client may make at once (at least 1)
+    pub burst: u32,
+
+    /// Calls per second that a client may keep making (greater than 0)
+    pub refill_per_sec: f64,
+}
+
+impl RpcLimit {
+    /// A limit of `refill_per_sec` calls per second, of which up to `burst` may come at once.
+    pub fn new(burst: u32, refill_per_sec: f64) -> Result<RpcLimit, &'static str> {
+        if burst == 0 {
+            Err("Burst must be at least 1")
+        } else if !(refill_per_sec > 0.0) || !refill_per_sec.is_finite() {
+            Err("Refill rate must be a positive number of calls per second")
+        } else {
+            Ok(RpcLimit {
+                burst: burst,
+                refill_per_sec: refill_per_sec,
+            })
+        }
+    }
+}
+
+/// Whose calls a token bucket counts.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Caller {
+    /// A registered client (the first one registered through the connection)
+    Client(u64),
+
+    /// A connection through which no client has registered yet
+    Connection(u64),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant, // when tokens was last brought up to date
+}
+
+/// Token buckets of every caller.
+pub struct RateLimiter {
+    limit: RpcLimit,
+    buckets: HashMap<Caller, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RpcLimit) -> RateLimiter {
+        RateLimiter {
+            limit: limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the caller's bucket (which starts full), returning whether there
+    /// was one.
+    pub fn allow(&mut self, caller: Caller) -> bool {
+        let now = Instant::now();
+        let limit = self.limit;
+
+        let bucket = self.buckets.entry(caller).or_insert_with(|| TokenBucket {
+            tokens: limit.burst as f64,
+            last: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+        bucket.tokens = (bucket.tokens + secs * limit.refill_per_sec).min(limit.burst as f64);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forgets the bucket of a caller that is gone.
+    pub fn forget(&mut self, caller: Caller) {
+        self.buckets.remove(&caller);
+    }
+}
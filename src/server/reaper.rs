@@ -7,6 +7,6 @@ pub struct Reaper;
 
 impl gj::TaskReaper<(), capnp::Error> for Reaper {
     fn task_failed(&mut self, error: capnp::Error) {
-        println!("Task failed: {}", error);
+        warn!("Task failed: {}", error);
     }
 }
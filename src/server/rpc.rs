@@ -1,27 +1,39 @@
 // Implementation of the server's RPC call (each timely dataflow worker is an RPC server)
 
+use capnp;
 use capnp::Error;
 
 use db;
 use gj;
+use proto;
+use proto::ErrorCode;
 
 // RPC Stubs
 use pung_capnp::pung_rpc;
 use pung_capnp::pung_rpc::{ChangeExtraParams, ChangeExtraResults, CloseParams, CloseResults,
-                           GetBloomParams, GetBloomResults, GetMappingParams, GetMappingResults,
-                           RegisterParams, RegisterResults, RetrParams, RetrResults, SendParams,
-                           SendResults, SyncParams, SyncResults};
+                           DebugDumpParams, DebugDumpResults, DebugLookupParams, DebugLookupResults,
+                           GetBloomParams, GetBloomResults, GetKeyParams, GetKeyResults,
+                           GetMappingParams, GetMappingResults, PingParams, PingResults,
+                           RegisterParams, RegisterResults, RetrBatchParams, RetrBatchResults,
+                           RetrParams, RetrResults, SendParams, SendResults, StatsParams,
+                           StatsResults, StatusParams, StatusResults, SyncParams, SyncResults};
+use pung_capnp::Phase as RoundPhase;
+use pung_capnp::Piggyback;
 
-use rand::ChaChaRng;
 use rand::Rng;
+use rand::{ChaChaRng, OsRng, SeedableRng};
+use server::key_dir;
+use server::rate_limit::{Caller, RateLimiter};
 use server::timely_shim;
-use std::collections::HashMap;
+use server::ServerConfig;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-
-// Naiad libraries
-use timely::dataflow::scopes::root::Root;
-use timely::progress::timestamp::RootTimestamp;
-use timely_communication::allocator::generic::Generic;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use util;
 
@@ -34,9 +46,9 @@ enum Phase {
 
 struct SendCtx {
     reqs: HashMap<u64, u32>, // client id -> requests received so far
-    // map from round number to (id, tuple, fulfiller) tuple for queuing requests
-    queue: HashMap<u64, Vec<(u64, Vec<db::PungTuple>, timely_shim::SendFulfiller)>>,
-    handler: timely_shim::SendHandler,
+    // map from round number to (id, (tuple, ttl) list, fulfiller) tuple for queuing requests
+    queue: HashMap<u64, Vec<(u64, Vec<(db::PungTuple, u32)>, timely_shim::SendFulfiller)>>,
+    handler: Box<timely_shim::SendPath>,
     count: u32,
 }
 
@@ -44,47 +56,95 @@ struct RetCtx {
     reqs: HashMap<u64, u32>, // client id -> requests received so far
 }
 
-pub struct PungRpc {
+// A past round whose database is kept around for late retrievals
+struct RetainedRound {
     round: u64,
-    clients: HashMap<u64, u32>, // client id -> request rate
+    dbase: db::Database<'static>,
+    reqs: HashMap<u64, u32>, // client id -> retrievals made so far
+}
 
-    worker: Root<Generic>,
+pub struct PungRpc {
+    round: u64,
+    clients: HashMap<u64, u32>,     // client id -> request rate
+    ret_rates: HashMap<u64, u32>,   // client id -> retrieval rate (absent if the default)
+    compressed: HashSet<u64>,       // clients that negotiated compressed responses
+    names: HashMap<u64, String>,    // client id -> name under which its public key is listed
+    keys: HashMap<String, Vec<u8>>, // client name -> public key (the key directory)
+    key_file: Option<PathBuf>,      // where the key directory is kept (see save_keys)
+    shards: HashMap<u64, (u32, u32)>, // client id -> (shard, shards) of sharded clients
+    helpers: HashSet<u64>,            // retrieval-only registrations (shard > 0, see register)
+    ids_issued: u64,                  // registrations so far (ids are never reused)
+    connections: u64,                 // connections opened so far (see open_connection)
+    limiter: Option<RateLimiter>,     // per-caller limit on calls (see throttle)
 
     phase: Phase,
     send_ctx: SendCtx,
     ret_ctx: RetCtx,
 
     dbase: db::DatabasePtr,
+    retained: VecDeque<RetainedRound>, // oldest first
+    retain_rounds: u32,                // number of past rounds kept for late retrievals
 
     extra_tuples: Vec<db::PungTuple>, // blows up the collection size by extra_tuples.len()
+    allow_change_extra: bool,         // whether clients may change extra_tuples (testing only)
+    debug: bool,                      // whether non-private debugging RPCs are allowed
+    #[cfg(feature = "fault-injection")]
+    corrupt_answers: bool, // whether PIR answers are corrupted (testing only)
 
     min_messages: u32, // hack to prevent server from advancing round until all clients have sent
+    expected_clients: u32, // if > 0, the fixed number of clients that take part in every round
+    max_query_factor: f64, // queries larger than this many times the expected size are rejected
+    send_lookahead: u64, // how many rounds ahead of the current one clients may send for
+    bloom_hashes: Option<u32>, // hash functions of the bloom filters (told to clients)
+    bloom_fp: f64,             // false positive rate of the bloom filters (told to clients)
+    alpha: Option<u64>,        // PIR aggregation of every level, if set (told to clients)
+    dump_db_after_round: Option<PathBuf>, // where each round's layout is written (debugging)
+    round_timeout: Option<Duration>, // longest a send phase may last (see check_round_timeout)
+    send_deadline: Option<Instant>,  // when the current send phase times out (once it started)
+    pir_timings: util::stats::PirTimings, // only populated with the pir-timing feature
+    rng: ChaChaRng, // source of extra tuples (seeded with --tuple-seed, or from the OS)
     opt_scheme: db::OptScheme,
 }
 
 
 impl PungRpc {
+    /// Sets up the server's state for `config`, or returns an error if its key file (see
+    /// `ServerConfig::key_file`) exists but cannot be loaded.
     pub fn new(
-        worker: Root<Generic>,
-        send: timely_shim::SendHandler,
+        send: Box<timely_shim::SendPath>,
         dbase: db::DatabasePtr,
-        extra: usize,
-        min_messages: u32,
-        opt_scheme: db::OptScheme,
-    ) -> PungRpc {
-        let mut extra_tuples = Vec::with_capacity(extra);
-        let mut rng = ChaChaRng::new_unseeded();
-
-        for _ in 0..extra {
-            let mut temp = [0u8; db::TUPLE_SIZE];
-            rng.fill_bytes(&mut temp);
-            extra_tuples.push(db::PungTuple::new(&temp[..]));
-        }
-
-        PungRpc {
+        config: &ServerConfig,
+    ) -> io::Result<PungRpc> {
+        // Padding is only reproducible when asked for (it is otherwise predictable)
+        let mut rng = match config.tuple_seed {
+            Some(seed) => ChaChaRng::from_seed(&[seed as u32, (seed >> 32) as u32]),
+            None => OsRng::new().expect("OS randomness is unavailable").gen::<ChaChaRng>(),
+        };
+
+        let extra_tuples = random_tuples(&mut rng, config.extra_tuples);
+
+        // Keys registered before a restart are still listed
+        let keys = match config.key_file {
+            Some(ref path) if path.exists() => key_dir::load(path).map_err(|e| {
+                let msg = format!("Could not load the key directory from {}", path.display());
+                io::Error::new(e.kind(), format!("{}: {}", msg, e))
+            })?,
+            _ => HashMap::new(),
+        };
+
+        Ok(PungRpc {
             round: 0,
             clients: HashMap::new(),
-            worker: worker,
+            ret_rates: HashMap::new(),
+            compressed: HashSet::new(),
+            names: HashMap::new(),
+            keys: keys,
+            key_file: config.key_file.clone(),
+            shards: HashMap::new(),
+            helpers: HashSet::new(),
+            ids_issued: 0,
+            connections: 0,
+            limiter: config.rpc_limit.map(RateLimiter::new),
             phase: Phase::Sending,
             send_ctx: SendCtx {
                 reqs: HashMap::new(), // gets updated every round
@@ -96,31 +156,672 @@ impl PungRpc {
                 reqs: HashMap::new(),
             },
             dbase: dbase,
+            retained: VecDeque::new(),
+            retain_rounds: config.retain_rounds,
             extra_tuples: extra_tuples,
-            min_messages: min_messages,
-            opt_scheme: opt_scheme,
+            allow_change_extra: config.allow_change_extra,
+            debug: config.debug,
+            #[cfg(feature = "fault-injection")]
+            corrupt_answers: config.corrupt_answers,
+            min_messages: config.min_messages,
+            expected_clients: config.expected_clients,
+            max_query_factor: config.max_query_factor,
+            send_lookahead: config.send_lookahead,
+            bloom_hashes: config.bloom_hashes,
+            bloom_fp: config.bloom_fp,
+            alpha: config.alpha,
+            dump_db_after_round: config.dump_db_after_round.clone(),
+            round_timeout: config.round_timeout,
+            send_deadline: None,
+            pir_timings: util::stats::PirTimings::new(),
+            rng: rng,
+            opt_scheme: config.opt_scheme,
+        })
+    }
+
+    /// Ends the send phase of the current round if it has lasted longer than the round
+    /// timeout (see `ServerConfig::round_timeout`). Clients that have not sent all of their
+    /// tuples by then get random tuples in place of the missing ones (like the extra tuples),
+    /// so the size of the round does not reveal who failed to send.
+    pub fn check_round_timeout(&mut self) {
+        let expired = match self.send_deadline {
+            Some(deadline) => Instant::now() >= deadline,
+            None => false,
+        };
+
+        if !expired || self.phase != Phase::Sending {
+            return;
+        }
+
+        // With aliasing, every tuple a client sends is stored under two labels
+        let alias = if self.opt_scheme >= db::OptScheme::Aliasing {
+            2
+        } else {
+            1
+        };
+
+        let stalled: Vec<(u64, u32)> = self.send_ctx
+            .reqs
+            .iter()
+            .filter(|&(_, &left)| left > 0)
+            .map(|(&id, &left)| (id, left))
+            .collect();
+
+        for (id, left) in stalled {
+            warn!(
+                "Round {} timed out: padding {} tuples that client {} did not send",
+                self.round,
+                left,
+                id
+            );
+
+            for t in random_tuples(&mut self.rng, (left * alias) as usize) {
+                self.send_ctx.count += 1;
+                self.send_ctx.handler.send((t, 0));
+            }
+
+            self.send_ctx.reqs.insert(id, 0);
         }
+
+        self.end_send_phase();
     }
 
-    pub fn max_retries(&self, buckets: usize) -> u32 {
-        match self.opt_scheme {
-            db::OptScheme::Normal => retry_bound!(buckets),
-            db::OptScheme::Aliasing => retry_bound!(buckets, 2),
-            db::OptScheme::Hybrid2 => retry_bound!(buckets, 2) / 2,
-            db::OptScheme::Hybrid4 => 1,
+    // Adds the extra tuples, lays out the round's database, and moves on to the receive phase
+    fn end_send_phase(&mut self) {
+        debug!(
+            "Ending the send phase of round {} ({} tuples sent, {} extra)",
+            self.round,
+            self.send_ctx.count,
+            self.extra_tuples.len()
+        );
+
+        for t in &self.extra_tuples {
+            self.send_ctx.handler.send((t.clone(), 0));
+        }
+
+        self.send_ctx.handler.finish_round(self.round);
+
+        if let Some(ref path) = self.dump_db_after_round {
+            if let Err(e) = dump_layout(path, self.round, &self.dbase.borrow()) {
+                error!("Could not dump the database to {}: {}", path.display(), e);
+            }
+        }
+
+        let quotas: Vec<(u64, u32)> = {
+            let db = self.dbase.borrow();
+
+            // Update the number of expected retrievals per client.
+            self.ret_ctx
+                .reqs
+                .keys()
+                .map(|&id| (id, self.ret_quota(id, &db)))
+                .collect()
+        };
+
+        for (id, quota) in quotas {
+            self.ret_ctx.reqs.insert(id, quota);
+        }
+
+        self.send_deadline = None;
+        self.phase = Phase::Receiving;
+    }
+
+    // Checks what a retrieval (retr or retrBatch) must satisfy regardless of its round
+    fn check_retrieval(&self, id: u64, bucket_idx: usize, verify: bool) -> Result<(), Error> {
+        if verify && !self.debug {
+            return Err(Error::failed("Answer verification is disabled".to_string()));
+        }
+
+        if !self.clients.contains_key(&id) && !self.helpers.contains(&id) {
+            return Err(ErrorCode::NotSynchronized.error("Invalid id during send."));
+        }
+
+        // Sharded clients retrieve each bucket from one worker, which accounts for it
+        if let Some(&(shard, shards)) = self.shards.get(&id) {
+            if !in_shard(bucket_idx, shard, shards) {
+                return Err(Error::failed(format!(
+                    "Bucket {} is not in shard {} of {}",
+                    bucket_idx,
+                    shard,
+                    shards
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks that the client may make `num` more retrievals from the current round
+    fn check_current_round(&self, id: u64, round: u64, num: u32) -> Result<(), Error> {
+        if round != self.round {
+            Err(ErrorCode::WrongPhase.error("Invalid round number"))
+        } else if self.phase != Phase::Receiving {
+            Err(ErrorCode::WrongPhase.error("Invalid phase for retrieval"))
+        } else if !self.ret_ctx.reqs.contains_key(&id) {
+            Err(ErrorCode::NotSynchronized.error("(ret) Client is not synchronized."))
+        } else if self.ret_ctx.reqs[&id] < num {
+            Err(ErrorCode::RateExceeded.error("retrieveal rate exceeded."))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Accounts for `num` retrievals from the current round, and moves on to the next round
+    // once every client has made all of its retrievals
+    fn account_retrievals(&mut self, id: u64, num: u32) {
+        if let Some(entry) = self.ret_ctx.reqs.get_mut(&id) {
+            *entry -= num;
+        }
+
+        self.end_receive_phase_if_done();
+    }
+
+    // Moves on to the next round once every participant has made all of its retrievals
+    fn end_receive_phase_if_done(&mut self) {
+        if self.ret_ctx.reqs.values().any(|&x| x > 0) {
+            return;
+        }
+
+        let dbase = self.dbase.clone();
+        let mut db = dbase.borrow_mut();
+
+        if self.retain_rounds > 0 {
+            // Keep this round around for late retrievals (dropping the oldest one)
+            self.retained.push_back(RetainedRound {
+                round: self.round,
+                dbase: db.take(),
+                reqs: HashMap::new(),
+            });
+
+            while self.retained.len() > self.retain_rounds as usize {
+                self.retained.pop_front();
+            }
+        } else {
+            db.clear(); // Garbage collect the whole thing
+        }
+
+        self.send_ctx.reqs = self.clients.clone();
+        self.send_ctx.count = 0;
+        self.round += 1;
+        self.phase = Phase::Sending;
+
+        // Evict tuples in retained rounds whose TTL has elapsed. A round without PIR after
+        // that cannot serve retrievals, so it is no longer retained.
+        let round = self.round;
+        let mut failed = Vec::new();
+
+        for (i, past) in self.retained.iter_mut().enumerate() {
+            if let Err(e) = past.dbase.evict_expired((round - past.round) as u32) {
+                error!("Dropping retained round {}: {}", past.round, e);
+                failed.push(i);
+            }
+        }
+
+        for i in failed.into_iter().rev() {
+            self.retained.remove(i);
         }
+
+        info!("Advancing to round {}", self.round);
+    }
+
+    pub fn max_retries(&self, buckets: usize) -> u32 {
+        util::max_retries(self.opt_scheme, buckets as u64)
+    }
+
+    // Number of retrievals a client makes per round. Clients that retrieve more labels than
+    // there are buckets (e.g., for cover traffic) make as many retrievals as if there were
+    // that many buckets. Sharded clients only retrieve from the buckets of their shard.
+    fn ret_quota(&self, id: u64, db: &db::Database) -> u32 {
+        let buckets = db.num_buckets();
+
+        let rate = match self.ret_rates.get(&id) {
+            Some(&r) => cmp::max(r as usize, buckets),
+            None => buckets,
+        };
+
+        let total_dbs = match self.shards.get(&id) {
+            Some(&(shard, shards)) => (0..buckets)
+                .filter(|&b| in_shard(b, shard, shards))
+                .map(|b| db.get_bucket(b).total_dbs())
+                .sum(),
+            None => db.total_dbs(),
+        };
+
+        total_dbs as u32 * self.max_retries(rate)
     }
 
+    /// Id that the next successful registration gets.
     pub fn next_id(&self) -> u64 {
-        self.clients.len() as u64
+        self.ids_issued
+    }
+
+    // When clients are pre-registered, the set of participants is fixed once all of them
+    // have registered. Otherwise participants are whoever synced this round.
+    fn fixed_clients(&self) -> bool {
+        self.expected_clients > 0
+    }
+
+    // Whether no tuples have been sent (or queued) for the current round yet
+    fn is_clean_round(&self) -> bool {
+        self.phase == Phase::Sending && self.send_ctx.count == 0 && self.send_ctx.queue.is_empty()
+    }
+
+    // Whether all expected clients (if any) have registered
+    fn all_registered(&self) -> bool {
+        self.clients.len() as u32 >= self.expected_clients
+    }
+
+    // Whether the send phase can end: every participant has sent all of its tuples
+    fn all_sent(&self) -> bool {
+        !self.send_ctx.reqs.values().any(|&x| x > 0) && self.phase == Phase::Sending
+            && self.send_ctx.count >= self.min_messages && self.all_registered()
+    }
+
+    // Forgets everything about a client (or helper), returning whether it was known
+    fn remove_client(&mut self, id: u64) -> bool {
+        if !self.clients.contains_key(&id) && !self.helpers.contains(&id) {
+            return false;
+        }
+
+        self.clients.remove(&id);
+        self.helpers.remove(&id);
+        self.shards.remove(&id);
+        self.ret_rates.remove(&id);
+        self.compressed.remove(&id);
+
+        // Unless a later registration took over the name, its key goes with the client
+        if let Some(name) = self.names.remove(&id) {
+            if !self.names.values().any(|n| *n == name) {
+                self.keys.remove(&name);
+                self.save_keys();
+            }
+        }
+
+        self.send_ctx.reqs.remove(&id);
+        self.ret_ctx.reqs.remove(&id);
+
+        true
+    }
+
+    /// Numbers a new connection, whose calls are rate limited under that number until a
+    /// client registers through it (see `throttle`).
+    pub fn open_connection(&mut self) -> u64 {
+        self.connections += 1;
+        self.connections - 1
+    }
+
+    /// Forgets the rate limit of a connection that is gone (see `open_connection`).
+    pub fn close_connection(&mut self, connection: u64) {
+        if let Some(ref mut limiter) = self.limiter {
+            limiter.forget(Caller::Connection(connection));
+        }
+    }
+
+    /// Counts a call against the caller's limit (see `ServerConfig::rpc_limit`), returning
+    /// an `overloaded` error if it has made too many calls lately.
+    pub fn throttle(&mut self, caller: Caller) -> Result<(), Error> {
+        match self.limiter {
+            Some(ref mut limiter) if !limiter.allow(caller) => {
+                debug!("Throttling a call from {:?}", caller);
+                Err(Error::overloaded("Too many requests. Try again later.".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets a client whose connection was lost, as `close` would, so that the round does
+    /// not wait for it forever: its sends queued for later rounds are dropped, and the send
+    /// or receive phase ends if the other participants are done. A send phase to which no
+    /// tuples were sent yet goes on, as it would without the client. Unknown ids (e.g., of
+    /// clients that closed before disconnecting) are ignored.
+    pub fn disconnect(&mut self, id: u64) {
+        if let Some(ref mut limiter) = self.limiter {
+            limiter.forget(Caller::Client(id));
+        }
+
+        if !self.remove_client(id) {
+            return;
+        }
+
+        info!("Client {} disconnected during round {}", id, self.round);
+
+        for queued in self.send_ctx.queue.values_mut() {
+            queued.retain(|&(cid, _, _)| cid != id);
+        }
+
+        self.send_ctx.queue.retain(|_, queued| !queued.is_empty());
+
+        match self.phase {
+            Phase::Sending => {
+                if self.send_ctx.count > 0 && self.all_sent() {
+                    self.end_send_phase();
+                }
+            }
+
+            Phase::Receiving => self.end_receive_phase_if_done(),
+        }
+    }
+
+    // Database of a past round, if it is still retained
+    fn retained_db(&self, round: u64) -> Option<&db::Database<'static>> {
+        self.retained
+            .iter()
+            .find(|r| r.round == round)
+            .map(|r| &r.dbase)
+    }
+
+    // Writes the key directory to the key file, if any, after a key changed. A failure does
+    // not affect the directory in memory, so it is only logged.
+    fn save_keys(&self) {
+        if let Some(ref path) = self.key_file {
+            if let Err(e) = key_dir::save(&self.keys, path) {
+                error!("Could not save the key directory to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    // Whether PIR answers are corrupted (only ever with the fault-injection feature)
+    #[cfg(feature = "fault-injection")]
+    fn corrupt_answers(&self) -> bool {
+        self.corrupt_answers
+    }
+
+    #[cfg(not(feature = "fault-injection"))]
+    fn corrupt_answers(&self) -> bool {
+        false
+    }
+}
+
+
+// Whether a bucket is one of those that a sharded client retrieves from the worker on which
+// it registered `shard` (see register)
+fn in_shard(bucket: usize, shard: u32, shards: u32) -> bool {
+    bucket % shards as usize == shard as usize
+}
+
+// Generates `num` random tuples (used to pad the database)
+fn random_tuples(rng: &mut ChaChaRng, num: usize) -> Vec<db::PungTuple> {
+    let mut tuples = Vec::with_capacity(num);
+
+    for _ in 0..num {
+        let mut temp = [0u8; db::TUPLE_SIZE];
+        rng.fill_bytes(&mut temp);
+        tuples.push(db::PungTuple::new(&temp[..]));
+    }
+
+    tuples
+}
+
+// Parses one tuple of a send request. With aliasing, the client sends (label1, label2, cipher,
+// mac), which is stored as two tuples, one under each label. The data comes from the client,
+// so its length is checked rather than trusted.
+fn parse_tuple(data: &[u8], aliasing: bool) -> Result<Vec<db::PungTuple>, Error> {
+    let to_err = |e: &'static str| Error::failed(e.to_string());
+
+    if !aliasing {
+        return Ok(vec![db::PungTuple::try_new(data).map_err(to_err)?]);
+    }
+
+    if data.len() != db::TUPLE_SIZE + db::LABEL_SIZE {
+        return Err(to_err("Invalid tuple length"));
+    }
+
+    let offset = db::LABEL_SIZE;
+    let mut tuple_alias_data = Vec::with_capacity(db::TUPLE_SIZE);
+    tuple_alias_data.extend_from_slice(&data[..offset]);
+    tuple_alias_data.extend_from_slice(&data[offset * 2..]);
+
+    Ok(vec![
+        db::PungTuple::try_new(&tuple_alias_data[..]).map_err(to_err)?,
+        db::PungTuple::try_new(&data[offset..]).map_err(to_err)?,
+    ])
+}
+
+// Answers a PIR query against a collection of the given database, handing the answer (as
+// sent, i.e., compressed if asked for) and its number of ciphertexts to `write`. The answer
+// is only borrowed from the PIR library, so that write can copy it straight into the response.
+#[cfg_attr(not(feature = "pir-timing"), allow(unused_variables))]
+#[cfg_attr(not(feature = "fault-injection"), allow(unused_variables))]
+fn answer_query(
+    dbase: &db::Database,
+    bucket_idx: usize,
+    collection_idx: usize,
+    level_idx: usize,
+    query: &[u8],
+    q_num: u64,
+    max_query_factor: f64,
+    compress: bool,
+    corrupt: bool,
+    timings: &mut util::stats::PirTimings,
+    write: &mut FnMut(&[u8], u64),
+) -> Result<(), Error> {
+    if bucket_idx >= dbase.num_buckets() {
+        return Err(Error::failed("invalid bucket requested".to_string()));
+    }
+
+    let bucket = dbase.get_bucket(bucket_idx);
+
+    if collection_idx >= bucket.num_collections() {
+        return Err(Error::failed("invalid collection requested".to_string()));
+    }
+
+    let collection = bucket.get_collection(collection_idx);
+
+    if level_idx >= collection.num_levels() {
+        return Err(Error::failed("invalid level requested".to_string()));
+    }
+
+    {
+        let pir_handler = match collection.pir_handler(level_idx) {
+            Ok(handler) => handler,
+            Err(e) => return Err(Error::failed(e.to_string())),
+        };
+
+        // Reject oversized queries before handing them to PIR
+        if let Err(e) = pir_handler.check_query(query, q_num, max_query_factor) {
+            return Err(Error::failed(e.to_string()));
+        }
+
+        #[cfg(feature = "pir-timing")]
+        let start = Instant::now();
+
+        #[cfg_attr(not(feature = "fault-injection"), allow(unused_mut))]
+        let mut answer = match pir_handler.gen_answer(query, q_num) {
+            Ok(answer) => answer,
+            Err(e) => return Err(Error::failed(e.to_string())),
+        };
+
+        #[cfg(feature = "pir-timing")]
+        {
+            let elapsed = start.elapsed();
+
+            timings.record(
+                bucket.unencoded_len() as u64,
+                pir_handler.alpha(),
+                level_idx as u32,
+                elapsed,
+            );
+        }
+
+        // Every bit flipped decodes into garbage, which the client takes for another tuple
+        #[cfg(feature = "fault-injection")]
+        {
+            if corrupt {
+                for b in answer.answer.iter_mut() {
+                    *b = !*b;
+                }
+            }
+        }
+
+        if compress {
+            write(&util::rle::compress(answer.answer), answer.num);
+        } else {
+            write(answer.answer, answer.num);
+        }
+
+        Ok(())
     }
 }
 
+// Writes the labels of each collection in `label_collections` of every bucket
+fn write_labels(
+    dbase: &db::Database,
+    label_collections: &[usize],
+    mut collection_list: capnp::list_list::Builder<capnp::data_list::Owned>,
+) {
+    let mut collection_idx = 0;
+
+    for bucket in dbase.get_buckets() {
+        for i in label_collections {
+            let collection = bucket.get_collection(*i);
+            let mut label_list = collection_list
+                .borrow()
+                .init(collection_idx, collection.len() as u32);
+
+            for j in 0..collection.len() {
+                label_list.set(j as u32, collection.get_label(j));
+            }
+
+            collection_idx += 1;
+        }
+    }
+}
+
+// Like write_labels, but each collection's labels are concatenated and compressed into
+// a single blob
+fn write_packed(
+    dbase: &db::Database,
+    label_collections: &[usize],
+    mut packed_list: capnp::data_list::Builder,
+) {
+    let mut collection_idx = 0;
+
+    for bucket in dbase.get_buckets() {
+        for i in label_collections {
+            let collection = bucket.get_collection(*i);
+            let mut labels = Vec::with_capacity(collection.len() * db::LABEL_SIZE);
+
+            for j in 0..collection.len() {
+                labels.extend_from_slice(collection.get_label(j));
+            }
+
+            packed_list.set(collection_idx, &util::rle::compress(&labels));
+            collection_idx += 1;
+        }
+    }
+}
+
+// Writes the bloom filter of each collection in `label_collections` of every bucket
+fn write_blooms(
+    dbase: &db::Database,
+    label_collections: &[usize],
+    compress: bool,
+    mut collection_list: capnp::data_list::Builder,
+) {
+    let mut collection_idx = 0;
+
+    for bucket in dbase.get_buckets() {
+        for i in label_collections {
+            let collection = bucket.get_collection(*i);
+            let bloom = collection.get_bloom().to_bytes();
+
+            if compress {
+                collection_list.set(collection_idx, &util::rle::compress(&bloom));
+            } else {
+                collection_list.set(collection_idx, &bloom);
+            }
+
+            collection_idx += 1;
+        }
+    }
+}
+
+// Writes the filters that a retrieval asked for along with its answer
+fn write_piggyback(
+    dbase: &db::Database,
+    opt_scheme: db::OptScheme,
+    piggyback: Piggyback,
+    compress: bool,
+    res: &mut RetrResults,
+) {
+    let label_collections: Vec<usize> = util::label_collections(opt_scheme);
+    let num_lists = (dbase.num_buckets() * label_collections.len()) as u32;
+
+    match piggyback {
+        Piggyback::None => (),
+        Piggyback::Mapping => {
+            if compress {
+                write_packed(dbase, &label_collections, res.get().init_packed(num_lists));
+            } else {
+                write_labels(dbase, &label_collections, res.get().init_labels(num_lists));
+            }
+        }
+        Piggyback::Bloom => {
+            write_blooms(
+                dbase,
+                &label_collections,
+                compress,
+                res.get().init_blooms(num_lists),
+            );
+        }
+    }
+}
+
+// Returns the checksum of every tuple in the queried level (which answer_query has
+// validated). This reveals nothing about the query, only the (public) size of the level.
+fn level_checksums(
+    dbase: &db::Database,
+    bucket_idx: usize,
+    collection_idx: usize,
+    level_idx: usize,
+) -> Vec<u32> {
+    let collection = dbase.get_bucket(bucket_idx).get_collection(collection_idx);
+    let level = collection.get_level(level_idx).expect("level was validated");
+
+    level.iter().map(|tuple| tuple.checksum()).collect()
+}
+
+// Writes the checksums of a level (see level_checksums) into a list of as many entries
+fn write_checksums(checksums: &[u32], mut list: capnp::primitive_list::Builder<u32>) {
+    for (i, checksum) in checksums.iter().enumerate() {
+        list.set(i as u32, *checksum);
+    }
+}
+
+// Appends the layout of the round's database (see ServerConfig::dump_db_after_round) to the
+// file at path. Each bucket lists its collections, with their first label and lmid if any.
+fn dump_layout(path: &Path, round: u64, dbase: &db::Database) -> io::Result<()> {
+    let mut out = OpenOptions::new().create(true).append(true).open(path)?;
+
+    writeln!(out, "round {}", round)?;
+
+    for (i, bucket) in dbase.get_buckets().enumerate() {
+        writeln!(out, "bucket {}: {} tuples", i, bucket.len())?;
+
+        for (j, summary) in bucket.layout_summary().iter().enumerate() {
+            let kind = if summary.is_systematic { "systematic" } else { "parity" };
+            write!(out, "  collection {}: {} tuples ({})", j, summary.len, kind)?;
+
+            if let Some(ref label) = summary.first_label {
+                write!(out, ", first label {}", util::secret::to_hex(label))?;
+            }
+
+            if let Some(ref lmid) = summary.lmid {
+                write!(out, ", lmid {}", util::secret::to_hex(lmid))?;
+            }
+
+            writeln!(out, "")?;
+        }
+    }
+
+    Ok(())
+}
+
+
 
 // Implementation of RPC stubs (see schema/pung.capnp)
 
 impl pung_rpc::Server for PungRpc {
-    // TODO: Upgrade this to receive keys for directory service
     fn register(
         &mut self,
         params: RegisterParams,
@@ -128,28 +829,152 @@ impl pung_rpc::Server for PungRpc {
     ) -> gj::Promise<(), Error> {
         let req = pry!(params.get());
         let rate: u32 = req.get_rate();
+        let ret_rate: u32 = req.get_ret_rate();
+        let client_buckets: u32 = req.get_buckets();
+        let name = pry!(req.get_name()).to_string();
+        let public_key = pry!(req.get_public_key());
+        let shard: u32 = req.get_shard();
+        let shards: u32 = cmp::max(req.get_shards(), 1);
+        let buckets = self.dbase.borrow().num_buckets() as u32;
         let id: u64 = self.next_id();
 
+        // Shards other than the first only retrieve (see schema/pung.capnp)
+        let helper = shard > 0;
+
         if rate == 0 {
             return gj::Promise::err(Error::failed("Invalid rate (0)".to_string()));
+        } else if client_buckets != 0 && client_buckets != buckets {
+            return gj::Promise::err(Error::failed(format!(
+                "Client partitions labels into {} buckets but the server has {}",
+                client_buckets,
+                buckets
+            )));
+        } else if ret_rate != 0 && ret_rate < buckets {
+            return gj::Promise::err(Error::failed(format!(
+                "Invalid retrieval rate ({}). It must be at least the number of buckets ({})",
+                ret_rate,
+                buckets
+            )));
+        } else if shard >= shards {
+            return gj::Promise::err(Error::failed(format!(
+                "Invalid shard {} of {}",
+                shard,
+                shards
+            )));
+        } else if helper && !public_key.is_empty() {
+            return gj::Promise::err(Error::failed(
+                "Only the first shard of a client may register a public key".to_string(),
+            ));
+        } else if !helper && self.fixed_clients() && self.all_registered() {
+            return gj::Promise::err(Error::failed(
+                "All expected clients have already registered".to_string(),
+            ));
+        } else if !public_key.is_empty() && name.is_empty() {
+            return gj::Promise::err(Error::failed(
+                "A public key must be registered under a name".to_string(),
+            ));
+        } else if !public_key.is_empty() && public_key.len() != proto::DH_KEY_SIZE {
+            return gj::Promise::err(Error::failed("Invalid public key length".to_string()));
+        }
+
+        if helper {
+            // Never sends, but takes part in the receive phase of every round from now on
+            self.helpers.insert(id);
+            self.ret_ctx.reqs.insert(id, 0);
+        } else {
+            self.clients.insert(id, rate);
+        }
+
+        if shards > 1 {
+            self.shards.insert(id, (shard, shards));
+        }
+
+        // The latest registration under a name is the one whose key peers get
+        if !public_key.is_empty() {
+            self.keys.insert(name.clone(), public_key.to_vec());
+            self.names.insert(id, name);
+            self.save_keys();
+        }
+
+        if ret_rate > 0 {
+            self.ret_rates.insert(id, ret_rate);
         }
 
-        self.clients.insert(id, rate);
+        // Compression is always available; echo back whether it is in use
+        let compress = req.get_compress();
+
+        if compress {
+            self.compressed.insert(id);
+        }
+
+        res.get().set_compress(compress);
+        res.get().set_bloom_hashes(self.bloom_hashes.unwrap_or(0));
+        res.get().set_bloom_fp(self.bloom_fp);
+        res.get().set_alpha(self.alpha.unwrap_or(0));
+
+        // Expected clients take part in the current round from the moment they register
+        if self.fixed_clients() && !helper {
+            self.send_ctx.reqs.insert(id, rate);
+            self.ret_ctx.reqs.insert(id, 0);
+        }
+
+        self.ids_issued += 1;
+
         res.get().set_id(id);
         gj::Promise::ok(())
     }
 
-    // TODO: upgrade to be able to replace directory service key
     fn sync(&mut self, params: SyncParams, mut res: SyncResults) -> gj::Promise<(), Error> {
-        let id = pry!(params.get()).get_id();
+        let req = pry!(params.get());
+        let id = req.get_id();
+        let rate = req.get_rate();
+        let public_key = pry!(req.get_public_key());
 
         if !self.clients.contains_key(&id) {
-            return gj::Promise::err(Error::failed("Invalid id during sync".to_string()));
+            return gj::Promise::err(ErrorCode::NotSynchronized.error("Invalid id during sync"));
+        }
+
+        // A new public key (empty keeps the current one). Only clients that registered a key
+        // have a name under which to replace it.
+        if !public_key.is_empty() {
+            if public_key.len() != proto::DH_KEY_SIZE {
+                return gj::Promise::err(Error::failed("Invalid public key length".to_string()));
+            }
+
+            match self.names.get(&id) {
+                Some(name) => {
+                    self.keys.insert(name.clone(), public_key.to_vec());
+                    self.save_keys();
+                }
+
+                None => {
+                    return gj::Promise::err(Error::failed(
+                        "Client registered without a public key".to_string(),
+                    ));
+                }
+            }
+        }
+
+        // A new send rate (0 keeps the current one). It also applies to the current round's
+        // sends if the client has not sent any yet.
+        if rate > 0 {
+            let old_rate = self.clients.insert(id, rate).unwrap();
+
+            if self.phase == Phase::Sending {
+                if let Some(entry) = self.send_ctx.reqs.get_mut(&id) {
+                    if *entry == old_rate {
+                        *entry = rate;
+                    }
+                }
+            }
         }
 
         // If we are already in receive phase, client has to wait for next send phase to begin
         if self.phase == Phase::Receiving {
             res.get().set_round(self.round + 1);
+        } else if self.fixed_clients() {
+            // The set of participants was fixed at registration
+            res.get().set_round(self.round);
         } else {
             self.send_ctx.reqs.entry(id).or_insert(self.clients[&id]);
             self.ret_ctx.reqs.entry(id).or_insert(0);
@@ -164,18 +989,8 @@ impl pung_rpc::Server for PungRpc {
         let req = pry!(params.get());
         let id: u64 = req.get_id();
 
-        if !self.clients.contains_key(&id) {
-            return gj::Promise::err(Error::failed("Id does not exist".to_string()));
-        }
-
-        self.clients.remove(&id);
-
-        if self.send_ctx.reqs.contains_key(&id) {
-            self.send_ctx.reqs.remove(&id);
-        }
-
-        if self.ret_ctx.reqs.contains_key(&id) {
-            self.ret_ctx.reqs.remove(&id);
+        if !self.remove_client(id) {
+            return gj::Promise::err(ErrorCode::NotSynchronized.error("Id does not exist"));
         }
 
         res.get().set_success(true);
@@ -190,89 +1005,222 @@ impl pung_rpc::Server for PungRpc {
         let req = pry!(params.get());
         let extra: u64 = req.get_extra();
 
-        let mut extra_tuples = Vec::with_capacity(extra as usize);
-        let mut rng = ChaChaRng::new_unseeded();
-
-        for _ in 0..extra {
-            let mut temp = [0u8; db::TUPLE_SIZE];
-            rng.fill_bytes(&mut temp);
-            extra_tuples.push(db::PungTuple::new(&temp[..]));
+        // Changing the padding affects every client, so it is an admin-only test hook and
+        // it may not change in the middle of a round.
+        if !self.allow_change_extra {
+            return gj::Promise::err(Error::failed("Changing extra tuples is disabled".to_string()));
+        } else if !self.is_clean_round() {
+            return gj::Promise::err(Error::failed(
+                "Cannot change extra tuples while sends are in flight".to_string(),
+            ));
         }
 
-        self.extra_tuples = extra_tuples;
+        self.extra_tuples = random_tuples(&mut self.rng, extra as usize);
 
         res.get().set_success(true);
         gj::Promise::ok(())
     }
 
-    fn get_mapping(
+    // This reveals labels to the server, so it is only for diagnosing why a client cannot
+    // find a message and it is only accepted when the server is started with --debug.
+    fn debug_lookup(
         &mut self,
-        params: GetMappingParams,
-        mut res: GetMappingResults,
+        params: DebugLookupParams,
+        mut res: DebugLookupResults,
     ) -> gj::Promise<(), Error> {
-        let round = pry!(params.get()).get_round();
+        if !self.debug {
+            return gj::Promise::err(Error::failed("Debugging RPCs are disabled".to_string()));
+        }
 
-        if round != self.round {
-            return gj::Promise::err(Error::failed("Invalid round number".to_string()));
-        } else if self.phase != Phase::Receiving {
-            return gj::Promise::err(Error::failed("Not a receive phase".to_string()));
+        let req = pry!(params.get());
+        let bucket_idx = req.get_bucket() as usize;
+        let label: &[u8] = pry!(req.get_label());
+
+        let db = self.dbase.borrow();
+
+        if bucket_idx >= db.num_buckets() {
+            return gj::Promise::err(Error::failed("invalid bucket requested".to_string()));
         }
 
+        if let Some((collection, index)) = db.get_bucket(bucket_idx).find_by_label(label) {
+            res.get().set_found(true);
+            res.get().set_collection(collection as u32);
+            res.get().set_index(index as u64);
+        }
+
+        gj::Promise::ok(())
+    }
+
+    fn debug_dump(
+        &mut self,
+        params: DebugDumpParams,
+        mut res: DebugDumpResults,
+    ) -> gj::Promise<(), Error> {
+        if !self.debug {
+            return gj::Promise::err(Error::failed("Debugging RPCs are disabled".to_string()));
+        }
+
+        let bucket_idx = pry!(params.get()).get_bucket() as usize;
+
         let db = self.dbase.borrow();
-        // Indices of collections that contain meaningful labels
-        let label_collections: Vec<usize> = util::label_collections(self.opt_scheme);
 
-        let mut collection_list = res.get()
-            .init_labels((db.num_buckets() * label_collections.len()) as u32);
-        let mut collection_idx = 0;
+        if bucket_idx >= db.num_buckets() {
+            return gj::Promise::err(Error::failed("invalid bucket requested".to_string()));
+        }
+
+        let bucket = db.get_bucket(bucket_idx);
+        let tuples: Vec<&db::PungTuple> = util::label_collections(self.opt_scheme)
+            .into_iter()
+            .flat_map(|i| bucket.get_collection(i).get_tuples())
+            .collect();
 
-        for bucket in db.get_buckets() {
-            for i in &label_collections {
-                let collection = bucket.get_collection(*i);
-                let mut label_list = collection_list
-                    .borrow()
-                    .init(collection_idx, collection.len() as u32);
+        let mut tuple_list = res.get().init_tuples(tuples.len() as u32);
 
-                for j in 0..collection.len() {
-                    label_list.set(j as u32, collection.get_label(j));
-                }
+        for (i, tuple) in tuples.iter().enumerate() {
+            tuple_list.set(i as u32, &tuple.data[..]);
+        }
+
+        gj::Promise::ok(())
+    }
 
-                collection_idx += 1;
+    // Stateless: this does not look at (or change) clients or the current round
+    fn ping(&mut self, params: PingParams, mut res: PingResults) -> gj::Promise<(), Error> {
+        let nonce = pry!(params.get()).get_nonce();
+
+        // A clock set before the epoch is reported as 0
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let millis = now.as_secs() * 1000 + (now.subsec_nanos() / 1_000_000) as u64;
+
+        let mut res = res.get();
+        res.set_nonce(nonce);
+        res.set_time(millis);
+        gj::Promise::ok(())
+    }
+
+    // Like ping, this does not require registering (peers may look up keys before they do)
+    fn get_key(&mut self, params: GetKeyParams, mut res: GetKeyResults) -> gj::Promise<(), Error> {
+        let name = pry!(pry!(params.get()).get_name());
+
+        match self.keys.get(name) {
+            Some(key) => {
+                res.get().set_public_key(key);
+                gj::Promise::ok(())
+            }
+
+            None => gj::Promise::err(Error::failed(format!(
+                "Invalid peer {}: no public key is registered under that name",
+                name
+            ))),
+        }
+    }
+
+    fn stats(&mut self, _params: StatsParams, mut res: StatsResults) -> gj::Promise<(), Error> {
+        {
+            let db = self.dbase.borrow();
+            res.get().set_tuples(db.len() as u64);
+            res.get().set_unencoded_tuples(db.unencoded_len() as u64);
+            res.get().set_duplicate_labels(db.duplicate_labels() as u64);
+        }
+
+        let timings = self.pir_timings.to_vec();
+        let mut timing_list = res.get().init_pir_timings(timings.len() as u32);
+
+        for (i, timing) in timings.iter().enumerate() {
+            let mut entry = timing_list.borrow().get(i as u32);
+            entry.set_bucket_size(timing.bucket_size);
+            entry.set_alpha(timing.alpha);
+            entry.set_level(timing.level);
+            entry.set_count(timing.latency.count);
+            entry.set_total_usec(timing.latency.total_usec);
+
+            let mut histogram = entry.init_histogram(timing.latency.buckets.len() as u32);
+
+            for (j, bucket) in timing.latency.buckets.iter().enumerate() {
+                histogram.set(j as u32, *bucket);
             }
         }
 
         gj::Promise::ok(())
     }
 
-    fn get_bloom(
+    // Read-only, so it may be called in any phase and leaves the round's accounting alone
+    fn status(&mut self, _params: StatusParams, mut res: StatusResults) -> gj::Promise<(), Error> {
+        res.get().set_round(self.round);
+        res.get().set_phase(match self.phase {
+            Phase::Sending => RoundPhase::Sending,
+            Phase::Receiving => RoundPhase::Receiving,
+        });
+        res.get().set_clients(self.clients.len() as u64);
+
+        let db = self.dbase.borrow();
+        res.get().set_tuples(db.len() as u64);
+        res.get().set_total_dbs(db.total_dbs() as u64);
+
+        gj::Promise::ok(())
+    }
+
+    fn get_mapping(
         &mut self,
-        params: GetBloomParams,
-        mut res: GetBloomResults,
+        params: GetMappingParams,
+        mut res: GetMappingResults,
     ) -> gj::Promise<(), Error> {
-        let round = pry!(params.get()).get_round();
+        let req = pry!(params.get());
+        let round = req.get_round();
+        let compress = self.compressed.contains(&req.get_id());
+
+        // Either the current round (once it is receiving) or a retained past round
+        let current;
+        let db: &db::Database = if round == self.round && self.phase == Phase::Receiving {
+            current = self.dbase.borrow();
+            &*current
+        } else if let Some(past) = self.retained_db(round) {
+            past
+        } else if round == self.round {
+            return gj::Promise::err(ErrorCode::WrongPhase.error("Not a receive phase"));
+        } else {
+            return gj::Promise::err(ErrorCode::WrongPhase.error("Invalid round number"));
+        };
 
-        if round != self.round {
-            return gj::Promise::err(Error::failed("Invalid round number".to_string()));
-        } else if self.phase != Phase::Receiving {
-            return gj::Promise::err(Error::failed("Not a receive phase".to_string()));
+        // Indices of collections that contain meaningful labels
+        let label_collections: Vec<usize> = util::label_collections(self.opt_scheme);
+        let num_lists = (db.num_buckets() * label_collections.len()) as u32;
+
+        if compress {
+            write_packed(db, &label_collections, res.get().init_packed(num_lists));
+        } else {
+            write_labels(db, &label_collections, res.get().init_labels(num_lists));
         }
 
-        let db = self.dbase.borrow();
+        gj::Promise::ok(())
+    }
+
+    fn get_bloom(
+        &mut self,
+        params: GetBloomParams,
+        mut res: GetBloomResults,
+    ) -> gj::Promise<(), Error> {
+        let req = pry!(params.get());
+        let round = req.get_round();
+        let compress = self.compressed.contains(&req.get_id());
+
+        // Either the current round (once it is receiving) or a retained past round
+        let current;
+        let db: &db::Database = if round == self.round && self.phase == Phase::Receiving {
+            current = self.dbase.borrow();
+            &*current
+        } else if let Some(past) = self.retained_db(round) {
+            past
+        } else if round == self.round {
+            return gj::Promise::err(ErrorCode::WrongPhase.error("Not a receive phase"));
+        } else {
+            return gj::Promise::err(ErrorCode::WrongPhase.error("Invalid round number"));
+        };
 
         // Indices of collections that contain meaningful labels
         let label_collections: Vec<usize> = util::label_collections(self.opt_scheme);
+        let num_lists = (db.num_buckets() * label_collections.len()) as u32;
 
-        let mut collection_list = res.get()
-            .init_blooms((db.num_buckets() * label_collections.len()) as u32);
-        let mut collection_idx = 0;
-
-        for bucket in db.get_buckets() {
-            for i in &label_collections {
-                let collection = bucket.get_collection(*i);
-                collection_list.set(collection_idx, &collection.get_bloom().to_bytes());
-                collection_idx += 1;
-            }
-        }
+        write_blooms(db, &label_collections, compress, res.get().init_blooms(num_lists));
 
         gj::Promise::ok(())
     }
@@ -285,11 +1233,23 @@ impl pung_rpc::Server for PungRpc {
 
         // Ensure client is allowed to send.
         if !self.clients.contains_key(&id) {
-            return gj::Promise::err(Error::failed("Invalid id during send.".to_string()));
+            return gj::Promise::err(ErrorCode::NotSynchronized.error("Invalid id during send."));
         } else if round < self.round {
-            return gj::Promise::err(Error::failed("Invalid round number.".to_string()));
+            return gj::Promise::err(ErrorCode::WrongPhase.error(format!(
+                "Round {} is in the past (the current round is {})",
+                round,
+                self.round
+            )));
+        } else if round - self.round > self.send_lookahead {
+            return gj::Promise::err(ErrorCode::WrongPhase.error(format!(
+                "Round {} is too far ahead (the current round is {}, and sends may be queued \
+                 for at most {} rounds ahead)",
+                round,
+                self.round,
+                self.send_lookahead
+            )));
         } else if self.phase != Phase::Sending && round == self.round {
-            return gj::Promise::err(Error::failed("Not sending phase.".to_string()));
+            return gj::Promise::err(ErrorCode::WrongPhase.error("Not sending phase."));
         }
 
 
@@ -304,68 +1264,71 @@ impl pung_rpc::Server for PungRpc {
 
             let tuple_data_list = pry!(req.get_tuples());
 
-            let send_fulfillers = &mut self.send_ctx.handler.fulfillers.borrow_mut();
+            // TTLs are optional. If present, there is one per tuple.
+            let ttl_list = pry!(req.get_ttls());
 
-            if round > self.round {
-                // Queue request if round > self.round
-                let queue_list = &mut self.send_ctx.queue.entry(round).or_insert_with(Vec::new);
+            if ttl_list.len() != 0 && ttl_list.len() != tuple_data_list.len() {
+                return gj::Promise::err(Error::failed(
+                    "Number of TTLs does not match number of tuples".to_string(),
+                ));
+            }
 
-                let mut tuple_list: Vec<db::PungTuple> =
-                    Vec::with_capacity(tuple_data_list.len() as usize);
+            let ttl_of = |i: u32| if ttl_list.len() == 0 { 0 } else { ttl_list.get(i) };
 
-                for i in 0..tuple_data_list.len() {
-                    let tuple_data = pry!(tuple_data_list.get(i));
-                    let mut offset: usize = 0;
+            // Parse every tuple before acting on any, so that a malformed one rejects the
+            // whole request instead of leaving part of it in the database
+            let aliasing = self.opt_scheme >= db::OptScheme::Aliasing;
+            let mut tuple_list: Vec<(db::PungTuple, u32)> =
+                Vec::with_capacity(tuple_data_list.len() as usize);
 
-                    // If power of two, clone the tuple under the two provided labels
-                    // The format of the message is: (label1, label2, cipher, mac)
-                    if self.opt_scheme >= db::OptScheme::Aliasing {
-                        offset = db::LABEL_SIZE;
-                        let mut tuple_alias_data = Vec::with_capacity(db::TUPLE_SIZE);
-                        tuple_alias_data.extend_from_slice(&tuple_data[..offset]);
-                        tuple_alias_data.extend_from_slice(&tuple_data[offset * 2..]);
+            for i in 0..tuple_data_list.len() {
+                let tuple_data = pry!(tuple_data_list.get(i));
 
-                        tuple_list.push(db::PungTuple::new(&tuple_alias_data[..]));
-                    }
+                for tuple in pry!(parse_tuple(tuple_data, aliasing)) {
+                    tuple_list.push((tuple, ttl_of(i)));
+                }
+            }
+
+            let fulfillers = self.send_ctx.handler.fulfillers();
+            let send_fulfillers = &mut fulfillers.borrow_mut();
+
+            if round > self.round {
+                // Queue request if round > self.round. Rounds are at most send_lookahead
+                // ahead, and each client may queue at most its send rate for each of them,
+                // which bounds the queue (the rate is checked again once the round starts).
+                let alias = if aliasing { 2 } else { 1 };
+                let rate = self.clients[&id] as usize * alias;
 
-                    tuple_list.push(db::PungTuple::new(&tuple_data[offset..]));
+                let queue_list = &mut self.send_ctx.queue.entry(round).or_insert_with(Vec::new);
+                let queued: usize = queue_list
+                    .iter()
+                    .filter(|&&(cid, _, _)| cid == id)
+                    .map(|&(_, ref tuples, _)| tuples.len())
+                    .sum();
+
+                if queued + tuple_list.len() > rate {
+                    return gj::Promise::err(ErrorCode::RateExceeded.error(
+                        "Send rate exceeded (queue).",
+                    ));
                 }
 
                 queue_list.push((id, tuple_list, fulfiller));
             } else {
                 if !self.send_ctx.reqs.contains_key(&id) {
-                    return gj::Promise::err(Error::failed(
-                        "Client is not synchronized.".to_string(),
+                    return gj::Promise::err(ErrorCode::NotSynchronized.error(
+                        "Client is not synchronized.",
                     ));
                 } else if self.send_ctx.reqs[&id] < tuple_data_list.len() {
-                    return gj::Promise::err(Error::failed("Send rate exceeded.".to_string()));
+                    return gj::Promise::err(ErrorCode::RateExceeded.error("Send rate exceeded."));
                 }
 
                 if let Some(entry) = self.send_ctx.reqs.get_mut(&id) {
                     *entry -= tuple_data_list.len() as u32;
                 }
 
-                for i in 0..tuple_data_list.len() {
-                    let tuple_data = pry!(tuple_data_list.get(i));
-                    let mut offset: usize = 0;
-
-                    // If power of two, clone the tuple under the two provided labels
-                    if self.opt_scheme >= db::OptScheme::Aliasing {
-                        offset = db::LABEL_SIZE;
-                        let mut tuple_alias_data = Vec::with_capacity(db::TUPLE_SIZE);
-                        tuple_alias_data.extend_from_slice(&tuple_data[..offset]);
-                        tuple_alias_data.extend_from_slice(&tuple_data[offset * 2..]);
-
-                        let tuple_alias = db::PungTuple::new(&tuple_alias_data[..]);
-
-                        self.send_ctx.count += 1;
-                        self.send_ctx.handler.input.send(tuple_alias);
-                    }
-
-                    let tuple = db::PungTuple::new(&tuple_data[offset..]);
-
+                for tuple in tuple_list {
                     self.send_ctx.count += 1;
-                    self.send_ctx.handler.input.send(tuple);
+                    self.send_ctx.handler.send(tuple);
                 }
 
                 send_fulfillers.push(fulfiller);
@@ -383,9 +1346,9 @@ impl pung_rpc::Server for PungRpc {
 
                     // Check if queued request is valid, if not, reject it
                     if !self.send_ctx.reqs.contains_key(&cid) {
-                        f.reject(Error::failed("Client is not synchronized.".to_string()));
+                        f.reject(ErrorCode::NotSynchronized.error("Client is not synchronized."));
                     } else if self.send_ctx.reqs[&cid] * alias < tuple_list.len() as u32 {
-                        f.reject(Error::failed("Send rate exceeded (queue).".to_string()));
+                        f.reject(ErrorCode::RateExceeded.error("Send rate exceeded (queue)."));
                     } else {
                         // if valid, process it as if it had been sent this round
 
@@ -395,7 +1358,7 @@ impl pung_rpc::Server for PungRpc {
 
                         for t in tuple_list.drain(..) {
                             self.send_ctx.count += 1;
-                            self.send_ctx.handler.input.send(t);
+                            self.send_ctx.handler.send(t);
                         }
 
                         send_fulfillers.push(f);
@@ -426,44 +1389,16 @@ impl pung_rpc::Server for PungRpc {
             gj::Promise::ok(())
         });
 
-        // TODO: not sure if this has any effect...
-        //    self.worker.step();
-
-        // TODO: maybe add timeout? Right now it waits for all clients to send.
-
-        // Check to see if all clients have sent all their tuples
-        if !self.send_ctx.reqs.values().any(|&x| x > 0) && self.phase == Phase::Sending
-            && self.send_ctx.count >= self.min_messages
+        // The round timeout counts from the first tuple sent in the round
+        if self.phase == Phase::Sending && self.send_deadline.is_none()
+            && self.send_ctx.count > 0
         {
-            for t in &self.extra_tuples {
-                self.send_ctx.handler.input.send(t.clone());
-            }
-
-            self.send_ctx
-                .handler
-                .input
-                .advance_to(self.round as usize + 1);
-
-            while self.send_ctx
-                .handler
-                .probe
-                .less_equal(&RootTimestamp::new(self.round as usize))
-            {
-                self.worker.step();
-            }
-
-
-            let db = self.dbase.borrow();
-
-            let total_dbs = db.total_dbs() as u32;
-            let retries = self.max_retries(db.num_buckets());
-
-            // Update the number of expected retrievals per client.
-            for v in self.ret_ctx.reqs.values_mut() {
-                *v = total_dbs * retries;
-            }
+            self.send_deadline = self.round_timeout.map(|timeout| Instant::now() + timeout);
+        }
 
-            self.phase = Phase::Receiving;
+        // Check to see if all clients have sent all their tuples
+        if self.all_sent() {
+            self.end_send_phase();
         }
 
         ret_promise
@@ -475,74 +1410,216 @@ impl pung_rpc::Server for PungRpc {
         let id: u64 = req.get_id();
         let round: u64 = req.get_round();
 
-        if !self.clients.contains_key(&id) {
-            return gj::Promise::err(Error::failed("Invalid id during send.".to_string()));
-        } else if round != self.round {
-            return gj::Promise::err(Error::failed("Invalid round number".to_string()));
-        } else if self.phase != Phase::Receiving {
-            return gj::Promise::err(Error::failed("Invalid phase for retrieval".to_string()));
-        } else if !self.ret_ctx.reqs.contains_key(&id) {
-            return gj::Promise::err(Error::failed(
-                "(ret) Client is not synchronized.".to_string(),
-            ));
-        } else if self.ret_ctx.reqs[&id] == 0 {
-            return gj::Promise::err(Error::failed("retrieveal rate exceeded.".to_string()));
-        }
-
         let bucket_idx: usize = req.get_bucket() as usize;
         let collection_idx: usize = req.get_collection() as usize;
         let level_idx: usize = req.get_level() as usize;
         let query: &[u8] = pry!(req.get_query());
         let q_num: u64 = req.get_qnum();
 
-        // check to make sure level
-        let mut db = self.dbase.borrow_mut();
+        let piggyback = match req.get_piggyback() {
+            Ok(p) => p,
+            Err(_) => return gj::Promise::err(Error::failed("Invalid piggyback".to_string())),
+        };
 
-        if bucket_idx >= db.num_buckets() {
-            return gj::Promise::err(Error::failed("invalid bucket requested".to_string()));
+        // Checksums are only for diagnosing corrupted answers
+        let verify = req.get_verify();
+
+        if let Err(e) = self.check_retrieval(id, bucket_idx, verify) {
+            return gj::Promise::err(e);
         }
 
-        // Process this bucket
-        {
-            let bucket = db.get_bucket(bucket_idx);
+        let compress = self.compressed.contains(&id);
+
+        // Late retrieval from a retained past round. These have their own per-round budget
+        // and do not hold up the current round.
+        if round < self.round {
+            let quota = match self.retained.iter().find(|r| r.round == round) {
+                Some(r) => self.ret_quota(id, &r.dbase),
+                None => {
+                    return gj::Promise::err(Error::failed(
+                        "Round is no longer retained".to_string(),
+                    ))
+                }
+            };
+
+            let past = self.retained.iter_mut().find(|r| r.round == round).unwrap();
+            let count = past.reqs.entry(id).or_insert(0);
 
-            if collection_idx >= bucket.num_collections() {
-                return gj::Promise::err(Error::failed("invalid collection requested".to_string()));
+            if *count >= quota {
+                return gj::Promise::err(ErrorCode::RateExceeded.error(
+                    "retrieveal rate exceeded (past round).",
+                ));
             }
 
-            let collection = bucket.get_collection(collection_idx);
+            let answered = answer_query(
+                &past.dbase,
+                bucket_idx,
+                collection_idx,
+                level_idx,
+                query,
+                q_num,
+                self.max_query_factor,
+                compress,
+                self.corrupt_answers(),
+                &mut self.pir_timings,
+                &mut |answer, a_num| {
+                    res.get().set_answer(answer);
+                    res.get().set_anum(a_num);
+                },
+            );
+
+            if let Err(e) = answered {
+                return gj::Promise::err(e);
+            }
 
-            if level_idx >= collection.num_levels() {
-                return gj::Promise::err(Error::failed("invalid level requested".to_string()));
+            if verify {
+                let checksums =
+                    level_checksums(&past.dbase, bucket_idx, collection_idx, level_idx);
+                write_checksums(&checksums, res.get().init_checksums(checksums.len() as u32));
             }
 
-            // let start = time::PreciseTime::now();
-            {
-                let pir_handler = collection.pir_handler(level_idx);
+            write_piggyback(&past.dbase, self.opt_scheme, piggyback, compress, &mut res);
+
+            *count += 1;
+            return gj::Promise::ok(());
+        }
+
+        if let Err(e) = self.check_current_round(id, round, 1) {
+            return gj::Promise::err(e);
+        }
+
+        {
+            let db = self.dbase.borrow();
+
+            let answered = answer_query(
+                &db,
+                bucket_idx,
+                collection_idx,
+                level_idx,
+                query,
+                q_num,
+                self.max_query_factor,
+                compress,
+                self.corrupt_answers(),
+                &mut self.pir_timings,
+                &mut |answer, a_num| {
+                    res.get().set_answer(answer);
+                    res.get().set_anum(a_num);
+                },
+            );
+
+            if let Err(e) = answered {
+                return gj::Promise::err(e);
+            }
 
-                let answer = pir_handler.gen_answer(query, q_num);
-                res.get().set_answer(answer.answer);
-                res.get().set_anum(answer.num);
+            if verify {
+                let checksums = level_checksums(&db, bucket_idx, collection_idx, level_idx);
+                write_checksums(&checksums, res.get().init_checksums(checksums.len() as u32));
             }
-            // let end = time::PreciseTime::now();
-            // println!("bucket {}, collection {}, level {}, answer time: {} usec",
-            // bucket_idx, collection_idx, level_idx, start.to(end).num_microseconds().unwrap());
+
+            write_piggyback(&db, self.opt_scheme, piggyback, compress, &mut res);
         }
 
-        // Account for this retrieval
-        if let Some(entry) = self.ret_ctx.reqs.get_mut(&id) {
-            *entry -= 1;
+        self.account_retrievals(id, 1);
+        gj::Promise::ok(())
+    }
+
+    fn retr_batch(
+        &mut self,
+        params: RetrBatchParams,
+        mut res: RetrBatchResults,
+    ) -> gj::Promise<(), Error> {
+        let req = pry!(params.get());
+        let id: u64 = req.get_id();
+        let round: u64 = req.get_round();
+        let bucket_idx: usize = req.get_bucket() as usize;
+        let queries = pry!(req.get_queries());
+        let verify = req.get_verify();
+
+        if let Err(e) = self.check_retrieval(id, bucket_idx, verify) {
+            return gj::Promise::err(e);
+        } else if queries.len() == 0 {
+            return gj::Promise::err(Error::failed("Empty batch of queries".to_string()));
         }
 
-        // Check to see if we are done and we can move on to next round
-        if !self.ret_ctx.reqs.values().any(|&x| x > 0) {
-            self.send_ctx.reqs = self.clients.clone();
-            self.send_ctx.count = 0;
-            self.round += 1;
-            self.phase = Phase::Sending;
-            db.clear(); // Garbage collect the whole thing
+        let num = queries.len();
+        let compress = self.compressed.contains(&id);
 
-            println!("Advancing to round {}", self.round);
+        // Every query counts as a retrieval, so the batch must fit in what the client has left
+        if round < self.round {
+            let left = match self.retained.iter().find(|r| r.round == round) {
+                Some(r) => {
+                    let count = r.reqs.get(&id).cloned().unwrap_or(0);
+                    self.ret_quota(id, &r.dbase).saturating_sub(count)
+                }
+
+                None => {
+                    return gj::Promise::err(Error::failed(
+                        "Round is no longer retained".to_string(),
+                    ))
+                }
+            };
+
+            if left < num {
+                return gj::Promise::err(ErrorCode::RateExceeded.error(
+                    "retrieveal rate exceeded (past round).",
+                ));
+            }
+        } else if let Err(e) = self.check_current_round(id, round, num) {
+            return gj::Promise::err(e);
+        }
+
+        {
+            let current;
+            let db: &db::Database = if round < self.round {
+                &self.retained.iter().find(|r| r.round == round).unwrap().dbase
+            } else {
+                current = self.dbase.borrow();
+                &current
+            };
+
+            let mut answers = res.get().init_answers(num);
+
+            for i in 0..num {
+                let query = queries.get(i);
+                let collection_idx = query.get_collection() as usize;
+                let level_idx = query.get_level() as usize;
+
+                let mut builder = answers.borrow().get(i);
+
+                let answered = answer_query(
+                    db,
+                    bucket_idx,
+                    collection_idx,
+                    level_idx,
+                    pry!(query.get_query()),
+                    query.get_qnum(),
+                    self.max_query_factor,
+                    compress,
+                    self.corrupt_answers(),
+                    &mut self.pir_timings,
+                    &mut |answer, a_num| {
+                        builder.set_answer(answer);
+                        builder.set_anum(a_num);
+                    },
+                );
+
+                if let Err(e) = answered {
+                    return gj::Promise::err(e);
+                }
+
+                if verify {
+                    let checksums = level_checksums(db, bucket_idx, collection_idx, level_idx);
+                    write_checksums(&checksums, builder.init_checksums(checksums.len() as u32));
+                }
+            }
+        }
+
+        if round < self.round {
+            let past = self.retained.iter_mut().find(|r| r.round == round).unwrap();
+            *past.reqs.entry(id).or_insert(0) += num;
+        } else {
+            self.account_retrievals(id, num);
         }
 
         gj::Promise::ok(())
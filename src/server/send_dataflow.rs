@@ -1,5 +1,6 @@
 // This deals with broadcasting the data across all workers
 
+use capnp::Error;
 use db;
 use server::timely_shim;
 use std::cell::RefCell;
@@ -10,25 +11,14 @@ use timely::dataflow::channels::pact::Pipeline;
 use timely::dataflow::operators::*;
 use timely::dataflow::scopes::root::Root;
 use timely_communication::allocator::generic::Generic;
-use util;
 
-pub fn graph(
-    worker: &mut Root<Generic>,
-    dbase: db::DatabasePtr,
-    buckets: usize,
-) -> timely_shim::SendHandler {
+pub fn graph(worker: &mut Root<Generic>, dbase: db::DatabasePtr) -> timely_shim::SendHandler {
     let fulfillers: timely_shim::SendFulfillerList = Rc::new(RefCell::new(Vec::new()));
     let send_fulfillers = fulfillers.clone();
 
-    let mut partitions: Vec<Vec<u8>> = Vec::with_capacity(buckets);
-
-    for i in 0..buckets {
-        partitions.push(util::label_marker(i, buckets));
-    }
-
     let (input, probe) = worker.dataflow(move |dataflow| {
         // Get input from RPCs
-        let (s_input, stream) = dataflow.new_input::<db::PungTuple>();
+        let (s_input, stream) = dataflow.new_input::<(db::PungTuple, u32)>();
 
         let s_probe = stream
             .broadcast()  // broadcast received Tuples to all workers
@@ -40,16 +30,8 @@ pub fn graph(
                 input.for_each(|time, data| {
                     notificator.notify_at(time);
 
-                    // Add tuples to the database
-                    for datum in data.drain(..) {
-                        for (i, label) in partitions.iter().enumerate() {
-                            if datum.label() <= label {
-                                // Push to bucket i
-                                db.push(i, datum);
-                                break;
-                            }
-                        }
-                    }
+                    // Add tuples to the database, each to the bucket its label falls in
+                    db.push_batch_with_ttl(data.drain(..));
 
                 });
 
@@ -57,33 +39,7 @@ pub fn graph(
                 let f_list = &mut send_fulfillers.borrow_mut();
 
                 notificator.for_each(|time, _num, _notify| {
-
-                    let mut buckets_len = Vec::with_capacity(db.num_buckets());
-                    let mut buckets_lmid: Vec<Vec<u8>> = Vec::new();
-
-                    // Encode each collection: BST + batch codes
-                    db.encode();
-
-                    // Number of tuples in each bucket (and lmid if applicable)
-                    for bucket in db.get_buckets() {
-                        buckets_len.push(bucket.unencoded_len() as u64);
-
-                        if db.opt_scheme() >= db::OptScheme::Hybrid2 {
-                            buckets_lmid.extend(bucket.mid_labels());
-                        }
-                    }
-
-                    // Result to be given to clients
-                    let buckets_info = Rc::new((buckets_len, buckets_lmid));
-
-                    // Notify each client of this worker the value of n
-                    for f in f_list.drain(..) {
-                        f.fulfill(buckets_info.clone());
-                    }
-
-                    // Setup PIR for each collection in the database
-                    db.pir_setup();
-
+                    build_round(db, f_list);
                     output.session(&time).give(0);
               });
             })
@@ -98,3 +54,55 @@ pub fn graph(
         fulfillers: fulfillers,
     }
 }
+
+/// Lays out a round's database once all of its tuples are in: encodes each bucket, sets up
+/// PIR, and sends every client waiting on the round the number of tuples (and lmids) of each
+/// bucket (or an error if PIR could not be set up).
+pub fn build_round(db: &mut db::Database, fulfillers: &mut Vec<timely_shim::SendFulfiller>) {
+    let mut buckets_len = Vec::with_capacity(db.num_buckets());
+    let mut buckets_lmid: Vec<Vec<u8>> = Vec::new();
+
+    // Encode each collection: BST + batch codes (all buckets in parallel). A bucket that
+    // cannot be encoded is served empty this round rather than taking the worker down.
+    let outcomes = db.encode_each();
+
+    for (i, (bucket, outcome)) in db.get_buckets_mut().zip(outcomes).enumerate() {
+        if let Err(e) = outcome {
+            error!("Dropping the tuples of bucket {}: {}", i, e);
+            bucket.clear();
+            bucket.encode().expect("the bucket was just cleared");
+        } else if bucket.duplicate_labels() > 0 {
+            warn!("Bucket {} has {} tuples with duplicate labels", i, bucket.duplicate_labels());
+        }
+    }
+
+    // Number of tuples in each bucket (and lmid if applicable)
+    for bucket in db.get_buckets() {
+        buckets_len.push(bucket.unencoded_len() as u64);
+
+        if db.opt_scheme() >= db::OptScheme::Hybrid2 {
+            buckets_lmid.extend(bucket.mid_labels());
+        }
+    }
+
+    // Setup PIR for each collection in the database. If that fails, the round fails: the
+    // clients waiting on it get an error instead of the buckets' sizes, and retrievals from
+    // the collections without a PIR database are refused (see Collection::pir_handler).
+    if let Err(e) = db.pir_setup() {
+        error!("Could not set up PIR: {}", e);
+
+        for f in fulfillers.drain(..) {
+            f.reject(Error::failed(format!("PIR is unavailable this round: {}", e)));
+        }
+
+        return;
+    }
+
+    // Result to be given to clients
+    let buckets_info = Rc::new((buckets_len, buckets_lmid));
+
+    // Notify each client of this worker the value of n
+    for f in fulfillers.drain(..) {
+        f.fulfill(buckets_info.clone());
+    }
+}
@@ -10,8 +10,10 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use timely::dataflow::operators::{input, probe};
+use timely::dataflow::scopes::root::Root;
 use timely::progress::nested::product::Product;
 use timely::progress::timestamp::RootTimestamp;
+use timely_communication::allocator::generic::Generic;
 
 pub type SendFulfiller = gj::PromiseFulfiller<Rc<(Vec<u64>, Vec<Vec<u8>>)>, Error>;
 pub type SendFulfillerList = Rc<RefCell<Vec<SendFulfiller>>>;
@@ -19,8 +21,9 @@ pub type SendFulfillerList = Rc<RefCell<Vec<SendFulfiller>>>;
 /// Handler used by the RPC server to interface with [timely dataflow]
 /// (../../../timely/index.html) during Pung's send phase.
 pub struct SendHandler {
-    /// input handle for a given round for passing PungTuples to the timely dataflow system
-    pub input: input::Handle<usize, PungTuple>,
+    /// input handle for a given round for passing PungTuples (and their TTL in rounds,
+    /// 0 if none) to the timely dataflow system
+    pub input: input::Handle<usize, (PungTuple, u32)>,
 
     /// allows the RPC server to check on the progress of a given round.
     pub probe: probe::Handle<Product<RootTimestamp, usize>>,
@@ -28,3 +31,46 @@ pub struct SendHandler {
     /// shared pointer to thestate of a send round (promises)
     pub fulfillers: SendFulfillerList,
 }
+
+/// How the RPC server turns the tuples that clients send during a round into the round's
+/// database: through timely dataflow (`TimelySend`), or directly when there is a single
+/// worker (see `server::direct_send`).
+pub trait SendPath {
+    /// Passes a tuple (and its TTL in rounds, 0 if none) on to the current round
+    fn send(&mut self, tuple: (PungTuple, u32));
+
+    /// Promises to fulfill (with the number of tuples and the lmids of each bucket) once
+    /// the current round's database is built
+    fn fulfillers(&self) -> SendFulfillerList;
+
+    /// Builds the database of `round` from the tuples sent so far and fulfills the promises
+    fn finish_round(&mut self, round: u64);
+}
+
+/// Sends tuples through the dataflow of `send_dataflow::graph`, which broadcasts them to
+/// every worker.
+pub struct TimelySend {
+    pub handler: SendHandler,
+    pub worker: Root<Generic>,
+}
+
+impl SendPath for TimelySend {
+    fn send(&mut self, tuple: (PungTuple, u32)) {
+        self.handler.input.send(tuple);
+    }
+
+    fn fulfillers(&self) -> SendFulfillerList {
+        self.handler.fulfillers.clone()
+    }
+
+    fn finish_round(&mut self, round: u64) {
+        self.handler.input.advance_to(round as usize + 1);
+
+        while self.handler
+            .probe
+            .less_equal(&RootTimestamp::new(round as usize))
+        {
+            self.worker.step();
+        }
+    }
+}
@@ -44,6 +44,22 @@ impl Bloom {
         }
     }
 
+    /// Create a new bloom filter structure with a given number of hash functions
+    /// (rather than the optimal one for the expected number of items).
+    /// bitmap_size is the size in bytes (not bits) that will be allocated in memory
+    pub fn new_with_k_num(bitmap_size: usize, k_num: u32) -> Bloom {
+        assert!(bitmap_size > 0 && k_num > 0);
+        let bitmap_bits = (bitmap_size as u64) * 8u64;
+        let bitmap = BitVec::from_elem(bitmap_bits as usize, false);
+        let sips = [Bloom::sip_new(0, 1), Bloom::sip_new(2, 3)];
+        Bloom {
+            bitmap: bitmap,
+            bitmap_bits: bitmap_bits,
+            k_num: k_num,
+            sips: sips,
+        }
+    }
+
     /// Create a new bloom filter structure.
     /// items_count is an estimation of the maximum number of items to store.
     /// fp_p is the wanted rate of false positives, in ]0.0, 1.0[
@@ -1,47 +1,90 @@
 use byteorder::{BigEndian, WriteBytesExt};
 use db;
 use std::cmp;
+use std::collections::BTreeSet;
 use std::io::Cursor;
 
 pub mod bloomfilter;
+pub mod rle;
+pub mod secret;
+pub mod stats;
 
-#[macro_export]
-macro_rules! retry_bound {
-    ($k:expr) => {
-        if $k < 9 { // special case since formula yields a very loose upper bound for k < 9
-            $k as u32 // we can always retrieve k elements in k rounds
-        } else {
-            3 * (($k as f64).ln() / ($k as f64).ln().ln()).ceil() as u32
-        }
-    };
+/// Upper bound on the number of retrieval rounds needed to fetch `k` labels from `k`
+/// buckets (balls and bins with a single choice). Always returns at least 1.
+pub fn retry_bound(k: u64) -> u32 {
+    if k <= 1 {
+        // k = 0 would yield no retrievals at all; k = 1 needs exactly one
+        1
+    } else if k < 9 {
+        // special case since formula yields a very loose upper bound for k < 9
+        k as u32 // we can always retrieve k elements in k rounds
+    } else {
+        let ln_k = (k as f64).ln();
+        checked_bound(3.0 * (ln_k / ln_k.ln()).ceil())
+    }
+}
 
-    ($k:expr, $d:expr) => {
-        if $k < 3 { // special case since formula yields a very loose upper bound for k < 3
-            $k as u32
-        } else {
-            ((($k as f64).ln().ln() / ($d as f64).ln()) + 1.0).ceil() as u32
-        }
-    };
+/// Upper bound on the number of retrieval rounds needed to fetch `k` labels from `k`
+/// buckets when each label can be placed in one of `d` buckets (power of d choices).
+/// Always returns at least 1.
+pub fn retry_bound_choices(k: u64, d: u64) -> u32 {
+    if d < 2 {
+        // ln(d) is 0 (or undefined), so the formula does not apply
+        retry_bound(k)
+    } else if k <= 1 {
+        1
+    } else if k < 3 {
+        // special case since formula yields a very loose upper bound for k < 3
+        k as u32
+    } else {
+        checked_bound(((k as f64).ln().ln() / (d as f64).ln() + 1.0).ceil())
+    }
+}
+
+// Converts a floating point bound into a retry count in [1, u32::MAX]
+#[inline]
+fn checked_bound(bound: f64) -> u32 {
+    if !bound.is_finite() || bound < 1.0 {
+        1
+    } else if bound >= u32::max_value() as f64 {
+        u32::max_value()
+    } else {
+        bound as u32
+    }
+}
+
+/// Number of retrieval rounds a client performs (and a server expects) per round for the
+/// given optimization scheme and number of buckets.
+pub fn max_retries(scheme: db::OptScheme, buckets: u64) -> u32 {
+    match scheme {
+        db::OptScheme::Normal => retry_bound(buckets),
+        db::OptScheme::Aliasing => retry_bound_choices(buckets, 2),
+        db::OptScheme::Hybrid2 => cmp::max(1, retry_bound_choices(buckets, 2) / 2),
+        db::OptScheme::Hybrid4 | db::OptScheme::Hybrid8 => 1,
+    }
 }
 
 
+// An empty collection (e.g., a part of a hybrid bucket with few tuples) has no index to pick
 #[macro_export]
 macro_rules! some_or_random {
     ($res:expr, $rng:expr, $len:expr) => {
         if let Some(idx) = $res {
             idx
+        } else if $len as u64 == 0 {
+            0
         } else {
            $rng.next_u64() % ($len as u64)
         }
     };
 }
 
-// Below is unsafe
+/// Compares two labels as big-endian integers, which is the same as comparing their bytes
+/// lexicographically (a slice compare, i.e., a memcmp). Unlike reinterpreting the labels as
+/// `[u64; 4]`, this neither reads past a short label nor depends on its alignment.
 #[inline]
 pub fn label_cmp(l1: &[u8], l2: &[u8]) -> cmp::Ordering {
-    unsafe {
-        (&*(l1 as *const [u8] as *const [u64; 4])).cmp(&*(l2 as *const [u8] as *const [u64; 4]))
-    }
+    l1.cmp(l2)
 }
 
 
@@ -71,7 +114,8 @@ pub fn get_idx_bloom(bloom: &bloomfilter::Bloom, label: &[u8], num: u64) -> Opti
 }
 
 
-// Returns number of elements in collection for given collection_idx (this assumes hybrid 2 or 4)
+// Returns number of elements in collection for given collection_idx (this assumes hybrid 2, 4,
+// or 8)
 pub fn collection_len(bucket_len: u64, collection_idx: u32, num_collections: u32) -> u64 {
     if num_collections == 1 {
         bucket_len
@@ -91,11 +135,148 @@ pub fn collection_len(bucket_len: u64, collection_idx: u32, num_collections: u32
             3 => bucket_len / 4,
             _ => panic!("Invalid collection idx"),
         }
+    } else if num_collections == 8 {
+        // hybrid 8: the bucket is halved three times, and each bit of the index (from the
+        // most significant) says whether the collection is in the first or second half
+        if collection_idx >= 8 {
+            panic!("Invalid collection idx");
+        }
+
+        let mut len = bucket_len;
+
+        for bit in (0..3).rev() {
+            len = if collection_idx & (1 << bit) == 0 {
+                (len + 1) / 2
+            } else {
+                len / 2
+            };
+        }
+
+        len
     } else {
         panic!("Invalid num collections");
     }
 }
 
+/// Returns the number of elements in collection `part` (0 to 8) of a hybrid 4 bucket.
+/// A parity collection is as long as the first of the two collections XORed into it,
+/// so it can be one element longer than the second one (see `Bucket::encode`).
+pub fn h4_part_len(bucket_len: u64, part: usize) -> u64 {
+    match part {
+        0..=3 => collection_len(bucket_len, part as u32, 4),
+        4 | 6 | 8 => collection_len(bucket_len, 0, 4), // 0 ^ 1, 0 ^ 2, and 6 ^ 7
+        5 => collection_len(bucket_len, 2, 4),         // 2 ^ 3
+        7 => collection_len(bucket_len, 1, 4),         // 1 ^ 3
+        _ => panic!("Invalid collection idx"),
+    }
+}
+
+
+// The 27 collections of a hybrid 8 bucket are the cells of a 3x3x3 cube. The systematic
+// collections are the cells in {0, 1}^3 (collection i is the cell of the bits of i, from the
+// most significant), and every other cell is a parity collection: the XOR of the two cells
+// with its last 2 replaced by 0 and by 1. Parity cells are ordered by how many 2s they have,
+// and then lexicographically, so every parity collection comes after the two it is built
+// from. (Hybrid 4 is the same code in two dimensions.)
+fn h8_cells() -> Vec<[usize; 3]> {
+    let mut parity = Vec::with_capacity(19);
+
+    for a in 0..3 {
+        for b in 0..3 {
+            for c in 0..3 {
+                if a == 2 || b == 2 || c == 2 {
+                    parity.push([a, b, c]);
+                }
+            }
+        }
+    }
+
+    // The sort is stable, so cells with as many 2s stay in lexicographic order
+    parity.sort_by_key(|cell| cell.iter().filter(|&&x| x == 2).count());
+
+    (0..8)
+        .map(|i| [(i >> 2) & 1, (i >> 1) & 1, i & 1])
+        .chain(parity)
+        .collect()
+}
+
+fn h8_collection_of(cells: &[[usize; 3]], cell: &[usize; 3]) -> usize {
+    cells.iter().position(|c| c == cell).expect("every cell is a collection")
+}
+
+/// Returns, for each parity collection of a hybrid 8 bucket (8 to 26, in order), the two
+/// collections whose XOR it holds. The first of the two is never shorter than the second
+/// (see `h8_part_len`).
+pub fn h8_plan() -> Vec<(usize, usize)> {
+    let cells = h8_cells();
+
+    cells[8..]
+        .iter()
+        .map(|cell| {
+            let dim = cell.iter().rposition(|&x| x == 2).expect("parity cells have a 2");
+            let (mut c1, mut c2) = (*cell, *cell);
+            c1[dim] = 0;
+            c2[dim] = 1;
+
+            (h8_collection_of(&cells, &c1), h8_collection_of(&cells, &c2))
+        })
+        .collect()
+}
+
+/// Returns the number of elements in collection `part` (0 to 26) of a hybrid 8 bucket. As
+/// with hybrid 4, a parity collection is as long as the first collection XORed into it, which
+/// is as long as the systematic collection of its cell with every 2 replaced by 0.
+pub fn h8_part_len(bucket_len: u64, part: usize) -> u64 {
+    let cells = h8_cells();
+    let cell = cells.get(part).expect("Invalid collection idx");
+    let idx = cell.iter().fold(0, |acc, &x| (acc << 1) | (x & 1)) as u32;
+
+    collection_len(bucket_len, idx, 8)
+}
+
+/// Returns, for each systematic collection of a hybrid 8 bucket (0 to 7), the 8 disjoint sets
+/// of parts whose XOR yields it, smallest first. Along each dimension, the collection's value
+/// x is either taken as is or rebuilt from the cells at 1 - x and 2; a set is one such choice
+/// for every dimension, and two sets differ in some dimension, where they share no cell.
+pub fn h8_mappings() -> Vec<Vec<BTreeSet<usize>>> {
+    let cells = h8_cells();
+
+    (0..8)
+        .map(|target| {
+            let x = cells[target];
+
+            let mut sets: Vec<BTreeSet<usize>> = (0..8usize)
+                .map(|choice| {
+                    // Values to take along each dimension
+                    let values: Vec<Vec<usize>> = (0..3)
+                        .map(|dim| {
+                            if choice & (1 << dim) == 0 {
+                                vec![x[dim]]
+                            } else {
+                                vec![1 - x[dim], 2]
+                            }
+                        })
+                        .collect();
+
+                    let mut set = BTreeSet::new();
+
+                    for &a in &values[0] {
+                        for &b in &values[1] {
+                            for &c in &values[2] {
+                                set.insert(h8_collection_of(&cells, &[a, b, c]));
+                            }
+                        }
+                    }
+
+                    set
+                })
+                .collect();
+
+            sets.sort_by_key(|set| set.len());
+            sets
+        })
+        .collect()
+}
 
 // Returns the indices of collections that contain a meaningful label
 #[inline]
@@ -104,12 +285,21 @@ pub fn label_collections(scheme: db::OptScheme) -> Vec<usize> {
         db::OptScheme::Normal | db::OptScheme::Aliasing => vec![0],
         db::OptScheme::Hybrid2 => vec![0, 1], // labels are in collections 0 and 1
         db::OptScheme::Hybrid4 => vec![0, 1, 2, 3], // labels are in collections 0, 1, 2, and 3
+        db::OptScheme::Hybrid8 => (0..8).collect(), // labels are in collections 0 to 7
     }
 }
 
+/// Returns the upper boundary of bucket `index` when the label space is split into
+/// `buckets` buckets, or an error if there are no buckets or the index is out of range.
 #[inline]
-pub fn label_marker(index: usize, buckets: usize) -> Vec<u8> {
-    assert!(index < buckets);
+pub fn label_marker(index: usize, buckets: usize) -> Result<Vec<u8>, &'static str> {
+    if buckets == 0 {
+        return Err("Number of buckets must be at least 1");
+    } else if buckets as u64 > u32::max_value() as u64 {
+        return Err("Number of buckets is too large");
+    } else if index >= buckets {
+        return Err("Bucket index is out of range");
+    }
 
     let max = u32::max_value();
     let mut limit = max / buckets as u32;
@@ -117,18 +307,73 @@ pub fn label_marker(index: usize, buckets: usize) -> Vec<u8> {
 
     let mut a = Cursor::new(Vec::with_capacity(4));
     a.write_u32::<BigEndian>(limit).unwrap();
-    a.into_inner()
+    Ok(a.into_inner())
 }
 
+/// Returns the bucket in which `label` falls, given the upper (inclusive) boundary of each
+/// bucket in increasing order (see `label_marker`): the first bucket whose boundary is not
+/// below the label. Labels above every boundary (the last one falls short of the top of the
+/// label space, and labels that start with it are above it) are in the last bucket.
 #[inline]
 pub fn bucket_idx(label: &[u8], partitions: &[Vec<u8>]) -> usize {
-    for (i, partition) in partitions.iter().enumerate() {
-        if label <= &partition[..] {
-            return i;
+    if partitions.is_empty() {
+        return 0;
+    }
+
+    // Index of the first boundary that is >= label
+    let res = partitions.binary_search_by(|partition| {
+        if &partition[..] < label {
+            cmp::Ordering::Less
+        } else {
+            cmp::Ordering::Greater
         }
+    });
+
+    match res {
+        Ok(i) | Err(i) => cmp::min(i, partitions.len() - 1),
     }
+}
 
-    0
+/// Static partitioning of the label space into buckets. Clients and servers must agree on
+/// it, so both build it from the number of buckets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Partitioning {
+    boundaries: Vec<Vec<u8>>, // upper (inclusive) boundary of each bucket, in increasing order
+}
+
+impl Partitioning {
+    /// Partitions the label space into `buckets` buckets (which must be at least 1).
+    pub fn new(buckets: usize) -> Result<Partitioning, &'static str> {
+        let mut boundaries = Vec::with_capacity(buckets);
+
+        if buckets == 0 {
+            return Err("Number of buckets must be at least 1");
+        }
+
+        for i in 0..buckets {
+            boundaries.push(label_marker(i, buckets)?);
+        }
+
+        Ok(Partitioning {
+            boundaries: boundaries,
+        })
+    }
+
+    /// Returns the bucket in which `label` falls (see `bucket_idx`).
+    #[inline]
+    pub fn bucket_of(&self, label: &[u8]) -> usize {
+        bucket_idx(label, &self.boundaries)
+    }
+
+    #[inline]
+    pub fn num_buckets(&self) -> usize {
+        self.boundaries.len()
+    }
+
+    #[inline]
+    pub fn boundaries(&self) -> &[Vec<u8>] {
+        &self.boundaries[..]
+    }
 }
 
 #[inline]
@@ -159,3 +404,30 @@ pub fn get_alpha(num: u64) -> u64 {
         8
     }
 }
+
+/// Returns the PIR aggregation for a collection of `num` elements: `alpha` if one is
+/// configured (capped at `num`, since PIR cannot aggregate more elements than there are),
+/// and otherwise `get_alpha(num)`. Clients and servers must both derive the alpha of a level
+/// with this, from the same `alpha`, or answers cannot be decoded.
+#[inline]
+pub fn pir_alpha(num: u64, alpha: Option<u64>) -> u64 {
+    match alpha {
+        Some(alpha) => cmp::max(1, cmp::min(alpha, num)),
+        None => get_alpha(num),
+    }
+}
+
+/// Collections (or BST levels) with fewer elements than this are queried without PIR
+/// recursion since it would only add overhead (see `get_depth`).
+pub const MIN_RECURSIVE_PIR_LEN: u64 = 256;
+
+/// Returns the PIR recursion depth for a collection of `num` elements when `depth` is the
+/// configured one. Clients and servers must both derive the depth of a level with this.
+#[inline]
+pub fn get_depth(num: u64, depth: u64) -> u64 {
+    if num < MIN_RECURSIVE_PIR_LEN {
+        cmp::min(1, depth)
+    } else {
+        depth
+    }
+}
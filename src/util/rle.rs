@@ -0,0 +1,81 @@
+//! A small, dependency-free run-length codec (in the style of PackBits) used to
+//! optionally compress bloom filters, label lists, and PIR answers on the wire.
+//!
+//! The encoded stream is a sequence of blocks. Each block starts with a control byte `c`:
+//! if `c < 128` it is followed by `c + 1` literal bytes; otherwise it is followed by a
+//! single byte that is repeated `c - 126` times. Incompressible input grows by at most
+//! one byte every 128 bytes.
+
+const MAX_LITERAL: usize = 128;
+const MAX_RUN: usize = 129;
+
+/// Compresses a byte slice.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_LITERAL + 1);
+    let mut i = 0;
+
+    while i < data.len() {
+        // Length of the run that starts at i
+        let mut run = 1;
+        while i + run < data.len() && run < MAX_RUN && data[i + run] == data[i] {
+            run += 1;
+        }
+
+        if run >= 2 {
+            out.push((run + 126) as u8);
+            out.push(data[i]);
+            i += run;
+        } else {
+            // Literal block: stop right before the next run (or at the maximum length)
+            let start = i;
+            i += 1;
+
+            while i < data.len() && i - start < MAX_LITERAL
+                && !(i + 1 < data.len() && data[i] == data[i + 1])
+            {
+                i += 1;
+            }
+
+            out.push((i - start - 1) as u8);
+            out.extend_from_slice(&data[start..i]);
+        }
+    }
+
+    out
+}
+
+/// Decompresses a byte slice produced by `compress`.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+
+    while i < data.len() {
+        let c = data[i] as usize;
+        i += 1;
+
+        if c < 128 {
+            let len = c + 1;
+
+            if i + len > data.len() {
+                return Err("Truncated literal block in compressed data");
+            }
+
+            out.extend_from_slice(&data[i..i + len]);
+            i += len;
+        } else {
+            if i >= data.len() {
+                return Err("Truncated run block in compressed data");
+            }
+
+            let len = c - 126;
+            let byte = data[i];
+            i += 1;
+
+            for _ in 0..len {
+                out.push(byte);
+            }
+        }
+    }
+
+    Ok(out)
+}
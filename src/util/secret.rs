@@ -0,0 +1,120 @@
+//! Dependency-free decoding of shared secrets given on the command line. Secrets can be
+//! passed verbatim (their UTF-8 bytes), as hex, or as standard base64 (with padding).
+
+use std::str::FromStr;
+
+/// How a secret is written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SecretFormat {
+    Raw,
+    Hex,
+    Base64,
+}
+
+impl FromStr for SecretFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<SecretFormat, &'static str> {
+        match s {
+            "raw" => Ok(SecretFormat::Raw),
+            "hex" => Ok(SecretFormat::Hex),
+            "base64" => Ok(SecretFormat::Base64),
+            _ => Err("Secret format must be raw, hex, or base64"),
+        }
+    }
+}
+
+/// Decodes a secret written in the given format.
+pub fn decode(secret: &str, format: SecretFormat) -> Result<Vec<u8>, &'static str> {
+    match format {
+        SecretFormat::Raw => Ok(secret.as_bytes().to_vec()),
+        SecretFormat::Hex => from_hex(secret),
+        SecretFormat::Base64 => from_base64(secret),
+    }
+}
+
+/// Decodes a hex string (either case). The string must have an even length.
+pub fn from_hex(s: &str) -> Result<Vec<u8>, &'static str> {
+    let bytes = s.as_bytes();
+
+    if bytes.len() % 2 != 0 {
+        return Err("Hex string has an odd length");
+    }
+
+    let mut out = Vec::with_capacity(bytes.len() / 2);
+
+    for pair in bytes.chunks(2) {
+        let hi = hex_value(pair[0])?;
+        let lo = hex_value(pair[1])?;
+        out.push((hi << 4) | lo);
+    }
+
+    Ok(out)
+}
+
+/// Encodes bytes as a lowercase hex string (the inverse of `from_hex`).
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hex_value(c: u8) -> Result<u8, &'static str> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err("Invalid hex digit"),
+    }
+}
+
+/// Decodes a standard (RFC 4648) base64 string. Padding is required.
+pub fn from_base64(s: &str) -> Result<Vec<u8>, &'static str> {
+    let bytes = s.as_bytes();
+
+    if bytes.len() % 4 != 0 {
+        return Err("Base64 string length is not a multiple of 4");
+    }
+
+    let mut out = Vec::with_capacity(bytes.len() / 4 * 3);
+
+    for (i, block) in bytes.chunks(4).enumerate() {
+        let last = (i + 1) * 4 == bytes.len();
+
+        // Padding may only appear at the end of the last block
+        let padding = block.iter().rev().take_while(|&&c| c == b'=').count();
+
+        if padding > 2 || (padding > 0 && !last) {
+            return Err("Invalid base64 padding");
+        }
+
+        let mut acc: u32 = 0;
+
+        for &c in &block[..4 - padding] {
+            acc = (acc << 6) | base64_value(c)? as u32;
+        }
+
+        acc <<= 6 * padding as u32;
+
+        out.push((acc >> 16) as u8);
+
+        if padding < 2 {
+            out.push((acc >> 8) as u8);
+        }
+
+        if padding < 1 {
+            out.push(acc as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+fn base64_value(c: u8) -> Result<u8, &'static str> {
+    match c {
+        b'A'..=b'Z' => Ok(c - b'A'),
+        b'a'..=b'z' => Ok(c - b'a' + 26),
+        b'0'..=b'9' => Ok(c - b'0' + 52),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err("Invalid base64 character"),
+    }
+}
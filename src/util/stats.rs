@@ -0,0 +1,156 @@
+//! Statistics that a Pung server collects and reports through the `stats` RPC.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Number of buckets in a latency histogram. Bucket i counts samples that took
+/// [2^i, 2^(i+1)) microseconds (bucket 0 also counts samples under 1 microsecond).
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+/// Latency histogram with power-of-two buckets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub count: u64,
+    pub total_usec: u64,
+    pub buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            count: 0,
+            total_usec: 0,
+            buckets: vec![0; HISTOGRAM_BUCKETS],
+        }
+    }
+
+    pub fn record(&mut self, elapsed: Duration) {
+        let usec = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_nanos() / 1000);
+
+        // floor(log2(usec)), capped at the last bucket
+        let mut idx = 0;
+        while idx + 1 < HISTOGRAM_BUCKETS && (usec >> (idx + 1)) > 0 {
+            idx += 1;
+        }
+
+        self.count += 1;
+        self.total_usec += usec;
+        self.buckets[idx] += 1;
+    }
+
+    #[inline]
+    pub fn mean_usec(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.total_usec / self.count
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+/// Latency of answering PIR queries over a level of a collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PirTiming {
+    /// Number of (unencoded) tuples in the bucket
+    pub bucket_size: u64,
+    pub alpha: u64,
+    pub level: u32,
+    pub latency: Histogram,
+}
+
+/// Accumulates PIR answer latencies per (bucket size, alpha, level).
+#[derive(Default)]
+pub struct PirTimings {
+    timings: BTreeMap<(u64, u64, u32), Histogram>,
+}
+
+impl PirTimings {
+    pub fn new() -> PirTimings {
+        PirTimings {
+            timings: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, bucket_size: u64, alpha: u64, level: u32, elapsed: Duration) {
+        self.timings
+            .entry((bucket_size, alpha, level))
+            .or_insert_with(Histogram::new)
+            .record(elapsed);
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.timings.is_empty()
+    }
+
+    /// Returns all timings, ordered by bucket size, alpha, and level.
+    pub fn to_vec(&self) -> Vec<PirTiming> {
+        self.timings
+            .iter()
+            .map(|(&(bucket_size, alpha, level), latency)| PirTiming {
+                bucket_size: bucket_size,
+                alpha: alpha,
+                level: level,
+                latency: latency.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Statistics reported by the server's `stats` RPC.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    /// PIR answer latencies (only collected by servers built with the `pir-timing` feature)
+    pub pir_timings: Vec<PirTiming>,
+
+    /// Tuples in the current round's database, including the ones added by encoding
+    pub tuples: u64,
+
+    /// Tuples in the current round's database before encoding
+    pub unencoded_tuples: u64,
+
+    /// Tuples in the current round's database whose label collides with that of another
+    /// tuple in the same bucket (see `Bucket::duplicate_labels`)
+    pub duplicate_labels: u64,
+}
+
+/// Phase of a server's current round (see `ServerStatus`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    Sending,
+    Receiving,
+}
+
+/// State of a server as reported by its `status` RPC.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerStatus {
+    pub round: u64,
+    pub phase: Phase,
+
+    /// Clients registered with the server
+    pub clients: u64,
+
+    /// Tuples in the current round's database, including the ones added by encoding
+    pub tuples: u64,
+
+    /// Databases (i.e., collections and their levels) over all buckets
+    pub total_dbs: u64,
+}
+
+impl Stats {
+    /// Ratio between the stored and the unencoded tuples of the current round (see
+    /// `Database::encoding_overhead_ratio`).
+    pub fn encoding_overhead_ratio(&self) -> f64 {
+        if self.unencoded_tuples == 0 {
+            1.0
+        } else {
+            self.tuples as f64 / self.unencoded_tuples as f64
+        }
+    }
+}
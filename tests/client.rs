@@ -0,0 +1,2284 @@
+extern crate capnp;
+extern crate capnp_rpc;
+extern crate gj;
+extern crate gjio;
+extern crate pung;
+extern crate rand;
+extern crate timely;
+
+mod common;
+
+use pung::client;
+use pung::client::assign;
+use pung::client::index;
+use pung::client::pcrypto;
+use pung::client::{ClientConfig, PungClient, PungClientBuilder, PungError};
+use pung::db;
+use pung::proto::ErrorCode;
+use pung::server::ServerConfig;
+use pung::util;
+use pung::util::bloomfilter;
+use rand::Rng;
+
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+
+#[test]
+fn peer_uids_are_symmetric() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+    let secret = b"shared secret";
+
+    common::run_client(|scope, port| {
+        let new_client = |name, port: &mut gjio::EventPort| {
+            PungClient::with_config(ClientConfig::new(name, &addr), scope, port)
+        };
+
+        let mut alice = new_client("alice", port)?;
+        let mut bob = new_client("bob", port)?;
+
+        alice.add_peer_with_secret("bob", secret);
+        bob.add_peer_with_secret("alice", secret);
+
+        assert_eq!(alice.peer_uids("bob"), Some((0, 1)));
+        assert_eq!(bob.peer_uids("alice"), Some((1, 0)));
+        assert!(alice.peer_uids("carol").is_none());
+
+        Ok(())
+    });
+
+    // Alice sends under bob's uid, and bob retrieves under his own uid (and vice versa)
+    let retrieved = exchange_with_alice("alice");
+    assert_eq!(retrieved.len(), 2);
+    assert_eq!(retrieved[0].len(), 1);
+    assert!(retrieved[0][0].starts_with(b"from bob"));
+    assert_eq!(retrieved[1].len(), 1);
+    assert!(retrieved[1][0].starts_with(b"from alice"));
+
+    // If bob knew alice under another name, both would send under uid 1 and retrieve under
+    // uid 0, so neither would find the other's message
+    let retrieved = exchange_with_alice("zalice");
+    assert!(retrieved.iter().all(|msgs| msgs.is_empty()));
+}
+
+// Runs a round in which alice and bob, who knows alice as alice_name, send each other a
+// message under a shared secret. Returns what alice and bob (in that order) retrieved.
+fn exchange_with_alice(alice_name: &'static str) -> Vec<Vec<Vec<u8>>> {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.expected_clients = 2;
+
+    let addr = common::spawn_server(opts);
+
+    let handles: Vec<_> = [("alice", "bob"), ("bob", alice_name)]
+        .iter()
+        .map(|&(name, peer)| {
+            let addr = addr.clone();
+
+            thread::spawn(move || {
+                let mut retrieved = Vec::new();
+
+                common::run_client(|scope, port| {
+                    let config = ClientConfig::new(name, &addr);
+                    let mut client =
+                        common::registered_client(config, &[(peer, "shared secret")], scope, port)?;
+
+                    let mut msgs = vec![format!("from {}", name).into_bytes()];
+                    client.send(peer, &mut msgs, scope, port)?;
+
+                    retrieved = client.retr(&[peer], scope, port)?;
+                    Ok(())
+                });
+
+                retrieved
+            })
+        })
+        .collect();
+
+    handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+}
+
+#[test]
+fn piggybacked_filters() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        let opts = ServerConfig::new(ret_scheme, db::OptScheme::Normal, 2);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 2,
+                    ret_rate: 2,
+                    ret_scheme: ret_scheme,
+                    piggyback: true,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            // The first retrieval brings the filters and the later ones find the message
+            for round in 0..2 {
+                let mut msgs = vec![format!("hello {}", round).into_bytes()];
+                client.send("user1", &mut msgs, scope, port)?;
+
+                let msgs = client.retr(&["user1"], scope, port)?;
+                assert_eq!(msgs.len(), 1);
+                assert!(msgs[0].starts_with(format!("hello {}", round).as_bytes()));
+
+                client.inc_round(1);
+            }
+
+            Ok(())
+        });
+    }
+}
+
+// With a single retrieval per round, the piggybacked one is all there is: retr refuses to
+// spend it on a random index (which would lose the message), and the message is still there
+// without piggybacking.
+#[test]
+fn piggyback_without_room() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        let opts = ServerConfig::new(ret_scheme, db::OptScheme::Normal, 1);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 1,
+                    ret_rate: 1,
+                    ret_scheme: ret_scheme,
+                    piggyback: true,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs = vec![b"hello".to_vec()];
+            client.send("user1", &mut msgs, scope, port)?;
+
+            assert!(client.retr(&["user1"], scope, port).is_err());
+
+            client.set_piggyback(false);
+            let msgs = client.retr(&["user1"], scope, port)?;
+            assert_eq!(msgs.len(), 1);
+            assert!(msgs[0].starts_with(b"hello"));
+
+            Ok(())
+        });
+    }
+}
+
+// With more buckets than messages, most buckets are empty. They have no PIR database, so
+// retrievals skip them, and the filters come with the first one from a bucket that is not.
+#[test]
+fn more_buckets_than_messages() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        for &piggyback in &[false, true] {
+            let opts = ServerConfig::new(ret_scheme, db::OptScheme::Normal, 8);
+            let addr = common::spawn_server(opts);
+
+            common::run_client(|scope, port| {
+                let mut client = common::registered_client(
+                    ClientConfig {
+                        send_rate: 1,
+                        ret_rate: 8,
+                        ret_scheme: ret_scheme,
+                        piggyback: piggyback,
+                        ..ClientConfig::new("user1", &addr)
+                    },
+                    &[("user1", "secret")],
+                    scope,
+                    port,
+                )?;
+
+                // Labels (and so buckets) change every round
+                for round in 0..4 {
+                    let mut msgs = vec![format!("hello {}", round).into_bytes()];
+                    client.send("user1", &mut msgs, scope, port)?;
+
+                    let msgs = client.retr(&["user1"], scope, port)?;
+                    assert_eq!(msgs.len(), 1, "{:?} (piggyback: {})", ret_scheme, piggyback);
+                    assert!(msgs[0].starts_with(format!("hello {}", round).as_bytes()));
+
+                    client.inc_round(1);
+                }
+
+                Ok(())
+            });
+        }
+    }
+}
+
+#[test]
+fn hybrid4_odd_bucket() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        let opts = ServerConfig::new(ret_scheme, db::OptScheme::Hybrid4, 2);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            // Aliasing puts each tuple in both buckets, and the 5 tuples of a bucket are split
+            // into collections of 2, 1, 1, and 1
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 5,
+                    ret_rate: 2,
+                    ret_scheme: ret_scheme,
+                    opt_scheme: db::OptScheme::Hybrid4,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs: Vec<Vec<u8>> = (0..5).map(|i| format!("msg {}", i).into_bytes()).collect();
+            client.send("user1", &mut msgs, scope, port)?;
+
+            // Hybrid4 retrieves 4 labels per bucket (padded with dummy labels)
+            let msgs = client.retr(&["user1", "user1"], scope, port)?;
+            assert_eq!(msgs.len(), 2);
+
+            for i in 0..2 {
+                let expected = format!("msg {}", i);
+                assert!(msgs.iter().any(|m| m.starts_with(expected.as_bytes())));
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn hybrid4_small_bucket() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        let opts = ServerConfig::new(ret_scheme, db::OptScheme::Hybrid4, 2);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            // The 2 tuples of a bucket are split into collections of 1, 0, 1, and 0, so some
+            // parts of the bucket are empty
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 2,
+                    ret_rate: 2,
+                    ret_scheme: ret_scheme,
+                    opt_scheme: db::OptScheme::Hybrid4,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs = vec![b"msg 0".to_vec(), b"msg 1".to_vec()];
+            client.send("user1", &mut msgs, scope, port)?;
+
+            let msgs = client.retr(&["user1", "user1"], scope, port)?;
+            assert_eq!(msgs.len(), 2);
+
+            for i in 0..2 {
+                let expected = format!("msg {}", i);
+                assert!(msgs.iter().any(|m| m.starts_with(expected.as_bytes())));
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn hybrid8_small_bucket() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom, db::RetScheme::Tree] {
+        let opts = ServerConfig::new(ret_scheme, db::OptScheme::Hybrid8, 2);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            // The 3 tuples of a bucket are spread over 8 systematic collections, most of which
+            // (and many of the parity collections) are then empty
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 3,
+                    ret_rate: 2,
+                    ret_scheme: ret_scheme,
+                    opt_scheme: db::OptScheme::Hybrid8,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs: Vec<Vec<u8>> =
+                (0..3).map(|i| format!("msg {}", i).into_bytes()).collect();
+            client.send("user1", &mut msgs, scope, port)?;
+
+            // Hybrid8 retrieves 8 labels per bucket (padded with dummy labels)
+            let msgs = client.retr(&["user1", "user1"], scope, port)?;
+            assert_eq!(msgs.len(), 2, "{:?}", ret_scheme);
+
+            for i in 0..2 {
+                let expected = format!("msg {}", i);
+                assert!(msgs.iter().any(|m| m.starts_with(expected.as_bytes())));
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn hybrid4_tree_collisions() {
+    let opts = ServerConfig::new(db::RetScheme::Tree, db::OptScheme::Hybrid4, 4);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 4,
+                ret_rate: 4,
+                ret_scheme: db::RetScheme::Tree,
+                opt_scheme: db::OptScheme::Hybrid4,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        client.set_bucket_assigner(Box::new(ToBucketZero));
+
+        // Look for a secret under which all 4 labels can be retrieved from bucket 0 (each
+        // label has bucket 0 as a candidate about half of the time)
+        let peers = ["user1"; 4];
+        let mut colliding = false;
+
+        for i in 0..1000 {
+            client.rotate_peer_secret("user1", format!("secret {}", i).as_bytes())?;
+
+            if client.bucket_loads(&peers)? == vec![4, 0, 0, 0] {
+                colliding = true;
+                break;
+            }
+        }
+
+        assert!(colliding);
+
+        let mut msgs: Vec<Vec<u8>> = (0..4).map(|i| format!("msg {}", i).into_bytes()).collect();
+        client.send("user1", &mut msgs, scope, port)?;
+
+        // Each of the 4 labels is found by descending the tree of its systematic collection
+        let msgs = client.retr(&peers, scope, port)?;
+        assert_eq!(msgs.len(), 4);
+
+        for i in 0..4 {
+            let expected = format!("msg {}", i);
+            assert!(msgs.iter().any(|m| m.starts_with(expected.as_bytes())));
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn rotate_peer_secret() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "old secret")],
+            scope,
+            port,
+        )?;
+
+        let uids = client.peer_uids("user1");
+        assert!(client.rotate_peer_secret("carol", b"new secret").is_err());
+
+        // Round 0: old keys
+        let mut msgs = vec![b"before".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+        let msgs = client.retr(&["user1"], scope, port)?;
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].starts_with(b"before"));
+
+        client.inc_round(1);
+
+        // Round 1: both ends use the new keys
+        client.rotate_peer_secret("user1", b"new secret")?;
+        assert_eq!(client.peer_uids("user1"), uids);
+
+        let mut msgs = vec![b"after".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+        let msgs = client.retr(&["user1"], scope, port)?;
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].starts_with(b"after"));
+
+        client.inc_round(1);
+
+        // Round 2: a message sent under the new keys is not found with the old ones
+        let mut msgs = vec![b"lost".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+        client.rotate_peer_secret("user1", b"old secret")?;
+        assert!(client.retr(&["user1"], scope, port)?.is_empty());
+
+        Ok(())
+    });
+}
+
+#[test]
+fn single_bucket_retrieval() {
+    let schemes = [db::RetScheme::Explicit, db::RetScheme::Bloom, db::RetScheme::Tree];
+
+    for &ret_scheme in &schemes {
+        let opts = ServerConfig::new(ret_scheme, db::OptScheme::Normal, 1);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    ret_scheme: ret_scheme,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            for round in 0..3 {
+                assert_eq!(client.get_round(), round);
+
+                let mut msgs = vec![format!("round {}", round).into_bytes()];
+                assert_eq!(client.send("user1", &mut msgs, scope, port)?, 1);
+
+                // A single bucket means a single label per round
+                assert!(client.retr(&["user1", "user1"], scope, port).is_err());
+
+                let msgs = client.retr(&["user1"], scope, port)?;
+                assert_eq!(msgs.len(), 1);
+                assert!(msgs[0].starts_with(format!("round {}", round).as_bytes()));
+
+                client.inc_round(1);
+                client.sync(scope, port)?;
+            }
+
+            Ok(())
+        });
+    }
+}
+
+// Clients use the alpha that the server tells them when they register, and those configured
+// with another one fail to register rather than decode garbage
+#[test]
+fn pir_alpha_from_server() {
+    let mut opts = ServerConfig::new(db::RetScheme::Tree, db::OptScheme::Normal, 1);
+    opts.alpha = Some(2);
+    opts.extra_tuples = 20;
+
+    let addr = common::spawn_server(opts.clone());
+    let other_addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut mismatched = PungClientBuilder::new()
+            .name("user2")
+            .address(&other_addr)
+            .alpha(4)
+            .build(scope, port)?;
+
+        mismatched.init_dummy_peer();
+        assert!(mismatched.register(scope, port).is_err());
+
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        for round in 0..2 {
+            let mut msgs = vec![format!("round {}", round).into_bytes()];
+            client.send("user1", &mut msgs, scope, port)?;
+
+            let msgs = client.retr(&["user1"], scope, port)?;
+            assert_eq!(msgs.len(), 1);
+            assert!(msgs[0].starts_with(format!("round {}", round).as_bytes()));
+
+            client.inc_round(1);
+            client.sync(scope, port)?;
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn several_messages_per_round() {
+    for &opt_scheme in &[db::OptScheme::Normal, db::OptScheme::Aliasing] {
+        let opts = ServerConfig::new(db::RetScheme::Explicit, opt_scheme, 4);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 3,
+                    ret_rate: 4,
+                    opt_scheme: opt_scheme,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            // Each message is encrypted under its own nonce, and decrypts with it
+            let mut msgs = vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()];
+            client.send("user1", &mut msgs, scope, port)?;
+
+            let outcomes = client.retr_outcomes(&["user1", "user1", "user1"], scope, port)?;
+            let expected: [&[u8]; 3] = [b"first", b"second", b"third"];
+
+            for (outcome, msg) in outcomes.iter().zip(&expected) {
+                match *outcome {
+                    client::RetrOutcome::Found(ref m) => assert!(m.starts_with(msg)),
+                    _ => panic!("a message was not retrieved"),
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn chunk_reassembly() {
+    use pung::client::chunk;
+
+    let msg: Vec<u8> = (0..chunk::CHUNK_PAYLOAD * 2 + 5).map(|i| i as u8).collect();
+    let chunks = chunk::split(&msg, 7).unwrap();
+    assert_eq!(chunks.len(), 3);
+    assert!(chunks.iter().all(|c| c.len() <= client::MESSAGE_SIZE));
+
+    // Retrieved messages are padded, and chunks come in any order
+    let padded = |c: &Vec<u8>| {
+        let mut m = c.clone();
+        m.resize(client::MESSAGE_SIZE, 0);
+        ("alice".to_string(), m)
+    };
+
+    let msgs = vec![padded(&chunks[2]), padded(&chunks[0]), padded(&chunks[1])];
+    let whole = chunk::reassemble(msgs);
+    assert_eq!(whole.len(), 1);
+    assert!(whole[0].is_complete());
+    assert_eq!(whole[0].sender, "alice");
+    assert_eq!(whole[0].id, 7);
+    assert_eq!(whole[0].data, msg);
+
+    // A missing chunk leaves the message incomplete rather than dropping it
+    let whole = chunk::reassemble(vec![padded(&chunks[2]), padded(&chunks[0])]);
+    assert_eq!(whole.len(), 1);
+    assert!(!whole[0].is_complete());
+    assert_eq!(whole[0].missing, vec![1]);
+    assert_eq!(&whole[0].data[..chunk::CHUNK_PAYLOAD], &msg[..chunk::CHUNK_PAYLOAD]);
+    assert_eq!(&whole[0].data[chunk::CHUNK_PAYLOAD..], &msg[chunk::CHUNK_PAYLOAD * 2..]);
+
+    // A message that fits in one chunk (including an empty one), and the same id from
+    // another sender, are messages of their own
+    let short = chunk::split(b"short", 7).unwrap();
+    let empty = chunk::split(b"", 8).unwrap();
+    assert_eq!(short.len(), 1);
+    assert_eq!(empty.len(), 1);
+
+    let mut msgs = vec![padded(&chunks[0]), padded(&chunks[1]), padded(&chunks[2])];
+    msgs.push(("bob".to_string(), padded(&short[0]).1));
+    msgs.push(("bob".to_string(), padded(&empty[0]).1));
+
+    let whole = chunk::reassemble(msgs);
+    assert_eq!(whole.len(), 3);
+    assert!(whole.iter().all(|m| m.is_complete()));
+    assert_eq!(whole[0].data, msg);
+    assert_eq!((&whole[1].sender[..], whole[1].id), ("bob", 7));
+    assert_eq!(whole[1].data, b"short");
+    assert_eq!((&whole[2].sender[..], whole[2].id), ("bob", 8));
+    assert!(whole[2].data.is_empty());
+
+    // Malformed chunks are dropped
+    assert!(chunk::reassemble(vec![("alice".to_string(), b"bad".to_vec())]).is_empty());
+
+    // Chunks kept by a Reassembler complete their message whenever the last one comes
+    let mut reassembler = chunk::Reassembler::new();
+    assert_eq!(reassembler.add("alice", &padded(&chunks[1]).1), None);
+    assert_eq!(reassembler.add("alice", &padded(&chunks[0]).1), None);
+    let done = reassembler.add("alice", &padded(&chunks[2]).1).expect("message not complete");
+    assert_eq!(done.data, msg);
+    assert!(reassembler.take_incomplete().is_empty());
+
+    assert_eq!(reassembler.add("alice", &padded(&chunks[0]).1), None);
+    let partial = reassembler.take_incomplete();
+    assert_eq!(partial.len(), 1);
+    assert_eq!(partial[0].missing, vec![1, 2]);
+    assert!(reassembler.take_incomplete().is_empty());
+
+    assert!(chunk::split(&vec![0u8; chunk::MAX_LARGE_SIZE + 1], 7).is_err());
+    assert!(chunk::split(b"id 0 is for plain messages", 0).is_err());
+}
+
+#[test]
+fn send_large() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 4);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 3,
+                ret_rate: 4,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        // Longer than a tuple: it takes all 3 messages of the send rate
+        let msg: Vec<u8> = (0..client::MESSAGE_SIZE * 2 + 1).map(|i| i as u8).collect();
+        let id = client.send_large("user1", &msg, scope, port)?;
+
+        let msgs = client.retr_large(&["user1", "user1", "user1"], scope, port)?;
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].is_complete(), "missing chunks {:?}", msgs[0].missing);
+        assert_ne!(id, 0);
+        assert_eq!(msgs[0].id, id);
+        assert_eq!(msgs[0].data, msg);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn send_large_across_rounds() {
+    use pung::client::chunk;
+
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 2,
+                ret_rate: 2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        let msg: Vec<u8> = (0..chunk::CHUNK_PAYLOAD * 2 + 1).map(|i| i as u8).collect();
+        let chunks = chunk::split(&msg, 9).unwrap();
+        assert_eq!(chunks.len(), 3);
+
+        // Round 0: the first two chunks, which are kept until the last one comes
+        {
+            let mut session = client.send_session();
+            session.add_chunk("user1", &chunks[0])?;
+            session.add_chunk("user1", &chunks[1])?;
+            session.finish(scope, port)?;
+        }
+
+        assert!(client.retr_large(&["user1", "user1"], scope, port)?.is_empty());
+        client.inc_round(1);
+
+        // Round 1: the last chunk, along with a plain message that has the bytes of a chunk
+        let lookalike = chunk::split(b"not a chunk", 10).unwrap().remove(0);
+
+        {
+            let mut session = client.send_session();
+            session.add_chunk("user1", &chunks[2])?;
+            session.add("user1", &lookalike)?;
+            session.finish(scope, port)?;
+        }
+
+        let msgs = client.retr_large(&["user1", "user1"], scope, port)?;
+        assert_eq!(msgs.len(), 2);
+
+        // Plain messages come first, as they were sent (padded), whatever they contain
+        assert_eq!(msgs[0].id, 0);
+        assert!(msgs[0].is_complete());
+        assert!(msgs[0].data.starts_with(&lookalike));
+        assert_eq!(msgs[0].data.len(), client::MESSAGE_SIZE);
+
+        assert_eq!(msgs[1].id, 9);
+        assert!(msgs[1].is_complete(), "missing chunks {:?}", msgs[1].missing);
+        assert_eq!(msgs[1].data, msg);
+
+        assert!(client.take_incomplete_large().is_empty());
+
+        Ok(())
+    });
+}
+
+#[test]
+fn retr_stream_delivers_each_message() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 2,
+                ret_rate: 2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        let mut msgs = vec![b"first".to_vec(), b"second".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        let mut calls = 0;
+        let mut delivered = Vec::new();
+
+        client.retr_stream(
+            &["user1", "user1"],
+            |m| {
+                calls += 1;
+                delivered.push(m);
+            },
+            scope,
+            port,
+        )?;
+
+        assert_eq!(calls, 2);
+        assert_eq!(delivered.len(), calls);
+        assert!(delivered.iter().any(|m| m.starts_with(b"first")));
+        assert!(delivered.iter().any(|m| m.starts_with(b"second")));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn round_trip() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 3,
+                ret_rate: 2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret"), ("user2", "other secret")],
+            scope,
+            port,
+        )?;
+
+        assert!(client.round_trip(&[], &["user1"], scope, port).is_err());
+        assert!(client.round_trip(&[("carol", &b"hi"[..])], &[], scope, port).is_err());
+
+        for round in 0..2 {
+            assert_eq!(client.get_round(), round);
+
+            // Messages to each peer are numbered separately, so user2's message in between
+            // does not affect the labels of the second message to user1
+            let outgoing = [
+                ("user1", &b"first"[..]),
+                ("user2", &b"elsewhere"[..]),
+                ("user1", &b"second"[..]),
+            ];
+
+            let msgs = client.round_trip(&outgoing, &["user1", "user1"], scope, port)?;
+            assert_eq!(msgs.len(), 2);
+            assert!(msgs.iter().any(|m| m.starts_with(b"first")));
+            assert!(msgs.iter().any(|m| m.starts_with(b"second")));
+        }
+
+        assert_eq!(client.get_round(), 2);
+
+        Ok(())
+    });
+}
+
+// Messages are bytes: non-UTF-8 content, zeros inside a message, and a message that fills the
+// whole tuple all come back as they were sent
+#[test]
+fn binary_messages_round_trip() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 2,
+                ret_rate: 2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        let full: Vec<u8> = (0..client::MESSAGE_SIZE).map(|i| (255 - i % 256) as u8).collect();
+        let short: Vec<u8> = vec![0xff, 0x00, 0xc5, 0x80, 0x00, 0xfe];
+        assert!(String::from_utf8(full.clone()).is_err());
+        assert!(String::from_utf8(short.clone()).is_err());
+
+        let mut msgs = vec![full.clone(), short.clone()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        let msgs = client.retr(&["user1", "user1"], scope, port)?;
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs.iter().any(|m| *m == full));
+
+        let retrieved = msgs.iter().find(|m| m.starts_with(&short)).expect("short message lost");
+        assert_eq!(retrieved.len(), client::MESSAGE_SIZE);
+        assert!(retrieved[short.len()..].iter().all(|b| *b == 0));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn cover_rates() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(
+            ClientConfig {
+                ret_rate: 2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            scope,
+            port,
+        )?;
+
+        // Cover rates cannot be below the real ones
+        assert!(client.set_cover_rates(0, 2).is_err());
+        assert!(client.set_cover_rates(1, 1).is_err());
+
+        client.set_cover_rates(3, 4)?;
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        for round in 0..2 {
+            assert_eq!(client.get_round(), round);
+
+            // A single real message still yields the cover rate's worth of tuples
+            let mut msgs = vec![b"hello".to_vec()];
+            client.send("user1", &mut msgs, scope, port)?;
+            assert_eq!(client.stats(scope, port)?.unencoded_tuples, 3);
+
+            let msgs = client.retr(&["user1"], scope, port)?;
+            assert_eq!(msgs.len(), 1);
+            assert!(msgs[0].starts_with(b"hello"));
+
+            // The server only moves on (and clears the round's tuples) once the client has
+            // made exactly the retrievals its cover rate calls for
+            assert_eq!(client.stats(scope, port)?.unencoded_tuples, 0);
+            client.inc_round(1);
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn empty_send_with_cover() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+
+        // Without cover traffic there is nothing to send
+        let mut msgs: Vec<Vec<u8>> = Vec::new();
+        assert!(client.send("user1", &mut msgs, scope, port).is_err());
+
+        client.set_cover_rates(2, 1)?;
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        // Only cover tuples are sent, as many as the cover send rate
+        assert_eq!(client.send("user1", &mut msgs, scope, port)?, 2);
+        assert_eq!(client.stats(scope, port)?.unencoded_tuples, 2);
+
+        let msgs = client.retr(&["user1"], scope, port)?;
+        assert!(msgs.is_empty());
+
+        Ok(())
+    });
+}
+
+// Cover tuples keep their two labels in separate buckets under aliasing, which cannot be done
+// with a single bucket: sending only cover tuples (as the client binary does in interactive
+// mode when nothing was typed) fails instead of looking for an alias label forever
+#[test]
+fn cover_single_bucket() {
+    for &opt_scheme in &[db::OptScheme::Aliasing, db::OptScheme::Hybrid2, db::OptScheme::Hybrid4] {
+        let opts = ServerConfig::new(db::RetScheme::Explicit, opt_scheme, 1);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    opt_scheme: opt_scheme,
+                    cover_rates: Some((2, 1)),
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs: Vec<Vec<u8>> = Vec::new();
+            assert!(client.send("user1", &mut msgs, scope, port).is_err(), "{:?}", opt_scheme);
+
+            Ok(())
+        });
+    }
+}
+
+// Cover tuples sent (on a fresh server) by a client created with the given seed, or with
+// OS randomness if there is none
+fn cover_tuples(seed: Option<u64>) -> Vec<Vec<u8>> {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.debug = true;
+    let addr = common::spawn_server(opts);
+    let mut tuples = Vec::new();
+
+    common::run_client(|scope, port| {
+        let config = ClientConfig {
+            cover_rates: Some((4, 1)),
+            ..ClientConfig::new("user1", &addr)
+        };
+
+        let mut client = match seed {
+            Some(seed) => PungClient::with_seed(config, seed, scope, port)?,
+            None => PungClient::with_config(config, scope, port)?,
+        };
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        let mut msgs: Vec<Vec<u8>> = Vec::new();
+        client.send("user1", &mut msgs, scope, port)?;
+
+        tuples = client
+            .retr_bucket_dump(0, scope, port)?
+            .iter()
+            .map(|t| t.to_binary())
+            .collect();
+
+        Ok(())
+    });
+
+    tuples
+}
+
+#[test]
+fn seeded_randomness() {
+    // The same seed yields the same (random) cover tuples, but by default every client
+    // draws its own
+    assert_eq!(cover_tuples(Some(7)), cover_tuples(Some(7)));
+    assert!(cover_tuples(Some(7)) != cover_tuples(Some(8)));
+    assert!(cover_tuples(None) != cover_tuples(None));
+}
+
+#[test]
+fn clients_on_separate_threads() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.expected_clients = 2;
+
+    let addr = common::spawn_server(opts);
+
+    // PungClient is neither Send nor Sync, so each thread runs its own event loop and
+    // creates its client there
+    let handles: Vec<_> = [("alice", "bob"), ("bob", "alice")]
+        .iter()
+        .map(|&(name, peer)| {
+            let addr = addr.clone();
+
+            thread::spawn(move || {
+                common::run_client(|scope, port| {
+                    let mut client = common::registered_client(
+                        ClientConfig::new(name, &addr),
+                        &[(peer, "threads")],
+                        scope,
+                        port,
+                    )?;
+
+                    for _ in 0..2 {
+                        let outgoing = [(peer, name.as_bytes())];
+                        let msgs = client.round_trip(&outgoing, &[peer], scope, port)?;
+                        assert_eq!(msgs.len(), 1);
+                        assert!(msgs[0].starts_with(peer.as_bytes()));
+                    }
+
+                    Ok(())
+                });
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn retr_attributed() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    opts.expected_clients = 2;
+
+    let addr = common::spawn_server(opts);
+
+    // Alice writes to Bob, and Bob writes a note to himself. Both retrieve from everyone.
+    let handles: Vec<_> = [("alice", "bob"), ("bob", "bob")]
+        .iter()
+        .map(|&(name, recipient)| {
+            let addr = addr.clone();
+            let senders: &[&str] = if name == "alice" { &["bob"] } else { &["alice", "bob"] };
+
+            thread::spawn(move || {
+                common::run_client(|scope, port| {
+                    let mut client = PungClient::with_config(
+                        ClientConfig {
+                            ret_rate: 2,
+                            ..ClientConfig::new(name, &addr)
+                        },
+                        scope,
+                        port,
+                    )?;
+
+                    // Every pair of peers has its own secret, so that a message can only be
+                    // attributed to the peer that sent it
+                    client.init_dummy_peer();
+                    let other = if name == "alice" { "bob" } else { "alice" };
+                    client.add_peer_with_secret(other, b"alice and bob");
+
+                    if name == "bob" {
+                        client.add_peer_with_secret("bob", b"bob's notes");
+                    }
+
+                    client.register(scope, port)?;
+                    client.sync(scope, port)?;
+
+                    let mut msgs = vec![format!("from {}", name).into_bytes()];
+                    client.send(recipient, &mut msgs, scope, port)?;
+
+                    let mut msgs = client.retr_attributed(senders, scope, port)?;
+                    msgs.sort();
+
+                    let senders: Vec<&str> = msgs.iter().map(|&(ref p, _)| &p[..]).collect();
+
+                    if name == "alice" {
+                        // Bob sent nothing to Alice
+                        assert!(msgs.is_empty());
+                    } else {
+                        assert_eq!(senders, ["alice", "bob"]);
+                        assert!(msgs[0].1.starts_with(b"from alice"));
+                        assert!(msgs[1].1.starts_with(b"from bob"));
+                    }
+
+                    Ok(())
+                });
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn retr_all() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 3);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                ret_rate: 3,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret"), ("user2", "other secret")],
+            scope,
+            port,
+        )?;
+
+        // Only the note to self is there, and it comes with its sender
+        let mut msgs = vec![b"note to self".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        let msgs = client.retr_all(scope, port)?;
+        assert_eq!(msgs.len(), 1);
+        assert_eq!(msgs[0].0, "user1");
+        assert!(msgs[0].1.starts_with(b"note to self"));
+
+        // There cannot be more peers than retrievals per round
+        client.add_peer_with_secret("user3", b"third secret");
+        client.add_peer_with_secret("user4", b"fourth secret");
+        assert!(client.retr_all(scope, port).is_err());
+
+        Ok(())
+    });
+}
+
+#[test]
+fn dh_peers() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.expected_clients = 2;
+
+    let addr = common::spawn_server(opts);
+    let registered = Arc::new(Barrier::new(2));
+
+    // Alice and Bob write to each other without sharing a secret beforehand
+    let handles: Vec<_> = [("alice", "bob"), ("bob", "alice")]
+        .iter()
+        .map(|&(name, peer)| {
+            let addr = addr.clone();
+            let registered = registered.clone();
+
+            thread::spawn(move || {
+                common::run_client(|scope, port| {
+                    let mut client =
+                        PungClient::with_config(ClientConfig::new(name, &addr), scope, port)?;
+
+                    client.init_dummy_peer();
+                    client.register(scope, port)?;
+
+                    // The directory lists our key, and knows nobody else yet
+                    assert_eq!(client.fetch_key(name, scope, port)?, client.public_key());
+
+                    match client.fetch_key("carol", scope, port) {
+                        Err(PungError::InvalidPeer(_)) => (),
+                        r => panic!("unexpected result for an unknown peer: {:?}", r),
+                    }
+
+                    registered.wait();
+
+                    client.add_peer(peer, scope, port)?;
+                    client.sync(scope, port)?;
+
+                    let mut msgs = vec![format!("from {}", name).into_bytes()];
+                    client.send(peer, &mut msgs, scope, port)?;
+
+                    let msgs = client.retr(&[peer], scope, port)?;
+                    assert_eq!(msgs.len(), 1);
+                    assert!(msgs[0].starts_with(format!("from {}", peer).as_bytes()));
+
+                    Ok(())
+                });
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn dh_key_rotation() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[],
+            scope,
+            port,
+        )?;
+
+        let old_key = client.public_key().to_vec();
+        assert_eq!(client.fetch_key("user1", scope, port)?, old_key);
+
+        // The new key is only listed once the client syncs
+        client.rotate_dh_key();
+        assert!(client.public_key() != &old_key[..]);
+        assert_eq!(client.fetch_key("user1", scope, port)?, old_key);
+
+        client.sync(scope, port)?;
+        assert_eq!(client.fetch_key("user1", scope, port)?, client.public_key());
+
+        // Notes to self work with a secret agreed on with our own key
+        client.add_peer("user1", scope, port)?;
+
+        let mut msgs = vec![b"note".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        let msgs = client.retr(&["user1"], scope, port)?;
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].starts_with(b"note"));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn invalid_rates() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let new_client = |send_rate, ret_rate, port: &mut gjio::EventPort| {
+            PungClient::with_config(
+                ClientConfig {
+                    send_rate: send_rate,
+                    ret_rate: ret_rate,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                scope,
+                port,
+            )
+        };
+
+        // Zero rates are rejected when the client is created
+        assert!(new_client(0, 2, port).is_err());
+        assert!(new_client(1, 0, port).is_err());
+
+        // Clients whose buckets differ from the server's cannot register
+        for &ret_rate in &[1, 3] {
+            let mut client = new_client(1, ret_rate, port)?;
+            assert!(client.register(scope, port).is_err());
+        }
+
+        let mut client = new_client(1, 2, port)?;
+        client.register(scope, port)?;
+
+        Ok(())
+    });
+}
+
+#[test]
+fn client_builder() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let builder = PungClientBuilder::new().name("user1").address(&addr).ret_rate(2);
+
+        // Missing settings, bad addresses, and unreachable servers are errors, not panics
+        assert!(PungClientBuilder::new().address(&addr).build(scope, port).is_err());
+        assert!(PungClientBuilder::new().name("user1").build(scope, port).is_err());
+        assert!(builder.clone().send_rate(0).build(scope, port).is_err());
+        assert!(builder.clone().address("not an address").build(scope, port).is_err());
+
+        let unreachable = format!("127.0.0.1:{}", common::free_port());
+        assert!(builder.clone().address(&unreachable).build(scope, port).is_err());
+
+        // The builder configures the client like ClientConfig does
+        let builder = builder.send_rate(2).depth(1);
+        assert_eq!(builder.config().send_rate, 2);
+        assert_eq!(builder.config().ret_rate, 2);
+        assert_eq!(builder.config().address, addr);
+
+        let mut client = builder.build(scope, port)?;
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        let mut msgs = vec![b"built".to_vec(), b"twice".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        let mut msgs = client.retr(&["user1", "user1"], scope, port)?;
+        msgs.sort();
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].starts_with(b"built"));
+        assert!(msgs[1].starts_with(b"twice"));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn counting_metrics() {
+    use pung::client::metrics::{CountingMetrics, RpcKind};
+    use std::rc::Rc;
+
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+        let metrics = Rc::new(CountingMetrics::new());
+        client.set_metrics(Box::new(metrics.clone()));
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        let mut msgs = vec![b"counted".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        // One tuple (and the 16-byte id and round) up, one bucket size down
+        let send = metrics.get(RpcKind::Send);
+        assert_eq!(send.uploaded, db::TUPLE_SIZE + 16);
+        assert_eq!(send.downloaded, 8);
+        assert_eq!(metrics.get(RpcKind::Pir), Default::default());
+
+        client.retr(&["user1"], scope, port)?;
+
+        // The bucket's only label comes down uncompressed, and so does a PIR answer
+        let labels = metrics.get(RpcKind::Labels);
+        assert_eq!(labels.uploaded, 16);
+        assert_eq!(labels.downloaded, db::LABEL_SIZE);
+        assert_eq!(labels.downloaded_uncompressed, labels.downloaded);
+
+        let pir = metrics.get(RpcKind::Pir);
+        assert!(pir.uploaded > 32);
+        assert!(pir.downloaded > 8);
+        assert_eq!(metrics.get(RpcKind::Bloom), Default::default());
+
+        let total = metrics.total();
+        assert_eq!(total.uploaded, send.uploaded + labels.uploaded + pir.uploaded);
+        assert_eq!(total.downloaded, send.downloaded + labels.downloaded + pir.downloaded);
+
+        metrics.reset();
+        assert_eq!(metrics.total(), Default::default());
+
+        Ok(())
+    });
+}
+
+#[test]
+fn aliasing_single_bucket() {
+    // Hybrid schemes alias labels too (e.g., a Hybrid4 bucket of odd size on a single bucket)
+    for &opt_scheme in &[db::OptScheme::Aliasing, db::OptScheme::Hybrid4] {
+        let opts = ServerConfig::new(db::RetScheme::Explicit, opt_scheme, 1);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    opt_scheme: opt_scheme,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            // Alias labels can never fall in a different bucket, so both sending and
+            // scheduling retrievals fail instead of searching for one forever
+            let mut msgs = vec![b"hello".to_vec()];
+            assert!(client.send("user1", &mut msgs, scope, port).is_err());
+            assert!(client.bucket_loads(&["user1"]).is_err());
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn ret_scheme_mismatch() {
+    let schemes = [
+        (db::RetScheme::Tree, db::RetScheme::Explicit), // labels come in BST order
+        (db::RetScheme::Explicit, db::RetScheme::Bloom), // filters are placeholders
+    ];
+
+    for &(server_scheme, client_scheme) in &schemes {
+        let opts = ServerConfig::new(server_scheme, db::OptScheme::Normal, 1);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 3,
+                    ret_scheme: client_scheme,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs: Vec<Vec<u8>> = (0..3).map(|i| format!("msg {}", i).into_bytes()).collect();
+            client.send("user1", &mut msgs, scope, port)?;
+
+            match client.retr(&["user1"], scope, port) {
+                Ok(_) => panic!("Retrieval succeeded despite the scheme mismatch"),
+                Err(PungError::SchemeMismatch(_)) => (),
+                Err(e) => panic!("Expected a scheme mismatch, got {:?}", e),
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn opt_scheme_mismatch() {
+    let schemes = [
+        // (ret scheme, server's opt scheme, client's opt scheme)
+        (db::RetScheme::Explicit, db::OptScheme::Hybrid2, db::OptScheme::Normal), // 2 lists
+        (db::RetScheme::Bloom, db::OptScheme::Hybrid2, db::OptScheme::Normal), // 2 filters
+        (db::RetScheme::Tree, db::OptScheme::Normal, db::OptScheme::Hybrid2), // no min labels
+        (db::RetScheme::Tree, db::OptScheme::Hybrid4, db::OptScheme::Hybrid2), // 3 min labels
+    ];
+
+    for &(ret_scheme, server_scheme, client_scheme) in &schemes {
+        let opts = ServerConfig::new(ret_scheme, server_scheme, 1);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 3,
+                    ret_scheme: ret_scheme,
+                    opt_scheme: client_scheme,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            // The mismatch shows either in the send's response (min labels) or in the
+            // retrieval's labels or filters, but never as a panic or a wrong message
+            let mut msgs: Vec<Vec<u8>> = (0..3).map(|i| format!("msg {}", i).into_bytes()).collect();
+            let result = client
+                .send("user1", &mut msgs, scope, port)
+                .and_then(|_| client.retr(&["user1"], scope, port));
+
+            match result {
+                Ok(_) => panic!("Send and retrieval succeeded despite the scheme mismatch"),
+                Err(PungError::SchemeMismatch(_)) => (),
+                Err(e) => panic!("Expected a scheme mismatch, got {:?}", e),
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn server_disconnects() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+    let proxy = common::Proxy::new(&addr);
+
+    common::run_client(|scope, port| {
+        let config = ClientConfig::new("user1", &proxy.addr);
+        let mut client = common::registered_client(config, &[("user1", "secret")], scope, port)?;
+
+        let mut msgs = vec![b"hello".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        // The connection goes away before the messages are retrieved
+        proxy.cut();
+
+        match client.retr(&["user1"], scope, port) {
+            Ok(_) => panic!("Retrieval succeeded without a connection"),
+            Err(e) => assert!(client::is_disconnected(&e), "{:?}", e),
+        }
+
+        // Failed requests are not mistaken for disconnections
+        let failed = capnp::Error::failed("Invalid id".to_string());
+        assert!(!client::is_disconnected(&PungError::from(failed)));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn error_variants() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+
+        let mut msgs = vec![b"hello".to_vec()];
+
+        match client.send("carol", &mut msgs, scope, port) {
+            Err(PungError::InvalidPeer(_)) => (),
+            r => panic!("Expected an invalid peer, got {:?}", r),
+        }
+
+        client.register(scope, port)?;
+
+        // The server only accepts sends from clients that synced for the round
+        match client.send("user1", &mut msgs, scope, port) {
+            Err(PungError::NotSynchronized(_)) => (),
+            r => panic!("Expected a client that is not synchronized, got {:?}", r),
+        }
+
+        client.sync(scope, port)?;
+
+        // The send rate is 1, and the server's description is kept as is
+        let mut msgs = vec![b"hello".to_vec(), b"world".to_vec()];
+
+        match client.send("user1", &mut msgs, scope, port) {
+            Err(e @ PungError::RateExceeded(_)) => assert_eq!(e.to_string(), "Send rate exceeded."),
+            r => panic!("Expected an exceeded rate, got {:?}", r),
+        }
+
+        match client.retr(&["user1", "user1"], scope, port) {
+            Err(PungError::RateExceeded(_)) => (),
+            r => panic!("Expected an exceeded rate, got {:?}", r),
+        }
+
+        Ok(())
+    });
+
+    // Errors that do not come from a client call are classified by their code alone
+    let wrong_phase = ErrorCode::WrongPhase.error("Not sending phase.");
+    let untagged = capnp::Error::failed("Not sending phase.".to_string());
+    let disconnected = capnp::Error::disconnected("Connection reset".to_string());
+
+    match PungError::from(wrong_phase) {
+        PungError::WrongPhase(ref s) => assert_eq!(s, "Not sending phase."),
+        e => panic!("Expected a wrong phase, got {:?}", e),
+    }
+
+    match PungError::from(untagged) {
+        PungError::Failed(ref s) => assert_eq!(s, "Not sending phase."),
+        e => panic!("Expected a failure, got {:?}", e),
+    }
+
+    // The variant survives a trip through capnp::Error
+    let corrupted = PungError::Corrupted("Corrupted PIR answer".to_string());
+
+    match PungError::from(capnp::Error::from(corrupted)) {
+        PungError::Corrupted(ref s) => assert_eq!(s, "Corrupted PIR answer"),
+        e => panic!("Expected a corrupted answer, got {:?}", e),
+    }
+
+    assert!(client::is_disconnected(&PungError::from(disconnected)));
+}
+
+// Retrieves every label from bucket 0 whenever it is one of the candidates
+struct ToBucketZero;
+
+impl assign::BucketAssigner for ToBucketZero {
+    fn assign(&self, candidates: &[usize; 2], _loads: &[usize]) -> usize {
+        if candidates[0] == 0 {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+#[test]
+fn custom_bucket_assigner() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Aliasing, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(
+            ClientConfig {
+                send_rate: 2,
+                ret_rate: 2,
+                opt_scheme: db::OptScheme::Aliasing,
+                ..ClientConfig::new("user1", &addr)
+            },
+            scope,
+            port,
+        )?;
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+
+        // With two buckets, every label can be retrieved from either of them. The default
+        // policy spreads the labels, and the custom one piles them up in bucket 0.
+        assert_eq!(client.bucket_loads(&["user1", "user1"])?, vec![1, 1]);
+
+        client.set_bucket_assigner(Box::new(ToBucketZero));
+        assert_eq!(client.bucket_loads(&["user1", "user1"])?, vec![2, 0]);
+
+        client.set_bucket_assigner(Box::new(assign::LeastFull));
+        assert_eq!(client.bucket_loads(&["user1", "user1"])?, vec![1, 1]);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn verified_answers() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Tree] {
+        for &debug in &[false, true] {
+            let mut opts = ServerConfig::new(ret_scheme, db::OptScheme::Normal, 2);
+            opts.debug = debug;
+            let addr = common::spawn_server(opts);
+
+            common::run_client(|scope, port| {
+                let mut client = common::registered_client(
+                    ClientConfig {
+                        ret_rate: 2,
+                        ret_scheme: ret_scheme,
+                        verify_answers: true,
+                        ..ClientConfig::new("user1", &addr)
+                    },
+                    &[("user1", "secret"), ("user2", "other secret")],
+                    scope,
+                    port,
+                )?;
+
+                let mut msgs = vec![b"hello".to_vec()];
+                client.send("user1", &mut msgs, scope, port)?;
+
+                if !debug {
+                    assert!(client.retr(&["user1", "user2"], scope, port).is_err());
+                    return Ok(());
+                }
+
+                // user2 sent nothing, which is a miss rather than a corrupted answer
+                let msgs = client.retr(&["user1", "user2"], scope, port)?;
+                assert_eq!(msgs.len(), 1);
+                assert!(msgs[0].starts_with(b"hello"));
+
+                Ok(())
+            });
+        }
+    }
+
+    // An answer that decodes into the wrong bytes is caught, while a genuine tuple whose
+    // label is not the one sought (a miss) is not
+    let mut rng = rand::ChaChaRng::new_unseeded();
+    let tuples: Vec<db::PungTuple> = (0..4)
+        .map(|_| {
+            let mut data = [0u8; db::TUPLE_SIZE];
+            rng.fill_bytes(&mut data);
+            db::PungTuple::new(&data)
+        })
+        .collect();
+
+    let checksums: Vec<u32> = tuples.iter().map(|t| t.checksum()).collect();
+
+    for (i, tuple) in tuples.iter().enumerate() {
+        assert!(client::verify_answer(tuple, &checksums, i as u64).is_ok());
+    }
+
+    let mut corrupted = tuples[2].clone();
+    corrupted.data[db::LABEL_SIZE] ^= 1; // the label is intact, so it would look like a hit
+
+    let e = client::verify_answer(&corrupted, &checksums, 2).unwrap_err();
+    assert!(client::is_corrupted(&e), "{:?}", e);
+
+    let e = client::verify_answer(&tuples[0], &checksums, 4).unwrap_err();
+    assert!(client::is_corrupted(&e), "{:?}", e);
+
+    let failed = capnp::Error::failed("Invalid id".to_string());
+    assert!(!client::is_corrupted(&PungError::from(failed)));
+}
+
+#[test]
+fn label_index() {
+    let opt_schemes = [
+        db::OptScheme::Normal,
+        db::OptScheme::Aliasing,
+        db::OptScheme::Hybrid2,
+        db::OptScheme::Hybrid4,
+    ];
+
+    let mut rng = rand::ChaChaRng::new_unseeded();
+
+    let mut random_tuple = || {
+        let mut data = [0u8; db::TUPLE_SIZE];
+        rng.fill_bytes(&mut data);
+        db::PungTuple::new(&data)
+    };
+
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        for &opt_scheme in &opt_schemes {
+            let mut buckets = Vec::new();
+            let mut label_index = index::LabelIndex::new();
+
+            // Index the collections as the client does with the server's filters
+            for b in 0..2 {
+                let mut bucket = db::Bucket::new(ret_scheme, opt_scheme, 1).unwrap();
+
+                for _ in 0..(50 + b) {
+                    bucket.push(random_tuple());
+                }
+
+                bucket.encode().unwrap();
+
+                for c in util::label_collections(opt_scheme) {
+                    let collection = bucket.get_collection(c);
+                    let len = collection.len();
+
+                    if ret_scheme == db::RetScheme::Explicit {
+                        let labels = (0..len).map(|i| collection.get_label(i).to_vec()).collect();
+                        label_index.insert_labels(b, c, labels);
+                    } else {
+                        let mut bloom = bloomfilter::Bloom::new_for_fp_rate(len, db::BLOOM_FP);
+                        bloom.from_bytes(&collection.get_bloom().to_bytes());
+                        label_index.insert_bloom(b, c, bloom, len as u64);
+                    }
+                }
+
+                buckets.push(bucket);
+            }
+
+            // Lookups agree with searching the labels or probing the bloom filter directly
+            for (b, bucket) in buckets.iter().enumerate() {
+                for c in util::label_collections(opt_scheme) {
+                    let collection = bucket.get_collection(c);
+                    let len = collection.len() as u64;
+                    let labels: Vec<Vec<u8>> =
+                        (0..collection.len()).map(|i| collection.get_label(i).to_vec()).collect();
+
+                    assert_eq!(label_index.len(b, c), Some(len));
+
+                    let missing = random_tuple().label().to_vec();
+
+                    for (i, label) in labels.iter().chain(Some(&missing)).enumerate() {
+                        let expected = if ret_scheme == db::RetScheme::Explicit {
+                            util::get_index(&labels, label)
+                        } else {
+                            util::get_idx_bloom(collection.get_bloom(), label, len)
+                        };
+
+                        assert_eq!(label_index.index_of(b, c, label), expected);
+
+                        if i < labels.len() {
+                            assert!(expected.is_some());
+                        }
+                    }
+
+                    if ret_scheme == db::RetScheme::Explicit {
+                        assert_eq!(label_index.index_of(b, c, &missing), None);
+                    }
+                }
+            }
+
+            // Collections that were never added are empty
+            assert_eq!(label_index.len(2, 0), None);
+            assert_eq!(label_index.index_of(2, 0, buckets[0].get_collection(0).get_label(0)), None);
+        }
+    }
+}
+
+#[test]
+fn deterministic_query_order() {
+    let schemes = [
+        (db::OptScheme::Normal, 2),
+        (db::OptScheme::Hybrid2, 2),
+        (db::OptScheme::Hybrid4, 4),
+    ];
+
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        for &(opt_scheme, ret_rate) in &schemes {
+            // The round is retained so that it can be retrieved from a second time
+            let mut opts = ServerConfig::new(ret_scheme, opt_scheme, ret_rate);
+            opts.retain_rounds = 1;
+            let addr = common::spawn_server(opts);
+
+            common::run_client(|scope, port| {
+                let mut client = common::registered_client(
+                    ClientConfig {
+                        send_rate: ret_rate,
+                        ret_rate: ret_rate,
+                        ret_scheme: ret_scheme,
+                        opt_scheme: opt_scheme,
+                        ..ClientConfig::new("user1", &addr)
+                    },
+                    &[("user1", "secret"), ("user2", "other secret")],
+                    scope,
+                    port,
+                )?;
+
+                let mut msgs: Vec<Vec<u8>> =
+                    (0..ret_rate).map(|i| format!("msg {}", i).into_bytes()).collect();
+                client.send("user1", &mut msgs, scope, port)?;
+
+                let mut peers = vec!["user1"; ret_rate as usize - 1];
+                peers.push("user2");
+
+                let first = client.retr(&peers, scope, port)?;
+                let queries = client.last_queries();
+                assert!(!queries.is_empty());
+
+                // The same retrieval (of the retained round) issues the same queries, in the same
+                // order, and finds the same messages
+                let second = client.retr(&peers, scope, port)?;
+                assert_eq!(client.last_queries(), queries);
+                assert_eq!(second, first);
+
+                // Buckets are always visited in order, as are collections within a bucket
+                let num_buckets = client.bucket_loads(&peers)?.len();
+                let per_bucket = match opt_scheme {
+                    db::OptScheme::Hybrid2 => 3,
+                    db::OptScheme::Hybrid4 => 9,
+                    _ => 1,
+                };
+
+                assert_eq!(queries.len() % (num_buckets * per_bucket), 0);
+
+                for (i, chunk) in queries.chunks(per_bucket).enumerate() {
+                    let bucket = i % num_buckets;
+                    assert!(chunk.iter().all(|&(b, _, level, _)| b == bucket && level == 0));
+
+                    let collections: Vec<u32> = chunk.iter().map(|&(_, c, _, _)| c).collect();
+                    assert_eq!(collections, (0..per_bucket as u32).collect::<Vec<u32>>());
+                }
+
+                Ok(())
+            });
+        }
+    }
+}
+
+#[test]
+fn hybrid4_query_trace() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom] {
+        // The round is retained so that it can be retrieved from a second time
+        let mut opts = ServerConfig::new(ret_scheme, db::OptScheme::Hybrid4, 4);
+        opts.retain_rounds = 1;
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 4,
+                    ret_rate: 4,
+                    ret_scheme: ret_scheme,
+                    opt_scheme: db::OptScheme::Hybrid4,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret"), ("user2", "other secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs: Vec<Vec<u8>> =
+                (0..4).map(|i| format!("msg {}", i).into_bytes()).collect();
+            client.send("user1", &mut msgs, scope, port)?;
+
+            // Every label is present, and then none is
+            assert_eq!(client.retr(&["user1"; 4], scope, port)?.len(), 4);
+            let present = client.last_queries();
+
+            assert!(client.retr(&["user2"; 4], scope, port)?.is_empty());
+            let absent = client.last_queries();
+
+            // The (collection, level, length) of every request is the same either way
+            assert_eq!(present, absent);
+
+            for (i, &(_, collection, level, len)) in present.iter().enumerate() {
+                assert_eq!(collection, (i % 9) as u32);
+                assert_eq!(level, 0);
+                assert!(len > 0);
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn hybrid2_tree_query_trace() {
+    let opts = ServerConfig::new(db::RetScheme::Tree, db::OptScheme::Hybrid2, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 2,
+                ret_rate: 2,
+                ret_scheme: db::RetScheme::Tree,
+                opt_scheme: db::OptScheme::Hybrid2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret"), ("user2", "other secret")],
+            scope,
+            port,
+        )?;
+
+        // user2 sends nothing, so its labels fall on either side of each bucket's lmid at
+        // random. Over these rounds, both labels of a bucket end up in collection 0, in
+        // collection 1, and in different collections (in either order).
+        let peer_sets = [
+            ["user1", "user1"],
+            ["user1", "user2"],
+            ["user2", "user1"],
+            ["user2", "user2"],
+        ];
+
+        let mut trace = None;
+
+        for i in 0..24 {
+            let peers = &peer_sets[i % peer_sets.len()];
+
+            let mut msgs = vec![b"msg 0".to_vec(), b"msg 1".to_vec()];
+            client.send("user1", &mut msgs, scope, port)?;
+
+            let msgs = client.retr(peers, scope, port)?;
+            assert_eq!(msgs.len(), peers.iter().filter(|&&p| p == "user1").count());
+
+            let queries = client.last_queries();
+
+            // Within a bucket, each level of collections 0, 1, and 2 is requested in order
+            for pair in queries.windows(2) {
+                let (b1, c1, level1, _) = pair[0];
+                let (b2, c2, level2, _) = pair[1];
+                assert!(b1 != b2 || (level1, c1) < (level2, c2), "{:?}", queries);
+            }
+
+            // Buckets are the same size every round, so the requests must be too
+            match trace {
+                None => trace = Some(queries),
+                Some(ref t) => assert_eq!(&queries, t),
+            }
+
+            client.inc_round(1);
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn wrong_length_pir_result() {
+    // What a PIR backend that disagrees with the server on the tuple size would decode
+    let mut rng = rand::ChaChaRng::new_unseeded();
+    let mut data = vec![0u8; db::TUPLE_SIZE + 1];
+    rng.fill_bytes(&mut data);
+
+    assert!(client::decode_tuple(&data[..]).is_err());
+    assert!(client::decode_tuple(&data[..db::TUPLE_SIZE - 1]).is_err());
+    assert!(client::decode_tuple(&[]).is_err());
+
+    let tuple = client::decode_tuple(&data[..db::TUPLE_SIZE]).unwrap();
+    assert_eq!(&tuple.data[..], &data[..db::TUPLE_SIZE]);
+}
+
+#[test]
+fn overridden_bloom_hashes() {
+    overridden_bloom(Some(2), db::BLOOM_FP);
+}
+
+#[test]
+fn overridden_bloom_fp() {
+    overridden_bloom(None, 0.01);
+}
+
+// The client reads the server's filters with the server's number of hash functions and
+// false positive rate, whatever those are
+fn overridden_bloom(hashes: Option<u32>, fp: f64) {
+    for &opt_scheme in &[db::OptScheme::Normal, db::OptScheme::Hybrid2] {
+        let mut opts = ServerConfig::new(db::RetScheme::Bloom, opt_scheme, 2);
+        opts.bloom_hashes = hashes;
+        opts.bloom_fp = fp;
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 2,
+                    ret_rate: 2,
+                    ret_scheme: db::RetScheme::Bloom,
+                    opt_scheme: opt_scheme,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs = vec![b"msg 0".to_vec(), b"msg 1".to_vec()];
+            client.send("user1", &mut msgs, scope, port)?;
+
+            let msgs = client.retr(&["user1", "user1"], scope, port)?;
+            assert_eq!(msgs.len(), 2);
+
+            for i in 0..2 {
+                let expected = format!("msg {}", i);
+                assert!(msgs.iter().any(|m| m.starts_with(expected.as_bytes())));
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn silent_peer_not_present() {
+    for &ret_scheme in &[db::RetScheme::Explicit, db::RetScheme::Bloom, db::RetScheme::Tree] {
+        let opts = ServerConfig::new(ret_scheme, db::OptScheme::Normal, 2);
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    ret_rate: 2,
+                    ..ClientConfig::new("alice", &addr)
+                },
+                &[("alice", "notes"), ("bob", "silence")],
+                scope,
+                port,
+            )?;
+
+            // Bob sends nothing, so only alice's note is there to be found
+            let mut msgs = vec![b"note".to_vec()];
+            client.send("alice", &mut msgs, scope, port)?;
+
+            let outcomes = client.retr_outcomes(&["alice", "bob"], scope, port)?;
+            assert_eq!(outcomes.len(), 2);
+
+            match outcomes[0] {
+                client::RetrOutcome::Found(ref m) => assert!(m.starts_with(b"note")),
+                _ => panic!("alice's note was not found"),
+            }
+
+            match outcomes[1] {
+                client::RetrOutcome::NotPresent => (),
+                _ => panic!("bob's missing message was not reported as not present"),
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn self_check_flags_missed_messages() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    opts.debug = true;
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                ret_rate: 2,
+                ..ClientConfig::new("alice", &addr)
+            },
+            &[("alice", "notes"), ("bob", "silence")],
+            scope,
+            port,
+        )?;
+
+        // A correct retrieval misses nothing (bob sent nothing, so there is nothing to miss)
+        let mut msgs = vec![b"note 0".to_vec()];
+        client.send("alice", &mut msgs, scope, port)?;
+
+        let (outcomes, missed) = client.retr_self_check(&["alice", "bob"], scope, port)?;
+        assert_eq!(outcomes.len(), 2);
+        assert!(missed.is_empty());
+
+        client.inc_round(1);
+
+        // A retrieval bug that silently drops alice's note is caught
+        let mut msgs = vec![b"note 1".to_vec()];
+        client.send("alice", &mut msgs, scope, port)?;
+
+        let present = client.debug_present(&["alice", "bob"], scope, port)?;
+        assert_eq!(present, vec![true, false]);
+
+        let mut outcomes = client.retr_outcomes(&["alice", "bob"], scope, port)?;
+        assert!(client::missed_messages(&outcomes, &present).is_empty());
+
+        outcomes[0] = client::RetrOutcome::NotPresent;
+        assert_eq!(client::missed_messages(&outcomes, &present), vec![0]);
+
+        Ok(())
+    });
+}
+
+#[test]
+#[cfg(feature = "fault-injection")]
+fn self_check_flags_corrupted_answers() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    opts.debug = true;
+    opts.corrupt_answers = true; // PIR answers decode into garbage
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                ret_rate: 2,
+                ..ClientConfig::new("alice", &addr)
+            },
+            &[("alice", "notes"), ("bob", "silence")],
+            scope,
+            port,
+        )?;
+
+        let mut msgs = vec![b"note".to_vec()];
+        client.send("alice", &mut msgs, scope, port)?;
+
+        // The note is on the server, but the retrieval silently did not find it
+        let (outcomes, missed) = client.retr_self_check(&["alice", "bob"], scope, port)?;
+        assert_eq!(outcomes.len(), 2);
+
+        match outcomes[0] {
+            client::RetrOutcome::Found(_) => panic!("corrupted answer decoded"),
+            _ => (),
+        }
+
+        assert_eq!(missed, vec![0]);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn send_to_self() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.debug = true;
+    let addr = common::spawn_server(opts);
+    let secret = b"notes to self";
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("alice", &addr), scope, port)?;
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("alice", secret);
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        // Messages to oneself are sent and retrieved under the same uid
+        let (uid_self, uid_peer) = client.peer_uids("alice").unwrap();
+        assert_eq!((uid_self, uid_peer), (0, 0));
+
+        let keys = pcrypto::derive_keys(secret, pcrypto::AeadAlgorithm::default());
+
+        for round in 0..2 {
+            let note = format!("note {}", round).into_bytes();
+            client.send("alice", &mut vec![note.clone()], scope, port)?;
+
+            // The server holds the note under the label that retrieving it derives
+            let label = pcrypto::gen_label(&keys.k_l, round, uid_peer, 0, 0);
+            assert!(client.debug_lookup(0, &label, scope, port)?.is_some());
+
+            let msgs = client.retr(&["alice"], scope, port)?;
+            client.inc_round(1);
+
+            assert_eq!(msgs.len(), 1);
+            assert!(msgs[0].starts_with(&note));
+        }
+
+        assert_eq!(client.get_round(), 2);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn planned_buckets() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 4);
+    opts.debug = true;
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 3,
+                ret_rate: 4,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        let peers = ["user1", "user1", "user1"];
+        assert!(client.planned_buckets(&["carol"]).is_err());
+        assert!(client.planned_buckets(&[])?.is_empty());
+
+        let planned = client.planned_buckets(&peers)?;
+        assert_eq!(client.probed_buckets(), vec![0, 1, 2, 3]);
+
+        let mut msgs: Vec<Vec<u8>> = (0..3).map(|i| format!("msg {}", i).into_bytes()).collect();
+        client.send("user1", &mut msgs, scope, port)?;
+
+        // The buckets with real labels are exactly those to which the messages were sent
+        let mut sent_to = Vec::new();
+
+        for bucket in client.probed_buckets() {
+            if !client.retr_bucket_dump(bucket, scope, port)?.is_empty() {
+                sent_to.push(bucket);
+            }
+        }
+
+        assert_eq!(planned, sent_to);
+
+        let msgs = client.retr(&peers, scope, port)?;
+        assert_eq!(msgs.len(), 3);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn send_session() {
+    let sent: Vec<Vec<u8>> = (0..3).map(|i| format!("msg {}", i).into_bytes()).collect();
+    let mut dumps = Vec::new();
+
+    for &streamed in &[false, true] {
+        let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+        opts.debug = true;
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 3,
+                    ret_rate: 2,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            if streamed {
+                let mut session = client.send_session();
+                assert!(session.add("carol", b"hi").is_err());
+
+                for msg in &sent {
+                    session.add("user1", msg)?;
+                }
+
+                assert_eq!(session.len(), 3);
+                session.finish(scope, port)?;
+            } else {
+                assert!(client.send_session().finish(scope, port).is_err());
+                client.send("user1", &mut sent.clone(), scope, port)?;
+            }
+
+            let mut tuples: Vec<Vec<u8>> = Vec::new();
+
+            for bucket in 0..2 {
+                let dump = client.retr_bucket_dump(bucket, scope, port)?;
+                tuples.extend(dump.iter().map(|t| t.to_binary()));
+            }
+
+            tuples.sort();
+            dumps.push(tuples);
+
+            Ok(())
+        });
+    }
+
+    // A streamed send produces the same tuples as a batch send of the same messages
+    assert_eq!(dumps[0].len(), 3);
+    assert_eq!(dumps[0], dumps[1]);
+}
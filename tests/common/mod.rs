@@ -0,0 +1,217 @@
+// Helpers for tests that run a Pung server and clients in-process.
+#![allow(dead_code)]
+
+use capnp;
+use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use gj;
+use gjio;
+use pung;
+use pung::client::{ClientConfig, PungClient};
+use pung::pung_capnp::pung_rpc;
+use pung::server::send_dataflow;
+use pung::server::ServerConfig;
+use timely;
+
+use std::cell::RefCell;
+use std::io;
+use std::io::Read;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Returns a port that is currently free on the loopback interface.
+pub fn free_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+/// Waits until a server is listening at `addr`. By the time this returns, the server is done
+/// with the probe connection, so it does not count against any connection limit.
+pub fn wait_for_server(addr: &SocketAddr) {
+    for _ in 0..500 {
+        if let Ok(mut stream) = TcpStream::connect(addr) {
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+
+            // The server closes its end once it has let go of the connection
+            let _ = stream.read_to_end(&mut Vec::new());
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    panic!("No server is listening at {}", addr);
+}
+
+/// Runs a single-worker Pung server in a background thread and returns its address.
+/// The server runs until the test process exits.
+pub fn spawn_server(config: ServerConfig) -> String {
+    let addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+
+    thread::spawn(move || {
+        timely::execute(timely::Configuration::Thread, move |mut worker| {
+            let dbase = config.database().expect("Invalid server configuration");
+            let dbase = Rc::new(RefCell::new(dbase));
+            let send_handle = send_dataflow::graph(&mut worker, dbase.clone());
+
+            pung::server::run(addr, worker.clone(), send_handle, dbase, config.clone())
+                .expect("Could not start the server");
+        }).expect("Timely dataflow error");
+    });
+
+    wait_for_server(&addr);
+
+    addr.to_string()
+}
+
+/// Runs a Pung server with `workers` timely workers (each with its own RPC endpoint) in a
+/// background thread and returns the address of each worker, in order of worker index.
+pub fn spawn_workers(config: ServerConfig, workers: usize) -> Vec<String> {
+    let addrs: Vec<SocketAddr> = (0..workers)
+        .map(|_| format!("127.0.0.1:{}", free_port()).parse().unwrap())
+        .collect();
+
+    let worker_addrs = addrs.clone();
+
+    thread::spawn(move || {
+        timely::execute(timely::Configuration::Process(workers), move |mut worker| {
+            let addr = worker_addrs[worker.index()];
+            let dbase = config.database().expect("Invalid server configuration");
+            let dbase = Rc::new(RefCell::new(dbase));
+            let send_handle = send_dataflow::graph(&mut worker, dbase.clone());
+
+            pung::server::run(addr, worker.clone(), send_handle, dbase, config.clone())
+                .expect("Could not start the server");
+        }).expect("Timely dataflow error");
+    });
+
+    for addr in &addrs {
+        wait_for_server(addr);
+    }
+
+    addrs.iter().map(|addr| addr.to_string()).collect()
+}
+
+/// Like `spawn_server`, but the server builds its rounds without timely dataflow (as with
+/// --no-timely).
+pub fn spawn_direct_server(config: ServerConfig) -> String {
+    let addr: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+
+    thread::spawn(move || {
+        let dbase = config.database().expect("Invalid server configuration");
+        pung::server::run_direct(addr, Rc::new(RefCell::new(dbase)), config)
+            .expect("Could not start the server");
+    });
+
+    wait_for_server(&addr);
+
+    addr.to_string()
+}
+
+/// Runs `f` inside a fresh event loop (one per thread).
+pub fn run_client<F>(f: F)
+where
+    F: FnOnce(&gj::WaitScope, &mut gjio::EventPort) -> Result<(), capnp::Error>,
+{
+    gj::EventLoop::top_level(move |wait_scope| -> Result<(), capnp::Error> {
+        let mut event_port = gjio::EventPort::new()?;
+        f(wait_scope, &mut event_port)
+    }).expect("top level error");
+}
+
+/// Connects a client with `config`, shares a secret with each of `peers` (name and secret),
+/// and registers and syncs it, which is where most tests start.
+pub fn registered_client<'a>(
+    config: ClientConfig<'a>,
+    peers: &[(&'a str, &str)],
+    scope: &gj::WaitScope,
+    port: &mut gjio::EventPort,
+) -> Result<PungClient<'a>, capnp::Error> {
+    let mut client = PungClient::with_config(config, scope, port)?;
+    client.init_dummy_peer();
+
+    for &(peer, secret) in peers {
+        client.add_peer_with_secret(peer, secret.as_bytes());
+    }
+
+    client.register(scope, port)?;
+    client.sync(scope, port)?;
+
+    Ok(client)
+}
+
+/// Connects to a Pung server and returns a raw RPC client (for requests that a well-behaved
+/// PungClient never makes).
+pub fn connect(
+    addr: &str,
+    scope: &gj::WaitScope,
+    port: &mut gjio::EventPort,
+) -> Result<pung_rpc::Client, capnp::Error> {
+    let addr: SocketAddr = addr.parse().unwrap();
+    let stream = port.get_network().get_tcp_address(addr).connect().wait(scope, port)?;
+
+    let network = Box::new(twoparty::VatNetwork::new(
+        stream.clone(),
+        stream,
+        rpc_twoparty_capnp::Side::Client,
+        Default::default(),
+    ));
+
+    let mut rpc_system = RpcSystem::new(network, None);
+    Ok(rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server))
+}
+
+/// Forwards connections from its own address to a server, so that tests can sever them
+/// (as if the server had gone away) while the server keeps running.
+pub struct Proxy {
+    pub addr: String,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    pub fn new(server_addr: &str) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let streams = Arc::new(Mutex::new(Vec::new()));
+
+        let server_addr = server_addr.to_string();
+        let proxy_streams = streams.clone();
+
+        thread::spawn(move || for client in listener.incoming() {
+            let client = client.unwrap();
+            let server = TcpStream::connect(&server_addr[..]).unwrap();
+
+            {
+                let mut streams = proxy_streams.lock().unwrap();
+                streams.push(client.try_clone().unwrap());
+                streams.push(server.try_clone().unwrap());
+            }
+
+            forward(client.try_clone().unwrap(), server.try_clone().unwrap());
+            forward(server, client);
+        });
+
+        Proxy {
+            addr: addr,
+            streams: streams,
+        }
+    }
+
+    /// Closes every connection made through the proxy (in both directions).
+    pub fn cut(&self) {
+        for stream in self.streams.lock().unwrap().iter() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// Copies bytes from one stream to the other until the former is closed
+fn forward(mut from: TcpStream, mut to: TcpStream) {
+    thread::spawn(move || {
+        let _ = io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Write);
+    });
+}
@@ -1,5 +1,7 @@
 extern crate pung;
 extern crate rand;
+#[cfg(feature = "serialize")]
+extern crate serde_json;
 
 use pung::db;
 use pung::db::bst::BSTOrder;
@@ -54,7 +56,7 @@ fn batch_code_2_explicit() {
     create_tuples(num, &mut tuples_1, Some(0));
     create_tuples(num, &mut tuples_2, Some(255));
 
-    let mut bucket = db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Hybrid2, 1);
+    let mut bucket = db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Hybrid2, 1).unwrap();
     
     for tuple in &tuples_1 {
         bucket.push(tuple.clone());
@@ -64,7 +66,7 @@ fn batch_code_2_explicit() {
         bucket.push(tuple.clone());
     }
 
-    bucket.encode();
+    bucket.encode().unwrap();
 
     tuples_1.sort();
     tuples_2.sort();
@@ -96,7 +98,7 @@ fn batch_code_2_bst() {
     create_tuples(num, &mut tuples_1, Some(0));
     create_tuples(num, &mut tuples_2, Some(255));
 
-    let mut bucket = db::Bucket::new(db::RetScheme::Tree, db::OptScheme::Hybrid2, 1);
+    let mut bucket = db::Bucket::new(db::RetScheme::Tree, db::OptScheme::Hybrid2, 1).unwrap();
     
     for tuple in &tuples_1 {
         bucket.push(tuple.clone());
@@ -106,7 +108,7 @@ fn batch_code_2_bst() {
         bucket.push(tuple.clone());
     }
 
-    bucket.encode();
+    bucket.encode().unwrap();
 
     tuples_1.sort(); 
     tuples_1.as_bst_order();
@@ -130,3 +132,815 @@ fn batch_code_2_bst() {
     assert!(tuples_2[120] == *bucket.get_collection(1).get_tuple(120));
     assert!((&tuples_1[120] ^ &tuples_2[120]) == *bucket.get_collection(2).get_tuple(120));
 }
+
+#[test]
+fn layout_summary_hybrid2() {
+    let num = 1001;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    let mut bucket = db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Hybrid2, 1).unwrap();
+
+    for tuple in &tuples {
+        bucket.push(tuple.clone());
+    }
+
+    bucket.encode().unwrap();
+    tuples.sort();
+
+    let summary = bucket.layout_summary();
+    assert_eq!(summary.len(), 3);
+
+    assert_eq!(summary.iter().filter(|c| c.is_systematic).count(), 2);
+    assert_eq!(summary.iter().filter(|c| c.is_parity).count(), 1);
+    assert!(summary[2].is_parity);
+
+    assert_eq!(summary[0].len, 501);
+    assert_eq!(summary[1].len, 500);
+    assert_eq!(summary[2].len, 501);
+
+    assert_eq!(summary[0].first_label, Some(tuples[0].label().to_vec()));
+    assert_eq!(summary[1].first_label, Some(tuples[501].label().to_vec()));
+
+    // lmid of collection 1 is its first label
+    assert_eq!(summary[0].lmid, None);
+    assert_eq!(summary[1].lmid, Some(tuples[501].label().to_vec()));
+    assert_eq!(summary[2].lmid, None);
+}
+
+#[test]
+fn get_level_out_of_range() {
+    let mut tuples = Vec::with_capacity(5);
+    create_tuples(5, &mut tuples, None);
+
+    // 5 tuples form a tree with 3 levels (1, 2, and 2 nodes)
+    let mut collection = db::Collection::new(db::RetScheme::Tree, 1);
+    collection.set_contents(tuples.clone());
+
+    assert_eq!(collection.num_levels(), 3);
+    assert_eq!(collection.get_level(0).unwrap().len(), 1);
+    assert_eq!(collection.get_level(1).unwrap().len(), 2);
+    assert_eq!(collection.get_level(2).unwrap().len(), 2);
+    assert!(collection.get_level(3).is_err());
+    assert!(collection.get_level(64).is_err());
+
+    // Explicit retrieval only has a single level
+    let mut collection = db::Collection::new(db::RetScheme::Explicit, 1);
+    collection.set_contents(tuples);
+
+    assert_eq!(collection.get_level(0).unwrap().len(), 5);
+    assert!(collection.get_level(1).is_err());
+
+    // An empty tree has no levels
+    let collection = db::Collection::new(db::RetScheme::Tree, 1);
+    assert!(collection.get_level(0).is_err());
+}
+
+
+// Pushes 8 runs of `num` tuples whose labels keep them apart once sorted, so that (once
+// encoded) run i is systematic collection i of a Hybrid8 bucket
+fn hybrid8_bucket(
+    ret_scheme: db::RetScheme,
+    num: usize,
+) -> (db::Bucket<'static>, Vec<Vec<db::PungTuple>>) {
+    let mut bucket = db::Bucket::new(ret_scheme, db::OptScheme::Hybrid8, 1).unwrap();
+    let mut runs = Vec::with_capacity(8);
+
+    for i in 0..8 {
+        let mut tuples = Vec::with_capacity(num);
+        create_tuples(num, &mut tuples, Some(i * 32));
+
+        for tuple in &tuples {
+            bucket.push(tuple.clone());
+        }
+
+        tuples.sort();
+        runs.push(tuples);
+    }
+
+    bucket.encode().unwrap();
+
+    (bucket, runs)
+}
+
+// Checks that every parity collection of a Hybrid8 bucket is the XOR of the two collections
+// of the plan, and that every mapping rebuilds its systematic collection
+fn check_hybrid8_parts(bucket: &db::Bucket, num: usize) {
+    for (i, (c1, c2)) in pung::util::h8_plan().into_iter().enumerate() {
+        for idx in 0..num {
+            let expected = bucket.get_collection(c1).get_tuple(idx)
+                ^ bucket.get_collection(c2).get_tuple(idx);
+            assert!(expected == *bucket.get_collection(i + 8).get_tuple(idx));
+        }
+    }
+
+    for (target, mappings) in pung::util::h8_mappings().iter().enumerate() {
+        assert_eq!(mappings.len(), 8);
+
+        for idx in &[0, 50, 120] {
+            for parts in mappings {
+                let mut tuple = db::PungTuple::default();
+
+                for &part in parts {
+                    tuple ^= bucket.get_collection(part).get_tuple(*idx).clone();
+                }
+
+                assert!(tuple == *bucket.get_collection(target).get_tuple(*idx));
+            }
+        }
+    }
+}
+
+#[test]
+fn batch_code_8_explicit() {
+    let num = 1000;
+    let (bucket, runs) = hybrid8_bucket(db::RetScheme::Explicit, num);
+
+    assert!(bucket.len() == 27 * num);
+    assert_eq!(bucket.unencoded_len(), 8 * num);
+
+    for i in 0..27 {
+        assert!(bucket.get_collection(i).len() == num);
+    }
+
+    for (i, run) in runs.iter().enumerate() {
+        for &idx in &[0, 50, 120] {
+            assert!(run[idx] == *bucket.get_collection(i).get_tuple(idx));
+        }
+    }
+
+    // The first label of every systematic collection but the first
+    let lmids = bucket.mid_labels();
+    assert_eq!(lmids.len(), 7);
+
+    for i in 1..8 {
+        assert_eq!(&lmids[i - 1][..], runs[i][0].label());
+    }
+
+    check_hybrid8_parts(&bucket, num);
+}
+
+#[test]
+fn batch_code_8_bst() {
+    let num = 1000;
+    let (bucket, mut runs) = hybrid8_bucket(db::RetScheme::Tree, num);
+
+    assert!(bucket.len() == 27 * num);
+
+    for (i, run) in runs.iter_mut().enumerate() {
+        run.as_bst_order();
+
+        for &idx in &[0, 50, 120] {
+            assert!(run[idx] == *bucket.get_collection(i).get_tuple(idx));
+        }
+    }
+
+    check_hybrid8_parts(&bucket, num);
+}
+
+#[test]
+fn batch_code_8_odd_split() {
+    for &num in &[1usize, 7, 9, 13, 100] {
+        let mut tuples = Vec::with_capacity(num);
+        create_tuples(num, &mut tuples, None);
+
+        let mut bucket =
+            db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Hybrid8, 1).unwrap();
+
+        for tuple in &tuples {
+            bucket.push(tuple.clone());
+        }
+
+        bucket.encode().unwrap();
+
+        assert_eq!(bucket.unencoded_len(), num);
+        assert_eq!(bucket.mid_labels().len(), 7);
+
+        for part in 0..27 {
+            let len = pung::util::h8_part_len(num as u64, part);
+            assert_eq!(bucket.get_collection(part).len() as u64, len);
+        }
+    }
+}
+
+#[test]
+fn batch_code_4_odd_split() {
+    for &num in &[1usize, 3, 5, 7, 9] {
+        let mut tuples = Vec::with_capacity(num);
+        create_tuples(num, &mut tuples, None);
+
+        let mut bucket =
+            db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Hybrid4, 1).unwrap();
+
+        for tuple in &tuples {
+            bucket.push(tuple.clone());
+        }
+
+        bucket.encode().unwrap();
+
+        // Collection sizes (and thus the presence of mid labels) follow collection_len,
+        // which is what clients use to validate a round before retrieving
+        let lmids = bucket.mid_labels();
+        assert_eq!(lmids.len(), 3);
+
+        for i in 0..4 {
+            let len = pung::util::collection_len(num as u64, i as u32, 4);
+            assert_eq!(bucket.get_collection(i).len() as u64, len);
+
+            if i > 0 {
+                assert_eq!(lmids[i - 1].is_empty(), len == 0);
+            }
+        }
+    }
+}
+
+
+#[test]
+fn batch_code_4_shorter_parts() {
+    // Parts from which clients rebuild each systematic collection (as in PungClient)
+    let mappings: [[&[usize]; 4]; 4] = [
+        [&[0], &[1, 4], &[2, 6], &[3, 5, 7, 8]],
+        [&[1], &[0, 4], &[3, 7], &[2, 5, 6, 8]],
+        [&[2], &[3, 5], &[0, 6], &[1, 4, 7, 8]],
+        [&[3], &[2, 5], &[1, 7], &[0, 4, 6, 8]],
+    ];
+
+    // In all of these sizes some parts are one element shorter than collection 0
+    for &num in &[5usize, 6, 7, 9, 10, 11] {
+        let mut tuples = Vec::with_capacity(num);
+        create_tuples(num, &mut tuples, None);
+
+        let mut bucket =
+            db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Hybrid4, 1).unwrap();
+
+        for tuple in &tuples {
+            bucket.push(tuple.clone());
+        }
+
+        bucket.encode().unwrap();
+
+        for part in 0..9 {
+            let len = pung::util::h4_part_len(num as u64, part);
+            assert_eq!(bucket.get_collection(part).len() as u64, len);
+        }
+
+        assert!(bucket.get_collection(3).len() < bucket.get_collection(0).len());
+
+        for (target, parts_list) in mappings.iter().enumerate() {
+            let collection = bucket.get_collection(target);
+
+            for idx in 0..collection.len() {
+                for parts in parts_list {
+                    let mut tuple = db::PungTuple::default();
+
+                    for &part in parts.iter() {
+                        let len = pung::util::h4_part_len(num as u64, part) as usize;
+
+                        // A part is at most one element shorter than the target collection,
+                        // and then the index is covered by the other parts
+                        assert!(idx <= len);
+
+                        if idx < len {
+                            tuple ^= bucket.get_collection(part).get_tuple(idx).clone();
+                        }
+                    }
+
+                    assert_eq!(&tuple.data[..], &collection.get_tuple(idx).data[..]);
+                }
+            }
+        }
+    }
+}
+
+
+#[test]
+fn encoding_overhead() {
+    let num = 1000;
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    let schemes = [
+        (db::OptScheme::Normal, 1.0),
+        (db::OptScheme::Hybrid2, 1.5),
+        (db::OptScheme::Hybrid4, 2.25),
+    ];
+
+    for &(scheme, ratio) in &schemes {
+        let mut dbase = db::Database::new(db::RetScheme::Explicit, scheme, 2, 1).unwrap();
+        assert_eq!(dbase.encoding_overhead_ratio(), 1.0);
+
+        for (i, tuple) in tuples.iter().enumerate() {
+            dbase.push(i % 2, tuple.clone());
+        }
+
+        // Nothing is added until the database is encoded
+        assert_eq!(dbase.unencoded_len(), num);
+        assert_eq!(dbase.len(), num);
+
+        dbase.encode().unwrap();
+
+        assert_eq!(dbase.unencoded_len(), num);
+        assert!((dbase.encoding_overhead_ratio() - ratio).abs() < 1e-9);
+    }
+}
+
+
+#[test]
+fn push_batch() {
+    let num = 1000;
+    let buckets = 4;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    let partitions = pung::util::Partitioning::new(buckets).unwrap();
+    let new_db = || {
+        db::Database::new(db::RetScheme::Explicit, db::OptScheme::Normal, buckets, 1).unwrap()
+    };
+
+    // Pushing one tuple at a time to the bucket its label falls in
+    let mut expected = new_db();
+
+    for tuple in &tuples {
+        expected.push(partitions.bucket_of(tuple.label()), tuple.clone());
+    }
+
+    let mut dbase = new_db();
+    dbase.push_batch(tuples.clone());
+
+    assert_eq!(dbase.len(), num);
+
+    for i in 0..buckets {
+        let expected_labels: Vec<&[u8]> =
+            expected.get_bucket(i).get_collection(0).get_tuples().map(|t| t.label()).collect();
+        let labels: Vec<&[u8]> =
+            dbase.get_bucket(i).get_collection(0).get_tuples().map(|t| t.label()).collect();
+
+        assert!(!labels.is_empty());
+        assert_eq!(labels, expected_labels);
+    }
+
+    // TTLs are kept per tuple
+    let mut dbase = new_db();
+    let with_ttls = tuples.iter().enumerate().map(|(i, t)| (t.clone(), (i % 2) as u32));
+    dbase.push_batch_with_ttl(with_ttls);
+
+    assert_eq!(dbase.evict_expired(1), Ok(num / 2));
+
+    // The next round's database (see take) splits batches the same way
+    let mut next = dbase.take();
+    next.push_batch(tuples.clone());
+
+    for i in 0..buckets {
+        assert_eq!(next.get_bucket(i).len(), expected.get_bucket(i).len());
+    }
+
+    // There is no partitioning into 0 buckets
+    assert!(db::Database::new(db::RetScheme::Explicit, db::OptScheme::Normal, 0, 1).is_err());
+}
+
+#[test]
+fn bloom_fp() {
+    let num = 1000;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    let mut sizes = Vec::new();
+
+    for &fp in &[0.01, db::BLOOM_FP] {
+        let mut dbase =
+            db::Database::new(db::RetScheme::Bloom, db::OptScheme::Normal, 1, 1).unwrap();
+        dbase.set_bloom_fp(fp).unwrap();
+        assert_eq!(dbase.bloom_fp(), fp);
+
+        // The rate carries over to the databases of later rounds
+        dbase.take();
+
+        for tuple in &tuples {
+            dbase.push(0, tuple.clone());
+        }
+
+        dbase.encode().unwrap();
+
+        let collection = dbase.get_bucket(0).get_collection(0);
+        let bloom = collection.get_bloom();
+        assert_eq!(bloom.number_of_bits(), db::new_bloom_with_fp(num, fp, None).number_of_bits());
+
+        // A client told the same rate reads the same filter
+        let mut client_bloom = db::new_bloom_with_fp(num, fp, None);
+        client_bloom.from_bytes(&bloom.to_bytes());
+
+        for i in 0..num {
+            assert!(client_bloom.check((i, collection.get_label(i))));
+        }
+
+        sizes.push(bloom.number_of_bits());
+    }
+
+    // A higher false positive rate makes for smaller filters
+    assert!(sizes[0] < sizes[1]);
+
+    let mut dbase = db::Database::new(db::RetScheme::Bloom, db::OptScheme::Normal, 1, 1).unwrap();
+
+    for &fp in &[0.0, 1.0, -0.5, 2.0, std::f64::NAN] {
+        assert!(db::check_bloom_fp(fp).is_err());
+        assert!(dbase.set_bloom_fp(fp).is_err());
+    }
+
+    assert_eq!(dbase.bloom_fp(), db::BLOOM_FP);
+}
+
+#[test]
+fn pir_alpha() {
+    let num = 1000;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    for &alpha in &[None, Some(1), Some(4), Some(64)] {
+        let mut dbase =
+            db::Database::new(db::RetScheme::Tree, db::OptScheme::Normal, 1, 1).unwrap();
+        dbase.set_alpha(alpha).unwrap();
+        assert_eq!(dbase.alpha(), alpha);
+
+        // The alpha carries over to the databases of later rounds
+        dbase.take();
+
+        for tuple in &tuples {
+            dbase.push(0, tuple.clone());
+        }
+
+        dbase.encode().unwrap();
+
+        // Until PIR is set up, retrievals are refused with an error that says so
+        assert_eq!(
+            dbase.get_bucket(0).get_collection(0).pir_handler(0).err(),
+            Some("PIR is unavailable for this collection")
+        );
+
+        dbase.pir_setup().unwrap();
+
+        // Every level uses the alpha that clients derive from its size
+        let collection = dbase.get_bucket(0).get_collection(0);
+
+        for level in 0..collection.num_levels() {
+            let len = collection.get_level(level).unwrap().len() as u64;
+            let handler = collection.pir_handler(level).unwrap();
+            assert_eq!(handler.alpha(), pung::util::pir_alpha(len, alpha));
+        }
+    }
+
+    let mut dbase = db::Database::new(db::RetScheme::Tree, db::OptScheme::Normal, 1, 1).unwrap();
+    assert!(dbase.set_alpha(Some(0)).is_err());
+    assert_eq!(dbase.alpha(), None);
+}
+
+#[test]
+fn parallel_encode() {
+    let num = 2000;
+    let buckets = 16;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    let ret_schemes = [db::RetScheme::Explicit, db::RetScheme::Bloom, db::RetScheme::Tree];
+    let opt_schemes = [
+        db::OptScheme::Normal,
+        db::OptScheme::Aliasing,
+        db::OptScheme::Hybrid2,
+        db::OptScheme::Hybrid4,
+    ];
+
+    for &ret_scheme in &ret_schemes {
+        for &opt_scheme in &opt_schemes {
+            let new_db = || {
+                let mut dbase = db::Database::new(ret_scheme, opt_scheme, buckets, 1).unwrap();
+                dbase.push_batch(tuples.clone());
+                dbase
+            };
+
+            let mut parallel = new_db();
+            parallel.encode().unwrap();
+
+            // Every bucket is laid out as if it were encoded on its own
+            let mut serial = new_db();
+
+            for bucket in serial.get_buckets_mut() {
+                bucket.encode().unwrap();
+            }
+
+            for (p, s) in parallel.get_buckets().zip(serial.get_buckets()) {
+                assert_eq!(p.mid_labels(), s.mid_labels());
+
+                for (pc, sc) in p.get_collections().zip(s.get_collections()) {
+                    assert!(pc.get_tuples().eq(sc.get_tuples()));
+                    assert_eq!(pc.get_bloom().to_bytes(), sc.get_bloom().to_bytes());
+                }
+            }
+
+            // Each bucket reports its own outcome
+            let outcomes = parallel.encode_each();
+            assert_eq!(outcomes.len(), buckets);
+            assert!(outcomes.iter().all(|o| *o == Err(db::EncodeError::AlreadyEncoded)));
+        }
+    }
+}
+
+#[test]
+fn tuple_xor() {
+    let num = 100;
+    let mut tuples = Vec::with_capacity(2 * num);
+    create_tuples(2 * num, &mut tuples, None);
+
+    for pair in tuples.chunks(2) {
+        // Byte by byte, for reference
+        let mut expected = pair[0].clone();
+
+        for (e, b) in expected.data.iter_mut().zip(pair[1].data.iter()) {
+            *e ^= *b;
+        }
+
+        let xored = &pair[0] ^ &pair[1];
+        assert!(xored.full_eq(&expected));
+
+        let mut assigned = pair[0].clone();
+        assigned ^= pair[1].clone();
+        assert!(assigned.full_eq(&expected));
+
+        // XORing twice gives the tuple back
+        assert!((&xored ^ &pair[1]).full_eq(&pair[0]));
+    }
+}
+
+#[test]
+fn save_and_load() {
+    let num = 500;
+    let buckets = 4;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    let path = std::env::temp_dir().join(format!("pung-db-save-{}.bin", std::process::id()));
+    let schemes = [db::OptScheme::Normal, db::OptScheme::Hybrid2, db::OptScheme::Hybrid4];
+
+    for &scheme in &schemes {
+        for &encode in &[false, true] {
+            let mut dbase = db::Database::new(db::RetScheme::Tree, scheme, buckets, 1).unwrap();
+
+            // Every other tuple expires after a round
+            let with_ttls = tuples.iter().enumerate().map(|(i, t)| (t.clone(), (i % 2) as u32));
+            dbase.push_batch_with_ttl(with_ttls);
+
+            if encode {
+                dbase.encode().unwrap();
+            }
+
+            dbase.save_to(&path).unwrap();
+            let mut loaded =
+                db::Database::load_from(&path, db::RetScheme::Tree, scheme, 1).unwrap();
+
+            assert_eq!(loaded.num_buckets(), buckets);
+            assert_eq!(loaded.len(), dbase.len());
+            assert_eq!(loaded.unencoded_len(), num);
+
+            // Every collection is laid out as it was (and is still to be encoded if it was not)
+            for (l, d) in loaded.get_buckets().zip(dbase.get_buckets()) {
+                for (lc, dc) in l.get_collections().zip(d.get_collections()) {
+                    assert!(lc.get_tuples().zip(dc.get_tuples()).all(|(a, b)| a.full_eq(b)));
+                    assert_eq!(lc.len(), dc.len());
+                }
+            }
+
+            assert_eq!(loaded.encode().is_ok(), !encode);
+
+            // and the TTLs are kept
+            assert_eq!(loaded.evict_expired(1), Ok(num / 2));
+        }
+    }
+
+    // Anything else is rejected
+    std::fs::write(&path, b"not a database").unwrap();
+    assert!(db::Database::load_from(&path, db::RetScheme::Tree, db::OptScheme::Normal, 1).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn unsupported_config() {
+    let ret_schemes = [db::RetScheme::Explicit, db::RetScheme::Bloom, db::RetScheme::Tree];
+    let opt_schemes = [
+        db::OptScheme::Normal,
+        db::OptScheme::Aliasing,
+        db::OptScheme::Hybrid2,
+        db::OptScheme::Hybrid4,
+    ];
+
+    // Every pair of schemes is supported
+    for &ret_scheme in &ret_schemes {
+        for &opt_scheme in &opt_schemes {
+            assert!(db::check_config(ret_scheme, opt_scheme, 1).is_ok());
+            assert!(db::Bucket::new(ret_scheme, opt_scheme, 1).is_ok());
+            assert!(db::Database::new(ret_scheme, opt_scheme, 2, 1).is_ok());
+
+            // PIR needs at least one level of recursion
+            assert!(db::Bucket::new(ret_scheme, opt_scheme, 0).is_err());
+        }
+    }
+}
+
+#[test]
+fn bloom_hashes() {
+    let num = 1000;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    for &hashes in &[None, Some(1), Some(3)] {
+        let mut bucket = db::Bucket::new(db::RetScheme::Bloom, db::OptScheme::Normal, 1).unwrap();
+        bucket.set_bloom_hashes(hashes);
+
+        for tuple in &tuples {
+            bucket.push(tuple.clone());
+        }
+
+        bucket.encode().unwrap();
+
+        let collection = bucket.get_collection(0);
+        let bloom = collection.get_bloom();
+
+        let optimal = db::new_bloom(num, None).number_of_hash_functions();
+        assert_eq!(bloom.number_of_hash_functions(), hashes.unwrap_or(optimal));
+
+        // A client told the same number of hash functions reads the same filter
+        let mut client_bloom = db::new_bloom(num, hashes);
+        client_bloom.from_bytes(&bloom.to_bytes());
+
+        for i in 0..num {
+            assert!(client_bloom.check((i, collection.get_label(i))));
+        }
+
+        // Filters keep their size, whatever the number of hash functions
+        assert_eq!(bloom.number_of_bits(), db::new_bloom(num, None).number_of_bits());
+    }
+
+    // The number of hash functions carries over to the databases of later rounds
+    let mut dbase = db::Database::new(db::RetScheme::Bloom, db::OptScheme::Hybrid2, 1, 1).unwrap();
+    dbase.set_bloom_hashes(Some(3));
+    dbase.take();
+
+    for tuple in &tuples {
+        dbase.push(0, tuple.clone());
+    }
+
+    dbase.encode().unwrap();
+
+    for collection in dbase.get_bucket(0).get_collections().take(2) {
+        assert_eq!(collection.get_bloom().number_of_hash_functions(), 3);
+    }
+}
+
+#[test]
+fn encode_twice() {
+    let num = 11;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    let mut bucket = db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Hybrid2, 1).unwrap();
+
+    for tuple in &tuples {
+        bucket.push(tuple.clone());
+    }
+
+    bucket.encode().unwrap();
+    let summary = bucket.layout_summary();
+
+    // Encoding again would split the already split tuples; the bucket is left as it was
+    assert_eq!(bucket.encode(), Err(db::EncodeError::AlreadyEncoded));
+    assert_eq!(bucket.layout_summary(), summary);
+
+    // Pushing more tuples does not make an encoded bucket encodable either
+    bucket.push(tuples[0].clone());
+    assert_eq!(bucket.encode(), Err(db::EncodeError::AlreadyEncoded));
+
+    // A cleared bucket can be encoded again
+    bucket.clear();
+
+    for tuple in &tuples {
+        bucket.push(tuple.clone());
+    }
+
+    bucket.encode().unwrap();
+    assert_eq!(bucket.layout_summary(), summary);
+
+    // Databases stop at the first bucket that cannot be encoded
+    let mut dbase =
+        db::Database::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2, 1).unwrap();
+    dbase.encode().unwrap();
+    assert_eq!(dbase.encode(), Err(db::EncodeError::AlreadyEncoded));
+
+    dbase.clear();
+    dbase.encode().unwrap();
+}
+
+#[test]
+fn colliding_labels() {
+    let num = 9;
+
+    let mut tuples = Vec::with_capacity(num);
+    create_tuples(num, &mut tuples, None);
+
+    // A tuple with the same label as another but a different ciphertext
+    let mut twin = tuples[4].clone();
+    twin.data[db::LABEL_SIZE] ^= 1;
+
+    assert!(twin == tuples[4]);
+    assert!(!twin.full_eq(&tuples[4]));
+    assert_ne!(twin.full_cmp(&tuples[4]), std::cmp::Ordering::Equal);
+    assert!(tuples[4].full_eq(&tuples[4].clone()));
+
+    let schemes = [db::OptScheme::Normal, db::OptScheme::Hybrid2, db::OptScheme::Hybrid4];
+
+    for &scheme in &schemes {
+        let mut layouts = Vec::new();
+
+        // The twins are pushed in either order
+        for &twin_first in &[false, true] {
+            let mut bucket = db::Bucket::new(db::RetScheme::Explicit, scheme, 1).unwrap();
+
+            if twin_first {
+                bucket.push(twin.clone());
+            }
+
+            for tuple in &tuples {
+                bucket.push(tuple.clone());
+            }
+
+            if !twin_first {
+                bucket.push(twin.clone());
+            }
+
+            bucket.encode().unwrap();
+
+            // Both survive encoding
+            let mut encoded = Vec::new();
+
+            for i in pung::util::label_collections(scheme) {
+                encoded.extend(bucket.get_collection(i).get_tuples().map(|t| t.to_binary()));
+            }
+
+            assert_eq!(encoded.len(), num + 1);
+            assert!(encoded.contains(&twin.to_binary()));
+            assert!(encoded.contains(&tuples[4].to_binary()));
+
+            // and the collision is counted
+            assert_eq!(bucket.duplicate_labels(), 1);
+
+            if scheme == db::OptScheme::Normal {
+                assert!(bucket.get_collection(0).has_duplicate_labels());
+            }
+
+            layouts.push(encoded);
+        }
+
+        // in the same place, whatever the order in which they arrived
+        assert_eq!(layouts[0], layouts[1]);
+    }
+
+    // Without the twin, there is no collision
+    let mut bucket = db::Bucket::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1).unwrap();
+
+    for tuple in &tuples {
+        bucket.push(tuple.clone());
+    }
+
+    bucket.encode().unwrap();
+    assert_eq!(bucket.duplicate_labels(), 0);
+    assert!(!bucket.get_collection(0).has_duplicate_labels());
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn serialize() {
+    let mut tuples = Vec::new();
+    create_tuples(3, &mut tuples, None);
+
+    let json = serde_json::to_string(&tuples).unwrap();
+    let back: Vec<db::PungTuple> = serde_json::from_str(&json).unwrap();
+
+    assert_eq!(back.len(), tuples.len());
+    assert!(back.iter().zip(&tuples).all(|(b, t)| b.full_eq(t)));
+
+    // A tuple must be exactly TUPLE_SIZE bytes
+    assert!(serde_json::from_str::<db::PungTuple>("[1, 2, 3]").is_err());
+
+    let long = serde_json::to_string(&vec![0u8; db::TUPLE_SIZE + 1]).unwrap();
+    assert!(serde_json::from_str::<db::PungTuple>(&long).is_err());
+
+    let schemes = (db::RetScheme::Bloom, db::OptScheme::Hybrid4);
+    let json = serde_json::to_string(&schemes).unwrap();
+    assert_eq!(json, r#"["Bloom","Hybrid4"]"#);
+    assert_eq!(serde_json::from_str::<(db::RetScheme, db::OptScheme)>(&json).unwrap(), schemes);
+}
@@ -0,0 +1,249 @@
+extern crate capnp;
+extern crate capnp_rpc;
+extern crate gj;
+extern crate gjio;
+extern crate pung;
+extern crate timely;
+
+mod common;
+
+use pung::client::metrics::{PungMetrics, RpcKind};
+use pung::client::{ClientConfig, PungClient};
+use pung::db;
+use pung::server::ServerConfig;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::thread;
+
+
+const RET_SCHEMES: [db::RetScheme; 3] =
+    [db::RetScheme::Explicit, db::RetScheme::Bloom, db::RetScheme::Tree];
+
+const OPT_SCHEMES: [db::OptScheme; 5] = [
+    db::OptScheme::Normal,
+    db::OptScheme::Aliasing,
+    db::OptScheme::Hybrid2,
+    db::OptScheme::Hybrid4,
+    db::OptScheme::Hybrid8,
+];
+
+const BUCKETS: u32 = 4;
+const ROUNDS: u64 = 2;
+
+// Alice and Bob write to each other for a few rounds, through a server that pads its
+// database with random tuples. Each checks that it gets exactly the other's messages back.
+fn exchange(ret_scheme: db::RetScheme, opt_scheme: db::OptScheme) {
+    let mut opts = ServerConfig::new(ret_scheme, opt_scheme, BUCKETS as usize);
+    opts.expected_clients = 2;
+    opts.extra_tuples = 20;
+
+    let addr = common::spawn_server(opts);
+
+    let handles: Vec<_> = [("alice", "bob"), ("bob", "alice")]
+        .iter()
+        .map(|&(name, peer)| {
+            let addr = addr.clone();
+
+            thread::spawn(move || {
+                common::run_client(|scope, port| {
+                    let mut client = PungClient::with_config(
+                        ClientConfig {
+                            ret_rate: BUCKETS,
+                            ret_scheme: ret_scheme,
+                            opt_scheme: opt_scheme,
+                            ..ClientConfig::new(name, &addr)
+                        },
+                        scope,
+                        port,
+                    )?;
+
+                    client.init_dummy_peer();
+                    client.add_peer_with_secret(peer, b"end to end");
+                    client.register(scope, port)?;
+                    client.sync(scope, port)?;
+
+                    for round in 0..ROUNDS {
+                        let msg = format!("{} to {}, round {}", name, peer, round);
+                        client.send(peer, &mut vec![msg.into_bytes()], scope, port)?;
+
+                        // The other buckets are queried with dummy requests, which must not
+                        // turn up anything
+                        let msgs = client.retr(&[peer], scope, port)?;
+                        let expected = format!("{} to {}, round {}", peer, name, round);
+
+                        assert_eq!(
+                            msgs.len(),
+                            1,
+                            "{:?}/{:?}: {} got {} messages in round {}",
+                            ret_scheme,
+                            opt_scheme,
+                            name,
+                            msgs.len(),
+                            round
+                        );
+                        assert!(
+                            msgs[0].starts_with(expected.as_bytes()),
+                            "{:?}/{:?}: {} got the wrong message in round {}",
+                            ret_scheme,
+                            opt_scheme,
+                            name,
+                            round
+                        );
+
+                        // Padding of the plaintext is zeros
+                        assert!(msgs[0][expected.len()..].iter().all(|b| *b == 0));
+
+                        client.inc_round(1);
+                        client.sync(scope, port)?;
+                    }
+
+                    Ok(())
+                });
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn every_scheme_pair() {
+    for &ret_scheme in &RET_SCHEMES {
+        for &opt_scheme in &OPT_SCHEMES {
+            exchange(ret_scheme, opt_scheme);
+        }
+    }
+}
+
+// Counts the PIR queries that a client sends before it decodes an answer, i.e., the queries
+// that are in flight at once
+#[derive(Default)]
+struct InFlight {
+    sent: Cell<usize>,
+    bursts: RefCell<Vec<usize>>,
+}
+
+impl PungMetrics for InFlight {
+    fn record_upload(&self, kind: RpcKind, bytes: usize) {
+        // Every query is larger than the 16 bytes that a retrBatch request adds to its queries
+        if kind == RpcKind::Pir && bytes > 16 {
+            self.sent.set(self.sent.get() + 1);
+        }
+    }
+
+    fn record_download(&self, kind: RpcKind, _bytes: usize, _uncompressed: usize) {
+        if kind == RpcKind::Pir && self.sent.get() > 0 {
+            self.bursts.borrow_mut().push(self.sent.get());
+            self.sent.set(0);
+        }
+    }
+}
+
+// Checks that the (bucket, collection, level, len) PIR queries of a retrieval, in the order
+// they were made, went out in the given bursts (see InFlight) so that nothing waited for an
+// answer it did not need: every burst holds queries for a single level, to every bucket that
+// is queried at that level (and thus to both workers).
+fn check_bursts(queries: &[(usize, u32, u32, u64)], bursts: &[usize], what: &str) {
+    assert_eq!(bursts.iter().sum::<usize>(), queries.len(), "{}: {:?}", what, bursts);
+
+    let mut start = 0;
+
+    for &size in bursts {
+        let burst = &queries[start..start + size];
+        start += size;
+
+        let level = burst[0].2;
+        assert!(burst.iter().all(|q| q.2 == level), "{}: {:?}", what, burst);
+
+        let buckets: HashSet<usize> = burst.iter().map(|q| q.0).collect();
+        let expected: HashSet<usize> =
+            queries.iter().filter(|q| q.2 == level).map(|q| q.0).collect();
+        assert_eq!(buckets, expected, "{}: level {} of {:?}", what, level, burst);
+
+        // Every bucket has a level 0 (the padding leaves none empty)
+        if level == 0 {
+            assert_eq!(buckets.len(), BUCKETS as usize, "{}: {:?}", what, burst);
+        }
+    }
+}
+
+// Like exchange, but the server has two workers and each client spreads its PIR requests over
+// both, with every request that does not depend on another's answer in flight at once (see
+// check_bursts). Alice registers (and sends) with worker 0, and Bob with worker 1, so that
+// each worker has a sender to end its send phase.
+fn sharded_exchange(ret_scheme: db::RetScheme, opt_scheme: db::OptScheme) {
+    let mut opts = ServerConfig::new(ret_scheme, opt_scheme, BUCKETS as usize);
+    opts.extra_tuples = 40;
+
+    let addrs = common::spawn_workers(opts, 2);
+
+    let handles: Vec<_> = [("alice", "bob", 0), ("bob", "alice", 1)]
+        .iter()
+        .map(|&(name, peer, home)| {
+            let addrs = addrs.clone();
+
+            thread::spawn(move || {
+                common::run_client(|scope, port| {
+                    let mut client = PungClient::with_config(
+                        ClientConfig {
+                            ret_rate: BUCKETS,
+                            ret_scheme: ret_scheme,
+                            opt_scheme: opt_scheme,
+                            workers: vec![addrs[1 - home].clone()],
+                            ..ClientConfig::new(name, &addrs[home])
+                        },
+                        scope,
+                        port,
+                    )?;
+
+                    client.init_dummy_peer();
+                    client.add_peer_with_secret(peer, b"sharded");
+                    client.register(scope, port)?;
+                    client.sync(scope, port)?;
+
+                    let in_flight = Rc::new(InFlight::default());
+                    client.set_metrics(Box::new(in_flight.clone()));
+
+                    for round in 0..ROUNDS {
+                        let schemes = format!("{:?}/{:?}", ret_scheme, opt_scheme);
+                        let what = format!("{}: {} in round {}", schemes, name, round);
+
+                        let msg = format!("{} to {}, round {}", name, peer, round);
+                        client.send(peer, &mut vec![msg.into_bytes()], scope, port)?;
+
+                        let msgs = client.retr(&[peer], scope, port)?;
+                        let expected = format!("{} to {}, round {}", peer, name, round);
+
+                        assert_eq!(msgs.len(), 1, "{}", what);
+                        assert!(msgs[0].starts_with(expected.as_bytes()), "{}", what);
+
+                        let bursts = in_flight.bursts.borrow_mut().split_off(0);
+                        check_bursts(&client.last_queries(), &bursts, &what);
+
+                        client.inc_round(1);
+                        client.sync(scope, port)?;
+                    }
+
+                    Ok(())
+                });
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn sharded_over_workers() {
+    for &ret_scheme in &RET_SCHEMES {
+        for &opt_scheme in &OPT_SCHEMES {
+            sharded_exchange(ret_scheme, opt_scheme);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+extern crate pung;
+extern crate rand;
+
+use pung::client::pcrypto;
+use pung::db;
+use pung::proto;
+use rand::ChaChaRng;
+
+
+#[test]
+fn mac_round_trip() {
+    let keys = pcrypto::derive_keys(b"secret", pcrypto::AeadAlgorithm::default());
+    let data = b"length header";
+
+    let tag = pcrypto::mac(&keys.k_mac[..], &data[..]);
+    assert_eq!(tag.len(), 32);
+    assert!(pcrypto::verify_mac(&keys.k_mac[..], &data[..], &tag[..]));
+
+    // The mac key is distinct from the other keys
+    assert!(keys.k_mac != keys.k_e);
+    assert!(keys.k_mac != keys.k_l);
+    assert!(keys.k_mac != keys.k_l2);
+}
+
+#[test]
+fn mac_tamper() {
+    let keys = pcrypto::derive_keys(b"secret", pcrypto::AeadAlgorithm::default());
+    let other_keys = pcrypto::derive_keys(b"other secret", pcrypto::AeadAlgorithm::default());
+    let data = b"length header";
+
+    let mut tag = pcrypto::mac(&keys.k_mac[..], &data[..]);
+
+    // Wrong data
+    assert!(!pcrypto::verify_mac(&keys.k_mac[..], b"length headeR", &tag[..]));
+
+    // Wrong key
+    assert!(!pcrypto::verify_mac(&other_keys.k_mac[..], &data[..], &tag[..]));
+
+    // Truncated tag
+    assert!(!pcrypto::verify_mac(&keys.k_mac[..], &data[..], &tag[..16]));
+
+    // Flipped bit in tag
+    tag[0] ^= 1;
+    assert!(!pcrypto::verify_mac(&keys.k_mac[..], &data[..], &tag[..]));
+}
+
+#[test]
+fn aead_round_trip() {
+    let algorithms = [
+        pcrypto::AeadAlgorithm::ChaCha20Poly1305,
+        pcrypto::AeadAlgorithm::AesGcm,
+    ];
+
+    for &aead in &algorithms {
+        let keys = pcrypto::derive_keys(b"secret", aead);
+        let message = b"hello";
+
+        for round in 0..3 {
+            let (c, mac) = pcrypto::encrypt(aead, &keys.k_e[..], round, 0, 0, &message[..]);
+            assert_eq!(c.len(), db::CIPHER_SIZE);
+            assert_eq!(mac.len(), db::MAC_SIZE);
+
+            // Messages are zero-padded
+            let m = pcrypto::decrypt(aead, &keys.k_e[..], round, 0, 0, &c[..], &mac[..]).unwrap();
+            assert_eq!(m.len(), pcrypto::MESSAGE_SIZE);
+            assert!(m.starts_with(&message[..]));
+            assert!(m[message.len()..].iter().all(|b| *b == 0));
+
+            // Wrong round
+            let k_e = &keys.k_e[..];
+            assert!(pcrypto::decrypt(aead, k_e, round + 1, 0, 0, &c[..], &mac[..]).is_err());
+
+            // Flipped bit in ciphertext
+            let mut tampered = c.clone();
+            tampered[0] ^= 1;
+            assert!(pcrypto::decrypt(aead, k_e, round, 0, 0, &tampered[..], &mac[..]).is_err());
+        }
+    }
+}
+
+#[test]
+fn aead_nonce_per_message() {
+    let algorithms = [
+        pcrypto::AeadAlgorithm::ChaCha20Poly1305,
+        pcrypto::AeadAlgorithm::AesGcm,
+    ];
+
+    for &aead in &algorithms {
+        let keys = pcrypto::derive_keys(b"secret", aead);
+
+        // The same message sent several times in a round (by either peer) is encrypted
+        // under a different nonce every time, and only decrypts with its own
+        let sent = [(0, 0), (0, 1), (0, 2), (1, 0)];
+        let mut ciphertexts = Vec::new();
+
+        for &(uid, idx) in &sent {
+            let (c, mac) = pcrypto::encrypt(aead, &keys.k_e[..], 5, uid, idx, b"hello");
+            assert!(!ciphertexts.contains(&c));
+
+            for &(other_uid, other_idx) in &sent {
+                let m = pcrypto::decrypt(aead, &keys.k_e[..], 5, other_uid, other_idx, &c, &mac);
+                assert_eq!(m.is_ok(), (other_uid, other_idx) == (uid, idx));
+            }
+
+            ciphertexts.push(c);
+        }
+    }
+}
+
+#[test]
+fn aead_deterministic_nonces() {
+    let algorithms = [
+        pcrypto::AeadAlgorithm::ChaCha20Poly1305,
+        pcrypto::AeadAlgorithm::AesGcm,
+    ];
+
+    for &aead in &algorithms {
+        let keys = pcrypto::derive_keys(b"secret", aead);
+
+        // Nonces are derived from the round, uid, and message number (so that the recipient
+        // can derive them too), so encrypting the same message twice gives the same ciphertext
+        let (c1, mac1) = pcrypto::encrypt(aead, &keys.k_e[..], 5, 1, 2, b"hello");
+        let (c2, mac2) = pcrypto::encrypt(aead, &keys.k_e[..], 5, 1, 2, b"hello");
+        assert_eq!(c1, c2);
+        assert_eq!(mac1, mac2);
+
+        // Rounds beyond 2^32 are fine under AES-GCM, whose nonces have room for all 64 bits
+        if aead == pcrypto::AeadAlgorithm::AesGcm {
+            let round = (1 << 32) + 5;
+            let (c, mac) = pcrypto::encrypt(aead, &keys.k_e[..], round, 1, 2, b"hello");
+            assert!(c != c1);
+            assert!(pcrypto::decrypt(aead, &keys.k_e[..], round, 1, 2, &c, &mac).is_ok());
+            assert!(pcrypto::decrypt(aead, &keys.k_e[..], 5, 1, 2, &c, &mac).is_err());
+        }
+    }
+}
+
+#[test]
+fn aead_algorithms_differ() {
+    let chacha = pcrypto::AeadAlgorithm::ChaCha20Poly1305;
+    let aes = pcrypto::AeadAlgorithm::AesGcm;
+    let keys = pcrypto::derive_keys(b"secret", chacha);
+
+    // Ciphertexts of one algorithm do not decrypt under the other
+    let (c, mac) = pcrypto::encrypt(chacha, &keys.k_e[..], 0, 0, 0, b"hello");
+    assert!(pcrypto::decrypt(aes, &keys.k_e[..], 0, 0, 0, &c[..], &mac[..]).is_err());
+
+    let (c, mac) = pcrypto::encrypt(aes, &keys.k_e[..], 0, 0, 0, b"hello");
+    assert!(pcrypto::decrypt(chacha, &keys.k_e[..], 0, 0, 0, &c[..], &mac[..]).is_err());
+}
+
+#[test]
+fn label_prfs() {
+    let prfs = [
+        pcrypto::LabelPrf::HmacSha256,
+        pcrypto::LabelPrf::Blake2b,
+        pcrypto::LabelPrf::Blake2s,
+    ];
+
+    let keys = pcrypto::derive_keys(b"secret", pcrypto::AeadAlgorithm::default());
+    let other_keys = pcrypto::derive_keys(b"other secret", pcrypto::AeadAlgorithm::default());
+
+    // HMAC-SHA256 is the default
+    assert_eq!(
+        pcrypto::gen_label(&keys.k_l[..], 1, 2, 3, 4),
+        pcrypto::gen_label_with(pcrypto::LabelPrf::default(), &keys.k_l[..], 1, 2, 3, 4)
+    );
+
+    let mut all_labels = Vec::new();
+
+    for &prf in &prfs {
+        assert_eq!(prf.output_bytes(), db::LABEL_SIZE);
+
+        let label = |key: &[u8], round, uid, msg_num, iter| {
+            pcrypto::gen_label_with(prf, key, round, uid, msg_num, iter)
+        };
+
+        // Deterministic
+        assert_eq!(label(&keys.k_l[..], 1, 2, 3, 4), label(&keys.k_l[..], 1, 2, 3, 4));
+
+        // Distinct for distinct inputs (each of them) and keys
+        let labels = vec![
+            label(&keys.k_l[..], 1, 2, 3, 4),
+            label(&keys.k_l[..], 0, 2, 3, 4),
+            label(&keys.k_l[..], 1, 0, 3, 4),
+            label(&keys.k_l[..], 1, 2, 0, 4),
+            label(&keys.k_l[..], 1, 2, 3, 0),
+            label(&keys.k_l2[..], 1, 2, 3, 4),
+            label(&other_keys.k_l[..], 1, 2, 3, 4),
+        ];
+
+        for (i, l) in labels.iter().enumerate() {
+            assert_eq!(l.len(), db::LABEL_SIZE);
+            assert!(labels[i + 1..].iter().all(|other| other != l));
+        }
+
+        all_labels.push(labels[0].clone());
+    }
+
+    // PRFs do not agree with each other
+    assert!(all_labels[0] != all_labels[1]);
+    assert!(all_labels[0] != all_labels[2]);
+    assert!(all_labels[1] != all_labels[2]);
+}
+
+#[test]
+fn encryptor_reuse() {
+    let aead = pcrypto::AeadAlgorithm::default();
+    let keys = pcrypto::derive_keys(b"secret", aead);
+    let mut encryptor = pcrypto::Encryptor::new();
+
+    let mut c = vec![0u8; pcrypto::MESSAGE_SIZE];
+    let mut mac = vec![0u8; db::MAC_SIZE];
+
+    // A long message followed by a short one: the padding of the latter must not keep
+    // anything from the former
+    let long = vec![7u8; pcrypto::MESSAGE_SIZE];
+    let short = b"hello";
+
+    for message in &[&long[..], &short[..]] {
+        encryptor.encrypt_into(aead, &keys.k_e[..], 3, 1, 2, message, &mut c[..], &mut mac[..]);
+
+        // Same output as the allocating version
+        let (expected_c, expected_mac) = pcrypto::encrypt(aead, &keys.k_e[..], 3, 1, 2, message);
+        assert_eq!(c, expected_c);
+        assert_eq!(mac, expected_mac);
+
+        let m = pcrypto::decrypt(aead, &keys.k_e[..], 3, 1, 2, &c[..], &mac[..]).unwrap();
+        assert!(m.starts_with(message));
+        assert!(m[message.len()..].iter().all(|b| *b == 0));
+    }
+}
+
+#[test]
+fn dh_agree() {
+    let mut rng = ChaChaRng::new_unseeded();
+
+    let alice = pcrypto::DhKeyPair::generate(&mut rng);
+    let bob = pcrypto::DhKeyPair::generate(&mut rng);
+    let eve = pcrypto::DhKeyPair::generate(&mut rng);
+
+    assert_eq!(alice.public_key().len(), proto::DH_KEY_SIZE);
+    assert!(alice.public_key() != bob.public_key());
+
+    // Both sides agree, and nobody else does
+    let ab = pcrypto::dh_agree(&alice, bob.public_key()).unwrap();
+    assert_eq!(ab, pcrypto::dh_agree(&bob, alice.public_key()).unwrap());
+    assert!(ab != pcrypto::dh_agree(&eve, bob.public_key()).unwrap());
+    assert!(ab != pcrypto::dh_agree(&alice, eve.public_key()).unwrap());
+
+    // ...and so do the keys derived from it
+    let aead = pcrypto::AeadAlgorithm::default();
+    let k1 = pcrypto::derive_keys(&ab, aead);
+    let k2 = pcrypto::derive_keys(&pcrypto::dh_agree(&bob, alice.public_key()).unwrap(), aead);
+    assert_eq!(k1.k_l, k2.k_l);
+    assert_eq!(k1.k_e, k2.k_e);
+
+    // A client may agree with itself (e.g., for notes to self)
+    assert!(pcrypto::dh_agree(&alice, alice.public_key()).is_ok());
+
+    // Malformed and small-order public keys are rejected
+    assert!(pcrypto::dh_agree(&alice, &[]).is_err());
+    assert!(pcrypto::dh_agree(&alice, &bob.public_key()[1..]).is_err());
+    assert!(pcrypto::dh_agree(&alice, &[0u8; proto::DH_KEY_SIZE]).is_err());
+
+    let mut one = [0u8; proto::DH_KEY_SIZE];
+    one[0] = 1;
+    assert!(pcrypto::dh_agree(&alice, &one).is_err());
+}
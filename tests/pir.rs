@@ -5,6 +5,7 @@ use std::mem;
 use pung::pir::pir_client::PirClient;
 use pung::pir::pir_server::PirServer;
 use pung::db::PungTuple;
+use pung::util;
 use rand::Rng;
 
 
@@ -33,20 +34,20 @@ fn pir_decode() {
     let truth = collection.clone();
 
     // Create the client
-    let client = PirClient::new(1, 1, alpha, d);
+    let client = PirClient::new(1, 1, alpha, d).unwrap();
 
     let first = 0;
     let last = 1;
     let test_num = last - first;
 
-    let server = PirServer::new(&collection[first..last], alpha, d);
+    let server = PirServer::new(&collection[first..last], alpha, d).unwrap();
     client.update_params(get_size!(PungTuple), test_num as u64, alpha);
 
 //    for i in 0..test_num {
     {
-        let query = client.gen_query(0 as u64);
-        let answer = server.gen_answer(query.query, query.num);
-        let result = client.decode_answer(answer.answer, answer.num);
+        let query = client.gen_query(0 as u64).unwrap();
+        let answer = server.gen_answer(query.query, query.num).unwrap();
+        let result = client.decode_answer(answer.answer, answer.num).unwrap();
         assert!(PungTuple::new(result.result) == truth[first + 0 as usize]);
     }
 
@@ -54,14 +55,14 @@ fn pir_decode() {
     let last = 3;
     let test_num = last - first;
 
-    let server_2 = PirServer::new(&collection[first..last], alpha, d);
+    let server_2 = PirServer::new(&collection[first..last], alpha, d).unwrap();
     client.update_params(get_size!(PungTuple), test_num as u64, alpha);
 
 //    for i in 0..test_num {
     {
-        let query = client.gen_query(1 as u64);
-        let answer = server_2.gen_answer(query.query, query.num);
-        let result = client.decode_answer(answer.answer, answer.num);
+        let query = client.gen_query(1 as u64).unwrap();
+        let answer = server_2.gen_answer(query.query, query.num).unwrap();
+        let result = client.decode_answer(answer.answer, answer.num).unwrap();
         assert!(PungTuple::new(result.result) == truth[first + 1 as usize]);
     }
 
@@ -70,17 +71,199 @@ fn pir_decode() {
     let test_num = last - first;
 
 
-    let server_3 = PirServer::new(&collection[first..last], alpha, d);
+    let server_3 = PirServer::new(&collection[first..last], alpha, d).unwrap();
     client.update_params(get_size!(PungTuple), test_num as u64, alpha);
 
 //    for i in 0..test_num {
     {
-        let query = client.gen_query(2 as u64);
-        let answer = server_3.gen_answer(query.query, query.num);
-        let result = client.decode_answer(answer.answer, answer.num);
+        let query = client.gen_query(2 as u64).unwrap();
+        let answer = server_3.gen_answer(query.query, query.num).unwrap();
+        let result = client.decode_answer(answer.answer, answer.num).unwrap();
         assert!(PungTuple::new(result.result) == truth[first + 2 as usize]);
     }
 
 
 
 }
+
+#[test]
+fn pir_autotune() {
+    let num = 16;
+    let mut collection: Vec<PungTuple> = Vec::new();
+
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..num {
+        let mut x: [u8; 286] = [0; 286];
+        rng.fill_bytes(&mut x);
+        collection.push(PungTuple::new(&x));
+    }
+
+    let (alpha, d) = PirServer::autotune(num as u64, get_size!(PungTuple));
+    assert!(alpha >= 1 && alpha <= num as u64);
+    assert!(d == 1 || d == 2);
+
+    // Results are cached
+    assert_eq!(PirServer::autotune(num as u64, get_size!(PungTuple)), (alpha, d));
+
+    // The chosen parameters work end to end
+    let server = PirServer::new(&collection, alpha, d).unwrap();
+    let client = PirClient::new(get_size!(PungTuple), num as u64, alpha, d).unwrap();
+
+    for idx in &[0, 7, 15] {
+        let query = client.gen_query(*idx as u64).unwrap();
+        let answer = server.gen_answer(query.query, query.num).unwrap();
+        let result = client.decode_answer(answer.answer, answer.num).unwrap();
+        assert!(PungTuple::new(result.result) == collection[*idx as usize]);
+    }
+}
+
+#[test]
+fn pir_check_query() {
+    let num = 16;
+    let alpha = 8;
+    let d = 1;
+    let mut collection: Vec<PungTuple> = Vec::new();
+
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..num {
+        let mut x: [u8; 286] = [0; 286];
+        rng.fill_bytes(&mut x);
+        collection.push(PungTuple::new(&x));
+    }
+
+    let server = PirServer::new(&collection[..], alpha, d).unwrap();
+    let client = PirClient::new(get_size!(PungTuple), num as u64, alpha, d).unwrap();
+
+    // Queries generated by clients are accepted
+    let query = client.gen_query(3).unwrap();
+    assert_eq!(server.expected_query().unwrap(), (query.query.len() as u64, query.num));
+    assert!(server.check_query(query.query, query.num, 1.0).is_ok());
+
+    // Oversized queries are rejected (without being processed)
+    let oversized = vec![0u8; query.query.len() * 4];
+    assert!(server.check_query(&oversized[..], query.num, 2.0).is_err());
+    assert!(server.check_query(query.query, query.num * 4, 2.0).is_err());
+    assert!(server.check_query(&oversized[..], query.num, 4.0).is_ok());
+
+    // Nothing to query in an empty collection
+    let empty = PirServer::new(&collection[..0], 1, d).unwrap();
+    assert!(empty.check_query(query.query, query.num, 2.0).is_err());
+}
+
+#[test]
+fn pir_depth_per_level() {
+    let max_depth = 2;
+    let mut rng = rand::thread_rng();
+
+    assert_eq!(util::get_depth(1, max_depth), 1);
+    assert_eq!(util::get_depth(util::MIN_RECURSIVE_PIR_LEN - 1, max_depth), 1);
+    assert_eq!(util::get_depth(util::MIN_RECURSIVE_PIR_LEN, max_depth), max_depth);
+    assert_eq!(util::get_depth(util::MIN_RECURSIVE_PIR_LEN, 1), 1);
+
+    let client = PirClient::new(1, 1, 1, max_depth).unwrap();
+
+    // Sizes of BST levels, some below and some above the threshold
+    for &num in &[1, 2, 8, util::MIN_RECURSIVE_PIR_LEN, 2 * util::MIN_RECURSIVE_PIR_LEN] {
+        let mut collection: Vec<PungTuple> = Vec::new();
+
+        for _ in 0..num {
+            let mut x: [u8; 286] = [0; 286];
+            rng.fill_bytes(&mut x);
+            collection.push(PungTuple::new(&x));
+        }
+
+        // Server and client derive alpha and depth from the level size alone
+        let alpha = util::get_alpha(num);
+        let depth = util::get_depth(num, max_depth);
+        let server = PirServer::new(&collection[..], alpha, depth).unwrap();
+        client.update_params_with_depth(get_size!(PungTuple), num, alpha, depth);
+
+        assert_eq!(server.depth(), depth);
+
+        let idx = num - 1;
+        let query = client.gen_query(idx).unwrap();
+        assert!(server.check_query(query.query, query.num, 1.0).is_ok());
+
+        let answer = server.gen_answer(query.query, query.num).unwrap();
+        let result = client.decode_answer(answer.answer, answer.num).unwrap();
+        assert!(PungTuple::new(result.result) == collection[idx as usize]);
+    }
+}
+
+#[test]
+fn pir_check_answer() {
+    let num = 16;
+    let alpha = 8;
+    let d = 1;
+    let mut collection: Vec<PungTuple> = Vec::new();
+
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..num {
+        let mut x: [u8; 286] = [0; 286];
+        rng.fill_bytes(&mut x);
+        collection.push(PungTuple::new(&x));
+    }
+
+    let server = PirServer::new(&collection[..], alpha, d).unwrap();
+    let client = PirClient::new(get_size!(PungTuple), num as u64, alpha, d).unwrap();
+
+    // Answers from a server with the same parameters are accepted
+    let query = client.gen_query(5).unwrap();
+    let answer = server.gen_answer(query.query, query.num).unwrap();
+    assert_eq!(client.expected_answer_len(), answer.answer.len() as u64);
+    assert!(client.check_answer(answer.answer, answer.num).is_ok());
+
+    // Truncated or padded answers are rejected before decoding
+    let len = answer.answer.len();
+    assert!(client.check_answer(&answer.answer[..len - 1], answer.num).is_err());
+
+    let mut padded = answer.answer.to_vec();
+    padded.extend_from_slice(&answer.answer[..]);
+    assert!(client.check_answer(&padded[..], answer.num).is_err());
+    assert!(client.check_answer(&padded[..], answer.num * 2).is_err());
+
+    // So are answers meant for other parameters (here, much larger tuples)
+    client.update_params(64 * get_size!(PungTuple), num as u64, alpha);
+    assert!(client.expected_answer_len() > len as u64);
+    assert!(client.check_answer(answer.answer, answer.num).is_err());
+}
+
+// Server and client derive the alpha of a level from its size and the configured alpha alone
+// (see util::pir_alpha), so answers decode whatever the override, including on levels with
+// fewer elements than it
+#[test]
+fn pir_alpha_override() {
+    let mut rng = rand::thread_rng();
+
+    let client = PirClient::new(1, 1, 1, 1).unwrap();
+
+    for &num in &[1u64, 2, 8, 100] {
+        let mut collection: Vec<PungTuple> = Vec::new();
+
+        for _ in 0..num {
+            let mut x: [u8; 286] = [0; 286];
+            rng.fill_bytes(&mut x);
+            collection.push(PungTuple::new(&x));
+        }
+
+        for &override_alpha in &[None, Some(1), Some(4), Some(64)] {
+            let alpha = util::pir_alpha(num, override_alpha);
+            assert!(alpha >= 1 && alpha <= num);
+
+            let server = PirServer::new(&collection[..], alpha, 1).unwrap();
+            client.update_params(get_size!(PungTuple), num, alpha);
+            assert_eq!(server.alpha(), alpha);
+
+            let idx = num - 1;
+            let query = client.gen_query(idx).unwrap();
+            let answer = server.gen_answer(query.query, query.num).unwrap();
+            assert!(client.check_answer(answer.answer, answer.num).is_ok());
+
+            let result = client.decode_answer(answer.answer, answer.num).unwrap();
+            assert!(PungTuple::new(result.result) == collection[idx as usize]);
+        }
+    }
+}
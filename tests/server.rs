@@ -0,0 +1,1141 @@
+extern crate capnp;
+extern crate capnp_rpc;
+extern crate gj;
+extern crate gjio;
+extern crate pung;
+extern crate timely;
+
+mod common;
+
+use pung::client;
+use pung::client::pcrypto;
+use pung::client::{ClientConfig, PungClient};
+use pung::db;
+use pung::proto;
+use pung::server::key_dir;
+use pung::server::{RpcLimit, ServerConfig};
+use pung::util;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::process;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+// Registers, syncs, and sends a single message to itself. Reports on `done` once the
+// send phase is over (i.e., the send RPC returned).
+fn spawn_sender(addr: String, name: String, done: mpsc::Sender<String>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig::new(&name, &addr),
+                &[(&name[..], "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs = vec![name.clone().into_bytes()];
+            client.send(&name, &mut msgs, scope, port)?;
+
+            done.send(name.clone()).unwrap();
+            Ok(())
+        });
+    })
+}
+
+#[test]
+fn expected_clients_gate_first_round() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.expected_clients = 3;
+    opts.min_messages = 1;
+
+    let addr = common::spawn_server(opts);
+    let (tx, rx) = mpsc::channel();
+
+    spawn_sender(addr.clone(), "user1".to_string(), tx.clone());
+    spawn_sender(addr.clone(), "user2".to_string(), tx.clone());
+
+    // Two out of three clients have sent: the send phase must not be over yet
+    assert!(rx.recv_timeout(Duration::from_millis(1000)).is_err());
+
+    spawn_sender(addr.clone(), "user3".to_string(), tx.clone());
+
+    // Once the third client participates, everyone's send completes
+    for _ in 0..3 {
+        rx.recv_timeout(Duration::from_secs(60)).unwrap();
+    }
+}
+
+// Registers a client, changes the extra tuples, and reports whether the change succeeded
+fn change_extra(addr: &str, extra: u64) -> bool {
+    let mut success = false;
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("admin", addr), scope, port)?;
+
+        client.register(scope, port)?;
+        success = client.extra(extra, scope, port).is_ok();
+        Ok(())
+    });
+
+    success
+}
+
+#[test]
+fn change_extra_disabled_by_default() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    assert!(!change_extra(&addr, 10));
+}
+
+#[test]
+fn change_extra_rejected_during_send_phase() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.allow_change_extra = true;
+    opts.min_messages = 2; // keeps the send phase open after a single message
+
+    let addr = common::spawn_server(opts);
+
+    // Allowed while the round is clean
+    assert!(change_extra(&addr, 10));
+
+    // Start a send that stays in flight
+    let (tx, rx) = mpsc::channel();
+    spawn_sender(addr.clone(), "user1".to_string(), tx);
+    assert!(rx.recv_timeout(Duration::from_millis(1000)).is_err());
+
+    assert!(!change_extra(&addr, 20));
+}
+
+#[test]
+fn retr_past_round() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.retain_rounds = 1;
+
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        client.set_history(1);
+
+        // Round 0: send a message but skip retrieving it
+        let mut msgs = vec![b"hello from round 0".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+        assert!(client.retr(&[], scope, port)?.is_empty());
+
+        client.inc_round(1);
+        client.sync(scope, port)?;
+        assert_eq!(client.get_round(), 1);
+
+        // Round 1: catch up on round 0
+        let msgs = client.retr_round(&["user1"], 0, scope, port)?;
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].starts_with(b"hello from round 0")); // messages are padded
+
+        Ok(())
+    });
+}
+
+#[cfg(feature = "pir-timing")]
+#[test]
+fn pir_timings_recorded() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        assert!(client.stats(scope, port)?.pir_timings.is_empty());
+
+        let mut msgs = vec![b"hello".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+        client.retr(&["user1"], scope, port)?;
+
+        let stats = client.stats(scope, port)?;
+        assert_eq!(stats.pir_timings.len(), 1);
+
+        let timing = &stats.pir_timings[0];
+        assert_eq!(timing.bucket_size, 1);
+        assert_eq!(timing.level, 0);
+        assert_eq!(timing.latency.count, 1);
+        assert_eq!(timing.latency.buckets.iter().sum::<u64>(), 1);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn ttl_evicts_retained_tuples() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    opts.retain_rounds = 3;
+
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 2,
+                ret_rate: 2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        client.set_history(3);
+
+        // Round 0: send a message that lives for 1 round and one that lives for 3
+        let mut msgs = vec![b"ttl one".to_vec(), b"ttl three".to_vec()];
+        client.send_with_ttl("user1", &mut msgs, &[1, 3], scope, port)?;
+        client.retr(&[], scope, port)?;
+
+        client.inc_round(1);
+        client.sync(scope, port)?;
+
+        // Round 1: only the TTL-3 message is left
+        let msgs = client.retr_round(&["user1", "user1"], 0, scope, port)?;
+        assert_eq!(msgs.len(), 1);
+        assert!(msgs[0].starts_with(b"ttl three"));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn oversized_query_rejected() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let conn = common::connect(&addr, scope, port)?;
+
+        let mut request = conn.register_request();
+        request.get().set_rate(1);
+        let id = request.send().promise.wait(scope, port)?.get()?.get_id();
+
+        let mut request = conn.sync_request();
+        request.get().set_id(id);
+        request.send().promise.wait(scope, port)?;
+
+        // A single tuple ends the send phase
+        let mut request = conn.send_request();
+        request.get().set_id(id);
+        request.get().set_round(0);
+        request.get().init_tuples(1).set(0, &[7u8; db::TUPLE_SIZE][..]);
+        request.send().promise.wait(scope, port)?;
+
+        // A query far beyond what a client would generate for a 1-tuple collection
+        let oversized = vec![0u8; 16 * 1024 * 1024];
+
+        let mut request = conn.retr_request();
+        request.get().set_id(id);
+        request.get().set_round(0);
+        request.get().set_query(&oversized[..]);
+        request.get().set_qnum(1);
+
+        match request.send().promise.wait(scope, port) {
+            Ok(_) => panic!("oversized query was answered"),
+            Err(e) => assert!(e.description.contains("larger than expected")),
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn send_round_bounds() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.send_lookahead = 2;
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        // One honest round, so that round 0 is in the past
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        let mut msgs = vec![b"hello".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+        client.retr(&["user1"], scope, port)?;
+
+        let conn = common::connect(&addr, scope, port)?;
+
+        let mut request = conn.register_request();
+        request.get().set_rate(1);
+        let id = request.send().promise.wait(scope, port)?.get()?.get_id();
+
+        let mut request = conn.sync_request();
+        request.get().set_id(id);
+        let round = request.send().promise.wait(scope, port)?.get()?.get_round();
+        assert_eq!(round, 1);
+
+        let request = |round: u64| {
+            let mut request = conn.send_request();
+            request.get().set_id(id);
+            request.get().set_round(round);
+            request.get().init_tuples(1).set(0, &[7u8; db::TUPLE_SIZE][..]);
+            request
+        };
+
+        let send = |round: u64, port: &mut gjio::EventPort| {
+            request(round).send().promise.wait(scope, port)
+        };
+
+        match send(0, port) {
+            Ok(_) => panic!("send for a past round was accepted"),
+            Err(e) => assert!(e.description.contains("in the past")),
+        }
+
+        // The current round is 1, so sends may be queued for rounds 2 and 3 but not 4
+        match send(4, port) {
+            Ok(_) => panic!("send too far ahead was accepted"),
+            Err(e) => assert!(e.description.contains("too far ahead")),
+        }
+
+        // A send for round 3 is queued (and only answered once round 3 ends), but the client
+        // may not queue more than its send rate for that round
+        let _queued = request(3).send();
+
+        match send(3, port) {
+            Ok(_) => panic!("send beyond the rate was queued"),
+            Err(e) => assert!(e.description.contains("Send rate exceeded")),
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn malformed_tuple_rejected() {
+    for &opt_scheme in &[db::OptScheme::Normal, db::OptScheme::Aliasing] {
+        let opts = ServerConfig::new(db::RetScheme::Explicit, opt_scheme, 1);
+        let addr = common::spawn_server(opts);
+
+        // With aliasing, a tuple carries a second label
+        let len = if opt_scheme >= db::OptScheme::Aliasing {
+            db::TUPLE_SIZE + db::LABEL_SIZE
+        } else {
+            db::TUPLE_SIZE
+        };
+
+        common::run_client(|scope, port| {
+            let conn = common::connect(&addr, scope, port)?;
+
+            let mut request = conn.register_request();
+            request.get().set_rate(1);
+            let id = request.send().promise.wait(scope, port)?.get()?.get_id();
+
+            let mut request = conn.sync_request();
+            request.get().set_id(id);
+            let round = request.send().promise.wait(scope, port)?.get()?.get_round();
+
+            let send = |round: u64, data: &[u8], port: &mut gjio::EventPort| {
+                let mut request = conn.send_request();
+                request.get().set_id(id);
+                request.get().set_round(round);
+                request.get().init_tuples(1).set(0, data);
+                request.send().promise.wait(scope, port)
+            };
+
+            // Too short and too long, both for this round and queued for the next one
+            for &bad_len in &[0, db::LABEL_SIZE, len - 1, len + 1] {
+                for &r in &[round, round + 1] {
+                    match send(r, &vec![7u8; bad_len][..], port) {
+                        Ok(_) => panic!("tuple of {} bytes was accepted", bad_len),
+                        Err(e) => assert!(e.description.contains("Invalid tuple length")),
+                    }
+                }
+            }
+
+            // The server is still up, and the rejected sends did not use up the send rate
+            let mut request = conn.ping_request();
+            request.get().set_nonce(42);
+            request.send().promise.wait(scope, port)?;
+
+            send(round, &vec![7u8; len][..], port)?;
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn stats_report_database_size() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig {
+                send_rate: 2,
+                ..ClientConfig::new("user1", &addr)
+            },
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        let mut msgs = vec![b"one".to_vec(), b"two".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        // Normal adds no encoding overhead
+        let stats = client.stats(scope, port)?;
+        assert_eq!(stats.tuples, 2);
+        assert_eq!(stats.unencoded_tuples, 2);
+        assert_eq!(stats.encoding_overhead_ratio(), 1.0);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn debug_lookup() {
+    for &debug in &[false, true] {
+        let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+        opts.debug = debug;
+
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 2,
+                    ret_rate: 2,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let mut msgs = vec![b"one".to_vec(), b"two".to_vec()];
+            client.send("user1", &mut msgs, scope, port)?;
+
+            // Labels of the tuples just sent (the client is its own peer, so its uid is 0)
+            let keys = pcrypto::derive_keys(b"secret", pcrypto::AeadAlgorithm::default());
+            let partitions = util::Partitioning::new(2).unwrap();
+            let label = pcrypto::gen_label(&keys.k_l[..], 0, 0, 1, 0);
+            let bucket = partitions.bucket_of(&label);
+
+            if !debug {
+                assert!(client.debug_lookup(bucket, &label, scope, port).is_err());
+                return Ok(());
+            }
+
+            let (collection, _) = client.debug_lookup(bucket, &label, scope, port)?.unwrap();
+            assert_eq!(collection, 0);
+
+            // Not in the other bucket, and a random label is nowhere
+            assert!(client.debug_lookup(1 - bucket, &label, scope, port)?.is_none());
+
+            let random = [42u8; db::LABEL_SIZE];
+            for b in 0..2 {
+                assert!(client.debug_lookup(b, &random[..], scope, port)?.is_none());
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn debug_dump() {
+    for &debug in &[false, true] {
+        let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+        opts.debug = debug;
+
+        let addr = common::spawn_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 3,
+                    ret_rate: 2,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            let sent = vec![b"one".to_vec(), b"two".to_vec(), b"three".to_vec()];
+            let mut msgs = sent.clone();
+            client.send("user1", &mut msgs, scope, port)?;
+
+            if !debug {
+                assert!(client.retr_bucket_dump(0, scope, port).is_err());
+                return Ok(());
+            }
+
+            assert!(client.retr_bucket_dump(2, scope, port).is_err());
+
+            let dumps = vec![
+                client.retr_bucket_dump(0, scope, port)?,
+                client.retr_bucket_dump(1, scope, port)?,
+            ];
+
+            assert_eq!(dumps[0].len() + dumps[1].len(), sent.len());
+
+            // Each message is in the bucket its label maps to, and decrypts to what was sent
+            // (the client is its own peer, so its uid is 0)
+            let keys = pcrypto::derive_keys(b"secret", pcrypto::AeadAlgorithm::default());
+            let partitions = util::Partitioning::new(2).unwrap();
+
+            for (i, msg) in sent.iter().enumerate() {
+                let label = pcrypto::gen_label(&keys.k_l[..], 0, 0, i as u64, 0);
+                let bucket = partitions.bucket_of(&label);
+
+                let tuple = dumps[bucket].iter().find(|t| t.label() == &label[..]).unwrap();
+                let (c, mac) = (tuple.cipher(), tuple.mac());
+                let plain =
+                    pcrypto::decrypt(keys.aead, &keys.k_e[..], 0, 0, i as u64, c, mac).unwrap();
+
+                assert!(plain.starts_with(msg));
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn register_validates_rates() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 2);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let conn = common::connect(&addr, scope, port)?;
+
+        // (send rate, retrieval rate, buckets, accepted)
+        let cases = [
+            (0, 0, 0, false),
+            (1, 1, 0, false),
+            (1, 0, 3, false),
+            (1, 0, 0, true),
+            (1, 2, 2, true),
+            (1, 4, 2, true),
+        ];
+
+        for &(rate, ret_rate, buckets, accepted) in &cases {
+            let mut request = conn.register_request();
+            request.get().set_rate(rate);
+            request.get().set_ret_rate(ret_rate);
+            request.get().set_buckets(buckets);
+
+            assert_eq!(request.send().promise.wait(scope, port).is_ok(), accepted);
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn ping() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        // The nonce is echoed back, with no need to register
+        let conn = common::connect(&addr, scope, port)?;
+
+        for &nonce in &[0, 42, u64::max_value()] {
+            let mut request = conn.ping_request();
+            request.get().set_nonce(nonce);
+
+            let response = request.send().promise.wait(scope, port)?;
+            assert_eq!(response.get()?.get_nonce(), nonce);
+
+            // The server's clock is this process's clock
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let server_time = response.get()?.get_time() / 1000;
+            assert!(server_time + 10 > now && server_time < now + 10);
+        }
+
+        let client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+
+        let rtt = client.ping(scope, port)?;
+        assert!(rtt < Duration::from_secs(10));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn status() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+
+        // Callable before registering
+        let status = client.status(scope, port)?;
+        assert_eq!(status.round, 0);
+        assert_eq!(status.phase, util::stats::Phase::Sending);
+        assert_eq!(status.clients, 0);
+        assert_eq!(status.tuples, 0);
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        let mut msgs = vec![b"hello".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        let status = client.status(scope, port)?;
+        assert_eq!(status.round, 0);
+        assert_eq!(status.phase, util::stats::Phase::Receiving);
+        assert_eq!(status.clients, 1);
+        assert_eq!(status.tuples, 1);
+        assert_eq!(status.total_dbs, 1);
+
+        // Asking for the status does not count as a retrieval, so the retrieval still ends
+        // the round
+        let retrieved = client.retr(&["user1"], scope, port)?;
+        assert_eq!(retrieved.len(), 1);
+
+        let status = client.status(scope, port)?;
+        assert_eq!(status.round, 1);
+        assert_eq!(status.phase, util::stats::Phase::Sending);
+
+        Ok(())
+    });
+}
+
+#[test]
+fn retr_batch_bounds() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let conn = common::connect(&addr, scope, port)?;
+
+        let mut request = conn.register_request();
+        request.get().set_rate(1);
+        let id = request.send().promise.wait(scope, port)?.get()?.get_id();
+
+        let mut request = conn.sync_request();
+        request.get().set_id(id);
+        request.send().promise.wait(scope, port)?;
+
+        // The only client sends its only tuple, which ends the send phase. The round's
+        // database has a single collection, so the client has a single retrieval.
+        let mut request = conn.send_request();
+        request.get().set_id(id);
+        request.get().set_round(0);
+        request.get().init_tuples(1).set(0, &[7u8; db::TUPLE_SIZE][..]);
+        request.send().promise.wait(scope, port)?;
+
+        let batch = |queries: u32, verify: bool, port: &mut gjio::EventPort| {
+            let mut request = conn.retr_batch_request();
+            request.get().set_id(id);
+            request.get().set_round(0);
+            request.get().set_bucket(0);
+            request.get().set_verify(verify);
+            request.get().init_queries(queries);
+            request.send().promise.wait(scope, port).map(|_| ())
+        };
+
+        match batch(0, false, port) {
+            Ok(_) => panic!("empty batch was accepted"),
+            Err(e) => assert!(e.description.contains("Empty batch")),
+        }
+
+        match batch(2, false, port) {
+            Ok(_) => panic!("batch beyond the retrieval rate was accepted"),
+            Err(e) => assert!(e.description.contains("rate exceeded")),
+        }
+
+        match batch(1, true, port) {
+            Ok(_) => panic!("verification was accepted without --debug"),
+            Err(e) => assert!(e.description.contains("verification is disabled")),
+        }
+
+        // None of the rejected batches counted as retrievals
+        let response = conn.status_request().send().promise.wait(scope, port)?;
+        assert_eq!(response.get()?.get_round(), 0);
+
+        Ok(())
+    });
+}
+
+// Runs a server with extra tuples drawn with the given seed, sends a message, and returns
+// everything in the (single) bucket once the send phase is over
+fn seeded_round(tuple_seed: Option<u64>) -> Vec<db::PungTuple> {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.extra_tuples = 3;
+    opts.debug = true;
+    opts.tuple_seed = tuple_seed;
+
+    let addr = common::spawn_server(opts);
+    let mut tuples = Vec::new();
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        let mut msgs = vec![b"hello".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        tuples = client.retr_bucket_dump(0, scope, port)?;
+        Ok(())
+    });
+
+    assert_eq!(tuples.len(), 4);
+    tuples
+}
+
+#[test]
+fn tuple_seed() {
+    // The client's own tuple is the same on every server, so dumps only differ in padding
+    assert!(seeded_round(Some(7)) == seeded_round(Some(7)));
+    assert!(seeded_round(Some(7)) != seeded_round(Some(8)));
+    assert!(seeded_round(None) != seeded_round(None));
+}
+
+#[test]
+fn max_connections() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.max_connections = Some(2);
+    let addr = common::spawn_server(opts);
+
+    // Connections through the proxy can be closed while the test holds on to them
+    let proxy = common::Proxy::new(&addr);
+
+    common::run_client(|scope, port| {
+        let ping = |conn: &pung::pung_capnp::pung_rpc::Client, port: &mut gjio::EventPort| {
+            let mut request = conn.ping_request();
+            request.get().set_nonce(7);
+            request.send().promise.wait(scope, port).is_ok()
+        };
+
+        let first = common::connect(&proxy.addr, scope, port)?;
+        let second = common::connect(&proxy.addr, scope, port)?;
+        assert!(ping(&first, port));
+        assert!(ping(&second, port));
+
+        // The third connection is refused while the other two are open
+        let third = common::connect(&addr, scope, port)?;
+        assert!(!ping(&third, port));
+
+        // Closing them makes room for new connections
+        proxy.cut();
+        thread::sleep(Duration::from_millis(250));
+
+        let fourth = common::connect(&addr, scope, port)?;
+        assert!(ping(&fourth, port));
+
+        Ok(())
+    });
+}
+
+#[test]
+fn max_pending_connections() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.max_pending = Some(2);
+    let addr = common::spawn_server(opts);
+
+    // Connections that never register (or send anything at all)
+    let idle: Vec<TcpStream> = (0..10).map(|_| TcpStream::connect(&addr).unwrap()).collect();
+    thread::sleep(Duration::from_millis(250));
+
+    // The server only keeps the first two, and closes the rest as soon as it accepts them
+    let mut closed = 0;
+
+    for mut stream in &idle {
+        stream.set_read_timeout(Some(Duration::from_millis(250))).unwrap();
+        let mut buf = [0; 8];
+
+        if let Ok(0) = stream.read(&mut buf) {
+            closed += 1;
+        }
+    }
+
+    assert_eq!(closed, idle.len() - 2);
+
+    common::run_client(|scope, port| {
+        let refused = common::connect(&addr, scope, port)?;
+        let mut request = refused.ping_request();
+        request.get().set_nonce(7);
+        assert!(request.send().promise.wait(scope, port).is_err());
+
+        // Closing the idle connections makes room for new ones
+        drop(idle);
+        thread::sleep(Duration::from_millis(250));
+
+        // Connections that synced no longer count, so there is no limit on those
+        let mut clients = Vec::new();
+
+        for i in 0..4 {
+            let name = format!("user{}", i);
+            let client = common::registered_client(
+                ClientConfig::new(&name, &addr),
+                &[],
+                scope,
+                port,
+            )?;
+            clients.push(client);
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn sync_updates_rate() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", b"secret");
+        client.register(scope, port)?;
+
+        assert!(client.set_send_rate(0).is_err());
+
+        // The rate changes from one round to the next without registering again
+        for &rate in &[1, 3, 2] {
+            client.set_send_rate(rate)?;
+            client.sync(scope, port)?;
+
+            let mut too_many: Vec<Vec<u8>> = (0..rate + 1).map(|i| vec![i as u8]).collect();
+            assert!(client.send("user1", &mut too_many, scope, port).is_err());
+
+            let mut msgs: Vec<Vec<u8>> =
+                (0..rate).map(|i| format!("msg {} of {}", i, rate).into_bytes()).collect();
+            client.send("user1", &mut msgs, scope, port)?;
+
+            let msgs = client.retr(&["user1"], scope, port)?;
+            assert_eq!(msgs.len(), 1);
+            assert!(msgs[0].starts_with(format!("msg 0 of {}", rate).as_bytes()));
+
+            client.inc_round(1);
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn dump_db_after_round() {
+    let path = env::temp_dir().join(format!("pung-db-dump-{}.txt", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.dump_db_after_round = Some(path.clone());
+    let addr = common::spawn_server(opts);
+    let secret = b"secret";
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+
+        client.init_dummy_peer();
+        client.add_peer_with_secret("user1", secret);
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        // The send phase (and thus the dump) is over once the send returns
+        let mut msgs = vec![b"hello".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        Ok(())
+    });
+
+    let keys = pcrypto::derive_keys(secret, pcrypto::AeadAlgorithm::default());
+    let label = pcrypto::gen_label(&keys.k_l, 0, 0, 0, 0);
+
+    let expected = format!(
+        "round 0\nbucket 0: 1 tuples\n  collection 0: 1 tuples (systematic), first label {}\n",
+        util::secret::to_hex(&label)
+    );
+
+    let mut dump = String::new();
+    fs::File::open(&path).unwrap().read_to_string(&mut dump).unwrap();
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(dump, expected);
+}
+
+#[test]
+fn key_directory_save_and_load() {
+    let path = env::temp_dir().join(format!("pung-keys-save-{}.bin", process::id()));
+
+    let mut keys = HashMap::new();
+    keys.insert("alice".to_string(), vec![1u8; proto::DH_KEY_SIZE]);
+    keys.insert("bob".to_string(), vec![2u8; proto::DH_KEY_SIZE]);
+
+    key_dir::save(&keys, &path).unwrap();
+    assert_eq!(key_dir::load(&path).unwrap(), keys);
+
+    // An empty directory is a directory too
+    key_dir::save(&HashMap::new(), &path).unwrap();
+    assert!(key_dir::load(&path).unwrap().is_empty());
+
+    // Anything else is rejected, including a directory that was cut short
+    fs::write(&path, b"not a key directory").unwrap();
+    assert!(key_dir::load(&path).is_err());
+
+    key_dir::save(&keys, &path).unwrap();
+    let mut data = fs::read(&path).unwrap();
+    data.pop();
+    fs::write(&path, &data).unwrap();
+    assert!(key_dir::load(&path).is_err());
+
+    fs::remove_file(&path).unwrap();
+}
+
+// A server started with the key file of another one lists the keys registered with the first
+// (as after a restart)
+#[test]
+fn key_file_survives_restart() {
+    let path = env::temp_dir().join(format!("pung-keys-restart-{}.bin", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.key_file = Some(path.clone());
+    let addr = common::spawn_server(opts.clone());
+
+    common::run_client(|scope, port| {
+        let mut alice = PungClient::with_config(ClientConfig::new("alice", &addr), scope, port)?;
+        alice.init_dummy_peer();
+        alice.register(scope, port)?;
+
+        // Alice is still connected (and thus listed) when the second server loads the file
+        let restarted = common::spawn_server(opts.clone());
+        let bob = PungClient::with_config(ClientConfig::new("bob", &restarted), scope, port)?;
+
+        assert_eq!(bob.fetch_key("alice", scope, port)?, alice.public_key());
+
+        Ok(())
+    });
+
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn unreadable_key_file() {
+    let path = env::temp_dir().join(format!("pung-keys-bad-{}.bin", process::id()));
+    fs::write(&path, b"not a key directory").unwrap();
+
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.key_file = Some(path.clone());
+
+    // The server reports the bad file instead of panicking (or starting without the keys)
+    let addr = format!("127.0.0.1:{}", common::free_port()).parse().unwrap();
+    let dbase = opts.database().unwrap();
+    let result = pung::server::run_direct(addr, Rc::new(RefCell::new(dbase)), opts);
+
+    let _ = fs::remove_file(&path);
+    assert!(result.is_err());
+}
+
+#[test]
+fn without_timely() {
+    let schemes = [
+        (db::RetScheme::Explicit, db::OptScheme::Normal),
+        (db::RetScheme::Bloom, db::OptScheme::Hybrid2),
+        (db::RetScheme::Explicit, db::OptScheme::Hybrid4),
+    ];
+
+    for &(ret_scheme, opt_scheme) in &schemes {
+        let opts = ServerConfig::new(ret_scheme, opt_scheme, 2);
+        let addr = common::spawn_direct_server(opts);
+
+        common::run_client(|scope, port| {
+            let mut client = common::registered_client(
+                ClientConfig {
+                    send_rate: 2,
+                    ret_rate: 2,
+                    ret_scheme: ret_scheme,
+                    opt_scheme: opt_scheme,
+                    ..ClientConfig::new("user1", &addr)
+                },
+                &[("user1", "secret")],
+                scope,
+                port,
+            )?;
+
+            // Clients cannot tell the difference, round after round
+            for round in 0..2 {
+                let first = format!("first of round {}", round).into_bytes();
+                let second = format!("second of round {}", round).into_bytes();
+                let outgoing = [("user1", &first[..]), ("user1", &second[..])];
+
+                let msgs = client.round_trip(&outgoing, &["user1", "user1"], scope, port)?;
+                assert_eq!(msgs.len(), 2);
+                assert!(msgs.iter().any(|m| m.starts_with(&first)));
+                assert!(msgs.iter().any(|m| m.starts_with(&second)));
+            }
+
+            Ok(())
+        });
+    }
+}
+
+#[test]
+fn round_timeout_pads_stalled_client() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.expected_clients = 2;
+    opts.round_timeout = Some(Duration::from_millis(500));
+
+    let addr = common::spawn_server(opts);
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+    // Registers and syncs with a rate of 3, but never sends (until the test is over)
+    let staller_addr = addr.clone();
+    thread::spawn(move || {
+        common::run_client(|scope, port| {
+            let mut client = PungClient::with_config(
+                ClientConfig {
+                    send_rate: 3,
+                    ..ClientConfig::new("staller", &staller_addr)
+                },
+                scope,
+                port,
+            )?;
+
+            client.register(scope, port)?;
+            client.sync(scope, port)?;
+
+            ready_tx.send(()).unwrap();
+            let _ = stop_rx.recv();
+            Ok(())
+        });
+    });
+
+    ready_rx.recv_timeout(Duration::from_secs(60)).unwrap();
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        // Completes once the send phase times out, with the staller's tuples as padding
+        let mut msgs = vec![b"hello".to_vec()];
+        client.send("user1", &mut msgs, scope, port)?;
+
+        let stats = client.stats(scope, port)?;
+        assert_eq!(stats.tuples, 4);
+
+        let retrieved = client.retr(&["user1"], scope, port)?;
+        assert_eq!(retrieved.len(), 1);
+        assert!(retrieved[0].starts_with(b"hello"));
+
+        Ok(())
+    });
+
+    drop(stop_tx);
+}
+
+#[test]
+fn disconnected_client_does_not_stall_round() {
+    let opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    let addr = common::spawn_server(opts);
+
+    // Registers and syncs (so the round waits for its message), and then hangs up
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("leaver", &addr), scope, port)?;
+
+        client.register(scope, port)?;
+        client.sync(scope, port)?;
+
+        Ok(())
+    });
+
+    common::run_client(|scope, port| {
+        let mut client = common::registered_client(
+            ClientConfig::new("user1", &addr),
+            &[("user1", "secret")],
+            scope,
+            port,
+        )?;
+
+        // Both phases of the round end without the client that left, and so does the next round
+        for round in 0..2 {
+            let msg = format!("round {}", round);
+            client.send("user1", &mut vec![msg.clone().into_bytes()], scope, port)?;
+
+            let retrieved = client.retr(&["user1"], scope, port)?;
+            assert_eq!(retrieved.len(), 1);
+            assert!(retrieved[0].starts_with(msg.as_bytes()));
+
+            client.inc_round(1);
+            client.sync(scope, port)?;
+        }
+
+        Ok(())
+    });
+}
+
+#[test]
+fn rpc_limit_throttles_and_recovers() {
+    let mut opts = ServerConfig::new(db::RetScheme::Explicit, db::OptScheme::Normal, 1);
+    opts.rpc_limit = Some(RpcLimit::new(3, 2.0).unwrap());
+
+    let addr = common::spawn_server(opts);
+
+    common::run_client(|scope, port| {
+        let mut client = PungClient::with_config(ClientConfig::new("user1", &addr), scope, port)?;
+
+        // Before registering, calls count against the connection's limit
+        let mut allowed = 0;
+
+        for _ in 0..20 {
+            match client.status(scope, port) {
+                Ok(_) => allowed += 1,
+                Err(e) => {
+                    assert!(client::is_overloaded(&e), "{:?}", e);
+                    break;
+                }
+            }
+        }
+
+        assert!(allowed >= 3 && allowed < 20, "{} calls were allowed", allowed);
+
+        // Tokens come back over time
+        thread::sleep(Duration::from_millis(1000));
+        client.status(scope, port)?;
+
+        // A registered client has its own limit
+        client.register(scope, port)?;
+
+        for _ in 0..3 {
+            client.status(scope, port)?;
+        }
+
+        Ok(())
+    });
+}
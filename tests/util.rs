@@ -0,0 +1,327 @@
+extern crate byteorder;
+extern crate pung;
+extern crate rand;
+
+use pung::db;
+use pung::util;
+use pung::util::rle;
+use rand::{ChaChaRng, Rng};
+
+
+#[test]
+fn retry_bound_is_positive() {
+    for &k in &[0u64, 1, 2, 8, 9, 100, 10000] {
+        let single = util::retry_bound(k);
+        let double = util::retry_bound_choices(k, 2);
+
+        assert!(single >= 1, "retry_bound({}) = {}", k, single);
+        assert!(double >= 1, "retry_bound_choices({}, 2) = {}", k, double);
+
+        // never more rounds than there are buckets (when there are buckets)
+        if k > 0 {
+            assert!(single as u64 <= k, "retry_bound({}) = {}", k, single);
+            assert!(double as u64 <= k, "retry_bound_choices({}, 2) = {}", k, double);
+        }
+    }
+
+    assert_eq!(util::retry_bound(0), 1);
+    assert_eq!(util::retry_bound(1), 1);
+    assert_eq!(util::retry_bound(2), 2);
+    assert_eq!(util::retry_bound(8), 8);
+    assert_eq!(util::retry_bound_choices(0, 2), 1);
+    assert_eq!(util::retry_bound_choices(1, 2), 1);
+    assert_eq!(util::retry_bound_choices(2, 2), 2);
+
+    // a single choice falls back to the standard bound rather than dividing by ln(1) = 0
+    assert_eq!(util::retry_bound_choices(100, 1), util::retry_bound(100));
+    assert_eq!(util::retry_bound_choices(100, 0), util::retry_bound(100));
+}
+
+#[test]
+fn max_retries_is_positive() {
+    let schemes = [
+        db::OptScheme::Normal,
+        db::OptScheme::Aliasing,
+        db::OptScheme::Hybrid2,
+        db::OptScheme::Hybrid4,
+        db::OptScheme::Hybrid8,
+    ];
+
+    for scheme in &schemes {
+        for &k in &[0u64, 1, 2, 8, 9, 100, 10000] {
+            assert!(util::max_retries(*scheme, k) >= 1);
+        }
+    }
+}
+
+#[test]
+fn hybrid8_layout() {
+    // The 8 systematic collections split the bucket, and differ in length by at most one
+    for len in 0..100u64 {
+        let lens: Vec<u64> = (0..8).map(|i| util::collection_len(len, i, 8)).collect();
+        assert_eq!(lens.iter().sum::<u64>(), len);
+        assert!(lens.iter().all(|&l| l == len / 8 || l == (len + 7) / 8));
+    }
+
+    // Every parity collection is built from earlier ones, the first of which is not shorter
+    let plan = util::h8_plan();
+    assert_eq!(plan.len(), 19);
+
+    for (i, &(c1, c2)) in plan.iter().enumerate() {
+        assert!(c1 < i + 8 && c2 < i + 8);
+        assert!(util::h8_part_len(13, c1) >= util::h8_part_len(13, c2));
+        assert_eq!(util::h8_part_len(13, i + 8), util::h8_part_len(13, c1));
+    }
+
+    // Each collection's mappings share no part, and the first is the collection itself
+    let mappings = util::h8_mappings();
+    assert_eq!(mappings.len(), 8);
+
+    for (target, sets) in mappings.iter().enumerate() {
+        assert_eq!(sets.len(), 8);
+        assert_eq!(sets[0].iter().cloned().collect::<Vec<_>>(), vec![target]);
+
+        for (i, a) in sets.iter().enumerate() {
+            for b in &sets[i + 1..] {
+                assert!(a.is_disjoint(b));
+            }
+        }
+    }
+}
+
+#[test]
+fn pir_alpha() {
+    for &num in &[0u64, 1, 7, 8, 2048, 100000] {
+        assert_eq!(util::pir_alpha(num, None), util::get_alpha(num));
+    }
+
+    // An override is capped at the number of elements (and is at least 1)
+    assert_eq!(util::pir_alpha(100, Some(8)), 8);
+    assert_eq!(util::pir_alpha(3, Some(8)), 3);
+    assert_eq!(util::pir_alpha(0, Some(8)), 1);
+    assert_eq!(util::pir_alpha(100, Some(1)), 1);
+}
+
+#[test]
+fn rle_round_trip() {
+    let mut rng = ChaChaRng::new_unseeded();
+
+    let mut random = vec![0u8; 4096];
+    rng.fill_bytes(&mut random);
+
+    let mut sparse = vec![0u8; 4096];
+    for i in 0..20 {
+        sparse[i * 200] = 0xff;
+    }
+
+    let inputs: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![7],
+        vec![1, 1],
+        vec![1, 2, 3, 3, 3, 4, 5, 5],
+        vec![9; 1000], // longer than a single run
+        random.clone(),
+        sparse.clone(),
+    ];
+
+    for input in &inputs {
+        let compressed = rle::compress(input);
+        assert_eq!(&rle::decompress(&compressed).unwrap(), input);
+    }
+
+    // Runs shrink and incompressible data only grows slightly
+    assert!(rle::compress(&sparse).len() < sparse.len() / 10);
+    assert!(rle::compress(&random).len() <= random.len() + random.len() / 128 + 1);
+}
+
+#[test]
+fn rle_rejects_truncated_input() {
+    // literal block announces 3 bytes but only has 2
+    assert!(rle::decompress(&[2, 1, 2]).is_err());
+
+    // run block is missing the repeated byte
+    assert!(rle::decompress(&[130]).is_err());
+}
+
+#[test]
+fn partitioning_matches_markers() {
+    let mut rng = ChaChaRng::new_unseeded();
+
+    for &buckets in &[1usize, 2, 3, 7, 16, 100] {
+        // Clients and servers both build the partitioning from the bucket count
+        let client = util::Partitioning::new(buckets).unwrap();
+        let server = util::Partitioning::new(buckets).unwrap();
+        assert_eq!(client, server);
+        assert_eq!(client.num_buckets(), buckets);
+
+        // Boundaries are what label_marker produces
+        let markers: Vec<Vec<u8>> = (0..buckets)
+            .map(|i| util::label_marker(i, buckets).unwrap())
+            .collect();
+        assert_eq!(client.boundaries(), &markers[..]);
+
+        let mut label = [0u8; db::LABEL_SIZE];
+
+        for _ in 0..1000 {
+            rng.fill_bytes(&mut label);
+
+            let idx = client.bucket_of(&label);
+            assert_eq!(idx, server.bucket_of(&label));
+            assert_eq!(idx, util::bucket_idx(&label, &markers));
+        }
+
+        // Labels right at a boundary
+        for marker in &markers {
+            assert_eq!(client.bucket_of(marker), util::bucket_idx(marker, &markers));
+        }
+    }
+}
+
+#[test]
+fn label_cmp_is_big_endian() {
+    use byteorder::{BigEndian, ByteOrder};
+    use std::cmp::Ordering;
+
+    let mut rng = ChaChaRng::new_unseeded();
+    let mut l1 = [0u8; db::LABEL_SIZE];
+    let mut l2 = [0u8; db::LABEL_SIZE];
+
+    for i in 0..1000 {
+        rng.fill_bytes(&mut l1);
+        rng.fill_bytes(&mut l2);
+
+        // Labels that share a prefix differ only in a later word
+        if i % 2 == 0 {
+            let shared = (i / 2) % db::LABEL_SIZE;
+            l2[..shared].copy_from_slice(&l1[..shared]);
+        }
+
+        let words = |l: &[u8]| -> Vec<u64> {
+            (0..db::LABEL_SIZE / 8).map(|w| BigEndian::read_u64(&l[w * 8..])).collect()
+        };
+
+        let ord = util::label_cmp(&l1, &l2);
+        assert_eq!(ord, l1.cmp(&l2));
+        assert_eq!(ord, words(&l1).cmp(&words(&l2)));
+        assert_eq!(util::label_cmp(&l2, &l1), ord.reverse());
+
+        // Tuples compare by label the same way
+        let mut data = vec![0u8; db::TUPLE_SIZE];
+        data[..db::LABEL_SIZE].copy_from_slice(&l1);
+        let tuple = db::PungTuple::new(&data);
+
+        assert_eq!(tuple.lt(&l2), ord == Ordering::Less);
+        assert_eq!(tuple.gt(&l2), ord == Ordering::Greater);
+        assert!(!tuple.lt(&l1) && !tuple.gt(&l1));
+    }
+
+    // Labels that are not LABEL_SIZE long (e.g., an empty lmid) are compared, not read past
+    assert_eq!(util::label_cmp(&[], &l1), Ordering::Less);
+    assert_eq!(util::label_cmp(&l1[..4], &l1), Ordering::Less);
+}
+
+#[test]
+fn labels_above_last_boundary() {
+    // 7 does not divide 2^32 - 1, so the last boundary (0xfffffffc) is below the top of the
+    // label space, and so are labels that start with it (they are longer)
+    let partitions = util::Partitioning::new(7).unwrap();
+    let markers = partitions.boundaries().to_vec();
+    assert_eq!(markers[6], vec![0xff, 0xff, 0xff, 0xfc]);
+
+    let mut label = [0xffu8; db::LABEL_SIZE];
+    assert_eq!(util::bucket_idx(&label, &markers), 6);
+    assert_eq!(partitions.bucket_of(&label), 6);
+
+    label[..4].copy_from_slice(&markers[6]);
+    assert_eq!(util::bucket_idx(&label, &markers), 6);
+    assert_eq!(partitions.bucket_of(&label), 6);
+
+    // The same goes when the last boundary is the top of the space (3 divides 2^32 - 1)
+    let partitions = util::Partitioning::new(3).unwrap();
+    assert_eq!(partitions.boundaries()[2], vec![0xff; 4]);
+    assert_eq!(partitions.bucket_of(&[0xffu8; db::LABEL_SIZE]), 2);
+
+    // Labels at or below a boundary are in its bucket
+    assert_eq!(util::bucket_idx(&[0u8; db::LABEL_SIZE], &markers), 0);
+    assert_eq!(util::bucket_idx(&markers[0], &markers), 0);
+    assert_eq!(util::bucket_idx(&markers[3], &markers), 3);
+    assert_eq!(util::bucket_idx(&[0u8; db::LABEL_SIZE], &[]), 0);
+}
+
+#[test]
+fn label_marker_bounds() {
+    // No buckets is an error rather than a division by zero
+    assert!(util::label_marker(0, 0).is_err());
+    assert!(util::Partitioning::new(0).is_err());
+
+    // A single bucket covers the whole label space
+    assert_eq!(util::label_marker(0, 1).unwrap(), vec![0xff; 4]);
+    assert!(util::label_marker(1, 1).is_err());
+
+    let single = util::Partitioning::new(1).unwrap();
+    assert_eq!(single.num_buckets(), 1);
+    assert_eq!(single.bucket_of(&[0u8; db::LABEL_SIZE]), 0);
+    assert_eq!(single.bucket_of(&[0xffu8; db::LABEL_SIZE]), 0);
+}
+
+#[test]
+fn single_bucket_partitioning() {
+    let single = util::Partitioning::new(1).unwrap();
+
+    // Labels that start with the (4-byte) boundary sort above it but still fall in bucket 0
+    let mut label = [0xffu8; db::LABEL_SIZE];
+    assert_eq!(single.bucket_of(&label), 0);
+
+    label[db::LABEL_SIZE - 1] = 0;
+    assert_eq!(single.bucket_of(&label), 0);
+    assert_eq!(single.bucket_of(&[]), 0);
+}
+
+#[test]
+fn secret_formats() {
+    use pung::util::secret::{self, SecretFormat};
+
+    assert_eq!("raw".parse::<SecretFormat>(), Ok(SecretFormat::Raw));
+    assert_eq!("hex".parse::<SecretFormat>(), Ok(SecretFormat::Hex));
+    assert_eq!("base64".parse::<SecretFormat>(), Ok(SecretFormat::Base64));
+    assert!("b64".parse::<SecretFormat>().is_err());
+
+    // Raw secrets are used verbatim
+    assert_eq!(secret::decode("pass word", SecretFormat::Raw).unwrap(), b"pass word".to_vec());
+    assert_eq!(secret::decode("", SecretFormat::Raw).unwrap(), Vec::<u8>::new());
+
+    // Hex (either case)
+    assert_eq!(secret::decode("00ff10Ab", SecretFormat::Hex).unwrap(), vec![0, 255, 16, 171]);
+    assert_eq!(secret::decode("", SecretFormat::Hex).unwrap(), Vec::<u8>::new());
+
+    let key: Vec<u8> = (0..32).collect();
+    let hex: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(secret::decode(&hex, SecretFormat::Hex).unwrap(), key);
+
+    // Base64 (with 0, 1, and 2 padding characters)
+    assert_eq!(secret::decode("Zm9v", SecretFormat::Base64).unwrap(), b"foo".to_vec());
+    assert_eq!(secret::decode("Zm9vYg==", SecretFormat::Base64).unwrap(), b"foob".to_vec());
+    assert_eq!(secret::decode("Zm9vYmE=", SecretFormat::Base64).unwrap(), b"fooba".to_vec());
+    assert_eq!(
+        secret::decode("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=", SecretFormat::Base64)
+            .unwrap(),
+        key
+    );
+}
+
+#[test]
+fn secret_formats_reject_malformed() {
+    use pung::util::secret::{self, SecretFormat};
+
+    // Odd length and non-hex digits
+    assert!(secret::decode("abc", SecretFormat::Hex).is_err());
+    assert!(secret::decode("zz", SecretFormat::Hex).is_err());
+    assert!(secret::decode("0x00", SecretFormat::Hex).is_err());
+
+    // Bad length, bad characters, and misplaced padding
+    assert!(secret::decode("Zm9", SecretFormat::Base64).is_err());
+    assert!(secret::decode("Zm9v!A==", SecretFormat::Base64).is_err());
+    assert!(secret::decode("Zm=v", SecretFormat::Base64).is_err());
+    assert!(secret::decode("Zg==Zm9v", SecretFormat::Base64).is_err());
+    assert!(secret::decode("Z===", SecretFormat::Base64).is_err());
+}